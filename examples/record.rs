@@ -17,12 +17,11 @@ fn main() {
         raw_path: Some(std::path::PathBuf::from("rbspy-raw.txt")),
         out_path: Some(out_path.clone()),
         pid: process.id() as rbspy::Pid,
-        with_subprocesses: false,
         sample_rate: 99,
         maybe_duration: Some(std::time::Duration::from_secs(1)),
         flame_min_width: 10.0,
         lock_process: true,
-        force_version: None,
+        ..Default::default()
     };
     let recorder = Recorder::new(config);
     match recorder.record() {
@@ -0,0 +1,24 @@
+mod include;
+extern crate rbspy;
+
+use crate::include::path_to_ruby_binary;
+use rbspy::sampler::{start, Config};
+
+fn main() {
+    let mut process = std::process::Command::new(path_to_ruby_binary())
+        .arg("ci/ruby-programs/infinite.rb")
+        .spawn()
+        .unwrap();
+
+    let mut config = Config::new(process.id() as rbspy::Pid);
+    config.time_limit = Some(std::time::Duration::from_secs(1));
+    let (traces, handle) = start(config).expect("sampler failed to start");
+    for trace in traces {
+        println!("{}", trace);
+    }
+    if let Err(e) = handle.wait() {
+        println!("Failed to sample: {:?}", e);
+    }
+
+    process.kill().expect("couldn't clean up ruby process");
+}
@@ -1,14 +1,68 @@
 use anyhow::{anyhow, format_err, Context, Error, Result};
-use remoteprocess::{Process, ProcessMemory};
+use remoteprocess::ProcessMemory;
 use semver::Version;
 use spytools::ProcessInfo;
 
+use crate::core::errors::ErrorCode;
+use crate::core::memory::MemoryReader;
+
 /// Inspect a running Ruby process, finding key memory addresses that are needed for profiling
 pub fn inspect_ruby_process(
-    process: &Process,
+    process: &MemoryReader,
     process_info: &ProcessInfo,
     force_version: Option<String>,
 ) -> Result<(Version, usize, usize, Option<usize>)> {
+    let version = get_ruby_version(process, process_info, force_version)?;
+    let version = match crate::core::ruby_version::compatible_version(&version) {
+        Some(compatible) if compatible != version => {
+            warn!(
+                "rbspy doesn't know about Ruby {} yet, so it's reading the process as Ruby {}, the closest supported release",
+                version, compatible
+            );
+            compatible
+        }
+        Some(version) => version,
+        None => return Err(crate::core::ruby_version::unsupported_version(&version)),
+    };
+
+    let vm_address = match process_info.get_symbol(&ruby_current_vm_symbol(&version)) {
+        Some(addr) => *addr as usize,
+        None => {
+            return Err(anyhow::format_err!("Couldn't find Ruby VM address")
+                .context(ErrorCode::StrippedBinary.error("Couldn't find the Ruby VM")))
+        }
+    };
+    let current_thread_address =
+        get_current_thread_address(process_info, process, &version, vm_address)?;
+    let global_symbols_address = match process_info.get_symbol(&ruby_globals_symbol(&version)) {
+        Some(addr) => Some(*addr as usize),
+        // The global symbols address lookup is allowed to fail (e.g. on older rubies)
+        None => None,
+    };
+
+    let addresses_status = format!(
+        "version: {:x?}\n\
+        current thread address: {:#x?}\n\
+        VM address: {:#x?}\n\
+        global symbols address: {:#x?}\n",
+        version, &current_thread_address, &vm_address, global_symbols_address
+    );
+
+    info!("Ruby VM addresses: {}", addresses_status);
+    return Ok((
+        version,
+        current_thread_address,
+        vm_address,
+        global_symbols_address,
+    ));
+}
+
+/// Finds out which version of Ruby a process is running, unless it's forced
+pub fn get_ruby_version<T: ProcessMemory>(
+    process: &T,
+    process_info: &ProcessInfo,
+    force_version: Option<String>,
+) -> Result<Version> {
     let version = match force_version {
         Some(ref v) => {
             info!("Assuming Ruby version is {}", v);
@@ -23,11 +77,15 @@ pub fn inspect_ruby_process(
                     Some(root_cause)
                         if root_cause.kind() == std::io::ErrorKind::PermissionDenied =>
                     {
-                        return Err(e.context("Failed to initialize due to a permissions error. If you are running rbspy as a normal (non-root) user, please try running it again with `sudo --preserve-env !!`. If you are running it in a container, e.g. with Docker or Kubernetes, make sure that your container has been granted the SYS_PTRACE capability. See the rbspy documentation for more details."));
+                        return Err(e.context(
+                            ErrorCode::PermissionDenied
+                                .error(crate::core::check::permission_error_hint()),
+                        ));
                     }
                     _ => {}
                 }
-                return Err(anyhow::format_err!("Couldn't get ruby version: {:?}", e));
+                return Err(anyhow::format_err!("Couldn't get ruby version: {:?}", e)
+                    .context(ErrorCode::StrippedBinary.error("Couldn't find the Ruby version")));
             };
             let version_addr = version_addr.unwrap();
             let raw_version: [u8; 15] = process
@@ -50,45 +108,18 @@ pub fn inspect_ruby_process(
             version
         }
     };
-
-    let vm_address = match process_info.get_symbol(&ruby_current_vm_symbol(&version)) {
-        Some(addr) => *addr as usize,
-        None => return Err(anyhow::format_err!("Couldn't find Ruby VM address")),
-    };
-    let current_thread_address =
-        get_current_thread_address(process_info, process, &version, vm_address)?;
-    let global_symbols_address = match process_info.get_symbol(&ruby_globals_symbol(&version)) {
-        Some(addr) => Some(*addr as usize),
-        // The global symbols address lookup is allowed to fail (e.g. on older rubies)
-        None => None,
-    };
-
-    let addresses_status = format!(
-        "version: {:x?}\n\
-        current thread address: {:#x?}\n\
-        VM address: {:#x?}\n\
-        global symbols address: {:#x?}\n",
-        version, &current_thread_address, &vm_address, global_symbols_address
-    );
-
-    info!("Ruby VM addresses: {}", addresses_status);
-    return Ok((
-        version,
-        current_thread_address,
-        vm_address,
-        global_symbols_address,
-    ));
+    Ok(version)
 }
 
 fn get_current_thread_address(
     process_info: &ProcessInfo,
-    process: &remoteprocess::Process,
+    process: &MemoryReader,
     version: &Version,
     vm_address: usize,
 ) -> Result<usize> {
     if *version >= Version::new(3, 0, 0) {
         // Current thread is not directly accessible on Ruby 3+, so get it from the VM
-        let get_execution_context = crate::core::ruby_version::get_execution_context(&version);
+        let get_execution_context = crate::core::ruby_version::get_execution_context(&version)?;
         return get_execution_context(0, vm_address, process);
     }
 
@@ -105,7 +136,7 @@ fn get_current_thread_address(
             &[addr as usize],
             &process_info.maps,
             process,
-            crate::core::ruby_version::is_maybe_thread_function(&version),
+            crate::core::ruby_version::is_maybe_thread_function(&version)?,
         ) {
             Ok(addr) => return Ok(addr),
             Err(e) => {
@@ -124,7 +155,7 @@ fn get_current_thread_address(
             binary,
             &process_info.maps,
             process,
-            crate::core::ruby_version::is_maybe_thread_function(&version),
+            crate::core::ruby_version::is_maybe_thread_function(&version)?,
         ) {
             Ok(addr) => return Ok(addr),
             Err(err) => Some(Err(err)),
@@ -139,7 +170,7 @@ fn get_current_thread_address(
             library,
             &process_info.maps,
             process,
-            crate::core::ruby_version::is_maybe_thread_function(&version),
+            crate::core::ruby_version::is_maybe_thread_function(&version)?,
         ) {
             Ok(addr) => return Ok(addr),
             Err(lib_err) => Err(err).unwrap_or(Err(lib_err)),
@@ -155,7 +186,7 @@ use spytools::binary_parser::BinaryInfo;
 fn get_thread_address_from_binary(
     binary: &BinaryInfo,
     maps: &[MapRange],
-    process: &remoteprocess::Process,
+    process: &MemoryReader,
     is_maybe_thread: crate::core::types::IsMaybeThreadFn,
 ) -> Result<usize, Error> {
     // We're going to scan the BSS/data section for things, and try to narrowly scan things that
@@ -177,7 +208,7 @@ fn get_thread_address_from_binary(
 fn check_thread_addresses(
     addrs: &[usize],
     maps: &[MapRange],
-    process: &remoteprocess::Process,
+    process: &MemoryReader,
     is_maybe_thread: crate::core::types::IsMaybeThreadFn,
 ) -> Result<usize, Error> {
     // On windows, we can't just check if a pointer is valid by looking to see if it points
@@ -193,7 +224,7 @@ fn check_thread_addresses(
     fn check(
         addrs: &[usize],
         maps: &[MapRange],
-        process: &remoteprocess::Process,
+        process: &MemoryReader,
         is_maybe_thread: crate::core::types::IsMaybeThreadFn,
     ) -> Result<usize, Error> {
         for &addr in addrs {
@@ -216,6 +247,29 @@ fn check_thread_addresses(
     check(addrs, maps, process, is_maybe_thread)
 }
 
+/// The symbols that rbspy finds the Ruby VM through, for the given version or, when it's not known,
+/// for every version
+pub(crate) fn vm_symbols(version: Option<&Version>) -> Vec<String> {
+    let mut symbols = vec![ruby_version_symbol()];
+    match version {
+        Some(version) => {
+            symbols.push(ruby_current_vm_symbol(version));
+            if *version < Version::new(3, 0, 0) {
+                symbols.push(ruby_execution_context_symbol(version));
+            }
+            symbols.push(ruby_globals_symbol(version));
+        }
+        None => {
+            for version in [Version::new(2, 4, 0), Version::new(2, 7, 0)] {
+                symbols.push(ruby_current_vm_symbol(&version));
+                symbols.push(ruby_execution_context_symbol(&version));
+                symbols.push(ruby_globals_symbol(&version));
+            }
+        }
+    }
+    symbols
+}
+
 fn ruby_version_symbol() -> String {
     "ruby_version".to_string()
 }
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+
+use crate::core::process::Pid;
+
+/// Where audit records are written
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuditTarget {
+    /// Appends JSON records to a file, one per line
+    File(PathBuf),
+    /// Sends records to the system log with the `authpriv` facility
+    #[cfg(unix)]
+    Syslog,
+}
+
+/// What an audit record is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A recording started
+    Start,
+    /// rbspy started reading a process's memory
+    Attach,
+    /// rbspy stopped reading a process's memory
+    Detach,
+    /// A recording ended
+    Stop,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    event: AuditEvent,
+    /// The user that rbspy is running as
+    user: String,
+    uid: Option<u32>,
+    /// rbspy's own process ID
+    rbspy_pid: u32,
+    /// The process being profiled
+    pid: Option<Pid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<&'a BTreeMap<&'static str, String>>,
+}
+
+/// An append-only log of which processes rbspy attached to, when, by whom, and with what options
+#[derive(Debug)]
+pub struct AuditLog {
+    target: AuditTarget,
+    file: Mutex<Option<File>>,
+}
+
+impl AuditLog {
+    /// The log file is opened when the first record is written
+    pub fn new(target: AuditTarget) -> AuditLog {
+        AuditLog {
+            target,
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Writes a record. `options` describes how the recording was configured.
+    pub fn record(
+        &self,
+        event: AuditEvent,
+        pid: Option<Pid>,
+        options: Option<&BTreeMap<&'static str, String>>,
+    ) -> Result<()> {
+        #[cfg(unix)]
+        let uid = Some(nix::unistd::getuid().as_raw());
+        #[cfg(not(unix))]
+        let uid: Option<u32> = None;
+        #[cfg(unix)]
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .ok()
+            .flatten()
+            .map(|user| user.name);
+        #[cfg(not(unix))]
+        let user: Option<String> = None;
+
+        let record = AuditRecord {
+            time: chrono::Local::now().to_rfc3339(),
+            event,
+            user: user
+                .or_else(|| std::env::var("USER").ok())
+                .or_else(|| std::env::var("USERNAME").ok())
+                .unwrap_or_else(|| "unknown".to_string()),
+            uid,
+            rbspy_pid: std::process::id(),
+            pid,
+            options,
+        };
+        let json = serde_json::to_string(&record)?;
+
+        match &self.target {
+            AuditTarget::File(path) => {
+                let mut file = self.file.lock().unwrap();
+                if file.is_none() {
+                    *file = Some(
+                        OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .context(format!("Failed to open audit log {}", path.display()))?,
+                    );
+                }
+                let file = file.as_mut().unwrap();
+                writeln!(file, "{}", json)?;
+                file.sync_data()?;
+            }
+            #[cfg(unix)]
+            AuditTarget::Syslog => {
+                let message = std::ffi::CString::new(format!("rbspy: {}", json))?;
+                unsafe {
+                    libc::syslog(
+                        libc::LOG_AUTHPRIV | libc::LOG_NOTICE,
+                        "%s\0".as_ptr() as *const std::os::raw::c_char,
+                        message.as_ptr(),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_log_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        std::fs::write(&path, "earlier record\n").unwrap();
+
+        let log = AuditLog::new(AuditTarget::File(path.clone()));
+        let mut options = BTreeMap::new();
+        options.insert("sample_rate", "100".to_string());
+        log.record(AuditEvent::Start, Some(42), Some(&options))
+            .unwrap();
+        log.record(AuditEvent::Attach, Some(42), None).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "earlier record");
+        let start: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(start["event"], "start");
+        assert_eq!(start["pid"], 42);
+        assert_eq!(start["options"]["sample_rate"], "100");
+        let attach: serde_json::Value = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(attach["event"], "attach");
+        assert!(attach.get("options").is_none());
+    }
+}
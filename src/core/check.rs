@@ -0,0 +1,690 @@
+use std::fmt;
+
+use spytools::ProcessInfo;
+
+use crate::core::memory::{MemoryReader, ReadMethod};
+use crate::core::process::{Pid, Process};
+
+/// The outcome of one of the checks done by `run_checks`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    /// Profiling may work, but only in some situations (e.g. only for some processes)
+    Warn,
+    Fail,
+}
+
+/// The result of checking one of the things that profiling depends on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub message: String,
+    /// What to do about a warning or failure
+    pub remediation: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, message: String) -> CheckResult {
+        CheckResult {
+            name,
+            status: CheckStatus::Pass,
+            message,
+            remediation: None,
+        }
+    }
+
+    fn warn(name: &'static str, message: String, remediation: &str) -> CheckResult {
+        CheckResult {
+            name,
+            status: CheckStatus::Warn,
+            message,
+            remediation: Some(remediation.to_string()),
+        }
+    }
+
+    fn fail(name: &'static str, message: String, remediation: &str) -> CheckResult {
+        CheckResult {
+            name,
+            status: CheckStatus::Fail,
+            message,
+            remediation: Some(remediation.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+        };
+        write!(f, "[{}] {}: {}", status, self.name, self.message)?;
+        if let Some(remediation) = &self.remediation {
+            write!(f, "\n       {}", remediation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether rbspy has what it needs to profile on this system, and if a PID is given,
+/// whether it can profile that process. Every check is run, even after one fails, so that all of
+/// the problems can be fixed at once.
+pub fn run_checks(pid: Option<Pid>, force_version: Option<String>) -> Vec<CheckResult> {
+    let mut results = system_checks();
+    if let Some(pid) = pid {
+        process_checks(pid, force_version, &mut results);
+    }
+    results
+}
+
+#[cfg(target_os = "linux")]
+fn system_checks() -> Vec<CheckResult> {
+    let cap_sys_ptrace = has_cap_sys_ptrace();
+    let mut results = vec![
+        match cap_sys_ptrace {
+            Some(true) => CheckResult::pass("capabilities", "CAP_SYS_PTRACE is available".into()),
+            Some(false) => CheckResult::warn(
+                "capabilities",
+                "CAP_SYS_PTRACE isn't available, so only processes owned by the same user can be profiled".into(),
+                "Run rbspy as root, or grant the SYS_PTRACE capability to its container",
+            ),
+            None => CheckResult::warn(
+                "capabilities",
+                "Couldn't read rbspy's capabilities from /proc/self/status".into(),
+                "Make sure /proc is mounted",
+            ),
+        },
+        ptrace_scope_check(ptrace_scope(), cap_sys_ptrace == Some(true)),
+        security_module_check(confining_security_module()),
+    ];
+    if in_container() {
+        results.push(CheckResult::warn(
+            "container",
+            "rbspy is running in a container, so it can only see processes in the same PID namespace".into(),
+            "To profile processes in other containers or on the host, share their PID namespace (e.g. `docker run --pid=host` or `shareProcessNamespace: true` in Kubernetes)",
+        ));
+    }
+    results
+}
+
+#[cfg(target_os = "macos")]
+fn system_checks() -> Vec<CheckResult> {
+    vec![if nix::unistd::geteuid().is_root() {
+        CheckResult::pass("privileges", "Running as root".into())
+    } else {
+        CheckResult::fail(
+            "privileges",
+            "macOS only allows root to read other processes' memory (task_for_pid)".into(),
+            "Run rbspy with `sudo --preserve-env`",
+        )
+    }]
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn system_checks() -> Vec<CheckResult> {
+    Vec::new()
+}
+
+fn process_checks(pid: Pid, force_version: Option<String>, results: &mut Vec<CheckResult>) {
+    let process = match Process::new(pid) {
+        Ok(process) => process,
+        Err(err) => {
+            results.push(CheckResult::fail(
+                "process",
+                format!("Couldn't find process {}: {}", pid, err),
+                "Check that the process is running. If rbspy is running in a container, the process has to be in the same PID namespace.",
+            ));
+            return;
+        }
+    };
+    let exe = process
+        .exe()
+        .unwrap_or_else(|_| "unknown executable".to_string());
+    results.push(CheckResult::pass(
+        "process",
+        format!("Found process {} ({})", pid, exe),
+    ));
+
+    #[cfg(target_os = "macos")]
+    if ["/usr/bin/", "/bin/", "/System/"]
+        .iter()
+        .any(|dir| exe.starts_with(dir))
+    {
+        results.push(CheckResult::fail(
+            "system integrity protection",
+            format!("{} is protected by System Integrity Protection", exe),
+            "Profile a Ruby that was installed separately, e.g. with Homebrew, rbenv or asdf",
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    if in_other_mount_namespace(pid) {
+        results.push(if can_read_process_root(pid) {
+            CheckResult::pass(
+                "container filesystem",
+                format!("Can read process {}'s files through /proc/{}/root", pid, pid),
+            )
+        } else {
+            CheckResult::fail(
+                "container filesystem",
+                format!("Can't read process {}'s files through /proc/{}/root", pid, pid),
+                "Grant rbspy the CAP_SYS_PTRACE capability, which it also needs to read the process's memory",
+            )
+        });
+    }
+
+    let memory = match MemoryReader::new(pid) {
+        Ok(memory) => memory,
+        Err(err) => {
+            results.push(CheckResult::fail(
+                "memory access",
+                format!("{:#}", err),
+                "Grant rbspy the access that's missing, or run it outside of the sandbox",
+            ));
+            return;
+        }
+    };
+    results.push(match memory.method() {
+        ReadMethod::Native => CheckResult::pass(
+            "memory access",
+            format!("Can read process {}'s memory with {}", pid, ReadMethod::Native),
+        ),
+        method => CheckResult::warn(
+            "memory access",
+            format!(
+                "{} isn't allowed, so process {}'s memory is read through {} instead",
+                ReadMethod::Native,
+                pid,
+                method
+            ),
+            "Profiling works, but reads are slower. If rbspy runs under seccomp, allow process_vm_readv in its profile.",
+        ),
+    });
+
+    #[allow(unused_mut)]
+    let mut process_info = ProcessInfo::new::<spytools::process::RubyProcessType>(&process);
+    #[cfg(target_os = "linux")]
+    if let Ok(process_info) = &mut process_info {
+        crate::core::debuginfo::add_debug_symbols(process_info, pid);
+    }
+    let version = process_info.and_then(|process_info| {
+        crate::core::address_finder::get_ruby_version(&memory, &process_info, force_version.clone())
+    });
+    let compatible = version
+        .as_ref()
+        .ok()
+        .and_then(crate::core::ruby_version::compatible_version);
+    match (version, compatible) {
+        (Ok(version), Some(compatible)) if compatible == version => {
+            results.push(CheckResult::pass(
+                "ruby version",
+                format!("Ruby {} is supported", version),
+            ));
+        }
+        (Ok(version), Some(compatible)) => {
+            results.push(CheckResult::warn(
+                "ruby version",
+                format!(
+                    "Ruby {} isn't known to rbspy yet, so it'll be read as Ruby {}",
+                    version, compatible
+                ),
+                "Upgrade rbspy to get support for this Ruby release",
+            ));
+        }
+        (Ok(version), None) => {
+            results.push(CheckResult::fail(
+                "ruby version",
+                format!("Ruby {} isn't supported yet", version),
+                "Upgrade rbspy, or use `force_version` with the closest supported version",
+            ));
+            return;
+        }
+        (Err(err), _) => {
+            results.push(CheckResult::fail(
+                "ruby symbols",
+                format!("Couldn't find the Ruby version in process {}: {:#}", pid, err),
+                "Check that the process is running Ruby and that its binary and libruby haven't been stripped of symbols",
+            ));
+            return;
+        }
+    }
+
+    let trace = crate::core::ruby_spy::RubySpy::new(pid, force_version)
+        .and_then(|mut spy| spy.get_stack_trace(false, false));
+    results.push(match trace {
+        Ok(_) => CheckResult::pass("stack trace", "Read a stack trace".into()),
+        Err(err) => CheckResult::fail(
+            "stack trace",
+            format!("Couldn't read a stack trace: {:#}", err),
+            "Please report this, along with the Ruby version and how it was installed",
+        ),
+    });
+}
+
+/// The value of the `kernel.yama.ptrace_scope` sysctl, if the YAMA security module is enabled
+#[cfg(target_os = "linux")]
+pub(crate) fn ptrace_scope() -> Option<u8> {
+    std::fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope")
+        .ok()
+        .and_then(|scope| scope.trim().parse().ok())
+}
+
+/// Whether rbspy has the `CAP_SYS_PTRACE` capability, which lets it read any process's memory
+#[cfg(target_os = "linux")]
+pub(crate) fn has_cap_sys_ptrace() -> Option<bool> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    cap_sys_ptrace_from_status(&status)
+}
+
+#[cfg(target_os = "linux")]
+fn cap_sys_ptrace_from_status(status: &str) -> Option<bool> {
+    const CAP_SYS_PTRACE: u32 = 19;
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    let effective = u64::from_str_radix(effective.trim(), 16).ok()?;
+    Some(effective & (1 << CAP_SYS_PTRACE) != 0)
+}
+
+/// Whether rbspy seems to be running in a Docker, Podman or Kubernetes container
+#[cfg(target_os = "linux")]
+pub(crate) fn in_container() -> bool {
+    if std::path::Path::new("/.dockerenv").exists()
+        || std::path::Path::new("/run/.containerenv").exists()
+    {
+        return true;
+    }
+    std::fs::read_to_string("/proc/1/cgroup").map_or(false, |cgroup| {
+        ["docker", "kubepods", "containerd", "libpod"]
+            .iter()
+            .any(|runtime| cgroup.contains(runtime))
+    })
+}
+
+/// Whether a process is in a different mount namespace than rbspy, e.g. because it's in another
+/// container, so that the paths to its binaries may not exist for rbspy
+#[cfg(target_os = "linux")]
+pub(crate) fn in_other_mount_namespace(pid: Pid) -> bool {
+    match (
+        std::fs::read_link("/proc/self/ns/mnt"),
+        std::fs::read_link(format!("/proc/{}/ns/mnt", pid)),
+    ) {
+        (Ok(ours), Ok(theirs)) => ours != theirs,
+        _ => false,
+    }
+}
+
+/// Whether rbspy can see the filesystem of a process in another mount namespace. The Ruby binary
+/// and libruby are read from there, since their paths in the process's memory maps only exist in
+/// its own filesystem.
+#[cfg(target_os = "linux")]
+pub(crate) fn can_read_process_root(pid: Pid) -> bool {
+    std::fs::read_dir(format!("/proc/{}/root", pid)).is_ok()
+}
+
+/// Explains why rbspy was denied access to a process and what to do about it, based on what's
+/// restricting access on this system
+#[cfg(target_os = "linux")]
+pub(crate) fn permission_error_hint() -> String {
+    linux_permission_hint(
+        ptrace_scope(),
+        has_cap_sys_ptrace() == Some(true),
+        nix::unistd::geteuid().is_root(),
+        in_container(),
+        confining_security_module().as_ref(),
+    )
+}
+
+/// Explains why neither `process_vm_readv` nor `/proc/<pid>/mem` could read a process's memory,
+/// along with everything that might be restricting rbspy
+#[cfg(target_os = "linux")]
+pub(crate) fn memory_access_report(
+    pid: Pid,
+    native_error: &std::io::Error,
+    mem_error: &std::io::Error,
+) -> String {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    sandbox_report(
+        pid,
+        native_error,
+        mem_error,
+        seccomp_mode_from_status(&status),
+        cap_sys_ptrace_from_status(&status),
+        ptrace_scope(),
+        confining_security_module().as_ref(),
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn sandbox_report(
+    pid: Pid,
+    native_error: &std::io::Error,
+    mem_error: &std::io::Error,
+    seccomp_mode: Option<u8>,
+    cap_sys_ptrace: Option<bool>,
+    scope: Option<u8>,
+    security_module: Option<&SecurityModule>,
+) -> String {
+    let mut report = format!(
+        "Couldn't read process {}'s memory with process_vm_readv ({}) or through /proc/{}/mem ({}).",
+        pid, native_error, pid, mem_error
+    );
+    if native_error.raw_os_error() == Some(libc::ENOSYS) {
+        report.push_str(" process_vm_readv isn't implemented here, which happens in sandboxes like gVisor that emulate the kernel.");
+    }
+    match seccomp_mode {
+        Some(2) => report.push_str(" rbspy is running under a seccomp filter, which may block process_vm_readv and ptrace; container runtimes' default profiles only allow them with the SYS_PTRACE capability."),
+        Some(1) => report.push_str(" rbspy is running in strict seccomp mode, which only allows reading files that are already open."),
+        _ => {}
+    }
+    match cap_sys_ptrace {
+        Some(true) => report.push_str(" rbspy has CAP_SYS_PTRACE."),
+        Some(false) => report.push_str(" rbspy doesn't have CAP_SYS_PTRACE."),
+        None => {}
+    }
+    if let Some(scope) = scope {
+        report.push_str(&format!(" kernel.yama.ptrace_scope is {}.", scope));
+    }
+    if let Some(security_module) = security_module {
+        report.push_str(&format!(
+            " {} may also deny access. {}",
+            security_module.name(),
+            security_module.remediation()
+        ));
+    }
+    report
+}
+
+/// The seccomp mode from `/proc/<pid>/status`: 0 for none, 1 for strict and 2 for a filter
+#[cfg(target_os = "linux")]
+fn seccomp_mode_from_status(status: &str) -> Option<u8> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Seccomp:"))
+        .and_then(|mode| mode.trim().parse().ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn permission_error_hint() -> String {
+    "Failed to initialize due to a permissions error. If you are running rbspy as a normal (non-root) user, please try running it again with `sudo --preserve-env !!`. See the rbspy documentation for more details.".to_string()
+}
+
+#[cfg(target_os = "linux")]
+fn linux_permission_hint(
+    scope: Option<u8>,
+    cap_sys_ptrace: bool,
+    root: bool,
+    container: bool,
+    security_module: Option<&SecurityModule>,
+) -> String {
+    let grant = if container {
+        "grant its container the SYS_PTRACE capability (e.g. `docker run --cap-add SYS_PTRACE`, or `securityContext.capabilities.add: [\"SYS_PTRACE\"]` in Kubernetes)"
+    } else if root {
+        "grant it the CAP_SYS_PTRACE capability"
+    } else {
+        "run it as root (e.g. `sudo --preserve-env !!`), or grant the rbspy binary the CAP_SYS_PTRACE capability with `rbspy::setcap_self`"
+    };
+    let cause = match scope {
+        Some(scope) if scope >= 3 => {
+            return "Failed to initialize because ptrace is disabled on this system (kernel.yama.ptrace_scope is 3). It stays disabled until the next reboot.".to_string();
+        }
+        _ if cap_sys_ptrace => {
+            return match security_module {
+                Some(security_module) => format!(
+                    "Failed to initialize due to a permissions error even though rbspy has CAP_SYS_PTRACE, so access was probably denied by {}. {}",
+                    security_module.name(),
+                    security_module.remediation()
+                ),
+                None => "Failed to initialize due to a permissions error even though rbspy has CAP_SYS_PTRACE. Access was probably denied by a security module such as SELinux or AppArmor.".to_string(),
+            };
+        }
+        Some(2) => "kernel.yama.ptrace_scope is 2, which only allows processes with CAP_SYS_PTRACE to read other processes' memory",
+        Some(1) => "kernel.yama.ptrace_scope is 1, which only allows rbspy to read the memory of its own subprocesses without CAP_SYS_PTRACE",
+        _ if container => "rbspy's container doesn't have the SYS_PTRACE capability",
+        _ => "rbspy can only read the memory of processes owned by the same user without CAP_SYS_PTRACE",
+    };
+    let mut hint = format!(
+        "Failed to initialize due to a permissions error: {}. To fix this, {}.",
+        cause, grant
+    );
+    if let Some(security_module) = security_module {
+        hint.push_str(&format!(
+            " {} may also deny access. {}",
+            security_module.name(),
+            security_module.remediation()
+        ));
+    }
+    hint
+}
+
+/// A Linux security module that restricts what rbspy may do
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SecurityModule {
+    /// SELinux in enforcing mode, with rbspy's security context
+    SELinux(String),
+    /// An AppArmor profile in enforce mode
+    AppArmor(String),
+}
+
+#[cfg(target_os = "linux")]
+impl SecurityModule {
+    fn name(&self) -> &'static str {
+        match self {
+            SecurityModule::SELinux(_) => "SELinux",
+            SecurityModule::AppArmor(_) => "AppArmor",
+        }
+    }
+
+    fn remediation(&self) -> String {
+        match self {
+            SecurityModule::SELinux(context) => format!(
+                "rbspy runs in the SELinux context {}. Look for denials with `ausearch -m avc -ts recent`, and allow the `ptrace` permission for rbspy's domain with a policy module (e.g. from `audit2allow -M rbspy`), or run rbspy in an unconfined domain (e.g. `runcon -t unconfined_t`).",
+                context
+            ),
+            SecurityModule::AppArmor(profile) => format!(
+                "rbspy is confined by the AppArmor profile {}. Add `capability sys_ptrace,` and `ptrace (read, trace),` rules to the profile, or run rbspy unconfined (e.g. `docker run --security-opt apparmor=unconfined`).",
+                profile
+            ),
+        }
+    }
+}
+
+/// Finds out whether SELinux is enforcing or an AppArmor profile is confining rbspy
+#[cfg(target_os = "linux")]
+pub(crate) fn confining_security_module() -> Option<SecurityModule> {
+    let read = |path: &str| {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|value| value.trim_end_matches(&['\0', '\n'][..]).to_string())
+    };
+    if read("/sys/fs/selinux/enforce").as_deref() == Some("1") {
+        let context = read("/proc/self/attr/current").unwrap_or_else(|| "unknown".to_string());
+        return Some(SecurityModule::SELinux(context));
+    }
+    if read("/sys/module/apparmor/parameters/enabled").as_deref() == Some("Y") {
+        // Newer kernels keep each security module's attributes separately
+        let label =
+            read("/proc/self/attr/apparmor/current").or_else(|| read("/proc/self/attr/current"))?;
+        return apparmor_profile(&label).map(SecurityModule::AppArmor);
+    }
+    None
+}
+
+/// Gets the profile name from an AppArmor label like `docker-default (enforce)`, if the profile
+/// is being enforced
+#[cfg(target_os = "linux")]
+fn apparmor_profile(label: &str) -> Option<String> {
+    label
+        .strip_suffix(" (enforce)")
+        .map(|profile| profile.to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn security_module_check(security_module: Option<SecurityModule>) -> CheckResult {
+    const NAME: &str = "security modules";
+    match security_module {
+        Some(security_module) => CheckResult::warn(
+            NAME,
+            format!(
+                "{} restricts rbspy, and may stop it from reading processes' memory",
+                security_module.name()
+            ),
+            &security_module.remediation(),
+        ),
+        None => CheckResult::pass(
+            NAME,
+            "Neither SELinux nor AppArmor is restricting rbspy".into(),
+        ),
+    }
+}
+
+/// Grants the running executable the `CAP_SYS_PTRACE` capability with `setcap`, so that it can
+/// profile other users' processes without running as root. Needs to be run as root once, e.g.
+/// after installing rbspy.
+#[cfg(target_os = "linux")]
+pub fn setcap_self() -> anyhow::Result<()> {
+    let exe = std::env::current_exe()?;
+    let status = std::process::Command::new("setcap")
+        .arg("cap_sys_ptrace+ep")
+        .arg(&exe)
+        .status()
+        .map_err(|err| {
+            anyhow::format_err!("Failed to run setcap: {}. Is libcap installed?", err)
+        })?;
+    if !status.success() {
+        return Err(anyhow::format_err!(
+            "setcap failed to grant CAP_SYS_PTRACE to {} ({})",
+            exe.display(),
+            status
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn ptrace_scope_check(scope: Option<u8>, cap_sys_ptrace: bool) -> CheckResult {
+    const NAME: &str = "ptrace scope";
+    const RELAX: &str =
+        "Run rbspy as root, or allow tracing other processes with `sysctl kernel.yama.ptrace_scope=0`";
+    match scope {
+        None => CheckResult::pass(NAME, "YAMA isn't restricting ptrace".into()),
+        Some(0) => CheckResult::pass(NAME, "kernel.yama.ptrace_scope is 0".into()),
+        Some(1) | Some(2) if cap_sys_ptrace => CheckResult::pass(
+            NAME,
+            format!(
+                "kernel.yama.ptrace_scope is {}, which CAP_SYS_PTRACE overrides",
+                scope.unwrap()
+            ),
+        ),
+        Some(1) => CheckResult::warn(
+            NAME,
+            "kernel.yama.ptrace_scope is 1, so only rbspy's own subprocesses can be profiled".into(),
+            RELAX,
+        ),
+        Some(2) => CheckResult::fail(
+            NAME,
+            "kernel.yama.ptrace_scope is 2, so profiling requires CAP_SYS_PTRACE".into(),
+            RELAX,
+        ),
+        Some(scope) => CheckResult::fail(
+            NAME,
+            format!("kernel.yama.ptrace_scope is {}, which disables ptrace entirely", scope),
+            "Tracing stays disabled until the next reboot; set kernel.yama.ptrace_scope to 0 or 1 at boot",
+        ),
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_sys_ptrace_from_status() {
+        let status = |cap_eff: &str| format!("Name:\trbspy\nCapInh:\t0\nCapEff:\t{}\n", cap_eff);
+        assert_eq!(
+            cap_sys_ptrace_from_status(&status("000001ffffffffff")),
+            Some(true)
+        );
+        assert_eq!(
+            cap_sys_ptrace_from_status(&status("00000000a80425fb")),
+            Some(false)
+        );
+        assert_eq!(cap_sys_ptrace_from_status("Name:\trbspy\n"), None);
+    }
+
+    #[test]
+    fn test_ptrace_scope_check() {
+        let status = |scope, cap| ptrace_scope_check(scope, cap).status;
+        assert_eq!(status(None, false), CheckStatus::Pass);
+        assert_eq!(status(Some(0), false), CheckStatus::Pass);
+        assert_eq!(status(Some(1), false), CheckStatus::Warn);
+        assert_eq!(status(Some(1), true), CheckStatus::Pass);
+        assert_eq!(status(Some(2), false), CheckStatus::Fail);
+        assert_eq!(status(Some(2), true), CheckStatus::Pass);
+        assert_eq!(status(Some(3), true), CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_linux_permission_hint() {
+        let hint = linux_permission_hint(Some(1), false, false, false, None);
+        assert!(hint.contains("ptrace_scope is 1"));
+        assert!(hint.contains("sudo"));
+
+        let hint = linux_permission_hint(None, false, true, true, None);
+        assert!(hint.contains("container doesn't have the SYS_PTRACE capability"));
+        assert!(hint.contains("--cap-add SYS_PTRACE"));
+
+        let hint = linux_permission_hint(Some(2), true, true, false, None);
+        assert!(hint.contains("SELinux or AppArmor"));
+
+        let hint = linux_permission_hint(Some(3), true, true, false, None);
+        assert!(hint.contains("ptrace is disabled"));
+    }
+
+    #[test]
+    fn test_sandbox_report() {
+        assert_eq!(
+            seccomp_mode_from_status("Name:\trbspy\nSeccomp:\t2\nSeccomp_filters:\t1\n"),
+            Some(2)
+        );
+        assert_eq!(seccomp_mode_from_status("Name:\trbspy\n"), None);
+
+        let enosys = std::io::Error::from_raw_os_error(libc::ENOSYS);
+        let eacces = std::io::Error::from_raw_os_error(libc::EACCES);
+        let report = sandbox_report(42, &enosys, &eacces, Some(2), Some(false), Some(1), None);
+        assert!(report.starts_with("Couldn't read process 42's memory with process_vm_readv"));
+        assert!(report.contains("/proc/42/mem"));
+        assert!(report.contains("gVisor"));
+        assert!(report.contains("seccomp filter"));
+        assert!(report.contains("doesn't have CAP_SYS_PTRACE"));
+        assert!(report.contains("ptrace_scope is 1"));
+
+        let eperm = std::io::Error::from_raw_os_error(libc::EPERM);
+        let report = sandbox_report(42, &eperm, &eacces, Some(0), Some(true), None, None);
+        assert!(!report.contains("gVisor"));
+        assert!(!report.contains("seccomp"));
+    }
+
+    #[test]
+    fn test_security_modules() {
+        assert_eq!(
+            apparmor_profile("docker-default (enforce)"),
+            Some("docker-default".to_string())
+        );
+        assert_eq!(apparmor_profile("docker-default (complain)"), None);
+        assert_eq!(apparmor_profile("unconfined"), None);
+
+        let apparmor = SecurityModule::AppArmor("docker-default".to_string());
+        let hint = linux_permission_hint(Some(1), true, true, true, Some(&apparmor));
+        assert!(hint.contains("denied by AppArmor"));
+        assert!(hint.contains("docker-default"));
+
+        let selinux = SecurityModule::SELinux("system_u:system_r:container_t:s0".to_string());
+        let hint = linux_permission_hint(Some(1), false, false, false, Some(&selinux));
+        assert!(hint.contains("ptrace_scope is 1"));
+        assert!(hint.contains("SELinux may also deny access"));
+    }
+}
@@ -0,0 +1,553 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{format_err, Result};
+
+use crate::core::errors::ErrorCode;
+use crate::core::process::Pid;
+use crate::core::types::{EnrichmentHook, StackTrace};
+
+/// What rbspy could find out about the container that a process runs in, from its cgroup and
+/// its container runtime's state on the host
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContainerInfo {
+    /// The container runtime's ID for the container
+    pub id: String,
+    /// The container runtime, e.g. `docker`, `containerd`, `cri-o` or `podman`, if it can be told
+    /// from the cgroup
+    pub runtime: Option<String>,
+    pub image: Option<String>,
+    /// The container's name, e.g. from the pod spec
+    pub name: Option<String>,
+    pub pod: Option<String>,
+    pub namespace: Option<String>,
+    pub pod_uid: Option<String>,
+}
+
+impl ContainerInfo {
+    /// Labels that identify the container in profiles. The container ID is shortened to 12
+    /// characters, like `docker ps` shows it.
+    pub fn labels(&self) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::new();
+        let id: String = self.id.chars().take(12).collect();
+        labels.insert("container_id".to_string(), id);
+        let optional = [
+            ("container_runtime", &self.runtime),
+            ("image", &self.image),
+            ("container", &self.name),
+            ("pod", &self.pod),
+            ("namespace", &self.namespace),
+            ("pod_uid", &self.pod_uid),
+        ];
+        for (key, value) in optional.iter() {
+            if let Some(value) = value {
+                labels.insert(key.to_string(), value.clone());
+            }
+        }
+        labels
+    }
+}
+
+/// The container that the process belonging to `pid` runs in, if any. The runtime's state is
+/// looked up on the host's filesystem, so the image and pod metadata are only found when rbspy
+/// runs on the host (or has the runtime's state directories mounted).
+#[cfg(target_os = "linux")]
+pub fn container_info(pid: Pid) -> Option<ContainerInfo> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let mut info = from_cgroup(&cgroup)?;
+    for path in runtime_config_paths(&info.id) {
+        if let Ok(config) = std::fs::read(&path) {
+            add_runtime_config(&mut info, &config);
+            break;
+        }
+    }
+    Some(info)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn container_info(_pid: Pid) -> Option<ContainerInfo> {
+    None
+}
+
+/// An enrichment hook that labels each trace with the container that its process runs in. The
+/// lookup is done once per process.
+pub fn container_labels_hook() -> EnrichmentHook {
+    let labels: Mutex<HashMap<Pid, BTreeMap<String, String>>> = Mutex::new(HashMap::new());
+    Box::new(move |trace: &mut StackTrace| {
+        let pid = match trace.pid {
+            Some(pid) => pid,
+            None => return,
+        };
+        let mut labels = labels.lock().unwrap();
+        let container_labels = labels.entry(pid).or_insert_with(|| {
+            container_info(pid)
+                .map(|info| info.labels())
+                .unwrap_or_default()
+        });
+        for (key, value) in container_labels.iter() {
+            // Labels from the operator take precedence
+            trace
+                .labels
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    })
+}
+
+/// Finds the container in a process's cgroup paths, which container runtimes name after it,
+/// e.g. `/docker/<id>`, `/kubepods/burstable/pod<uid>/<id>` or
+/// `/kubepods.slice/.../cri-containerd-<id>.scope` with the systemd cgroup driver
+pub(crate) fn from_cgroup(cgroup: &str) -> Option<ContainerInfo> {
+    cgroup.lines().find_map(|line| {
+        let path = line.splitn(3, ':').nth(2)?;
+        let (dir, id) = path.rsplit('/').find_map(|dir| {
+            let name = dir.trim_end_matches(".scope");
+            let id = name.rsplit('-').next()?;
+            if id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit()) {
+                Some((name, id))
+            } else {
+                None
+            }
+        })?;
+        let runtime = match dir.strip_suffix(id).unwrap_or_default() {
+            "docker-" => Some("docker"),
+            "cri-containerd-" => Some("containerd"),
+            "crio-" => Some("cri-o"),
+            "libpod-" => Some("podman"),
+            _ if path.starts_with("/docker/") => Some("docker"),
+            _ => None,
+        };
+        // `pod<uid>`, or `kubepods-<qos>-pod<uid>.slice` with underscores for dashes
+        let pod_uid = path.split('/').find_map(|dir| {
+            let uid = match dir.strip_suffix(".slice") {
+                Some(slice) => slice.rsplit_once("-pod")?.1,
+                None => dir.strip_prefix("pod")?,
+            };
+            Some(uid.replace('_', "-")).filter(|uid| !uid.is_empty())
+        });
+        Some(ContainerInfo {
+            id: id.to_string(),
+            runtime: runtime.map(|runtime| runtime.to_string()),
+            pod_uid,
+            ..Default::default()
+        })
+    })
+}
+
+/// Where container runtimes keep a container's configuration on the host: Docker's container
+/// config, and the OCI bundles of containerd (in each of its namespaces) and CRI-O
+#[cfg(target_os = "linux")]
+fn runtime_config_paths(id: &str) -> Vec<std::path::PathBuf> {
+    let mut paths = vec![std::path::PathBuf::from(format!(
+        "/var/lib/docker/containers/{}/config.v2.json",
+        id
+    ))];
+    if let Ok(namespaces) = std::fs::read_dir("/run/containerd/io.containerd.runtime.v2.task") {
+        for namespace in namespaces.flatten() {
+            paths.push(namespace.path().join(id).join("config.json"));
+        }
+    }
+    paths.push(std::path::PathBuf::from(format!(
+        "/run/containers/storage/overlay-containers/{}/userdata/config.json",
+        id
+    )));
+    paths
+}
+
+/// Fills in the image and pod metadata from a Docker container config or an OCI bundle's
+/// `config.json`. Kubernetes runtimes record the pod in the labels or annotations.
+fn add_runtime_config(info: &mut ContainerInfo, config: &[u8]) {
+    let config: serde_json::Value = match serde_json::from_slice(config) {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    let annotations = config
+        .pointer("/Config/Labels")
+        .or_else(|| config.get("annotations"));
+    let annotation = |keys: &[&str]| {
+        keys.iter().find_map(|key| {
+            annotations?
+                .get(*key)?
+                .as_str()
+                .map(|value| value.to_string())
+        })
+    };
+    let string = |pointer: &str| {
+        config
+            .pointer(pointer)
+            .and_then(|value| value.as_str())
+            .map(|value| value.to_string())
+    };
+    info.image = annotation(&[
+        "io.kubernetes.cri.image-name",
+        "io.kubernetes.cri-o.ImageName",
+    ])
+    .or_else(|| string("/Config/Image"));
+    info.pod = annotation(&["io.kubernetes.pod.name", "io.kubernetes.cri.sandbox-name"]);
+    info.namespace = annotation(&[
+        "io.kubernetes.pod.namespace",
+        "io.kubernetes.cri.sandbox-namespace",
+    ]);
+    info.name = annotation(&[
+        "io.kubernetes.container.name",
+        "io.kubernetes.cri.container-name",
+    ])
+    .or_else(|| string("/Name").map(|name| name.trim_start_matches('/').to_string()));
+    if info.pod_uid.is_none() {
+        info.pod_uid = annotation(&["io.kubernetes.pod.uid", "io.kubernetes.cri.sandbox-uid"]);
+    }
+}
+
+/// A container that `resolve_container` found, and the process in it to profile
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContainerTarget {
+    /// The container's init process, as rbspy's PID namespace sees it
+    pub pid: Pid,
+    pub info: ContainerInfo,
+}
+
+impl ContainerTarget {
+    /// Where a path in the container's filesystem can be read from rbspy's mount namespace,
+    /// through `/proc/<pid>/root`. rbspy reads the Ruby binary's symbols the same way, so frame
+    /// paths in profiles are left as the container sees them.
+    pub fn host_path(&self, path: &str) -> PathBuf {
+        PathBuf::from(format!("/proc/{}/root", self.pid)).join(path.trim_start_matches('/'))
+    }
+}
+
+/// A container as its runtime's state on the host describes it
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RuntimeContainer {
+    id: String,
+    name: Option<String>,
+    /// The container's init process in the host's PID namespace, if it's running
+    pid: Option<Pid>,
+}
+
+/// Finds a container by its ID, a unique prefix of its ID (like `docker ps` shows) or its name,
+/// and the process to profile in it: the container's init process, which is usually the Ruby
+/// process or the process manager that starts it (so `with_subprocesses` is worth setting).
+///
+/// The container runtime's state (Docker's, containerd's or CRI-O's) is used to look up names
+/// and PIDs. Those PIDs are in the host's PID namespace, so when rbspy runs in a container of
+/// its own, or can't read the runtime's state, the container's processes are found by their
+/// cgroups instead, and the PID is the one that rbspy's namespace sees.
+#[cfg(target_os = "linux")]
+pub fn resolve_container(container: &str) -> Result<ContainerTarget> {
+    let containers = runtime_containers();
+    let runtime = find_container(&containers, container)?;
+    if let Some(runtime) = runtime {
+        if let Some(pid) = runtime.pid {
+            if let Some(info) = container_info(pid).filter(|info| info.id == runtime.id) {
+                return Ok(ContainerTarget { pid, info });
+            }
+        }
+    }
+
+    let id = runtime.map_or(container, |runtime| runtime.id.as_str());
+    let mut processes: Vec<(Pid, ContainerInfo, bool)> = Vec::new();
+    for entry in std::fs::read_dir("/proc")?.flatten() {
+        let pid: Pid = match entry.file_name().to_str().and_then(|pid| pid.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let cgroup = match std::fs::read_to_string(entry.path().join("cgroup")) {
+            Ok(cgroup) => cgroup,
+            Err(_) => continue,
+        };
+        if let Some(info) = from_cgroup(&cgroup).filter(|info| info.id.starts_with(id)) {
+            let status = std::fs::read_to_string(entry.path().join("status")).unwrap_or_default();
+            processes.push((pid, info, is_namespace_init(&status)));
+        }
+    }
+    pick_init_process(id, processes)?
+        .map(|(pid, mut info)| {
+            for path in runtime_config_paths(&info.id) {
+                if let Ok(config) = std::fs::read(&path) {
+                    add_runtime_config(&mut info, &config);
+                    break;
+                }
+            }
+            ContainerTarget { pid, info }
+        })
+        .ok_or_else(|| match runtime {
+            Some(runtime) => ErrorCode::ContainerNamespace
+                .error(format!(
+                    "Container {} is known to its runtime, but none of its processes are visible to rbspy",
+                    runtime.name.as_deref().unwrap_or(container)
+                ))
+                .into(),
+            None => format_err!("Failed to find a running container called {}", container),
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resolve_container(_container: &str) -> Result<ContainerTarget> {
+    Err(format_err!(
+        "Profiling containers is only supported on Linux"
+    ))
+}
+
+/// The containers that Docker, containerd and CRI-O keep state for on the host
+#[cfg(target_os = "linux")]
+fn runtime_containers() -> Vec<RuntimeContainer> {
+    let entries = |dir: &str| -> Vec<std::fs::DirEntry> {
+        std::fs::read_dir(dir)
+            .map(|entries| entries.flatten().collect())
+            .unwrap_or_default()
+    };
+    let read_pid =
+        |path: PathBuf| -> Option<Pid> { std::fs::read_to_string(path).ok()?.trim().parse().ok() };
+    let mut containers = Vec::new();
+    for entry in entries("/var/lib/docker/containers") {
+        if let Ok(config) = std::fs::read(entry.path().join("config.v2.json")) {
+            containers.extend(docker_container(&config));
+        }
+    }
+    for namespace in entries("/run/containerd/io.containerd.runtime.v2.task") {
+        for entry in std::fs::read_dir(namespace.path())
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            containers.push(RuntimeContainer {
+                id: entry.file_name().to_string_lossy().into_owned(),
+                name: None,
+                pid: read_pid(entry.path().join("init.pid")),
+            });
+        }
+    }
+    for entry in entries("/run/containers/storage/overlay-containers") {
+        let userdata = entry.path().join("userdata");
+        let mut name = None;
+        if let Ok(config) = std::fs::read(userdata.join("config.json")) {
+            let mut info = ContainerInfo::default();
+            add_runtime_config(&mut info, &config);
+            name = info.name;
+        }
+        containers.push(RuntimeContainer {
+            id: entry.file_name().to_string_lossy().into_owned(),
+            name,
+            pid: read_pid(userdata.join("pidfile")),
+        });
+    }
+    containers
+}
+
+/// A container from Docker's `config.v2.json`, which has its name and, while it's running, its
+/// PID
+fn docker_container(config: &[u8]) -> Option<RuntimeContainer> {
+    let config: serde_json::Value = serde_json::from_slice(config).ok()?;
+    let running = config.pointer("/State/Running")?.as_bool()?;
+    Some(RuntimeContainer {
+        id: config.get("ID")?.as_str()?.to_string(),
+        name: config
+            .get("Name")
+            .and_then(|name| name.as_str())
+            .map(|name| name.trim_start_matches('/').to_string()),
+        pid: config
+            .pointer("/State/Pid")
+            .and_then(|pid| pid.as_i64())
+            .filter(|pid| running && *pid > 0)
+            .map(|pid| pid as Pid),
+    })
+}
+
+/// The container called `query`, or whose ID is or starts with `query`. Names and whole IDs
+/// take precedence over prefixes, and a prefix has to be unique.
+fn find_container<'a>(
+    containers: &'a [RuntimeContainer],
+    query: &str,
+) -> Result<Option<&'a RuntimeContainer>> {
+    if query.is_empty() {
+        return Err(format_err!("No container given"));
+    }
+    let exact = containers
+        .iter()
+        .find(|container| container.id == query || container.name.as_deref() == Some(query));
+    if exact.is_some() {
+        return Ok(exact);
+    }
+    let mut matches = containers
+        .iter()
+        .filter(|container| container.id.starts_with(query));
+    match (matches.next(), matches.next()) {
+        (Some(_), Some(_)) => Err(format_err!(
+            "More than one container's ID starts with {}",
+            query
+        )),
+        (container, _) => Ok(container),
+    }
+}
+
+/// Whether a process is the first process in its PID namespace, from its `/proc/<pid>/status`.
+/// `NSpid` lists the process's PID in each namespace it's in, innermost last.
+fn is_namespace_init(status: &str) -> bool {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))
+        .and_then(|pids| pids.split_whitespace().last())
+        == Some("1")
+}
+
+/// The init process of the one container that `processes` (each with its container and whether
+/// it's its namespace's init process) run in. Without an init process, e.g. because the
+/// container shares another PID namespace, the container's oldest process is the lowest PID.
+fn pick_init_process(
+    query: &str,
+    mut processes: Vec<(Pid, ContainerInfo, bool)>,
+) -> Result<Option<(Pid, ContainerInfo)>> {
+    processes.sort_by_key(|(pid, _, init)| (!*init, *pid));
+    if let Some((_, first, _)) = processes.first() {
+        if processes.iter().any(|(_, info, _)| info.id != first.id) {
+            return Err(format_err!(
+                "More than one running container's ID starts with {}",
+                query
+            ));
+        }
+    }
+    Ok(processes
+        .into_iter()
+        .next()
+        .map(|(pid, info, _)| (pid, info)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4f1a3b0c9d2e8f7a6b5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b7c6d5e4f3a";
+
+    #[test]
+    fn test_from_cgroup() {
+        let cgroupfs = format!("0::/kubepods/burstable/pod1234-5678/{}\n", ID);
+        let info = from_cgroup(&cgroupfs).unwrap();
+        assert_eq!(info.id, ID);
+        assert_eq!(info.runtime, None);
+        assert_eq!(info.pod_uid.as_deref(), Some("1234-5678"));
+
+        let systemd = format!(
+            "12:memory:/system.slice\n\
+             0::/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod12_34.slice/cri-containerd-{}.scope\n",
+            ID
+        );
+        let info = from_cgroup(&systemd).unwrap();
+        assert_eq!(info.runtime.as_deref(), Some("containerd"));
+        assert_eq!(info.pod_uid.as_deref(), Some("12-34"));
+
+        let docker = from_cgroup(&format!("0::/system.slice/docker-{}.scope\n", ID)).unwrap();
+        assert_eq!(docker.runtime.as_deref(), Some("docker"));
+        assert_eq!(docker.pod_uid, None);
+        let docker = from_cgroup(&format!("4:cpu:/docker/{}\n", ID)).unwrap();
+        assert_eq!(docker.runtime.as_deref(), Some("docker"));
+
+        assert_eq!(from_cgroup("0::/user.slice/session-2.scope\n"), None);
+    }
+
+    #[test]
+    fn test_runtime_config() {
+        let mut info = from_cgroup(&format!("0::/system.slice/docker-{}.scope\n", ID)).unwrap();
+        add_runtime_config(
+            &mut info,
+            br#"{"Name": "/web", "Config": {"Image": "shop/web:1.2", "Labels": {}}}"#,
+        );
+        let labels = info.labels();
+        assert_eq!(labels["container_id"], &ID[..12]);
+        assert_eq!(labels["container_runtime"], "docker");
+        assert_eq!(labels["image"], "shop/web:1.2");
+        assert_eq!(labels["container"], "web");
+        assert!(!labels.contains_key("pod"));
+
+        let mut info = ContainerInfo {
+            id: ID.to_string(),
+            ..Default::default()
+        };
+        add_runtime_config(
+            &mut info,
+            br#"{"ociVersion": "1.0.2", "annotations": {
+                "io.kubernetes.cri.container-name": "puma",
+                "io.kubernetes.cri.image-name": "registry/web:3",
+                "io.kubernetes.cri.sandbox-name": "web-7d9f",
+                "io.kubernetes.cri.sandbox-namespace": "shop",
+                "io.kubernetes.cri.sandbox-uid": "u1"}}"#,
+        );
+        assert_eq!(info.name.as_deref(), Some("puma"));
+        assert_eq!(info.image.as_deref(), Some("registry/web:3"));
+        assert_eq!(info.pod.as_deref(), Some("web-7d9f"));
+        assert_eq!(info.namespace.as_deref(), Some("shop"));
+        assert_eq!(info.pod_uid.as_deref(), Some("u1"));
+
+        // Not a config
+        add_runtime_config(&mut info, b"<html>");
+        assert_eq!(info.pod.as_deref(), Some("web-7d9f"));
+    }
+
+    #[test]
+    fn test_find_container() {
+        let config = format!(
+            r#"{{"ID": "{}", "Name": "/web", "State": {{"Running": true, "Pid": 4321}}}}"#,
+            ID
+        );
+        let web = docker_container(config.as_bytes()).unwrap();
+        assert_eq!(web.name.as_deref(), Some("web"));
+        assert_eq!(web.pid, Some(4321));
+        let stopped = docker_container(
+            br#"{"ID": "4f1a3b0c", "Name": "/old", "State": {"Running": false, "Pid": 0}}"#,
+        )
+        .unwrap();
+        assert_eq!(stopped.pid, None);
+        let containers = vec![web, stopped];
+
+        assert_eq!(find_container(&containers, "web").unwrap().unwrap().id, ID);
+        assert_eq!(find_container(&containers, ID).unwrap().unwrap().id, ID);
+        // A whole ID is preferred to a longer ID that it's a prefix of
+        let old = find_container(&containers, "4f1a3b0c").unwrap().unwrap();
+        assert_eq!(old.name.as_deref(), Some("old"));
+        assert!(find_container(&containers, "4f1a3b").is_err());
+        assert_eq!(
+            find_container(&containers, "4f1a3b0c9")
+                .unwrap()
+                .unwrap()
+                .id,
+            ID
+        );
+        assert_eq!(find_container(&containers, "db").unwrap(), None);
+        assert!(find_container(&containers, "").is_err());
+    }
+
+    #[test]
+    fn test_pick_init_process() {
+        assert!(is_namespace_init("Name:\tpuma\nNSpid:\t4321\t1\n"));
+        assert!(!is_namespace_init("Name:\tpuma\nNSpid:\t4322\t7\n"));
+        assert!(!is_namespace_init("Name:\tpuma\n"));
+
+        let info = ContainerInfo {
+            id: ID.to_string(),
+            ..Default::default()
+        };
+        let processes = vec![(4322, info.clone(), false), (4321, info.clone(), true)];
+        assert_eq!(
+            pick_init_process("4f1a", processes).unwrap(),
+            Some((4321, info.clone()))
+        );
+        let processes = vec![(4323, info.clone(), false), (4322, info.clone(), false)];
+        assert_eq!(
+            pick_init_process("4f1a", processes).unwrap().unwrap().0,
+            4322
+        );
+        let other = ContainerInfo {
+            id: format!("{}0", &ID[..63]),
+            ..Default::default()
+        };
+        let processes = vec![(4321, info.clone(), true), (5000, other, true)];
+        assert!(pick_init_process("4f1a", processes).is_err());
+        assert_eq!(pick_init_process("4f1a", vec![]).unwrap(), None);
+
+        let target = ContainerTarget { pid: 4321, info };
+        assert_eq!(
+            target.host_path("/usr/local/bin/ruby"),
+            PathBuf::from("/proc/4321/root/usr/local/bin/ruby")
+        );
+    }
+}
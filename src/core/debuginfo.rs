@@ -0,0 +1,389 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{format_err, Result};
+use goblin::elf::Elf;
+use memmap2::Mmap;
+use spytools::binary_parser::BinaryInfo;
+use spytools::ProcessInfo;
+
+use crate::core::process::Pid;
+
+/*
+ * Distro packages of Ruby are usually stripped: their binaries keep the dynamic symbols, but not
+ * the symbol table that has `ruby_current_vm_ptr`, `ruby_current_execution_context_ptr` and the
+ * other symbols that rbspy looks up. The symbol table is shipped in a separate debug file (e.g.
+ * the `ruby-dbgsym` or `ruby-debuginfo` packages), which is found the same way gdb finds it:
+ *
+ * - by build ID, at /usr/lib/debug/.build-id/ab/cdef1234.debug
+ * - through the binary's .gnu_debuglink section, which names the debug file and has its CRC32
+ * - from the debuginfod servers listed in the DEBUGINFOD_URLS environment variable
+ *
+ * Debug files are looked for in the process's filesystem first (through /proc/<pid>/root), so
+ * that debug packages installed in a container are found, and then in rbspy's own. Downloads
+ * from debuginfod servers can be big and slow, so they happen in the background, into the cache
+ * that the next attempt to attach finds them in.
+ */
+
+const DEBUG_DIR: &str = "/usr/lib/debug";
+
+lazy_static::lazy_static! {
+    /// The build IDs whose debug files are being downloaded
+    static ref FETCHING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Adds the symbols from the debug files of the process's Ruby binary and libruby, for those that
+/// are stripped. Symbols that the binaries already have are kept.
+pub(crate) fn add_debug_symbols(process_info: &mut ProcessInfo, pid: Pid) {
+    let root = format!("/proc/{}/root", pid);
+    let exe = process_info.path.clone();
+    for binary in process_info
+        .binary
+        .iter_mut()
+        .chain(process_info.library.iter_mut())
+    {
+        // libruby is read through /proc/<pid>/root, and the executable through /proc/<pid>/exe
+        let path = match binary.filename.strip_prefix(&root) {
+            Ok(path) => Path::new("/").join(path),
+            Err(_) => exe.clone(),
+        };
+        if let Err(e) = add_binary_debug_symbols(binary, &path, &root) {
+            debug!("No debug symbols for {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+/// `path` is where the binary is in the process's filesystem, which its debug link is relative to
+fn add_binary_debug_symbols(binary: &mut BinaryInfo, path: &Path, root: &str) -> Result<()> {
+    let file = File::open(&binary.filename)?;
+    let buffer = unsafe { Mmap::map(&file)? };
+    let elf = Elf::parse(&buffer)?;
+    if !elf.syms.is_empty() {
+        // Not stripped
+        return Ok(());
+    }
+    let id = build_id(&elf, &buffer).map(|id| hex(&id));
+    let link = debug_link(&elf, &buffer);
+    let debug_file = find_debug_file(path, root, id.as_deref(), link.as_ref())
+        .or_else(|| id.as_deref().and_then(cached_debug_file));
+    let debug_file = match (debug_file, id) {
+        (Some(debug_file), _) => debug_file,
+        (None, Some(id)) if start_fetching(&id) => {
+            info!(
+                "Downloading the debug symbols for {} (build ID {}) in the background. They'll be used the next time rbspy attaches to it.",
+                path.display(),
+                id
+            );
+            return Err(format_err!("The debug file is being downloaded"));
+        }
+        (None, id) => {
+            return Err(format_err!(
+                "Couldn't find a debug file (build ID {:?})",
+                id
+            ))
+        }
+    };
+
+    let file = File::open(&debug_file)?;
+    let debug_buffer = unsafe { Mmap::map(&file)? };
+    let debug_elf = Elf::parse(&debug_buffer)?;
+    let offset = binary.offset;
+    let mut added = 0;
+    for sym in debug_elf.syms.iter() {
+        match debug_elf.strtab.get_at(sym.st_name) {
+            Some(name) if !name.is_empty() && sym.st_value != 0 => {
+                binary.symbols.entry(name.to_string()).or_insert_with(|| {
+                    added += 1;
+                    sym.st_value + offset
+                });
+            }
+            _ => {}
+        }
+    }
+    info!(
+        "Added {} symbols to {} from {}",
+        added,
+        path.display(),
+        debug_file.display()
+    );
+    Ok(())
+}
+
+/// The `.gnu_debuglink` section: the debug file's name and the CRC32 of its contents
+#[derive(Debug, PartialEq, Eq)]
+struct DebugLink {
+    name: String,
+    crc: u32,
+}
+
+fn build_id(elf: &Elf, buffer: &[u8]) -> Option<Vec<u8>> {
+    elf.iter_note_sections(buffer, None)?
+        .filter_map(|note| note.ok())
+        .find(|note| note.n_type == goblin::elf::note::NT_GNU_BUILD_ID && note.name == "GNU")
+        .map(|note| note.desc.to_vec())
+}
+
+fn debug_link(elf: &Elf, buffer: &[u8]) -> Option<DebugLink> {
+    let section = elf
+        .section_headers
+        .iter()
+        .find(|header| elf.shdr_strtab.get_at(header.sh_name) == Some(".gnu_debuglink"))?;
+    let start = section.sh_offset as usize;
+    let data = buffer.get(start..start.checked_add(section.sh_size as usize)?)?;
+    parse_debug_link(data, elf.little_endian)
+}
+
+/// The section has the file's name, NUL-terminated and padded to 4 bytes, followed by the CRC
+fn parse_debug_link(data: &[u8], little_endian: bool) -> Option<DebugLink> {
+    let name_len = data.iter().position(|&b| b == 0)?;
+    let name = std::str::from_utf8(&data[..name_len]).ok()?;
+    let crc_start = (name_len + 1 + 3) & !3;
+    let crc: [u8; 4] = data.get(crc_start..crc_start + 4)?.try_into().ok()?;
+    if name.is_empty() {
+        return None;
+    }
+    Some(DebugLink {
+        name: name.to_string(),
+        crc: if little_endian {
+            u32::from_le_bytes(crc)
+        } else {
+            u32::from_be_bytes(crc)
+        },
+    })
+}
+
+/// Where gdb looks for the debug file of the binary at `path`, in order
+fn debug_file_candidates(path: &Path, id: Option<&str>, link: Option<&DebugLink>) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(id) = id.filter(|id| id.len() > 2) {
+        candidates.push(
+            Path::new(DEBUG_DIR)
+                .join(".build-id")
+                .join(&id[..2])
+                .join(format!("{}.debug", &id[2..])),
+        );
+    }
+    if let (Some(link), Some(dir)) = (link, path.parent()) {
+        candidates.push(dir.join(&link.name));
+        candidates.push(dir.join(".debug").join(&link.name));
+        candidates.push(
+            Path::new(DEBUG_DIR)
+                .join(dir.strip_prefix("/").unwrap_or(dir))
+                .join(&link.name),
+        );
+    }
+    candidates
+}
+
+fn find_debug_file(
+    path: &Path,
+    root: &str,
+    id: Option<&str>,
+    link: Option<&DebugLink>,
+) -> Option<PathBuf> {
+    debug_file_candidates(path, id, link)
+        .into_iter()
+        .flat_map(|candidate| {
+            [
+                PathBuf::from(format!("{}{}", root, candidate.display())),
+                candidate,
+            ]
+        })
+        // The debug link can have the binary's own name, in another directory
+        .filter(|candidate| {
+            candidate != path && *candidate != Path::new(&format!("{}{}", root, path.display()))
+        })
+        .find(|candidate| match fs::read(candidate) {
+            Ok(contents) => matches_binary(&contents, id, link),
+            Err(_) => false,
+        })
+}
+
+/// Whether a debug file belongs to the binary, by build ID if it has one, and otherwise by the
+/// debug link's CRC
+fn matches_binary(contents: &[u8], id: Option<&str>, link: Option<&DebugLink>) -> bool {
+    match (id, link) {
+        (Some(id), _) => Elf::parse(contents)
+            .ok()
+            .and_then(|elf| build_id(&elf, contents))
+            .map_or(false, |debug_id| hex(&debug_id) == id),
+        (None, Some(link)) => {
+            let mut crc = flate2::Crc::new();
+            crc.update(contents);
+            crc.sum() == link.crc
+        }
+        (None, None) => false,
+    }
+}
+
+/// Where gdb and `debuginfod-find` cache the debug file with the given build ID, if it's there
+fn cached_debug_file(id: &str) -> Option<PathBuf> {
+    let cached = debuginfod_cache()?.join(id).join("debuginfo");
+    Some(cached).filter(|cached| cached.exists())
+}
+
+/// Starts downloading the debug file with the given build ID from the servers in
+/// `DEBUGINFOD_URLS` on another thread, unless it's already being downloaded. Returns whether
+/// there's a server to download it from. Only plain http:// servers can be downloaded from, but
+/// files that other tools already fetched over HTTPS are found in the cache.
+fn start_fetching(id: &str) -> bool {
+    let urls: Vec<String> = std::env::var("DEBUGINFOD_URLS")
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|url| {
+            let http = url.starts_with("http://");
+            if !http {
+                warn!(
+                    "Not downloading debug symbols from {}, since rbspy only supports plain http:// debuginfod servers. `debuginfod-find debuginfo {}` can download them into the cache that rbspy looks in.",
+                    url, id
+                );
+            }
+            http
+        })
+        .map(|url| url.trim_end_matches('/').to_string())
+        .collect();
+    if urls.is_empty() {
+        return false;
+    }
+    if !FETCHING.lock().unwrap().insert(id.to_string()) {
+        return true;
+    }
+    let fetched_id = id.to_string();
+    let spawned = std::thread::Builder::new()
+        .name("debuginfod".to_string())
+        .spawn(move || {
+            fetch_debug_file(&fetched_id, &urls);
+            FETCHING.lock().unwrap().remove(&fetched_id);
+        });
+    match spawned {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Failed to start downloading debug symbols: {}", e);
+            FETCHING.lock().unwrap().remove(id);
+            false
+        }
+    }
+}
+
+/// Downloads the debug file with the given build ID from the first of the servers that has it,
+/// into the same cache that gdb and `debuginfod-find` use
+fn fetch_debug_file(id: &str, urls: &[String]) {
+    let cached = match debuginfod_cache() {
+        Some(cache) => cache.join(id).join("debuginfo"),
+        None => return,
+    };
+    for url in urls {
+        let url = format!("{}/buildid/{}/debuginfo", url, id);
+        let contents = match crate::core::http::get(&url) {
+            Ok(contents) if matches_binary(&contents, Some(id), None) => contents,
+            Ok(_) => {
+                warn!("{} isn't the debug file for build ID {}", url, id);
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to download debug symbols from {}: {:?}", url, e);
+                continue;
+            }
+        };
+        info!("Downloaded debug symbols from {}", url);
+        // Written to a temporary file first, so that other tools never see a partial file
+        let partial = cached.with_extension("rbspy-partial");
+        let written = cached
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&partial, &contents))
+            .and_then(|_| fs::rename(&partial, &cached));
+        match written {
+            Ok(()) => return,
+            Err(e) => warn!(
+                "Failed to cache debug symbols in {}: {}",
+                cached.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// `$DEBUGINFOD_CACHE_PATH`, or `$XDG_CACHE_HOME/debuginfod_client`
+fn debuginfod_cache() -> Option<PathBuf> {
+    match std::env::var_os("DEBUGINFOD_CACHE_PATH") {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => directories::BaseDirs::new().map(|dirs| dirs.cache_dir().join("debuginfod_client")),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_debug_link() {
+        let mut data = b"libruby.so.3.1.2.debug\0\0".to_vec();
+        data.extend_from_slice(&0x1234_5678u32.to_le_bytes());
+        assert_eq!(
+            parse_debug_link(&data, true),
+            Some(DebugLink {
+                name: "libruby.so.3.1.2.debug".to_string(),
+                crc: 0x1234_5678,
+            })
+        );
+        // The name is padded to 4 bytes
+        let mut data = b"ruby.debug\0\0".to_vec();
+        data.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        assert_eq!(parse_debug_link(&data, false).unwrap().crc, 0x1234_5678);
+        assert_eq!(parse_debug_link(b"ruby.debug\0\0", true), None);
+        assert_eq!(parse_debug_link(b"\0\0\0\0\x01\x02\x03\x04", true), None);
+    }
+
+    #[test]
+    fn test_debug_file_candidates() {
+        let link = DebugLink {
+            name: "libruby.so.3.1.debug".to_string(),
+            crc: 0,
+        };
+        let candidates = debug_file_candidates(
+            Path::new("/usr/lib/x86_64-linux-gnu/libruby-3.1.so.3.1"),
+            Some("ab12cd34"),
+            Some(&link),
+        );
+        assert_eq!(
+            candidates,
+            [
+                "/usr/lib/debug/.build-id/ab/12cd34.debug",
+                "/usr/lib/x86_64-linux-gnu/libruby.so.3.1.debug",
+                "/usr/lib/x86_64-linux-gnu/.debug/libruby.so.3.1.debug",
+                "/usr/lib/debug/usr/lib/x86_64-linux-gnu/libruby.so.3.1.debug",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+        assert!(debug_file_candidates(Path::new("/usr/bin/ruby"), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_matches_binary() {
+        let link = DebugLink {
+            name: "ruby.debug".to_string(),
+            crc: 0x414f_a339,
+        };
+        assert!(matches_binary(
+            b"The quick brown fox jumps over the lazy dog",
+            None,
+            Some(&link)
+        ));
+        assert!(!matches_binary(b"something else", None, Some(&link)));
+        // Files that aren't ELF don't have the build ID
+        assert!(!matches_binary(
+            b"not an ELF file",
+            Some("ab12"),
+            Some(&link)
+        ));
+    }
+}
@@ -0,0 +1,357 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Result;
+use spytools::ProcessInfo;
+
+use crate::core::process::{Pid, Process};
+
+/// A Ruby process that's running on this system
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RubyProcess {
+    pub pid: Pid,
+    /// The user the process is running as, if it could be found out
+    pub user: Option<String>,
+    pub cmdline: String,
+    /// The process's Ruby version, if rbspy was able to read it
+    pub version: Option<String>,
+    /// How long the process has been running, in seconds
+    pub uptime_secs: Option<u64>,
+    /// Whether rbspy looks able to profile the process: it can read the process's memory, and
+    /// knows its Ruby version
+    pub profilable: bool,
+    /// Why the process doesn't look profilable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+}
+
+/// Lists the Ruby processes on this system that rbspy can see, in PID order. Linux and macOS
+/// only.
+pub fn list_ruby_processes() -> Result<Vec<RubyProcess>> {
+    let own_pid = std::process::id() as Pid;
+    let mut processes: Vec<RubyProcess> = all_pids()?
+        .into_iter()
+        .filter(|&pid| pid != own_pid)
+        .filter_map(ruby_process)
+        .collect();
+    processes.sort_by_key(|process| process.pid);
+    Ok(processes)
+}
+
+/// Finds the Ruby processes whose command line contains `name`
+pub fn find_ruby_processes(name: &str) -> Result<Vec<RubyProcess>> {
+    Ok(list_ruby_processes()?
+        .into_iter()
+        .filter(|process| process.cmdline.contains(name))
+        .collect())
+}
+
+/// Asks which of `processes` to profile, showing a numbered list of them on `output` and reading
+/// the choice from `input`. Returns `None` if there are no processes to choose from or nothing
+/// was chosen.
+pub fn pick_process(
+    processes: &[RubyProcess],
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> Result<Option<Pid>> {
+    if processes.is_empty() {
+        writeln!(output, "No Ruby processes found")?;
+        return Ok(None);
+    }
+    writeln!(
+        output,
+        "{:>3}  {:>7}  {:<12} {:<8} COMMAND",
+        "#", "PID", "USER", "VERSION"
+    )?;
+    for (i, process) in processes.iter().enumerate() {
+        writeln!(
+            output,
+            "{:>3}  {:>7}  {:<12} {:<8} {}",
+            i + 1,
+            process.pid,
+            process.user.as_deref().unwrap_or("?"),
+            process.version.as_deref().unwrap_or("?"),
+            process.cmdline
+        )?;
+    }
+    loop {
+        write!(output, "Profile which process? [1-{}] ", processes.len())?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        match line.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= processes.len() => return Ok(Some(processes[n - 1].pid)),
+            _ => writeln!(
+                output,
+                "Enter a number between 1 and {}, or nothing to cancel",
+                processes.len()
+            )?,
+        }
+    }
+}
+
+/// Writes a list of processes, as a table or as a JSON array
+pub fn write_process_list(
+    processes: &[RubyProcess],
+    json: bool,
+    output: &mut dyn Write,
+) -> Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *output, processes)?;
+        writeln!(output)?;
+        return Ok(());
+    }
+    writeln!(
+        output,
+        "{:>7}  {:<12} {:<8} {:>9}  {:<10} COMMAND",
+        "PID", "USER", "VERSION", "UPTIME", "PROFILABLE"
+    )?;
+    for process in processes {
+        let profilable = match &process.problem {
+            _ if process.profilable => "yes".to_string(),
+            Some(problem) => format!("no ({})", problem),
+            None => "no".to_string(),
+        };
+        writeln!(
+            output,
+            "{:>7}  {:<12} {:<8} {:>9}  {:<10} {}",
+            process.pid,
+            process.user.as_deref().unwrap_or("?"),
+            process.version.as_deref().unwrap_or("?"),
+            process.uptime_secs.map_or("?".to_string(), format_uptime),
+            profilable,
+            process.cmdline
+        )?;
+    }
+    Ok(())
+}
+
+/// Formats an uptime like `ps` does, e.g. `2-03:04:05` or `04:05`
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours) {
+        (0, 0) => format!("{:02}:{:02}", minutes, secs),
+        (0, _) => format!("{:02}:{:02}:{:02}", hours, minutes, secs),
+        _ => format!("{}-{:02}:{:02}:{:02}", days, hours, minutes, secs),
+    }
+}
+
+/// Describes the process if it's running Ruby, either as its executable or through libruby
+fn ruby_process(pid: Pid) -> Option<RubyProcess> {
+    let process = Process::new(pid).ok()?;
+    let exe = process.exe().ok()?;
+    let exe_is_ruby = std::path::Path::new(&exe)
+        .file_name()
+        .map_or(false, |name| name.to_string_lossy().starts_with("ruby"));
+    if !exe_is_ruby && !loads_libruby(pid) {
+        return None;
+    }
+    let cmdline = match process.cmdline() {
+        Ok(cmdline) if !cmdline.is_empty() => cmdline.join(" "),
+        _ => exe,
+    };
+    let version =
+        ProcessInfo::new::<spytools::process::RubyProcessType>(&process).and_then(|process_info| {
+            let memory = crate::core::memory::MemoryReader::new(pid)?;
+            crate::core::address_finder::get_ruby_version(&memory, &process_info, None)
+        });
+    let problem = match &version {
+        Ok(version) if crate::core::ruby_version::compatible_version(version).is_some() => None,
+        Ok(version) => Some(format!("Ruby {} isn't supported", version)),
+        Err(err) => Some(match crate::core::errors::error_code(err) {
+            Some(code) => format!("{}: {}", code, code.summary()),
+            None => err.to_string(),
+        }),
+    };
+    Some(RubyProcess {
+        pid,
+        user: process_user(pid),
+        cmdline,
+        version: version.ok().map(|version| version.to_string()),
+        uptime_secs: process_uptime(pid).map(|uptime| uptime.as_secs()),
+        profilable: problem.is_none(),
+        problem,
+    })
+}
+
+fn loads_libruby(pid: Pid) -> bool {
+    proc_maps::get_process_maps(pid).map_or(false, |maps| {
+        maps.iter().any(|map| {
+            map.filename().map_or(false, |filename| {
+                filename
+                    .file_name()
+                    .map_or(false, |name| name.to_string_lossy().starts_with("libruby"))
+            })
+        })
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn all_pids() -> Result<Vec<Pid>> {
+    Ok(std::fs::read_dir("/proc")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect())
+}
+
+#[cfg(target_os = "macos")]
+fn all_pids() -> Result<Vec<Pid>> {
+    use libproc::libproc::proc_pid::{listpids, ProcType};
+
+    Ok(listpids(ProcType::ProcAllPIDS)
+        .map_err(|e| anyhow::format_err!("Failed to list processes: {}", e))?
+        .into_iter()
+        .map(|pid| pid as Pid)
+        .collect())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn all_pids() -> Result<Vec<Pid>> {
+    Err(anyhow::format_err!(
+        "Listing processes isn't supported on this platform"
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn process_uid(pid: Pid) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(target_os = "macos")]
+fn process_uid(pid: Pid) -> Option<u32> {
+    use libproc::libproc::bsd_info::BSDInfo;
+    use libproc::libproc::proc_pid::pidinfo;
+
+    pidinfo::<BSDInfo>(pid, 0).ok().map(|info| info.pbi_uid)
+}
+
+#[cfg(target_os = "linux")]
+fn process_uptime(pid: Pid) -> Option<std::time::Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The start time is the 22nd field, and the 20th after the command name, which is in
+    // parentheses and may itself contain spaces
+    let start_ticks: u64 = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()?;
+    let ticks_per_sec = nix::unistd::sysconf(nix::unistd::SysconfVar::CLK_TCK)
+        .ok()
+        .flatten()? as u64;
+    let system_uptime: f64 = std::fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    let started = start_ticks as f64 / ticks_per_sec as f64;
+    Some(std::time::Duration::from_secs_f64(
+        (system_uptime - started).max(0.0),
+    ))
+}
+
+#[cfg(target_os = "macos")]
+fn process_uptime(pid: Pid) -> Option<std::time::Duration> {
+    use libproc::libproc::bsd_info::BSDInfo;
+    use libproc::libproc::proc_pid::pidinfo;
+
+    let info = pidinfo::<BSDInfo>(pid, 0).ok()?;
+    let started = std::time::UNIX_EPOCH + std::time::Duration::from_secs(info.pbi_start_tvsec);
+    std::time::SystemTime::now().duration_since(started).ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn process_uptime(_pid: Pid) -> Option<std::time::Duration> {
+    None
+}
+
+#[cfg(unix)]
+fn process_user(pid: Pid) -> Option<String> {
+    let uid = process_uid(pid)?;
+    match nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid)) {
+        Ok(Some(user)) => Some(user.name),
+        _ => Some(uid.to_string()),
+    }
+}
+
+#[cfg(not(unix))]
+fn process_user(_pid: Pid) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: Pid) -> RubyProcess {
+        RubyProcess {
+            pid,
+            user: Some("app".to_string()),
+            cmdline: format!("puma worker {}", pid),
+            version: Some("3.2.2".to_string()),
+            uptime_secs: Some(93784),
+            profilable: true,
+            problem: None,
+        }
+    }
+
+    #[test]
+    fn test_pick_process() {
+        let processes = vec![process(10), process(20)];
+        let mut output = Vec::new();
+        let picked = pick_process(&processes, &mut "3\n2\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(picked, Some(20));
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("  1       10  app          3.2.2    puma worker 10\n"));
+        assert!(output.contains("Enter a number between 1 and 2"));
+
+        let mut output = Vec::new();
+        assert_eq!(
+            pick_process(&processes, &mut "\n".as_bytes(), &mut output).unwrap(),
+            None
+        );
+        assert_eq!(
+            pick_process(&[], &mut "1\n".as_bytes(), &mut output).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_write_process_list() {
+        let mut stripped = process(20);
+        stripped.version = None;
+        stripped.uptime_secs = Some(65);
+        stripped.profilable = false;
+        stripped.problem = Some("RBSPY003: The process's Ruby binary is missing symbols".into());
+        let processes = vec![process(10), stripped];
+
+        let mut output = Vec::new();
+        write_process_list(&processes, false, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[1],
+            "     10  app          3.2.2    1-02:03:04  yes        puma worker 10"
+        );
+        assert!(lines[2].contains("    01:05  no (RBSPY003: "));
+
+        let mut output = Vec::new();
+        write_process_list(&processes, true, &mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json[0]["uptime_secs"], 93784);
+        assert_eq!(json[0]["profilable"], true);
+        assert!(json[0].get("problem").is_none());
+        assert_eq!(json[1]["profilable"], false);
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+use crate::core::process::Pid;
+use crate::core::types::{EnrichmentHook, StackTrace};
+
+/// The environment that the process belonging to `pid` was started with. Changes that the
+/// process makes to its own environment later (e.g. with `ENV[]=`) aren't seen. Reading another
+/// user's process's environment needs the same permissions as profiling it.
+#[cfg(target_os = "linux")]
+pub fn read_environ(pid: Pid) -> Result<BTreeMap<String, String>> {
+    use anyhow::Context;
+
+    let path = format!("/proc/{}/environ", pid);
+    let environ = std::fs::read(&path).context(format!("Failed to read {}", path))?;
+    Ok(parse_environ(&environ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_environ(_pid: Pid) -> Result<BTreeMap<String, String>> {
+    Err(anyhow::format_err!(
+        "Reading a process's environment is only supported on Linux"
+    ))
+}
+
+/// An enrichment hook that labels each trace with the values of the environment variables
+/// `names` in its process, e.g. `RAILS_ENV` or `GIT_SHA`. Variables that aren't set are left out.
+/// The environment is read once per process, and labels that are already set take precedence.
+pub fn capture_env_hook(names: Vec<String>) -> EnrichmentHook {
+    let labels: Mutex<HashMap<Pid, BTreeMap<String, String>>> = Mutex::new(HashMap::new());
+    Box::new(move |trace: &mut StackTrace| {
+        let pid = match trace.pid {
+            Some(pid) => pid,
+            None => return,
+        };
+        let mut labels = labels.lock().unwrap();
+        let env_labels = labels
+            .entry(pid)
+            .or_insert_with(|| match read_environ(pid) {
+                Ok(mut environ) => names
+                    .iter()
+                    .filter_map(|name| Some((name.clone(), environ.remove(name)?)))
+                    .collect(),
+                Err(e) => {
+                    warn!(
+                        "Couldn't capture environment variables from process {}: {:#}",
+                        pid, e
+                    );
+                    BTreeMap::new()
+                }
+            });
+        for (key, value) in env_labels.iter() {
+            trace
+                .labels
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    })
+}
+
+/// Parses the `NAME=value` entries, separated by null bytes, of `/proc/<pid>/environ`
+fn parse_environ(environ: &[u8]) -> BTreeMap<String, String> {
+    environ
+        .split(|&byte| byte == 0)
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (name, value) = entry.split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_environ() {
+        let environ =
+            parse_environ(b"RAILS_ENV=production\0GIT_SHA=4f1a3b0\0EMPTY=\0A=b=c\0junk\0");
+        assert_eq!(environ.len(), 4);
+        assert_eq!(environ["RAILS_ENV"], "production");
+        assert_eq!(environ["GIT_SHA"], "4f1a3b0");
+        assert_eq!(environ["EMPTY"], "");
+        assert_eq!(environ["A"], "b=c");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_capture_env_hook() {
+        let hook = capture_env_hook(vec!["PATH".to_string(), "RBSPY_UNSET".to_string()]);
+        let mut trace = StackTrace::new_empty();
+        trace.pid = Some(std::process::id() as Pid);
+        hook(&mut trace);
+        assert_eq!(trace.labels.len(), 1);
+        assert_eq!(trace.labels["PATH"], std::env::var("PATH").unwrap());
+
+        let mut trace = StackTrace::new_empty();
+        trace.pid = Some(std::process::id() as Pid);
+        trace.labels.insert("PATH".to_string(), "set".to_string());
+        hook(&mut trace);
+        assert_eq!(trace.labels["PATH"], "set");
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+
+use anyhow::Error;
+
+/// A common class of failure, with a short code that `explain` can look up
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// rbspy wasn't allowed to read the target process's memory
+    PermissionDenied,
+    /// The target is running a Ruby version that rbspy doesn't know how to read
+    UnsupportedVersion,
+    /// The target's Ruby binary doesn't have the symbols that rbspy uses to find the Ruby VM
+    StrippedBinary,
+    /// The target was built for a different architecture than rbspy (e.g. 32-bit vs 64-bit)
+    ArchitectureMismatch,
+    /// The target is in a different container than rbspy, and can't be seen from rbspy's
+    /// namespaces
+    ContainerNamespace,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 5] = [
+        ErrorCode::PermissionDenied,
+        ErrorCode::UnsupportedVersion,
+        ErrorCode::StrippedBinary,
+        ErrorCode::ArchitectureMismatch,
+        ErrorCode::ContainerNamespace,
+    ];
+
+    /// The short code that's shown with errors, e.g. `RBSPY001`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::PermissionDenied => "RBSPY001",
+            ErrorCode::UnsupportedVersion => "RBSPY002",
+            ErrorCode::StrippedBinary => "RBSPY003",
+            ErrorCode::ArchitectureMismatch => "RBSPY004",
+            ErrorCode::ContainerNamespace => "RBSPY005",
+        }
+    }
+
+    pub fn summary(&self) -> &'static str {
+        match self {
+            ErrorCode::PermissionDenied => "rbspy isn't allowed to read the process's memory",
+            ErrorCode::UnsupportedVersion => "The process's Ruby version isn't supported",
+            ErrorCode::StrippedBinary => "The process's Ruby binary is missing symbols",
+            ErrorCode::ArchitectureMismatch => {
+                "The process was built for a different architecture than rbspy"
+            }
+            ErrorCode::ContainerNamespace => "The process is in a different container than rbspy",
+        }
+    }
+
+    /// What to do about the error
+    pub fn remediation(&self) -> &'static str {
+        match self {
+            ErrorCode::PermissionDenied => "Reading another process's memory takes the same privileges as attaching a debugger to it. Run rbspy as root or as the user that owns the process, or grant it the CAP_SYS_PTRACE capability. On Linux, check kernel.yama.ptrace_scope and any SELinux or AppArmor policy that confines rbspy; inside a container, the container needs the SYS_PTRACE capability. In sandboxes that block process_vm_readv (e.g. gVisor or strict seccomp profiles), rbspy reads /proc/<pid>/mem instead, which needs the same privileges. `run_checks` reports which of these applies.",
+            ErrorCode::UnsupportedVersion => "rbspy reads Ruby's internal data structures, which change between versions, so each version needs explicit support. Upgrade rbspy to get support for newer Ruby releases. For a pre-release or patched Ruby, setting `force_version` to the closest supported version usually works.",
+            ErrorCode::StrippedBinary => "rbspy finds the Ruby VM through symbols such as `ruby_version` and `ruby_current_vm_ptr` in the Ruby binary or libruby. Some packages strip these symbols. Install a Ruby build that keeps them (e.g. one built with rbenv, ruby-build or the official Docker images), or install the package's debug symbols (e.g. `ruby-dbgsym` or `ruby-debuginfo`), which rbspy finds through the binary's build ID or debug link under /usr/lib/debug. rbspy also downloads debug symbols from the debuginfod servers in the `DEBUGINFOD_URLS` environment variable, over plain HTTP and in the background, so they're used from the next time rbspy attaches. It uses any that gdb or `debuginfod-find` already downloaded, which is how to get them from HTTPS servers.",
+            ErrorCode::ArchitectureMismatch => "rbspy can only read processes built for the same architecture as itself. Use a 32-bit build of rbspy to profile 32-bit Ruby, or a 64-bit build for 64-bit Ruby.",
+            ErrorCode::ContainerNamespace => "rbspy can only see processes in its own PID namespace, and needs to be able to read the process's files, which it does through /proc/<pid>/root, so they don't have to exist on the host. Run rbspy in the same container as the process, or share the process's PID namespace with rbspy's container (e.g. `docker run --pid=container:<name>`, or `shareProcessNamespace: true` in Kubernetes). From the host, use the PID that the host sees for the process.",
+        }
+    }
+
+    /// Looks up an error code, e.g. `RBSPY001`. Case-insensitive.
+    pub fn from_code(code: &str) -> Option<ErrorCode> {
+        ErrorCode::ALL
+            .iter()
+            .copied()
+            .find(|error| error.code().eq_ignore_ascii_case(code.trim()))
+    }
+
+    /// Tags an error with this code, with a message that describes what went wrong
+    pub(crate) fn error(self, message: impl Into<String>) -> CodedError {
+        CodedError {
+            code: self,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+/// An error in one of the classes that `ErrorCode` describes. It's attached as context to the
+/// underlying error, so the code can be found with `error_code`.
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (error {}; see `explain(\"{}\")` for how to fix it)",
+            self.message, self.code, self.code
+        )
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// The class of an error returned by rbspy, if it's one of the common ones that `ErrorCode`
+/// describes
+pub fn error_code(error: &Error) -> Option<ErrorCode> {
+    error.downcast_ref::<CodedError>().map(|error| error.code)
+}
+
+/// Explains an error code (e.g. `RBSPY001`): what it means and how to fix it
+pub fn explain(code: &str) -> Option<String> {
+    ErrorCode::from_code(code)
+        .map(|error| format!("{}: {}\n\n{}", error, error.summary(), error.remediation()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        let error = Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            .context(ErrorCode::PermissionDenied.error("Failed to read memory"))
+            .context("get ruby VM state");
+        assert_eq!(error_code(&error), Some(ErrorCode::PermissionDenied));
+        assert!(format!("{:#}", error).contains("(error RBSPY001;"));
+        assert_eq!(error_code(&anyhow::format_err!("other")), None);
+
+        assert_eq!(
+            ErrorCode::from_code("rbspy003"),
+            Some(ErrorCode::StrippedBinary)
+        );
+        assert!(explain("RBSPY005")
+            .unwrap()
+            .starts_with("RBSPY005: The process is in a different container"));
+        assert_eq!(explain("RBSPY999"), None);
+    }
+}
@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::io;
+
+use anyhow::{format_err, Context, Result};
+
+use crate::core::process::{Pid, ProcessMemory, SampleMemory};
+use crate::core::ruby_spy::RubySpy;
+use crate::core::types::{StackFrame, StackTrace};
+use crate::storage::integrity::{hex, unhex};
+
+/// A copy of the memory that reading one stack trace from a Ruby process touched, along with the
+/// addresses rbspy found in the process. A fixture can be replayed without the process, so it
+/// makes a reproducible test case for a Ruby version that rbspy reads incorrectly.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fixture {
+    pub ruby_version: String,
+    /// The architecture rbspy was built for when the fixture was made. Fixtures only replay on
+    /// the same architecture, since the Ruby structs they hold have its pointer size.
+    pub arch: String,
+    pub pid: Pid,
+    pub current_thread_addr_location: usize,
+    pub ruby_vm_addr_location: usize,
+    pub global_symbols_addr_location: Option<usize>,
+    /// The stack trace that was read when the fixture was made, which replays should match
+    pub expected_trace: Vec<StackFrame>,
+    /// The memory that was read, with overlapping and adjacent reads merged, in address order
+    pub regions: Vec<Region>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub address: usize,
+    #[serde(with = "hex_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl Region {
+    fn end(&self) -> usize {
+        self.address + self.data.len()
+    }
+}
+
+impl Fixture {
+    /// Reads the stack trace from the process again, using only the fixture's memory. Set
+    /// `ruby_version` first to read it as another version of Ruby.
+    pub fn replay(&self) -> Result<Option<StackTrace>> {
+        if self.arch != std::env::consts::ARCH {
+            return Err(format_err!(
+                "The fixture was made on {}, so it can't be replayed on {}",
+                self.arch,
+                std::env::consts::ARCH
+            ));
+        }
+        let version = semver::Version::parse(&self.ruby_version)
+            .context(format!("Invalid Ruby version {}", self.ruby_version))?;
+        let stack_trace_function = crate::core::ruby_version::stack_trace_function_for::<Fixture>(
+            &version,
+        )
+        .ok_or_else(|| format_err!("Ruby version not supported yet: {}", self.ruby_version))?;
+        let trace = stack_trace_function(
+            self.current_thread_addr_location,
+            self.ruby_vm_addr_location,
+            self.global_symbols_addr_location,
+            self,
+            self.pid,
+            false,
+        )?;
+        Ok(trace.map(|trace| StackTrace {
+            pid: Some(self.pid),
+            ..trace
+        }))
+    }
+
+    /// Fails unless replaying the fixture gives the stack trace that was read when it was made
+    pub fn check(&self) -> Result<()> {
+        let trace = self.replay()?.map(|trace| trace.trace).unwrap_or_default();
+        if trace != self.expected_trace {
+            return Err(format_err!(
+                "Replayed stack trace doesn't match the expected one.\nExpected: {:#?}\nReplayed: {:#?}",
+                self.expected_trace,
+                trace
+            ));
+        }
+        Ok(())
+    }
+
+    /// Writes the fixture as gzipped JSON
+    pub fn write(&self, w: &mut dyn io::Write) -> Result<()> {
+        let mut encoder = flate2::write::GzEncoder::new(w, flate2::Compression::default());
+        serde_json::to_writer(&mut encoder, self)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    pub fn from_reader(r: &mut dyn io::Read) -> Result<Fixture> {
+        let decoder = flate2::read::GzDecoder::new(r);
+        serde_json::from_reader(decoder).context("Failed to read the fixture")
+    }
+}
+
+impl ProcessMemory for Fixture {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        let index = self.regions.partition_point(|region| region.end() <= addr);
+        match self.regions.get(index) {
+            Some(region) if region.address <= addr && addr + buf.len() <= region.end() => {
+                let start = addr - region.address;
+                buf.copy_from_slice(&region.data[start..start + buf.len()]);
+                Ok(())
+            }
+            _ => Err(remoteprocess::Error::Other(format!(
+                "The fixture doesn't have the {} bytes at {:#x}",
+                buf.len(),
+                addr
+            ))),
+        }
+    }
+}
+
+impl SampleMemory for Fixture {}
+
+/// Passes reads on to another source of memory and keeps a copy of what was read
+pub(crate) struct RecordingMemory<'a, T: ProcessMemory> {
+    source: &'a T,
+    reads: RefCell<Vec<Region>>,
+}
+
+impl<'a, T: ProcessMemory> RecordingMemory<'a, T> {
+    pub fn new(source: &'a T) -> Self {
+        RecordingMemory {
+            source,
+            reads: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The memory that was read, with overlapping and adjacent reads merged
+    pub fn into_regions(self) -> Vec<Region> {
+        let mut reads = self.reads.into_inner();
+        reads.sort_by_key(|read| read.address);
+        let mut regions: Vec<Region> = Vec::new();
+        for read in reads {
+            match regions.last_mut() {
+                Some(last) if read.address <= last.end() => {
+                    if read.end() > last.end() {
+                        let overlap = last.end() - read.address;
+                        last.data.extend_from_slice(&read.data[overlap..]);
+                    }
+                }
+                _ => regions.push(read),
+            }
+        }
+        regions
+    }
+}
+
+impl<T: ProcessMemory> ProcessMemory for RecordingMemory<'_, T> {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        self.source.read(addr, buf)?;
+        self.reads.borrow_mut().push(Region {
+            address: addr,
+            data: buf.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+impl<T: ProcessMemory> SampleMemory for RecordingMemory<'_, T> {}
+
+/// Makes a fixture from a running Ruby process, e.g. to attach to a bug report about a Ruby
+/// version that rbspy reads incorrectly. The process is paused while its memory is copied.
+pub fn generate_testdata(pid: Pid, force_version: Option<String>) -> Result<Fixture> {
+    RubySpy::new(pid, force_version)?.capture_fixture()
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::hex(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        super::unhex(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixture_memory() {
+        let data: Vec<u8> = (0..64).collect();
+        let source = Fixture {
+            ruby_version: "3.2.2".to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            pid: 1,
+            current_thread_addr_location: 0,
+            ruby_vm_addr_location: 0,
+            global_symbols_addr_location: None,
+            expected_trace: vec![],
+            regions: vec![Region {
+                address: 0x1000,
+                data: data.clone(),
+            }],
+        };
+
+        let memory = RecordingMemory::new(&source);
+        memory.copy(0x1010, 8).unwrap();
+        memory.copy(0x1000, 4).unwrap();
+        memory.copy(0x1014, 8).unwrap();
+        memory.copy(0x101c, 4).unwrap();
+        memory.copy(0x1030, 4).unwrap();
+        assert!(memory.copy(0x1038, 16).is_err());
+        let regions = memory.into_regions();
+        assert_eq!(
+            regions,
+            vec![
+                Region {
+                    address: 0x1000,
+                    data: data[0..4].to_vec(),
+                },
+                Region {
+                    address: 0x1010,
+                    data: data[0x10..0x20].to_vec(),
+                },
+                Region {
+                    address: 0x1030,
+                    data: data[0x30..0x34].to_vec(),
+                },
+            ]
+        );
+
+        let fixture = Fixture { regions, ..source };
+        assert_eq!(fixture.copy(0x1018, 4).unwrap(), data[0x18..0x1c].to_vec());
+        assert!(fixture.copy(0x1004, 4).is_err());
+        assert!(fixture.copy(0x101e, 4).is_err());
+
+        let mut written = Vec::new();
+        fixture.write(&mut written).unwrap();
+        assert_eq!(
+            Fixture::from_reader(&mut written.as_slice()).unwrap(),
+            fixture
+        );
+
+        let other_arch = Fixture {
+            arch: "other".to_string(),
+            ..fixture.clone()
+        };
+        assert!(other_arch.replay().is_err());
+        let unsupported = Fixture {
+            ruby_version: "1.8.7".to_string(),
+            ..fixture
+        };
+        assert!(unsupported.replay().is_err());
+    }
+}
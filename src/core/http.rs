@@ -0,0 +1,253 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use anyhow::{format_err, Context, Result};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends a POST request over plain HTTP, which is enough for the webhooks and collectors on the
+/// local network that rbspy talks to. HTTPS isn't supported.
+pub(crate) fn post(url: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    request("POST", url, Some((content_type, body)))?;
+    Ok(())
+}
+
+/// Sends a GET request over plain HTTP and returns the response's body
+pub(crate) fn get(url: &str) -> Result<Vec<u8>> {
+    request("GET", url, None)
+}
+
+fn request(method: &str, url: &str, body: Option<(&str, &[u8])>) -> Result<Vec<u8>> {
+    let url = Url::parse(url)?;
+    let mut stream =
+        connect(&url).context(format!("Failed to connect to {}:{}", url.host, url.port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rbspy/{}\r\nConnection: close\r\n",
+        method,
+        url.path,
+        url.authority(),
+        env!("CARGO_PKG_VERSION"),
+    )?;
+    match body {
+        Some((content_type, body)) => {
+            write!(
+                stream,
+                "Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                content_type,
+                body.len()
+            )?;
+            stream.write_all(body)?;
+        }
+        None => write!(stream, "\r\n")?,
+    }
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format_err!("Invalid HTTP response: {}", status_line.trim()))?;
+    if !(200..300).contains(&status) {
+        return Err(format_err!(
+            "{} to {} failed: {}",
+            method,
+            url.authority(),
+            status_line.trim()
+        ));
+    }
+
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.trim().eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+    // The connection is closed after the response, so the body runs to the end of the stream
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    if chunked {
+        body = dechunk(&body)?;
+    }
+    Ok(body)
+}
+
+/// Connects to each of the host's addresses in turn, giving up on each one after `TIMEOUT`
+fn connect(url: &Url) -> Result<TcpStream> {
+    let mut error = None;
+    for address in (url.host.as_str(), url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(match error {
+        Some(e) => e.into(),
+        None => format_err!("{} has no addresses", url.host),
+    })
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(|| format_err!("Truncated chunked HTTP response"))?;
+        let size = std::str::from_utf8(&body[..line_end])?;
+        // Chunk extensions follow a `;`
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .context(format!("Invalid chunk size in HTTP response: {}", size))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if body.len() < size + 2 {
+            return Err(format_err!("Truncated chunked HTTP response"));
+        }
+        decoded.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Url {
+    host: String,
+    port: u16,
+    /// The path and query string
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format_err!("Only plain http:// URLs are supported, not {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .context(format!("Invalid port in URL {}", url))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format_err!("No host in URL {}", url));
+        }
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// The host and port, as sent in the `Host` header
+    fn authority(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            Url::parse("http://alerts.internal:8080/hooks/rbspy?team=web").unwrap(),
+            Url {
+                host: "alerts.internal".to_string(),
+                port: 8080,
+                path: "/hooks/rbspy?team=web".to_string(),
+            }
+        );
+        let url = Url::parse("http://localhost").unwrap();
+        assert_eq!((url.port, url.path.as_str()), (80, "/"));
+        assert_eq!(url.authority(), "localhost");
+        assert!(Url::parse("https://localhost/").is_err());
+        assert!(Url::parse("http://localhost:x/").is_err());
+        assert!(Url::parse("http:///path").is_err());
+    }
+
+    #[test]
+    fn test_post() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = [0; 2];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (request, body)
+        });
+
+        post(
+            &format!("http://127.0.0.1:{}/hook", port),
+            "application/json",
+            b"{}",
+        )
+        .unwrap();
+        let (request, body) = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Length: 2\r\n"));
+        assert_eq!(&body, b"{}");
+    }
+
+    #[test]
+    fn test_get_chunked() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            while !request.ends_with("\r\n\r\n") {
+                reader.read_line(&mut request).unwrap();
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      5\r\n{\"a\":\r\n3;ext=1\r\n12}\r\n0\r\n\r\n",
+                )
+                .unwrap();
+            request
+        });
+
+        let body = get(&format!("http://127.0.0.1:{}/pods", port)).unwrap();
+        assert!(server.join().unwrap().starts_with("GET /pods HTTP/1.1\r\n"));
+        assert_eq!(body, b"{\"a\":12}");
+        assert!(dechunk(b"5\r\nabc").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use spytools::ProcessInfo;
+
+use crate::core::maps::MapsReport;
+use crate::core::memory::{MemoryReader, ReadMethod};
+use crate::core::process::{Pid, Process};
+use crate::core::ruby_spy::RubySpy;
+
+/// Where one of the symbols that rbspy finds the Ruby VM through is
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolAddress {
+    pub name: String,
+    /// `None` if neither the Ruby executable nor libruby has the symbol
+    pub address: Option<u64>,
+    /// `executable` or `libruby`
+    pub binary: Option<&'static str>,
+}
+
+/// How the Ruby VM is linked into the process
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RubyLinkage {
+    /// Into the executable
+    Static,
+    /// Against libruby, which is at the given path
+    Dynamic(String),
+}
+
+/// Facts about a Ruby process, for bug reports and for finding out whether rbspy can profile it
+/// before recording
+#[derive(Debug)]
+pub struct Inspection {
+    pub pid: Pid,
+    pub exe: Option<String>,
+    /// The Ruby version that the process is running
+    pub ruby_version: Option<String>,
+    /// The version that rbspy reads the process as, which is the closest supported release for
+    /// versions that rbspy doesn't know about yet
+    pub read_as: Option<String>,
+    /// `None` if the process's binaries couldn't be read
+    pub linkage: Option<RubyLinkage>,
+    pub symbols: Vec<SymbolAddress>,
+    pub read_method: Option<ReadMethod>,
+    /// How many threads the process has, as the OS sees them
+    pub os_threads: Option<usize>,
+    /// How many Ruby threads the VM has. Fibers aren't counted: the VM doesn't keep a list of
+    /// them, so rbspy can only see the fiber that each thread is running.
+    pub ruby_threads: Option<usize>,
+    pub maps: MapsReport,
+    /// Why rbspy can't profile the process
+    pub error: Option<String>,
+}
+
+/// Attaches to the process belonging to `pid` the same way that recording does, reads its stack
+/// traces once, and reports what it found along the way
+pub fn inspect(pid: Pid, force_version: Option<String>) -> Result<Inspection> {
+    let process =
+        Process::new(pid).context(format!("Failed to find process {}. Is it running?", pid))?;
+    #[allow(unused_mut)]
+    let mut process_info = ProcessInfo::new::<spytools::process::RubyProcessType>(&process);
+    #[cfg(target_os = "linux")]
+    if let Ok(process_info) = &mut process_info {
+        crate::core::debuginfo::add_debug_symbols(process_info, pid);
+    }
+    let ruby_version = match &process_info {
+        Ok(process_info) => MemoryReader::new(pid).ok().and_then(|memory| {
+            crate::core::address_finder::get_ruby_version(
+                &memory,
+                process_info,
+                force_version.clone(),
+            )
+            .ok()
+        }),
+        Err(_) => None,
+    };
+    let symbols = match &process_info {
+        Ok(process_info) => crate::core::address_finder::vm_symbols(ruby_version.as_ref())
+            .into_iter()
+            .map(|name| find_symbol(process_info, name))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    let mut spy = RubySpy::new(pid, force_version);
+    let maps = crate::core::maps::maps_report(pid, spy.as_ref())?;
+    let mut inspection = Inspection {
+        pid,
+        exe: process.exe().ok(),
+        ruby_version: ruby_version.map(|version| version.to_string()),
+        read_as: None,
+        linkage: process_info
+            .as_ref()
+            .ok()
+            .map(|process_info| match &process_info.library {
+                Some(library) => {
+                    let root = format!("/proc/{}/root", pid);
+                    let path = library
+                        .filename
+                        .strip_prefix(&root)
+                        .unwrap_or(&library.filename);
+                    RubyLinkage::Dynamic(std::path::Path::new("/").join(path).display().to_string())
+                }
+                None => RubyLinkage::Static,
+            }),
+        symbols,
+        read_method: MemoryReader::new(pid).ok().map(|memory| memory.method()),
+        os_threads: process.threads().ok().map(|threads| threads.len()),
+        ruby_threads: None,
+        maps,
+        error: None,
+    };
+    match &mut spy {
+        Ok(spy) => {
+            inspection.read_as = Some(spy.ruby_version().to_string());
+            match spy.get_all_stack_traces(true, false) {
+                Ok(traces) => inspection.ruby_threads = Some(traces.len()),
+                Err(e) => inspection.error = Some(format!("{:#}", e)),
+            }
+        }
+        Err(e) => inspection.error = Some(format!("{:#}", e)),
+    }
+    Ok(inspection)
+}
+
+fn find_symbol(process_info: &ProcessInfo, name: String) -> SymbolAddress {
+    let binaries = [
+        ("executable", &process_info.binary),
+        ("libruby", &process_info.library),
+    ];
+    let found = binaries.iter().find_map(|(binary, info)| {
+        let address = info.as_ref()?.symbols.get(&name)?;
+        Some((*binary, *address))
+    });
+    SymbolAddress {
+        name,
+        address: found.map(|(_, address)| address),
+        binary: found.map(|(binary, _)| binary),
+    }
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = || "unknown".to_string();
+        writeln!(
+            f,
+            "{:<16}{} ({})",
+            "Process",
+            self.pid,
+            self.exe.clone().unwrap_or_else(unknown)
+        )?;
+        let version = match (&self.ruby_version, &self.read_as) {
+            (Some(version), Some(read_as)) if version != read_as => {
+                format!("{} (read as {})", version, read_as)
+            }
+            (Some(version), _) => version.clone(),
+            (None, _) => unknown(),
+        };
+        writeln!(f, "{:<16}{}", "Ruby version", version)?;
+        let libruby = match &self.linkage {
+            Some(RubyLinkage::Dynamic(path)) => format!("dynamic ({})", path),
+            Some(RubyLinkage::Static) => "static (linked into the executable)".to_string(),
+            None => unknown(),
+        };
+        writeln!(f, "{:<16}{}", "libruby", libruby)?;
+        writeln!(
+            f,
+            "{:<16}{}",
+            "Memory reads",
+            self.read_method
+                .map_or_else(unknown, |method| method.to_string())
+        )?;
+        writeln!(
+            f,
+            "{:<16}{} OS threads, {} Ruby threads",
+            "Threads",
+            self.os_threads
+                .map_or_else(unknown, |count| count.to_string()),
+            self.ruby_threads
+                .map_or_else(unknown, |count| count.to_string())
+        )?;
+        match &self.error {
+            Some(error) => writeln!(f, "{:<16}no: {}", "Profilable", error)?,
+            None => writeln!(f, "{:<16}yes", "Profilable")?,
+        }
+
+        if !self.symbols.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Symbols:")?;
+            for symbol in &self.symbols {
+                match (symbol.address, symbol.binary) {
+                    (Some(address), Some(binary)) => {
+                        writeln!(f, "  {:<40}{:#018x}  {}", symbol.name, address, binary)?
+                    }
+                    _ => writeln!(f, "  {:<40}missing", symbol.name)?,
+                }
+            }
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.maps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_inspection() {
+        let inspection = Inspection {
+            pid: 42,
+            exe: Some("/usr/bin/ruby".to_string()),
+            ruby_version: Some("3.4.9".to_string()),
+            read_as: Some("3.4.1".to_string()),
+            linkage: Some(RubyLinkage::Static),
+            symbols: vec![
+                SymbolAddress {
+                    name: "ruby_version".to_string(),
+                    address: Some(0x5555_5555_4000),
+                    binary: Some("executable"),
+                },
+                SymbolAddress {
+                    name: "ruby_current_vm_ptr".to_string(),
+                    address: None,
+                    binary: None,
+                },
+            ],
+            read_method: None,
+            os_threads: Some(5),
+            ruby_threads: None,
+            maps: MapsReport {
+                pid: 42,
+                regions: Vec::new(),
+                ruby_version: None,
+                vm_locations: Vec::new(),
+                vm_error: Some("Couldn't find the Ruby VM".to_string()),
+            },
+            error: Some("Couldn't find the Ruby VM".to_string()),
+        };
+        let output = inspection.to_string();
+        let lines: Vec<&str> = output.lines().take(10).collect();
+        assert_eq!(
+            lines,
+            [
+                "Process         42 (/usr/bin/ruby)",
+                "Ruby version    3.4.9 (read as 3.4.1)",
+                "libruby         static (linked into the executable)",
+                "Memory reads    unknown",
+                "Threads         5 OS threads, unknown Ruby threads",
+                "Profilable      no: Couldn't find the Ruby VM",
+                "",
+                "Symbols:",
+                "  ruby_version                            0x0000555555554000  executable",
+                "  ruby_current_vm_ptr                     missing",
+            ]
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+
+use crate::core::container::ContainerInfo;
+use crate::core::process::Pid;
+
+/// A running container in a pod on this node, as the kubelet or the container runtime describes
+/// it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PodContainer {
+    pub namespace: String,
+    pub pod: String,
+    pub pod_uid: String,
+    /// The container's name in the pod spec
+    pub name: String,
+    /// The container runtime's ID for the container, without the runtime's prefix (e.g.
+    /// `containerd://`)
+    pub id: String,
+    pub pod_labels: BTreeMap<String, String>,
+    pub pod_annotations: BTreeMap<String, String>,
+}
+
+impl PodContainer {
+    /// Labels that identify the container in profiles
+    pub fn profile_labels(&self) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::new();
+        labels.insert("namespace".to_string(), self.namespace.clone());
+        labels.insert("pod".to_string(), self.pod.clone());
+        labels.insert("container".to_string(), self.name.clone());
+        labels
+    }
+}
+
+// Just the parts of the kubelet's pod list that rbspy needs
+#[derive(Deserialize)]
+struct PodList {
+    items: Vec<Pod>,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    metadata: PodMetadata,
+    #[serde(default)]
+    status: PodStatus,
+}
+
+#[derive(Deserialize)]
+struct PodMetadata {
+    name: String,
+    namespace: String,
+    uid: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PodStatus {
+    #[serde(default)]
+    container_statuses: Vec<ContainerStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContainerStatus {
+    name: String,
+    /// Missing until the container has been started
+    #[serde(default, rename = "containerID")]
+    container_id: Option<String>,
+}
+
+/// Lists the containers in the pods that the kubelet at `pods_url` is running, e.g.
+/// `http://localhost:10255/pods` for its read-only port
+pub(crate) fn list_containers(pods_url: &str) -> Result<Vec<PodContainer>> {
+    let pods = crate::core::http::get(pods_url).context(format!(
+        "Failed to list pods from the kubelet at {}",
+        pods_url
+    ))?;
+    parse_pod_list(&pods)
+}
+
+fn parse_pod_list(json: &[u8]) -> Result<Vec<PodContainer>> {
+    let pods: PodList = serde_json::from_slice(json).context("Invalid pod list")?;
+    let mut containers = Vec::new();
+    for pod in pods.items {
+        for status in pod.status.container_statuses {
+            let id = match status.container_id {
+                // `containerd://<id>`, `docker://<id>`, `cri-o://<id>`
+                Some(id) => match id.split_once("://") {
+                    Some((_, id)) => id.to_string(),
+                    None => id,
+                },
+                None => continue,
+            };
+            containers.push(PodContainer {
+                namespace: pod.metadata.namespace.clone(),
+                pod: pod.metadata.name.clone(),
+                pod_uid: pod.metadata.uid.clone(),
+                name: status.name,
+                id,
+                pod_labels: pod.metadata.labels.clone(),
+                pod_annotations: pod.metadata.annotations.clone(),
+            });
+        }
+    }
+    Ok(containers)
+}
+
+/// The pod container that the process belonging to `pid` runs in, from its container runtime's
+/// state on the host (see `container::container_info`), for nodes whose kubelet can't be asked.
+/// Runtimes record the pod's name, namespace and UID along with the container's name, but not
+/// the pod's labels or annotations, so those are left empty.
+pub(crate) fn runtime_container(pid: Pid) -> Option<PodContainer> {
+    pod_container(crate::core::container::container_info(pid)?)
+}
+
+/// The pod container that a container belongs to, if its runtime says which pod it's in
+fn pod_container(info: ContainerInfo) -> Option<PodContainer> {
+    Some(PodContainer {
+        namespace: info.namespace?,
+        pod: info.pod?,
+        pod_uid: info.pod_uid.unwrap_or_default(),
+        name: info.name?,
+        id: info.id,
+        pod_labels: BTreeMap::new(),
+        pod_annotations: BTreeMap::new(),
+    })
+}
+
+/// The ID of the container that the process belonging to `pid` runs in, if any. rbspy has to
+/// share the host's PID namespace (`hostPID: true`) to see the processes in other pods.
+#[cfg(target_os = "linux")]
+pub(crate) fn container_id(pid: Pid) -> Option<String> {
+    let cgroup = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    container_id_from_cgroup(&cgroup)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn container_id(_pid: Pid) -> Option<String> {
+    None
+}
+
+/// Finds the container ID in a process's cgroup paths, which container runtimes name after it,
+/// e.g. `/kubepods/burstable/pod<uid>/<id>` or
+/// `/kubepods.slice/.../cri-containerd-<id>.scope` with the systemd cgroup driver
+fn container_id_from_cgroup(cgroup: &str) -> Option<String> {
+    crate::core::container::from_cgroup(cgroup).map(|container| container.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4f1a3b0c9d2e8f7a6b5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9a8b7c6d5e4f3a";
+
+    #[test]
+    fn test_parse_pod_list() {
+        let json = format!(
+            r#"{{"kind":"PodList","items":[
+                {{"metadata":{{"name":"web-7d9f","namespace":"shop","uid":"u1",
+                  "labels":{{"app":"web"}},"annotations":{{"rbspy.io/profile":"true"}}}},
+                 "status":{{"containerStatuses":[
+                   {{"name":"puma","containerID":"containerd://{}"}},
+                   {{"name":"sidecar"}}]}}}},
+                {{"metadata":{{"name":"pending","namespace":"shop","uid":"u2"}},"status":{{}}}}
+            ]}}"#,
+            ID
+        );
+        let containers = parse_pod_list(json.as_bytes()).unwrap();
+        assert_eq!(containers.len(), 1);
+        let container = &containers[0];
+        assert_eq!(container.id, ID);
+        assert_eq!(
+            (container.namespace.as_str(), container.pod.as_str()),
+            ("shop", "web-7d9f")
+        );
+        assert_eq!(container.name, "puma");
+        assert_eq!(container.pod_labels["app"], "web");
+        assert_eq!(container.pod_annotations["rbspy.io/profile"], "true");
+        assert_eq!(container.profile_labels()["pod"], "web-7d9f");
+
+        assert!(parse_pod_list(b"<html>").is_err());
+    }
+
+    #[test]
+    fn test_pod_container() {
+        let info = ContainerInfo {
+            id: ID.to_string(),
+            runtime: Some("containerd".to_string()),
+            name: Some("puma".to_string()),
+            pod: Some("web-7d9f".to_string()),
+            namespace: Some("shop".to_string()),
+            pod_uid: Some("u1".to_string()),
+            ..Default::default()
+        };
+        let container = pod_container(info.clone()).unwrap();
+        assert_eq!(container.id, ID);
+        assert_eq!(container.profile_labels()["namespace"], "shop");
+        assert_eq!(container.profile_labels()["pod"], "web-7d9f");
+        assert_eq!(container.profile_labels()["container"], "puma");
+        assert!(container.pod_labels.is_empty());
+
+        // A container that isn't in a pod, e.g. one that was started with `docker run`
+        let docker = ContainerInfo {
+            pod: None,
+            namespace: None,
+            ..info
+        };
+        assert_eq!(pod_container(docker), None);
+    }
+
+    #[test]
+    fn test_container_id_from_cgroup() {
+        let cgroupfs = format!("0::/kubepods/burstable/pod1234-5678/{}\n", ID);
+        assert_eq!(container_id_from_cgroup(&cgroupfs), Some(ID.to_string()));
+        let systemd = format!(
+            "12:memory:/system.slice\n\
+             0::/kubepods.slice/kubepods-besteffort.slice/cri-containerd-{}.scope\n",
+            ID
+        );
+        assert_eq!(container_id_from_cgroup(&systemd), Some(ID.to_string()));
+        assert_eq!(
+            container_id_from_cgroup("0::/user.slice/session-2.scope\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use crate::core::process::{Pid, ProcessMemory};
+use crate::core::ruby_spy::RubySpy;
+
+/// How many mapped files to list in a `MapsReport`
+const TOP_FILES: usize = 10;
+
+/// What a region of a process's memory holds, judging by what's mapped there
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RegionKind {
+    /// The Ruby binary or `libruby`
+    Ruby,
+    /// Native extensions in installed gems
+    Gem,
+    /// Other shared libraries
+    SharedObject,
+    /// Other mapped files
+    File,
+    /// The `brk` heap, where `malloc` puts small allocations
+    Heap,
+    /// Anonymous memory, e.g. Ruby's object heap pages, large `malloc` allocations and thread
+    /// stacks other than the main thread's
+    Anonymous,
+    /// The main thread's stack
+    Stack,
+    /// Memory that the kernel maps, like `[vdso]`
+    Kernel,
+}
+
+impl RegionKind {
+    fn name(self) -> &'static str {
+        match self {
+            RegionKind::Ruby => "ruby",
+            RegionKind::Gem => "gems",
+            RegionKind::SharedObject => "shared objects",
+            RegionKind::File => "other files",
+            RegionKind::Heap => "heap",
+            RegionKind::Anonymous => "anonymous",
+            RegionKind::Stack => "stack",
+            RegionKind::Kernel => "kernel",
+        }
+    }
+}
+
+/// One of the regions in a process's memory map
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapRegion {
+    pub start: usize,
+    pub size: usize,
+    /// e.g. `r-x`
+    pub permissions: String,
+    /// The mapped file, or a name like `[heap]`
+    pub path: Option<String>,
+    pub kind: RegionKind,
+    /// How much of the region is resident in memory, in bytes. Only known on Linux.
+    pub rss: Option<usize>,
+}
+
+/// Where one of the Ruby VM's structures is in memory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VmLocation {
+    pub name: &'static str,
+    pub address: usize,
+    /// The index of the region that the address is in
+    pub region: Option<usize>,
+}
+
+/// A summary of a process's memory map, for finding out where its memory goes and for debugging
+/// why rbspy can't find the Ruby VM in it
+#[derive(Debug)]
+pub struct MapsReport {
+    pub pid: Pid,
+    pub regions: Vec<MapRegion>,
+    pub ruby_version: Option<String>,
+    /// Where the Ruby VM's structures are, if rbspy could find them
+    pub vm_locations: Vec<VmLocation>,
+    /// Why the Ruby VM's structures couldn't be found
+    pub vm_error: Option<String>,
+}
+
+/// Reads and classifies the memory map of the process belonging to `pid`, and looks for the Ruby
+/// VM in it the same way that recording does
+pub fn memory_maps(pid: Pid, force_version: Option<String>) -> Result<MapsReport> {
+    maps_report(pid, RubySpy::new(pid, force_version).as_ref())
+}
+
+/// Reads and classifies the memory map of the process belonging to `pid`, with the Ruby VM's
+/// structures from `spy`, or why it couldn't attach
+pub(crate) fn maps_report(
+    pid: Pid,
+    spy: std::result::Result<&RubySpy, &anyhow::Error>,
+) -> Result<MapsReport> {
+    let maps = proc_maps::get_process_maps(pid)
+        .context(format!("Failed to read the memory map of process {}", pid))?;
+    let rss = resident_sizes(pid);
+    let mut regions: Vec<MapRegion> = maps
+        .iter()
+        .map(|map| {
+            let path = map
+                .filename()
+                .map(|path| path.to_string_lossy().to_string())
+                .filter(|path| !path.is_empty());
+            MapRegion {
+                start: map.start(),
+                size: map.size(),
+                permissions: permissions(map),
+                kind: classify(path.as_deref()),
+                rss: rss.get(&map.start()).copied(),
+                path,
+            }
+        })
+        .collect();
+    regions.sort_by_key(|region| region.start);
+
+    let mut report = MapsReport {
+        pid,
+        regions,
+        ruby_version: None,
+        vm_locations: Vec::new(),
+        vm_error: None,
+    };
+    match spy {
+        Ok(spy) => {
+            report.ruby_version = Some(spy.ruby_version().to_string());
+            let mut locations = spy.vm_locations();
+            if let Some(&(_, vm_pointer)) = locations.iter().find(|(name, _)| *name == VM_POINTER) {
+                if let Ok(vm) = spy.memory().copy_struct::<usize>(vm_pointer) {
+                    locations.push(("VM struct", vm));
+                }
+            }
+            report.vm_locations = locations
+                .into_iter()
+                .map(|(name, address)| VmLocation {
+                    name,
+                    address,
+                    region: report.region_index(address),
+                })
+                .collect();
+        }
+        Err(e) => report.vm_error = Some(format!("{:#}", e)),
+    }
+    Ok(report)
+}
+
+/// The name that `RubySpy::vm_locations` gives the pointer to the VM struct
+pub(crate) const VM_POINTER: &str = "VM pointer";
+
+impl MapsReport {
+    fn region_index(&self, address: usize) -> Option<usize> {
+        self.regions
+            .iter()
+            .position(|region| region.start <= address && address < region.start + region.size)
+    }
+
+    /// The number of regions, total size and resident size of each kind of region
+    pub fn totals(&self) -> BTreeMap<RegionKind, (usize, usize, usize)> {
+        let mut totals = BTreeMap::new();
+        for region in &self.regions {
+            let total = totals.entry(region.kind).or_insert((0, 0, 0));
+            total.0 += 1;
+            total.1 += region.size;
+            total.2 += region.rss.unwrap_or(0);
+        }
+        totals
+    }
+
+    /// The total size and resident size of each mapped file
+    fn files(&self) -> Vec<(&str, RegionKind, usize, usize)> {
+        let mut files: HashMap<&str, (RegionKind, usize, usize)> = HashMap::new();
+        for region in &self.regions {
+            let path = match (&region.path, region.kind) {
+                (Some(path), RegionKind::Ruby)
+                | (Some(path), RegionKind::Gem)
+                | (Some(path), RegionKind::SharedObject)
+                | (Some(path), RegionKind::File) => path,
+                _ => continue,
+            };
+            let file = files.entry(path).or_insert((region.kind, 0, 0));
+            file.1 += region.size;
+            file.2 += region.rss.unwrap_or(0);
+        }
+        let mut files: Vec<_> = files
+            .into_iter()
+            .map(|(path, (kind, size, rss))| (path, kind, size, rss))
+            .collect();
+        files.sort_by(|a, b| (b.3, b.2, a.0).cmp(&(a.3, a.2, b.0)));
+        files
+    }
+}
+
+impl fmt::Display for MapsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Memory map of process {}", self.pid)?;
+        if let Some(version) = &self.ruby_version {
+            write!(f, " (Ruby {})", version)?;
+        }
+        writeln!(f)?;
+        let has_rss = self.regions.iter().any(|region| region.rss.is_some());
+        let resident = |rss| {
+            if has_rss {
+                format_size(rss)
+            } else {
+                "-".to_string()
+            }
+        };
+        writeln!(
+            f,
+            "{:<16}{:>8}{:>12}{:>12}",
+            "kind", "regions", "size", "resident"
+        )?;
+        let mut total = (0, 0, 0);
+        for (kind, (count, size, rss)) in self.totals() {
+            writeln!(
+                f,
+                "{:<16}{:>8}{:>12}{:>12}",
+                kind.name(),
+                count,
+                format_size(size),
+                resident(rss)
+            )?;
+            total = (total.0 + count, total.1 + size, total.2 + rss);
+        }
+        writeln!(
+            f,
+            "{:<16}{:>8}{:>12}{:>12}",
+            "total",
+            total.0,
+            format_size(total.1),
+            resident(total.2)
+        )?;
+
+        let files = self.files();
+        if !files.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Largest mapped files:")?;
+            for (path, kind, size, rss) in files.into_iter().take(TOP_FILES) {
+                writeln!(
+                    f,
+                    "{:>12}{:>12}  {} ({})",
+                    format_size(size),
+                    resident(rss),
+                    path,
+                    kind.name()
+                )?;
+            }
+        }
+
+        writeln!(f)?;
+        if let Some(error) = &self.vm_error {
+            return writeln!(f, "Couldn't find the Ruby VM: {}", error);
+        }
+        writeln!(f, "Ruby VM structures:")?;
+        for location in &self.vm_locations {
+            let region = match location.region.map(|index| &self.regions[index]) {
+                Some(region) => format!(
+                    "{} ({})",
+                    region.path.as_deref().unwrap_or("anonymous"),
+                    region.permissions
+                ),
+                None => "not mapped".to_string(),
+            };
+            writeln!(
+                f,
+                "  {:<32}{:#018x}  {}",
+                location.name, location.address, region
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn permissions(map: &proc_maps::MapRange) -> String {
+    format!(
+        "{}{}{}",
+        if map.is_read() { 'r' } else { '-' },
+        if map.is_write() { 'w' } else { '-' },
+        if map.is_exec() { 'x' } else { '-' }
+    )
+}
+
+fn classify(path: Option<&str>) -> RegionKind {
+    let path = match path {
+        Some(path) => path,
+        None => return RegionKind::Anonymous,
+    };
+    if path == "[heap]" {
+        return RegionKind::Heap;
+    }
+    if path == "[stack]" {
+        return RegionKind::Stack;
+    }
+    if path.starts_with("[anon") {
+        return RegionKind::Anonymous;
+    }
+    if path.starts_with('[') {
+        return RegionKind::Kernel;
+    }
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    if file_name == "ruby" || file_name.starts_with("libruby") {
+        RegionKind::Ruby
+    } else if path.contains("/gems/") {
+        RegionKind::Gem
+    } else if file_name.ends_with(".so")
+        || file_name.contains(".so.")
+        || file_name.ends_with(".bundle")
+        || file_name.ends_with(".dylib")
+        || file_name.ends_with(".dll")
+    {
+        RegionKind::SharedObject
+    } else {
+        RegionKind::File
+    }
+}
+
+/// The resident size of each region, by its start address, from `/proc/<pid>/smaps`
+#[cfg(target_os = "linux")]
+fn resident_sizes(pid: Pid) -> HashMap<usize, usize> {
+    std::fs::read_to_string(format!("/proc/{}/smaps", pid))
+        .map(|smaps| parse_smaps(&smaps))
+        .unwrap_or_default()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_sizes(_pid: Pid) -> HashMap<usize, usize> {
+    HashMap::new()
+}
+
+#[cfg_attr(not(any(test, target_os = "linux")), allow(dead_code))]
+fn parse_smaps(smaps: &str) -> HashMap<usize, usize> {
+    let mut sizes = HashMap::new();
+    let mut start = None;
+    for line in smaps.lines() {
+        if let Some(kb) = line.strip_prefix("Rss:") {
+            if let (Some(start), Some(kb)) = (start, kb.trim().strip_suffix(" kB")) {
+                if let Ok(kb) = kb.trim().parse::<usize>() {
+                    sizes.insert(start, kb * 1024);
+                }
+            }
+        } else if let Some((range, _)) = line.split_once(' ') {
+            if let Some((from, _)) = range.split_once('-') {
+                if let Ok(from) = usize::from_str_radix(from, 16) {
+                    start = Some(from);
+                }
+            }
+        }
+    }
+    sizes
+}
+
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(None), RegionKind::Anonymous);
+        assert_eq!(classify(Some("[heap]")), RegionKind::Heap);
+        assert_eq!(classify(Some("[stack]")), RegionKind::Stack);
+        assert_eq!(classify(Some("[vdso]")), RegionKind::Kernel);
+        assert_eq!(classify(Some("[anon:rbspy-marker]")), RegionKind::Anonymous);
+        assert_eq!(classify(Some("/usr/bin/ruby")), RegionKind::Ruby);
+        assert_eq!(
+            classify(Some("/usr/lib/x86_64-linux-gnu/libruby-3.1.so.3.1.2")),
+            RegionKind::Ruby
+        );
+        assert_eq!(
+            classify(Some(
+                "/app/vendor/bundle/ruby/3.1.0/gems/nokogiri-1.15.4/lib/nokogiri/3.1/nokogiri.so"
+            )),
+            RegionKind::Gem
+        );
+        assert_eq!(
+            classify(Some("/lib/x86_64-linux-gnu/libc.so.6")),
+            RegionKind::SharedObject
+        );
+        assert_eq!(
+            classify(Some("/usr/lib/locale/C.utf8/LC_CTYPE")),
+            RegionKind::File
+        );
+    }
+
+    #[test]
+    fn test_parse_smaps() {
+        let smaps = "\
+55d4c2a00000-55d4c2a21000 rw-p 00000000 00:00 0                          [heap]
+Size:                132 kB
+Rss:                  88 kB
+Pss:                  88 kB
+7f1b2c000000-7f1b2c021000 r-xp 00000000 08:01 1234                       /usr/lib/libruby.so.3.1
+Size:                132 kB
+Rss:                   4 kB
+VmFlags: rd ex mr mw me sd
+";
+        let sizes = parse_smaps(smaps);
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[&0x55d4c2a00000], 88 * 1024);
+        assert_eq!(sizes[&0x7f1b2c000000], 4 * 1024);
+    }
+
+    #[test]
+    fn test_report() {
+        let region = |start, size, path: Option<&str>, rss| MapRegion {
+            start,
+            size,
+            permissions: "rw-".to_string(),
+            path: path.map(|path| path.to_string()),
+            kind: classify(path),
+            rss: Some(rss),
+        };
+        let report = MapsReport {
+            pid: 42,
+            regions: vec![
+                region(0x1000, 0x2000, Some("/usr/bin/ruby"), 0x1000),
+                region(0x4000, 0x100000, Some("[heap]"), 0x80000),
+                region(0x200000, 0x400000, None, 0x300000),
+            ],
+            ruby_version: Some("3.1.2".to_string()),
+            vm_locations: vec![VmLocation {
+                name: VM_POINTER,
+                address: 0x1800,
+                region: Some(0),
+            }],
+            vm_error: None,
+        };
+        assert_eq!(report.region_index(0x4000), Some(1));
+        assert_eq!(report.region_index(0x104000), None);
+        assert_eq!(
+            report.to_string(),
+            "\
+Memory map of process 42 (Ruby 3.1.2)
+kind             regions        size    resident
+ruby                   1     8.0 KiB     4.0 KiB
+heap                   1     1.0 MiB   512.0 KiB
+anonymous              1     4.0 MiB     3.0 MiB
+total                  3     5.0 MiB     3.5 MiB
+
+Largest mapped files:
+     8.0 KiB     4.0 KiB  /usr/bin/ruby (ruby)
+
+Ruby VM structures:
+  VM pointer                      0x0000000000001800  /usr/bin/ruby (rw-)
+"
+        );
+    }
+}
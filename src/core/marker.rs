@@ -0,0 +1,270 @@
+//! A small protocol for a Ruby process to tell rbspy which request each of its threads is
+//! working on, so that samples can be labelled with a request or trace ID and joined with
+//! distributed traces. It's optional: a companion gem (or any C extension) maps a shared region
+//! of memory and keeps it up to date, and rbspy reads it from outside the process at sample time.
+//!
+//! The region is found in the process's memory maps, either as a file mapping whose file name
+//! ends in `.rbspy-marker` (e.g. `/tmp/<pid>.rbspy-marker`) or, on Linux 5.17 and newer, as an
+//! anonymous mapping named `rbspy-marker` with `prctl(PR_SET_VMA_ANON_NAME)`. It's laid out as
+//! follows, with integers in the machine's native byte order:
+//!
+//! | Offset | Size | Field                                                           |
+//! |--------|------|-----------------------------------------------------------------|
+//! | 0      | 8    | Magic bytes `RBSPYMK1`                                          |
+//! | 8      | 4    | `slot_count`: the number of slots                               |
+//! | 12     | 4    | `slot_size`: the size of each slot in bytes, at least 24        |
+//! | 16     | ...  | `slot_count` slots of `slot_size` bytes                         |
+//!
+//! Each slot is:
+//!
+//! | Offset | Size             | Field                                                     |
+//! |--------|------------------|-----------------------------------------------------------|
+//! | 0      | 8                | `sequence`: odd while the writer is changing the slot     |
+//! | 8      | 8                | `thread`: which thread the slot is for                    |
+//! | 16     | 4                | `length`: the length of the ID, or 0 between requests     |
+//! | 20     | 4                | Reserved                                                  |
+//! | 24     | `slot_size - 24` | The request ID, in UTF-8                                  |
+//!
+//! `thread` is the thread's OS thread ID (`Thread#native_thread_id`) or its `pthread_t`. A slot
+//! with a `thread` of 0 is for every thread that doesn't have a slot of its own, e.g. in servers
+//! that handle one request at a time.
+//!
+//! To change a slot, the writer increments `sequence`, writes the other fields, and increments
+//! `sequence` again. Reads that overlap a write are thrown away rather than retried, so those
+//! samples go without a request ID.
+
+use std::time::{Duration, Instant};
+
+use crate::core::process::{Pid, ProcessMemory};
+use crate::core::types::StackTrace;
+
+/// The label that samples get the request ID in
+pub const REQUEST_ID_LABEL: &str = "request_id";
+
+const MAGIC: &[u8; 8] = b"RBSPYMK1";
+const FILE_SUFFIX: &str = ".rbspy-marker";
+const ANON_NAME: &str = "[anon:rbspy-marker]";
+const HEADER_SIZE: usize = 16;
+const SLOT_HEADER_SIZE: usize = 24;
+/// Larger regions are assumed to be corrupt rather than read at every sample
+const MAX_SIZE: usize = 1 << 20;
+/// How often to look for the region again when the process doesn't have one (yet)
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads request IDs from a process's marker region
+pub struct RequestMarker {
+    pid: Pid,
+    /// The start and size of the region
+    region: Option<(usize, usize)>,
+    last_scan: Option<Instant>,
+}
+
+impl RequestMarker {
+    pub fn new(pid: Pid) -> RequestMarker {
+        RequestMarker {
+            pid,
+            region: None,
+            last_scan: None,
+        }
+    }
+
+    /// Labels the trace with the ID of the request that its thread is working on, if the process
+    /// has published one
+    pub fn label<T: ProcessMemory>(&mut self, source: &T, trace: &mut StackTrace) {
+        if let Some(id) = self.request_id(source, trace) {
+            trace.labels.insert(REQUEST_ID_LABEL.to_string(), id);
+        }
+    }
+
+    /// The ID of the request that the trace's thread is working on
+    pub fn request_id<T: ProcessMemory>(
+        &mut self,
+        source: &T,
+        trace: &StackTrace,
+    ) -> Option<String> {
+        let (start, size) = self.region()?;
+        let mut header = [0u8; HEADER_SIZE];
+        if source.read(start, &mut header).is_err() {
+            // The process unmapped the region, so look for it again later
+            self.region = None;
+            return None;
+        }
+        let length = region_length(&header)?.min(size);
+        let mut region = vec![0u8; length];
+        source.read(start, &mut region).ok()?;
+        let threads = [trace.os_thread_id, trace.thread_id];
+        let (offset, sequence, id) = find_request_id(&region, &threads)?;
+        // The slot must not have changed while it was being copied
+        let mut reread = [0u8; 8];
+        source.read(start + offset, &mut reread).ok()?;
+        if u64::from_ne_bytes(reread) == sequence {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    fn region(&mut self) -> Option<(usize, usize)> {
+        if self.region.is_none()
+            && self
+                .last_scan
+                .map_or(true, |last_scan| last_scan.elapsed() >= RESCAN_INTERVAL)
+        {
+            self.last_scan = Some(Instant::now());
+            self.region = find_region(self.pid);
+        }
+        self.region
+    }
+}
+
+fn find_region(pid: Pid) -> Option<(usize, usize)> {
+    let maps = proc_maps::get_process_maps(pid).ok()?;
+    maps.iter()
+        .find(|map| {
+            map.is_read()
+                && map.size() >= HEADER_SIZE
+                && map.filename().map_or(false, |filename| {
+                    let filename = filename.to_string_lossy();
+                    filename.ends_with(FILE_SUFFIX) || filename == ANON_NAME
+                })
+        })
+        .map(|map| (map.start(), map.size()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_ne_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_ne_bytes(value)
+}
+
+/// The size of the header and slots, if the header is valid
+fn region_length(header: &[u8]) -> Option<usize> {
+    if header.len() < HEADER_SIZE || &header[..8] != MAGIC {
+        return None;
+    }
+    let slot_count = read_u32(header, 8) as usize;
+    let slot_size = read_u32(header, 12) as usize;
+    if slot_size < SLOT_HEADER_SIZE {
+        return None;
+    }
+    let length = slot_count
+        .checked_mul(slot_size)?
+        .checked_add(HEADER_SIZE)?;
+    Some(length).filter(|&length| length <= MAX_SIZE)
+}
+
+/// Finds the request ID for the first of `threads` that has a slot, or else the one for every
+/// thread. Returns the offset of the slot's sequence number, the sequence number, and the ID.
+fn find_request_id(region: &[u8], threads: &[Option<usize>]) -> Option<(usize, u64, String)> {
+    region_length(region)?;
+    let slot_size = read_u32(region, 12) as usize;
+    let slots: Vec<usize> = (HEADER_SIZE..)
+        .step_by(slot_size)
+        .take(read_u32(region, 8) as usize)
+        .take_while(|offset| offset + slot_size <= region.len())
+        .collect();
+    let slot_for = |thread: u64| {
+        slots
+            .iter()
+            .copied()
+            .find(|&offset| read_u64(region, offset + 8) == thread)
+    };
+    let offset = threads
+        .iter()
+        .flatten()
+        .find_map(|&thread| slot_for(thread as u64))
+        .or_else(|| slot_for(0))?;
+
+    let sequence = read_u64(region, offset);
+    let length = read_u32(region, offset + 16) as usize;
+    if sequence % 2 == 1 || length == 0 || length > slot_size - SLOT_HEADER_SIZE {
+        return None;
+    }
+    let id = &region[offset + SLOT_HEADER_SIZE..offset + SLOT_HEADER_SIZE + length];
+    let id = std::str::from_utf8(id).ok()?;
+    Some((offset, sequence, id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOT_SIZE: usize = 64;
+
+    fn region(slots: &[(u64, u64, &str)]) -> Vec<u8> {
+        let mut region = MAGIC.to_vec();
+        region.extend_from_slice(&(slots.len() as u32).to_ne_bytes());
+        region.extend_from_slice(&(SLOT_SIZE as u32).to_ne_bytes());
+        for (sequence, thread, id) in slots {
+            let mut slot = vec![0u8; SLOT_SIZE];
+            slot[..8].copy_from_slice(&sequence.to_ne_bytes());
+            slot[8..16].copy_from_slice(&thread.to_ne_bytes());
+            slot[16..20].copy_from_slice(&(id.len() as u32).to_ne_bytes());
+            slot[SLOT_HEADER_SIZE..SLOT_HEADER_SIZE + id.len()].copy_from_slice(id.as_bytes());
+            region.extend(slot);
+        }
+        region
+    }
+
+    #[test]
+    fn test_find_request_id() {
+        let region = region(&[
+            (2, 0, "boot"),
+            (4, 101, "req-1"),
+            (5, 102, "req-2"),
+            (6, 103, ""),
+        ]);
+        let id = |threads: &[Option<usize>]| find_request_id(&region, threads).map(|found| found.2);
+        assert_eq!(
+            find_request_id(&region, &[Some(101)]),
+            Some((80, 4, "req-1".to_string()))
+        );
+        assert_eq!(id(&[None, Some(101)]), Some("req-1".to_string()));
+        // Being written
+        assert_eq!(id(&[Some(102)]), None);
+        // Between requests
+        assert_eq!(id(&[Some(103)]), None);
+        // Threads without a slot get the process's ID
+        assert_eq!(id(&[Some(999), None]), Some("boot".to_string()));
+
+        let mut corrupt = region.clone();
+        corrupt[0] = b'X';
+        assert_eq!(find_request_id(&corrupt, &[Some(101)]), None);
+        // Slots past the end of the copy are ignored
+        assert_eq!(
+            find_request_id(&region[..100], &[Some(101)]).map(|found| found.2),
+            Some("boot".to_string())
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_read_own_marker() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.rbspy-marker");
+        let tid = 7;
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&region(&[(2, tid, "req-7")]))
+            .unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let map = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+
+        let pid = std::process::id() as Pid;
+        let reader = crate::core::memory::MemoryReader::new(pid).unwrap();
+        let mut marker = RequestMarker::new(pid);
+        let mut trace = StackTrace::new_empty();
+        trace.os_thread_id = Some(tid as usize);
+        marker.label(&reader, &mut trace);
+        assert_eq!(trace.labels[REQUEST_ID_LABEL], "req-7");
+        drop(map);
+    }
+}
@@ -0,0 +1,227 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::fs::File;
+
+use anyhow::Result;
+
+use crate::core::process::{
+    Pid, PlannedSample, Process, ProcessMemory, ProcessRetry, ReadPlan, SampleMemory,
+};
+
+/// A way of reading another process's memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadMethod {
+    /// The platform's usual API: `process_vm_readv` on Linux, `mach_vm_read` on macOS and
+    /// `ReadProcessMemory` on Windows
+    Native,
+    /// Reading `/proc/<pid>/mem` (Linux only). Sandboxes that don't allow `process_vm_readv`,
+    /// like gVisor or strict seccomp profiles, often still allow this.
+    ProcMem,
+}
+
+impl fmt::Display for ReadMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(target_os = "linux")]
+            ReadMethod::Native => write!(f, "process_vm_readv"),
+            #[cfg(not(target_os = "linux"))]
+            ReadMethod::Native => write!(f, "native memory reads"),
+            ReadMethod::ProcMem => write!(f, "/proc/<pid>/mem"),
+        }
+    }
+}
+
+/// The most class names to keep. Names are kept by address until there are this many, and then
+/// they're all looked up again.
+const MAX_CLASS_NAMES: usize = 100_000;
+
+/// Reads a process's memory with whichever `ReadMethod` works in the environment that rbspy is
+/// running in
+pub struct MemoryReader {
+    process: Process,
+    method: ReadMethod,
+    #[cfg(target_os = "linux")]
+    mem: Option<File>,
+    plan: ReadPlan,
+    class_names: RefCell<HashMap<usize, (String, bool)>>,
+}
+
+impl MemoryReader {
+    /// Opens the memory of the process belonging to `pid`. On Linux, this reads a few bytes with
+    /// `process_vm_readv`, and if that's denied or not implemented, falls back to
+    /// `/proc/<pid>/mem`. If neither works, the error reports why each of them failed and what
+    /// might be restricting rbspy.
+    pub fn new(pid: Pid) -> Result<MemoryReader> {
+        let process = Process::new_with_retry(pid)?;
+        #[cfg(target_os = "linux")]
+        {
+            let probe = match readable_address(pid) {
+                Some(probe) => probe,
+                // The process may still be starting up, so leave any errors to the reads that
+                // come later
+                None => return Ok(MemoryReader::native(process)),
+            };
+            let mut buf = [0u8; 8];
+            let native_error = match process.read(probe, &mut buf) {
+                Ok(()) => return Ok(MemoryReader::native(process)),
+                Err(remoteprocess::Error::IOError(e)) if is_restricted(&e) => e,
+                Err(_) => return Ok(MemoryReader::native(process)),
+            };
+            let mem_error = match open_proc_mem(pid, probe) {
+                Ok(mem) => {
+                    info!(
+                        "process_vm_readv isn't available ({}), so reading process {}'s memory through /proc/{}/mem instead",
+                        native_error, pid, pid
+                    );
+                    return Ok(MemoryReader {
+                        process,
+                        method: ReadMethod::ProcMem,
+                        mem: Some(mem),
+                        plan: ReadPlan::default(),
+                        class_names: RefCell::default(),
+                    });
+                }
+                Err(e) => e,
+            };
+            Err(crate::core::errors::ErrorCode::PermissionDenied
+                .error(crate::core::check::memory_access_report(
+                    pid,
+                    &native_error,
+                    &mem_error,
+                ))
+                .into())
+        }
+        #[cfg(not(target_os = "linux"))]
+        Ok(MemoryReader::native(process))
+    }
+
+    fn native(process: Process) -> MemoryReader {
+        MemoryReader {
+            process,
+            method: ReadMethod::Native,
+            #[cfg(target_os = "linux")]
+            mem: None,
+            plan: ReadPlan::default(),
+            class_names: RefCell::default(),
+        }
+    }
+
+    /// How the process's memory is being read
+    pub fn method(&self) -> ReadMethod {
+        self.method
+    }
+
+    /// Batches and caches reads until the returned guard is dropped (see `ReadPlan`), which
+    /// should be once a sample has been taken. Class names are kept for the same generations as
+    /// the memory that doesn't change, since a freed class's address can be reused by another.
+    pub(crate) fn sample(&self, generation: Option<u64>) -> PlannedSample<'_> {
+        if generation.is_none() || generation != self.plan.generation() {
+            self.class_names.borrow_mut().clear();
+        }
+        self.plan.sample(generation)
+    }
+
+    fn read_uncached(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(mem) = &self.mem {
+            use std::os::unix::fs::FileExt;
+            return mem
+                .read_exact_at(buf, addr as u64)
+                .map_err(remoteprocess::Error::IOError);
+        }
+        self.process.read(addr, buf)
+    }
+}
+
+impl ProcessMemory for MemoryReader {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        self.plan
+            .read(addr, buf, false, |addr, buf| self.read_uncached(addr, buf))
+    }
+}
+
+impl SampleMemory for MemoryReader {
+    fn read_immutable(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        self.plan
+            .read(addr, buf, true, |addr, buf| self.read_uncached(addr, buf))
+    }
+
+    fn class_name(&self, klass: usize) -> Option<(String, bool)> {
+        self.class_names.borrow().get(&klass).cloned()
+    }
+
+    fn remember_class_name(&self, klass: usize, name: &(String, bool)) {
+        let mut class_names = self.class_names.borrow_mut();
+        if class_names.len() >= MAX_CLASS_NAMES {
+            class_names.clear();
+        }
+        class_names.insert(klass, name.clone());
+    }
+}
+
+/// Whether a read failed because the system call was filtered out or isn't implemented, rather
+/// than because of the address
+#[cfg(target_os = "linux")]
+fn is_restricted(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::ENOSYS)
+    )
+}
+
+/// The start of the first readable region in the process's memory maps
+#[cfg(target_os = "linux")]
+fn readable_address(pid: Pid) -> Option<usize> {
+    let maps = proc_maps::get_process_maps(pid).ok()?;
+    maps.iter()
+        .find(|map| map.is_read() && map.size() >= 8)
+        .map(|map| map.start())
+}
+
+/// Opens `/proc/<pid>/mem` and checks that it can be read at `probe`
+#[cfg(target_os = "linux")]
+fn open_proc_mem(pid: Pid, probe: usize) -> std::io::Result<File> {
+    use std::os::unix::fs::FileExt;
+
+    let mem = File::open(format!("/proc/{}/mem", pid))?;
+    let mut buf = [0u8; 8];
+    mem.read_exact_at(&mut buf, probe as u64)?;
+    Ok(mem)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_own_memory() {
+        let value: u64 = 0x5262_7370_7921;
+        let reader = MemoryReader::new(std::process::id() as Pid).unwrap();
+        let copied: u64 = reader.copy_struct(&value as *const u64 as usize).unwrap();
+        assert_eq!(copied, value);
+
+        let mem = open_proc_mem(std::process::id() as Pid, &value as *const u64 as usize);
+        let proc_mem = MemoryReader {
+            method: ReadMethod::ProcMem,
+            mem: Some(mem.unwrap()),
+            ..MemoryReader::native(Process::new(std::process::id() as Pid).unwrap())
+        };
+        let copied: u64 = proc_mem.copy_struct(&value as *const u64 as usize).unwrap();
+        assert_eq!(copied, value);
+        assert_eq!(proc_mem.method().to_string(), "/proc/<pid>/mem");
+
+        assert_eq!(proc_mem.class_name(0x1000), None);
+        drop(proc_mem.sample(Some(1)));
+        proc_mem.remember_class_name(0x1000, &("Foo::Bar".to_string(), true));
+        drop(proc_mem.sample(Some(1)));
+        assert_eq!(
+            proc_mem.class_name(0x1000),
+            Some(("Foo::Bar".to_string(), true))
+        );
+        // The class may have been freed since
+        drop(proc_mem.sample(Some(2)));
+        assert_eq!(proc_mem.class_name(0x1000), None);
+    }
+}
@@ -1,4 +1,19 @@
 mod address_finder;
+pub mod audit;
+pub mod check;
+pub mod container;
+#[cfg(target_os = "linux")]
+mod debuginfo;
+pub mod discovery;
+pub mod environ;
+pub mod errors;
+pub mod fixture;
+pub(crate) mod http;
+pub mod inspect;
+pub mod kubernetes;
+pub mod maps;
+pub mod marker;
+pub mod memory;
 pub mod process;
 pub mod ruby_spy;
 mod ruby_version;
@@ -1,6 +1,195 @@
-use anyhow::Result;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
 pub use remoteprocess::{Pid, Process, ProcessMemory};
 
+use crate::core::types::Credentials;
+
+/// Memory that stack traces are read from. Some of what's read for each frame (a method's
+/// instruction sequence, its name and path, and its line table) doesn't change for as long as the
+/// method is loaded, so sources that are sampled repeatedly can keep it from one sample to the
+/// next.
+pub trait SampleMemory: ProcessMemory {
+    /// Reads memory that doesn't change for as long as the object it belongs to is alive
+    fn read_immutable(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        self.read(addr, buf)
+    }
+
+    /// The name of the class or module at `klass` and whether it's a singleton class, if it was
+    /// kept by `remember_class_name` in an earlier sample
+    fn class_name(&self, _klass: usize) -> Option<(String, bool)> {
+        None
+    }
+
+    /// Keeps a class's name for later samples, which saves scanning its instance variables again
+    fn remember_class_name(&self, _klass: usize, _name: &(String, bool)) {}
+}
+
+/// Reads through `SampleMemory::read_immutable`, for passing to code that reads through
+/// `ProcessMemory`
+pub(crate) struct Immutable<'a, T: SampleMemory>(pub &'a T);
+
+impl<T: SampleMemory> ProcessMemory for Immutable<'_, T> {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), remoteprocess::Error> {
+        self.0.read_immutable(addr, buf)
+    }
+}
+
+/// The size and alignment of the blocks of memory that a `ReadPlan` reads. A block is never
+/// bigger than a page, so the blocks around a readable address are readable too.
+const CHUNK_SIZE: usize = 1024;
+/// Reads bigger than this (e.g. of a deep stack's control frames) are passed straight through
+const MAX_PLANNED_READ: usize = 16 * CHUNK_SIZE;
+/// The most memory that doesn't change to keep, which is 16 MiB
+const MAX_IMMUTABLE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Cuts down the number of system calls it takes to read a stack trace. Reading a stack trace
+/// makes dozens of small reads (a struct or a string at a time) of memory that's often close
+/// together, so while a sample is taken, each read that misses fetches the aligned chunks around
+/// it in one go and later reads in the same chunks are served from them. What's read through
+/// `SampleMemory::read_immutable` is also kept across samples, keyed by its address and length,
+/// for as long as the samples' generation (see `sample`) stays the same. Only those exact reads
+/// are kept: the rest of their chunks can belong to other objects, which do change. Outside of
+/// samples, reads are passed straight through.
+#[derive(Default)]
+pub(crate) struct ReadPlan {
+    sampling: Cell<bool>,
+    /// The chunks read during the current sample, by address
+    chunks: RefCell<HashMap<usize, Vec<u8>>>,
+    /// The reads of memory that doesn't change, by address and length
+    immutable: RefCell<HashMap<(usize, usize), Vec<u8>>>,
+    /// The total length of the reads in `immutable`
+    immutable_bytes: Cell<usize>,
+    /// The generation that the reads in `immutable` were made in
+    generation: Cell<Option<u64>>,
+    /// The number of reads passed on to the source
+    source_reads: Cell<u64>,
+}
+
+/// Marks the end of a sample when it's dropped (see `ReadPlan::sample`)
+pub(crate) struct PlannedSample<'a>(&'a ReadPlan);
+
+impl Drop for PlannedSample<'_> {
+    fn drop(&mut self) {
+        self.0.sampling.set(false);
+        self.0.chunks.borrow_mut().clear();
+    }
+}
+
+impl ReadPlan {
+    /// Plans the reads until the returned guard is dropped, which should be when the sample has
+    /// been taken. Memory that doesn't change, which was read in earlier samples, is only used if
+    /// they were in the same `generation`. The memory of objects that the garbage collector frees
+    /// is reused for new ones, so the generation has to move on whenever that might have
+    /// happened. Without a generation, nothing is kept from one sample to the next.
+    pub fn sample(&self, generation: Option<u64>) -> PlannedSample<'_> {
+        self.chunks.borrow_mut().clear();
+        if generation.is_none()
+            || generation != self.generation.get()
+            || self.immutable_bytes.get() > MAX_IMMUTABLE_BYTES
+        {
+            self.immutable.borrow_mut().clear();
+            self.immutable_bytes.set(0);
+        }
+        self.generation.set(generation);
+        self.sampling.set(true);
+        PlannedSample(self)
+    }
+
+    /// The generation of the last sample
+    pub fn generation(&self) -> Option<u64> {
+        self.generation.get()
+    }
+
+    /// The number of reads that were passed on to the source
+    #[cfg(test)]
+    pub fn source_reads(&self) -> u64 {
+        self.source_reads.get()
+    }
+
+    /// Reads `buf.len()` bytes at `addr`, from what was already read if possible and otherwise
+    /// with `source`
+    pub fn read<F>(
+        &self,
+        addr: usize,
+        buf: &mut [u8],
+        immutable: bool,
+        source: F,
+    ) -> Result<(), remoteprocess::Error>
+    where
+        F: Fn(usize, &mut [u8]) -> Result<(), remoteprocess::Error>,
+    {
+        let planned = self.sampling.get() && !buf.is_empty() && buf.len() <= MAX_PLANNED_READ;
+        let end = match addr.checked_add(buf.len()) {
+            Some(end) if planned => end,
+            _ => return self.read_source(addr, buf, &source),
+        };
+        let kept = immutable && self.generation.get().is_some();
+        if kept {
+            if let Some(data) = self.immutable.borrow().get(&(addr, buf.len())) {
+                buf.copy_from_slice(data);
+                return Ok(());
+            }
+        }
+
+        let first = addr / CHUNK_SIZE * CHUNK_SIZE;
+        let last = (end - 1) / CHUNK_SIZE * CHUNK_SIZE;
+        let chunks: Vec<usize> = (first..=last).step_by(CHUNK_SIZE).collect();
+        let missing: Vec<usize> = {
+            let sample_chunks = self.chunks.borrow();
+            chunks
+                .iter()
+                .copied()
+                .filter(|chunk| !sample_chunks.contains_key(chunk))
+                .collect()
+        };
+        if let (Some(&from), Some(&to)) = (missing.first(), missing.last()) {
+            // Adjacent chunks that are missing are read together
+            let mut data = vec![0; to + CHUNK_SIZE - from];
+            if self.read_source(from, &mut data, &source).is_err() {
+                // Not all of the chunks could be read, e.g. because the process's memory maps
+                // changed, so fall back to reading just what was asked for
+                return self.read_source(addr, buf, &source);
+            }
+            let mut sample_chunks = self.chunks.borrow_mut();
+            for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+                sample_chunks.insert(from + i * CHUNK_SIZE, chunk.to_vec());
+            }
+        }
+
+        let sample_chunks = self.chunks.borrow();
+        for chunk in chunks {
+            let data = &sample_chunks[&chunk];
+            let start = addr.max(chunk);
+            let stop = end.min(chunk + CHUNK_SIZE);
+            buf[start - addr..stop - addr].copy_from_slice(&data[start - chunk..stop - chunk]);
+        }
+        if kept {
+            self.immutable
+                .borrow_mut()
+                .insert((addr, buf.len()), buf.to_vec());
+            self.immutable_bytes
+                .set(self.immutable_bytes.get() + buf.len());
+        }
+        Ok(())
+    }
+
+    fn read_source<F>(
+        &self,
+        addr: usize,
+        buf: &mut [u8],
+        source: &F,
+    ) -> Result<(), remoteprocess::Error>
+    where
+        F: Fn(usize, &mut [u8]) -> Result<(), remoteprocess::Error>,
+    {
+        self.source_reads.set(self.source_reads.get() + 1);
+        source(addr, buf)
+    }
+}
+
 pub trait ProcessRetry {
     fn new_with_retry(pid: Pid) -> Result<Process>;
 }
@@ -27,6 +216,143 @@ impl ProcessRetry for remoteprocess::Process {
     }
 }
 
+/// What the operating system reports a thread as doing
+#[derive(Debug, PartialEq, Eq)]
+pub struct OsThreadActivity {
+    /// Whether the thread is running or ready to run
+    pub running: bool,
+    /// Where the thread is waiting, if it isn't running
+    pub wait_channel: Option<String>,
+}
+
+/// Looks up a thread's scheduler state in `/proc/<pid>/task/<tid>`. A thread that Ruby considers
+/// runnable may still be blocked in the kernel, e.g. reading from a socket without the GVL.
+#[cfg(target_os = "linux")]
+pub fn os_thread_activity(pid: Pid, tid: usize) -> Result<OsThreadActivity> {
+    let task = format!("/proc/{}/task/{}", pid, tid);
+    let stat = std::fs::read_to_string(format!("{}/stat", task))?;
+    // The state follows the command name, which is in parentheses and may itself contain spaces
+    // or parentheses
+    let state = stat
+        .rfind(')')
+        .and_then(|i| stat[i + 1..].split_whitespace().next())
+        .ok_or_else(|| anyhow::format_err!("couldn't parse {}/stat", task))?;
+    if state == "R" {
+        return Ok(OsThreadActivity {
+            running: true,
+            wait_channel: None,
+        });
+    }
+
+    // wchan is "0" when the kernel won't tell us (e.g. without CAP_SYS_ADMIN), in which case the
+    // system call number is the next best thing
+    let wchan = std::fs::read_to_string(format!("{}/wchan", task)).unwrap_or_default();
+    let wait_channel = match wchan.trim() {
+        "" | "0" => std::fs::read_to_string(format!("{}/syscall", task))
+            .ok()
+            .and_then(|s| s.split_whitespace().next().map(|n| n.to_string()))
+            .filter(|n| n.chars().all(|c| c.is_ascii_digit()))
+            .map(|n| format!("syscall {}", n)),
+        wchan => Some(wchan.to_string()),
+    };
+    Ok(OsThreadActivity {
+        running: false,
+        wait_channel,
+    })
+}
+
+/// Switches rbspy to an unprivileged account for good. Handles that are already open (e.g. the
+/// target's task port on macOS) stay usable, but anything opened afterwards, including reads of
+/// the target's memory on Linux, is subject to the new account's permissions.
+#[cfg(unix)]
+pub fn drop_privileges(credentials: &Credentials) -> Result<()> {
+    use nix::unistd::{Group, Uid, User};
+
+    let user = User::from_name(&credentials.user)?
+        .ok_or_else(|| anyhow::format_err!("Unknown user: {}", credentials.user))?;
+    let gid = match &credentials.group {
+        Some(name) => {
+            Group::from_name(name)?
+                .ok_or_else(|| anyhow::format_err!("Unknown group: {}", name))?
+                .gid
+        }
+        None => user.gid,
+    };
+    // The group has to change first, since changing the user gives up the right to change it
+    #[cfg(not(target_os = "macos"))]
+    nix::unistd::setgroups(&[gid])?;
+    nix::unistd::setgid(gid)?;
+    nix::unistd::setuid(user.uid)?;
+
+    if user.uid != Uid::from_raw(0) && nix::unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err(anyhow::format_err!(
+            "Still able to regain root privileges after switching to {}",
+            credentials.user
+        ));
+    }
+    info!("Switched to user {} (uid {})", user.name, user.uid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_credentials: &Credentials) -> Result<()> {
+    Err(anyhow::format_err!(
+        "Dropping privileges isn't supported on this platform"
+    ))
+}
+
+/// The name of the host that rbspy is running on
+#[cfg(unix)]
+pub(crate) fn hostname() -> Option<String> {
+    nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Whether there's still a process with this PID
+#[cfg(unix)]
+pub(crate) fn is_running(pid: Pid) -> bool {
+    // Signal 0 only checks whether the process exists
+    let result = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None);
+    !matches!(result, Err(nix::errno::Errno::ESRCH))
+}
+
+/// Reads the PID from a pidfile, e.g. the one that Puma writes with `--pidfile`
+pub fn read_pidfile(path: &Path) -> Result<Pid> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Failed to read pidfile {}", path.display()))?;
+    parse_pid(&contents).context(format!("Invalid pidfile {}", path.display()))
+}
+
+fn parse_pid(contents: &str) -> Result<Pid> {
+    // The PID is on the first line; some programs add more after it
+    let line = contents.lines().next().unwrap_or_default().trim();
+    match line.parse::<Pid>() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => Err(anyhow::format_err!("{:?} isn't a PID", line)),
+    }
+}
+
+/// The time on the system's monotonic clock, which isn't affected by changes to the wall clock
+#[cfg(unix)]
+pub(crate) fn monotonic_clock() -> Option<std::time::Duration> {
+    let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC).ok()?;
+    Some(std::time::Duration::new(
+        now.tv_sec() as u64,
+        now.tv_nsec() as u32,
+    ))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn monotonic_clock() -> Option<std::time::Duration> {
+    None
+}
+
 #[cfg(test)]
 pub mod tests {
     use crate::core::process::{Pid, Process};
@@ -91,6 +417,106 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_read_plan() {
+        use super::ReadPlan;
+        use std::cell::RefCell;
+
+        let memory: RefCell<Vec<u8>> = RefCell::new((0..4000).map(|i| i as u8).collect());
+        let source = |addr: usize, buf: &mut [u8]| match memory.borrow().get(addr..addr + buf.len())
+        {
+            Some(data) => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            None => Err(remoteprocess::Error::Other("unmapped".to_string())),
+        };
+        let plan = ReadPlan::default();
+        let read = |addr: usize, len: usize, immutable: bool| {
+            let mut buf = vec![0; len];
+            plan.read(addr, &mut buf, immutable, source).map(|()| buf)
+        };
+        let expected = |addr: usize, len: usize| memory.borrow()[addr..addr + len].to_vec();
+
+        // Reads outside of samples go straight to the source
+        assert_eq!(read(10, 2, false).unwrap(), vec![10, 11]);
+        assert_eq!(read(10, 2, false).unwrap(), vec![10, 11]);
+        assert_eq!(plan.source_reads(), 2);
+
+        let sample = plan.sample(Some(1));
+        assert_eq!(read(100, 8, false).unwrap(), expected(100, 8));
+        assert_eq!(read(200, 40, true).unwrap(), expected(200, 40));
+        // Spans the first two chunks, so only the second one has to be read
+        assert_eq!(read(1000, 100, false).unwrap(), expected(1000, 100));
+        assert_eq!(plan.source_reads(), 4);
+        // The last chunk runs past the end of the memory, so just what was asked for is read
+        assert_eq!(read(3900, 100, false).unwrap(), expected(3900, 100));
+        assert!(read(3996, 8, false).is_err());
+        drop(sample);
+
+        // The objects next to the ones that don't change are freed and their memory reused
+        memory.borrow_mut()[100..108].copy_from_slice(&[0xff; 8]);
+        memory.borrow_mut()[240..248].copy_from_slice(&[0xee; 8]);
+
+        // Only the reads of memory that doesn't change are kept for the next sample in the same
+        // generation, and only for exactly what was read
+        let sample = plan.sample(Some(1));
+        let reads = plan.source_reads();
+        assert_eq!(read(200, 40, true).unwrap(), expected(200, 40));
+        assert_eq!(plan.source_reads(), reads);
+        assert_eq!(read(240, 8, true).unwrap(), vec![0xee; 8]);
+        assert_eq!(read(100, 8, false).unwrap(), vec![0xff; 8]);
+        assert_eq!(plan.source_reads(), reads + 1);
+        drop(sample);
+
+        // Objects may have been freed in between generations, so nothing is kept
+        memory.borrow_mut()[200..208].copy_from_slice(&[0xdd; 8]);
+        let sample = plan.sample(Some(2));
+        assert_eq!(read(200, 40, true).unwrap(), expected(200, 40));
+        assert_eq!(read(200, 8, true).unwrap(), vec![0xdd; 8]);
+        drop(sample);
+
+        // Or when the generation isn't known
+        memory.borrow_mut()[200..208].copy_from_slice(&[0xcc; 8]);
+        let _sample = plan.sample(None);
+        assert_eq!(read(200, 8, true).unwrap(), vec![0xcc; 8]);
+    }
+
+    #[test]
+    fn test_parse_pid() {
+        use super::parse_pid;
+
+        assert_eq!(parse_pid("1234\n").unwrap(), 1234);
+        assert_eq!(parse_pid("  1234  ").unwrap(), 1234);
+        assert_eq!(parse_pid("1234\nworker\n").unwrap(), 1234);
+        assert!(parse_pid("").is_err());
+        assert!(parse_pid("0\n").is_err());
+        assert!(parse_pid("-1\n").is_err());
+        assert!(parse_pid("puma\n").is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_os_thread_activity() {
+        use crate::core::process::os_thread_activity;
+
+        let pid = std::process::id() as Pid;
+        let gettid = || unsafe { libc::syscall(libc::SYS_gettid) } as usize;
+        assert!(os_thread_activity(pid, gettid()).unwrap().running);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sleeper = std::thread::spawn(move || {
+            sender.send(gettid()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        });
+        let sleeper_tid = receiver.recv().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let activity = os_thread_activity(pid, sleeper_tid).unwrap();
+        assert!(!activity.running);
+        assert!(activity.wait_channel.is_some());
+        sleeper.join().unwrap();
+    }
+
     impl Deref for RubyScript {
         type Target = Process;
 
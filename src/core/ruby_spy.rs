@@ -3,29 +3,81 @@ use anyhow::format_err;
 use anyhow::{Context, Error, Result};
 use spytools::ProcessInfo;
 
+use crate::core::errors::ErrorCode;
+use crate::core::fixture::{Fixture, RecordingMemory};
+use crate::core::memory::{MemoryReader, ReadMethod};
 use crate::core::process::{Pid, Process, ProcessRetry};
-use crate::core::types::{MemoryCopyError, StackTrace};
+use crate::core::types::{MemoryCopyError, StackTrace, ThreadState, VmStats};
 
 pub struct RubySpy {
     process: Process,
+    /// The program the process was running when it was attached to
+    exe: Option<String>,
+    memory: MemoryReader,
     current_thread_addr_location: usize,
     ruby_vm_addr_location: usize,
     global_symbols_addr_location: Option<usize>,
     stack_trace_function: crate::core::types::StackTraceFn,
+    all_stack_traces_function: crate::core::types::AllStackTracesFn,
+    vm_stats_function: crate::core::types::VmStatsFn,
+    /// `None` for versions of Ruby that don't record which thread holds the GVL
+    gvl_owner_function: Option<crate::core::types::GvlOwnerFn>,
+    ruby_version: semver::Version,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    os_thread_activity: bool,
+    gvl_labels: bool,
+    /// Whether the GVL's owner is read with each sample even without GVL labels (see
+    /// `set_gvl_owner_tracking`)
+    gvl_owner_tracking: bool,
+    /// What `read_gvl_owner` found in the last sample (see `sampled_gvl_owner`)
+    sampled_gvl_owner: Option<Option<usize>>,
+    /// Whether the GVL's owner has had to be guessed for a sample (see `gvl_owner_guessed`)
+    gvl_owner_guessed: bool,
 }
 
+/// The label that says whether a thread held the GVL when it was sampled (see
+/// `RubySpy::set_gvl_labels`)
+const GVL_LABEL: &str = "gvl";
+
 impl RubySpy {
     pub fn new(pid: Pid, force_version: Option<String>) -> Result<Self> {
         #[cfg(all(windows, target_arch = "x86_64"))]
         if is_wow64_process(pid).context("check wow64 process")? {
-            return Err(format_err!(
-                "Unable to profile 32-bit Ruby with 64-bit rbspy"
-            ));
+            return Err(ErrorCode::ArchitectureMismatch
+                .error("Unable to profile 32-bit Ruby with 64-bit rbspy")
+                .into());
         }
-        let process =
-            Process::new_with_retry(pid).context("Failed to find process. Is it running?")?;
+        let process = match Process::new_with_retry(pid) {
+            Ok(process) => process,
+            #[cfg(target_os = "linux")]
+            Err(e) if crate::core::check::in_container() => {
+                return Err(e.context(ErrorCode::ContainerNamespace.error(format!(
+                    "Failed to find process {}. rbspy is running in a container, so it can only see processes in the same PID namespace",
+                    pid
+                ))));
+            }
+            Err(e) => return Err(e.context("Failed to find process. Is it running?")),
+        };
 
-        let process_info = ProcessInfo::new::<spytools::process::RubyProcessType>(&process)?;
+        #[allow(unused_mut)]
+        let mut process_info = match ProcessInfo::new::<spytools::process::RubyProcessType>(
+            &process,
+        ) {
+            Ok(process_info) => process_info,
+            #[cfg(target_os = "linux")]
+            Err(e) if crate::core::check::in_other_mount_namespace(pid) => {
+                let message = if crate::core::check::can_read_process_root(pid) {
+                    "Failed to read the process's binaries, which are in another container's filesystem"
+                } else {
+                    "Failed to read the process's binaries, which are in another container's filesystem. rbspy reads them through /proc/<pid>/root, which takes the same privileges as reading the process's memory"
+                };
+                return Err(e.context(ErrorCode::ContainerNamespace.error(message)));
+            }
+            Err(e) => return Err(e),
+        };
+        #[cfg(target_os = "linux")]
+        crate::core::debuginfo::add_debug_symbols(&mut process_info, pid);
+        let memory = MemoryReader::new(pid)?;
 
         let (
             version,
@@ -33,23 +85,93 @@ impl RubySpy {
             ruby_vm_addr_location,
             global_symbols_addr_location,
         ) = crate::core::address_finder::inspect_ruby_process(
-            &process,
+            &memory,
             &process_info,
             force_version,
         )
         .context("get ruby VM state")?;
 
-        let stack_trace_function = crate::core::ruby_version::get_stack_trace_function(&version);
+        let stack_trace_function = crate::core::ruby_version::get_stack_trace_function(&version)?;
+        let all_stack_traces_function =
+            crate::core::ruby_version::get_all_stack_traces_function(&version)?;
+        let vm_stats_function = crate::core::ruby_version::get_vm_stats_function(&version)?;
+        let gvl_owner_function = match crate::core::ruby_version::get_gvl_owner_function(&version) {
+            Ok(function) => Some(function),
+            Err(e) => {
+                debug!("Can't read the GVL's owner: {}", e);
+                None
+            }
+        };
 
         Ok(Self {
+            exe: process.exe().ok(),
             process,
+            memory,
             current_thread_addr_location,
             ruby_vm_addr_location,
             global_symbols_addr_location,
             stack_trace_function,
+            all_stack_traces_function,
+            vm_stats_function,
+            gvl_owner_function,
+            ruby_version: version,
+            os_thread_activity: true,
+            gvl_labels: false,
+            gvl_owner_tracking: false,
+            sampled_gvl_owner: None,
+            gvl_owner_guessed: false,
         })
     }
 
+    /// The version of Ruby that the process is running
+    pub fn ruby_version(&self) -> &semver::Version {
+        &self.ruby_version
+    }
+
+    /// How the process's memory is being read, which depends on what the environment allows
+    pub fn read_method(&self) -> ReadMethod {
+        self.memory.method()
+    }
+
+    /// Whether to ask the OS what each sampled thread is doing, which refines their thread state
+    /// and records wait channels. Security modules often deny the reads this takes, so it can be
+    /// turned off for confined environments. Default: `true`.
+    pub fn set_os_thread_activity(&mut self, enabled: bool) {
+        self.os_thread_activity = enabled;
+    }
+
+    /// Whether to label each stack trace with whether its thread held the GVL (`gvl: held`), was
+    /// waiting to get it (`gvl: waiting`), or had released it to sleep, wait on IO or run C code
+    /// (`gvl: released`). The GVL's owner is read from the VM from Ruby 2.6 on; with older
+    /// versions, or if it can't be read, it's taken to be the VM's current thread, which is the
+    /// last one that acquired it (see `gvl_owner_guessed`). Waiting threads are told apart from
+    /// ones in IO with the OS's view of the threads, so without `set_os_thread_activity` (or
+    /// outside of Linux), every runnable thread other than the owner counts as waiting. Only the
+    /// threads that are sampled are labelled, so the waiting threads show up with
+    /// `get_all_stack_traces`. Default: `false`.
+    pub fn set_gvl_labels(&mut self, enabled: bool) {
+        self.gvl_labels = enabled;
+    }
+
+    /// Whether to read which thread holds the GVL with each sample even if traces aren't
+    /// labelled with the GVL's state, for `sampled_gvl_owner`. Default: `false`.
+    pub fn set_gvl_owner_tracking(&mut self, enabled: bool) {
+        self.gvl_owner_tracking = enabled;
+    }
+
+    /// The thread address of the thread that held the GVL when the last sample was taken, as
+    /// read from the VM. `None` if it isn't known (e.g. before Ruby 2.6, or without GVL labels or
+    /// `set_gvl_owner_tracking`), and `Some(None)` if no thread held it.
+    pub fn sampled_gvl_owner(&self) -> Option<Option<usize>> {
+        self.sampled_gvl_owner
+    }
+
+    /// Whether any sample's GVL labels took the VM's current thread to be the GVL's owner,
+    /// because the owner couldn't be read from the VM
+    pub fn gvl_owner_guessed(&self) -> bool {
+        self.gvl_owner_guessed
+    }
+
     /// Creates a RubySpy object, retrying up to max_retries times.
     ///
     /// Retrying is useful for a few reasons:
@@ -90,24 +212,243 @@ impl RubySpy {
     }
 
     pub fn get_stack_trace(&mut self, lock_process: bool, on_cpu: bool) -> Result<Option<StackTrace>> {
-        match self.get_trace_from_current_thread(lock_process, on_cpu) {
-            Ok(Some(mut trace)) => {
-                return {
-                    trace.pid = Some(self.process.pid);
-                    Ok(Some(trace))
-                };
+        let result = self.get_trace_from_current_thread(lock_process, on_cpu);
+        self.sampled_gvl_owner = result.as_ref().ok().and_then(|(_, gvl_owner)| *gvl_owner);
+        match result {
+            Ok((Some(trace), gvl_owner)) => {
+                let owner = self.gvl_owner(gvl_owner, &trace, true);
+                Ok(self.finish_trace(trace, on_cpu, owner))
             }
-            Ok(None) => return Ok(None),
+            Ok((None, _)) => Ok(None),
+            Err(e) => Err(self.trace_error(e)),
+        }
+    }
+
+    /// Reads the stack traces of all of the process's Ruby threads rather than just the one that
+    /// holds the GVL, e.g. to see what the other threads in a Puma or Sidekiq process are waiting
+    /// on. The traces are told apart by their thread IDs. Before Ruby 2.2, rbspy can only find
+    /// the current thread.
+    pub fn get_all_stack_traces(&mut self, lock_process: bool, on_cpu: bool) -> Result<Vec<StackTrace>> {
+        let (traces, gvl_owner) = {
+            let _lock;
+            if lock_process {
+                _lock = self
+                    .process
+                    .lock()
+                    .context("locking process during stack trace retrieval")?;
+            }
+            let _sample = self.memory.sample(self.generation());
+            // The current thread's trace comes first, but only if it isn't left out of on-CPU
+            // samples, so blocked threads are only left out afterwards when they're labelled
+            let traces = (self.all_stack_traces_function)(
+                self.current_thread_addr_location,
+                self.ruby_vm_addr_location,
+                self.global_symbols_addr_location,
+                &self.memory,
+                self.process.pid,
+                on_cpu && !self.gvl_labels,
+            );
+            (traces, self.read_gvl_owner())
+        };
+        self.sampled_gvl_owner = gvl_owner;
+        match traces {
+            Ok(traces) => {
+                let mut finished = Vec::with_capacity(traces.len());
+                for (i, trace) in traces.into_iter().enumerate() {
+                    let owner = self.gvl_owner(gvl_owner, &trace, i == 0);
+                    finished.extend(self.finish_trace(trace, on_cpu, owner));
+                }
+                Ok(finished)
+            }
+            Err(e) => Err(self.trace_error(e)),
+        }
+    }
+
+    /// Reads which thread holds the GVL, when traces are labelled with the GVL's state or its
+    /// owner is tracked. `None` if it isn't known, and `Some(None)` if no thread holds it.
+    fn read_gvl_owner(&self) -> Option<Option<usize>> {
+        if !self.gvl_labels && !self.gvl_owner_tracking {
+            return None;
+        }
+        let gvl_owner_function = self.gvl_owner_function.as_ref()?;
+        match gvl_owner_function(self.ruby_vm_addr_location, &self.memory) {
+            Ok(owner) => Some(owner),
             Err(e) => {
-                if self.process.exe().is_err() {
-                    return Err(MemoryCopyError::ProcessEnded.into());
+                debug!("Couldn't read the GVL's owner: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Whether the trace's thread holds the GVL, when traces are labelled with the GVL's state.
+    /// `gvl_owner` is what `read_gvl_owner` found, and `current` is whether the trace's thread
+    /// is the VM's current thread, which is taken to be the owner when the owner isn't known.
+    fn gvl_owner(
+        &mut self,
+        gvl_owner: Option<Option<usize>>,
+        trace: &StackTrace,
+        current: bool,
+    ) -> Option<GvlOwner> {
+        if !self.gvl_labels {
+            return None;
+        }
+        match (gvl_owner, trace.thread_address) {
+            (Some(owner), Some(thread_address)) => {
+                Some(GvlOwner::Read(owner == Some(thread_address)))
+            }
+            _ => {
+                self.gvl_owner_guessed = true;
+                Some(GvlOwner::Guessed(current))
+            }
+        }
+    }
+
+    /// Adds what the stack trace function can't tell from the process's memory, and leaves out
+    /// blocked threads from on-CPU samples. `gvl_owner` says whether the trace's thread is the
+    /// GVL's owner, when traces are labelled with the GVL's state.
+    fn finish_trace(
+        &self,
+        mut trace: StackTrace,
+        on_cpu: bool,
+        gvl_owner: Option<GvlOwner>,
+    ) -> Option<StackTrace> {
+        trace.pid = Some(self.process.pid);
+        let vm_runnable = trace.thread_state != Some(ThreadState::Blocked);
+        #[allow(unused_mut)]
+        let mut os_running = None;
+        #[cfg(target_os = "linux")]
+        if self.os_thread_activity {
+            os_running = self.add_os_thread_activity(&mut trace);
+        }
+        if let Some(owner) = gvl_owner {
+            let state = gvl_state(
+                owner,
+                vm_runnable,
+                os_running,
+                trace.wait_channel.as_deref(),
+            );
+            trace
+                .labels
+                .insert(GVL_LABEL.to_string(), state.to_string());
+        }
+        if on_cpu && trace.thread_state == Some(ThreadState::Blocked) {
+            return None;
+        }
+        Some(trace)
+    }
+
+    fn trace_error(&self, e: Error) -> Error {
+        match self.process.exe() {
+            Err(_) => return MemoryCopyError::ProcessEnded.into(),
+            // The process exec'd another program, so the Ruby VM it was running is gone
+            Ok(exe) if self.exe.as_ref().map_or(false, |attached| *attached != exe) => {
+                debug!("Process {} is now running {}", self.process.pid, exe);
+                return MemoryCopyError::ProcessEnded.into();
+            }
+            Ok(_) => {}
+        }
+        if let Some(MemoryCopyError::PermissionDenied) = e.downcast_ref() {
+            return e.context(
+                ErrorCode::PermissionDenied.error(crate::core::check::permission_error_hint()),
+            );
+        }
+        e
+    }
+
+    /// Reads a stack trace while keeping a copy of the memory it reads, so that it can be
+    /// replayed without the process. The process is paused while the stack trace is read, so
+    /// that the copy is consistent.
+    pub fn capture_fixture(&self) -> Result<Fixture> {
+        let stack_trace_function = crate::core::ruby_version::stack_trace_function_for::<
+            RecordingMemory<MemoryReader>,
+        >(&self.ruby_version)
+        .ok_or_else(|| anyhow::format_err!("Ruby version not supported yet: {}", self.ruby_version))?;
+        let _lock = self
+            .process
+            .lock()
+            .context("locking process while making a fixture")?;
+        let memory = RecordingMemory::new(&self.memory);
+        let trace = stack_trace_function(
+            self.current_thread_addr_location,
+            self.ruby_vm_addr_location,
+            self.global_symbols_addr_location,
+            &memory,
+            self.process.pid,
+            false,
+        )?;
+        Ok(Fixture {
+            ruby_version: self.ruby_version.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            pid: self.process.pid,
+            current_thread_addr_location: self.current_thread_addr_location,
+            ruby_vm_addr_location: self.ruby_vm_addr_location,
+            global_symbols_addr_location: self.global_symbols_addr_location,
+            expected_trace: trace.map(|trace| trace.trace).unwrap_or_default(),
+            regions: memory.into_regions(),
+        })
+    }
+
+    /// Reads allocation counters from the Ruby VM. Not supported for Ruby versions before 2.7.
+    pub fn get_vm_stats(&self) -> Result<VmStats> {
+        (self.vm_stats_function)(self.ruby_vm_addr_location, &self.memory)
+    }
+
+    /// The generation of the process's objects that memory which doesn't change is kept for
+    /// between samples (see `ReadPlan::sample`). The garbage collector's run count is private to
+    /// gc.c, so rbspy can't find it, but the VM's count of allocated objects stands in for it:
+    /// the memory of a freed object is only reused by allocating a new one, which moves the count
+    /// on. Before Ruby 2.7, nothing is kept.
+    fn generation(&self) -> Option<u64> {
+        self.get_vm_stats()
+            .ok()
+            .map(|stats| stats.total_allocated_objects)
+    }
+
+    /// Reads the process's memory
+    pub(crate) fn memory(&self) -> &MemoryReader {
+        &self.memory
+    }
+
+    /// The addresses of the global variables that rbspy finds the Ruby VM through
+    pub(crate) fn vm_locations(&self) -> Vec<(&'static str, usize)> {
+        let mut locations = vec![
+            ("current thread pointer", self.current_thread_addr_location),
+            (crate::core::maps::VM_POINTER, self.ruby_vm_addr_location),
+        ];
+        if let Some(address) = self.global_symbols_addr_location {
+            locations.push(("global symbol table", address));
+        }
+        locations
+    }
+
+    /// Ruby considers threads that are blocked in IO or other system calls to be runnable, so
+    /// ask the OS whether the thread is really running, and if not, where it's waiting. Returns
+    /// whether the OS says the thread is running, if it could tell.
+    #[cfg(target_os = "linux")]
+    fn add_os_thread_activity(&self, trace: &mut StackTrace) -> Option<bool> {
+        let tid = trace.os_thread_id?;
+        match crate::core::process::os_thread_activity(self.process.pid, tid) {
+            Ok(activity) => {
+                if !activity.running {
+                    trace.thread_state = Some(ThreadState::Blocked);
+                    trace.wait_channel = activity.wait_channel;
                 }
-                return Err(e.into());
+                Some(activity.running)
+            }
+            Err(e) => {
+                debug!("Couldn't get OS thread state for thread {}: {}", tid, e);
+                None
             }
         }
     }
 
-    fn get_trace_from_current_thread(&self, lock_process: bool, on_cpu: bool) -> Result<Option<StackTrace>> {
+    /// Reads the current thread's stack trace, along with the GVL's owner (see `read_gvl_owner`)
+    /// as of the same moment
+    fn get_trace_from_current_thread(
+        &self,
+        lock_process: bool,
+        on_cpu: bool,
+    ) -> Result<(Option<StackTrace>, Option<Option<usize>>)> {
         let _lock;
         if lock_process {
             _lock = self
@@ -116,17 +457,70 @@ impl RubySpy {
                 .context("locking process during stack trace retrieval")?;
         }
 
-        (&self.stack_trace_function)(
+        let _sample = self.memory.sample(self.generation());
+        let trace = (&self.stack_trace_function)(
             self.current_thread_addr_location,
             self.ruby_vm_addr_location,
             self.global_symbols_addr_location,
-            &self.process,
+            &self.memory,
             self.process.pid,
             on_cpu,
-        )
+        )?;
+        Ok((trace, self.read_gvl_owner()))
+    }
+}
+
+/// Whether a thread holds the GVL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum GvlOwner {
+    /// As read from the VM
+    Read(bool),
+    /// Whether it's the VM's current thread, which is the last one that acquired the GVL, but
+    /// may since have released it
+    Guessed(bool),
+}
+
+/// What a thread was doing with the GVL: `owner` is whether it holds it, `vm_runnable` whether
+/// the VM considers it runnable, and `os_running` whether the OS says it's running, if that's
+/// known
+fn gvl_state(
+    owner: GvlOwner,
+    vm_runnable: bool,
+    os_running: Option<bool>,
+    wait_channel: Option<&str>,
+) -> &'static str {
+    if owner == GvlOwner::Read(true) {
+        return "held";
+    }
+    if !vm_runnable {
+        // Sleeping, or waiting on a mutex, queue or another thread
+        return "released";
+    }
+    match (owner, os_running) {
+        // In a blocking region, e.g. reading from a socket
+        (GvlOwner::Guessed(true), Some(false)) => "released",
+        (GvlOwner::Guessed(true), _) => "held",
+        // Running C code that released the GVL
+        (_, Some(true)) => "released",
+        // Threads wait for the GVL on a condition variable, which sleeps in a futex
+        (_, Some(false)) => match wait_channel {
+            Some(wait_channel) if !is_futex_wait(wait_channel) => "released",
+            _ => "waiting",
+        },
+        (_, None) => "waiting",
     }
 }
 
+/// Whether the OS says a thread is waiting in a futex, e.g. `futex_wait_queue` or the system call
+/// number of `futex`
+fn is_futex_wait(wait_channel: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    if wait_channel == format!("syscall {}", libc::SYS_futex) {
+        return true;
+    }
+    wait_channel.contains("futex")
+}
+
 #[cfg(all(windows, target_arch = "x86_64"))]
 fn is_wow64_process(pid: Pid) -> Result<bool> {
     use std::os::windows::io::RawHandle;
@@ -162,6 +556,55 @@ mod tests {
     #[cfg(target_os = "macos")]
     use std::process::Command;
 
+    #[test]
+    fn test_gvl_state() {
+        use crate::core::ruby_spy::{gvl_state, GvlOwner};
+
+        let guessed = GvlOwner::Guessed;
+        assert_eq!(gvl_state(guessed(true), true, Some(true), None), "held");
+        assert_eq!(gvl_state(guessed(true), true, None, None), "held");
+        assert_eq!(
+            gvl_state(guessed(true), true, Some(false), Some("syscall 0")),
+            "released"
+        );
+        assert_eq!(gvl_state(guessed(true), false, None, None), "released");
+        assert_eq!(
+            gvl_state(guessed(false), true, Some(false), Some("futex_wait_queue")),
+            "waiting"
+        );
+        assert_eq!(
+            gvl_state(guessed(false), true, Some(false), None),
+            "waiting"
+        );
+        assert_eq!(gvl_state(guessed(false), true, None, None), "waiting");
+        assert_eq!(
+            gvl_state(guessed(false), true, Some(false), Some("do_epoll_wait")),
+            "released"
+        );
+        assert_eq!(
+            gvl_state(guessed(false), true, Some(true), None),
+            "released"
+        );
+        assert_eq!(
+            gvl_state(guessed(false), false, Some(false), None),
+            "released"
+        );
+
+        // A thread that's read to hold the GVL holds it, even in a system call
+        let read = GvlOwner::Read;
+        assert_eq!(
+            gvl_state(read(true), true, Some(false), Some("syscall 0")),
+            "held"
+        );
+        assert_eq!(gvl_state(read(true), false, None, None), "held");
+        assert_eq!(
+            gvl_state(read(false), true, Some(false), Some("futex_wait_queue")),
+            "waiting"
+        );
+        assert_eq!(gvl_state(read(false), true, Some(true), None), "released");
+        assert_eq!(gvl_state(read(false), false, None, None), "released");
+    }
+
     #[test]
     #[cfg(all(windows, target_arch = "x86_64"))]
     fn test_is_wow64_process() {
@@ -238,7 +681,7 @@ mod tests {
         let cmd = RubyScript::new("./ci/ruby-programs/infinite.rb");
         let pid = cmd.id() as Pid;
         let mut spy = RubySpy::retry_new(pid, 100, None).expect("couldn't initialize spy");
-        spy.get_stack_trace(false)
+        spy.get_stack_trace(false, false)
             .expect("couldn't get stack trace");
     }
 
@@ -257,7 +700,7 @@ mod tests {
 
         let mut i = 0;
         loop {
-            match getter.get_stack_trace(true) {
+            match getter.get_stack_trace(true, false) {
                 Err(e) => {
                     if let Some(crate::core::types::MemoryCopyError::ProcessEnded) =
                         e.downcast_ref()
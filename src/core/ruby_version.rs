@@ -29,7 +29,12 @@ macro_rules! ruby_version_v_1_9_1(
             stack_field_1_9_0!();
             get_thread_status_1_9_0!();
             get_thread_id_1_9_0!();
+            get_thread_name_unsupported!();
             get_cfunc_name_unsupported!();
+            get_class_name_unsupported!();
+            is_jit_frame_unsupported!();
+            get_threads_unsupported!();
+            get_vm_stats_unsupported!();
         }
     )
 );
@@ -54,12 +59,17 @@ macro_rules! ruby_version_v_1_9_2_to_3(
             stack_field_1_9_0!();
             get_thread_status_1_9_0!();
             get_thread_id_1_9_0!();
+            get_thread_name_unsupported!();
             get_cfunc_name_unsupported!();
+            get_class_name_unsupported!();
+            is_jit_frame_unsupported!();
+            get_threads_unsupported!();
+            get_vm_stats_unsupported!();
         }
     )
 );
 
-macro_rules! ruby_version_v_2_0_to_2_2(
+macro_rules! ruby_version_v_2_0_to_2_1(
     ($ruby_version:ident) => (
        pub mod $ruby_version {
            use std;
@@ -90,7 +100,43 @@ macro_rules! ruby_version_v_2_0_to_2_2(
             stack_field_1_9_0!();
             get_thread_status_1_9_0!();
             get_thread_id_1_9_0!();
+            get_thread_name_unsupported!();
             get_cfunc_name_unsupported!();
+            get_class_name_unsupported!();
+            is_jit_frame_unsupported!();
+            get_threads_unsupported!();
+            get_vm_stats_unsupported!();
+        }
+    )
+);
+
+macro_rules! ruby_version_v_2_2_x(
+    ($ruby_version:ident) => (
+       pub mod $ruby_version {
+           use std;
+           use anyhow::{Context, format_err, Result};
+           use bindings::$ruby_version::*;
+           use crate::core::process::ProcessMemory;
+
+            // Unlike earlier versions, Ruby 2.2 keeps its living threads in a linked list rather
+            // than a hash table, so all of them can be found
+            get_stack_trace!(rb_thread_struct);
+            get_execution_context_from_thread!(rb_thread_struct);
+            rstring_as_array_1_9_1!();
+            get_ruby_string_1_9_1!();
+            get_cfps!();
+            get_pos!(rb_iseq_struct);
+            get_lineno_2_0_0!();
+            get_stack_frame_2_0_0!();
+            stack_field_1_9_0!();
+            get_thread_status_1_9_0!();
+            get_thread_id_1_9_0!();
+            get_thread_name_unsupported!();
+            get_cfunc_name_unsupported!();
+            get_class_name_unsupported!();
+            is_jit_frame_unsupported!();
+            get_threads_2_2_0!();
+            get_vm_stats_unsupported!();
         }
     )
 );
@@ -114,7 +160,12 @@ macro_rules! ruby_version_v_2_3_to_2_4(
             stack_field_1_9_0!();
             get_thread_status_1_9_0!();
             get_thread_id_1_9_0!();
+            get_thread_name_2_3_0!();
             get_cfunc_name_unsupported!();
+            get_class_name_unsupported!();
+            is_jit_frame_unsupported!();
+            get_threads_2_2_0!();
+            get_vm_stats_unsupported!();
         }
     )
 );
@@ -139,10 +190,18 @@ macro_rules! ruby_version_v2_5_x(
             get_thread_status_2_5_0!();
             get_ruby_string_array_2_5_0!();
             get_thread_id_2_5_0!();
+            get_thread_name_2_5_0!();
             #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "windows"))]
             get_cfunc_name_unsupported!();
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "windows"))]
+            get_class_name_unsupported!();
             #[cfg(target_os = "linux")]
             get_cfunc_name!();
+            #[cfg(target_os = "linux")]
+            get_class_name!();
+            is_jit_frame_unsupported!();
+            get_threads_2_5_0!();
+            get_vm_stats_unsupported!();
         }
     )
 );
@@ -167,10 +226,19 @@ macro_rules! ruby_version_v2_6_x(
             stack_field_2_5_0!();
             get_thread_status_2_6_0!();
             get_thread_id_2_5_0!();
+            get_thread_name_2_5_0!();
             #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "windows"))]
             get_cfunc_name_unsupported!();
+            #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "windows"))]
+            get_class_name_unsupported!();
             #[cfg(target_os = "linux")]
             get_cfunc_name!();
+            #[cfg(target_os = "linux")]
+            get_class_name!();
+            is_jit_frame_unsupported!();
+            get_threads_2_5_0!();
+            get_vm_stats_unsupported!();
+            get_gvl_owner_2_6_0!();
         }
     )
 );
@@ -195,7 +263,13 @@ macro_rules! ruby_version_v2_7_x(
             stack_field_2_5_0!();
             get_thread_status_2_6_0!();
             get_thread_id_2_5_0!();
+            get_thread_name_2_5_0!();
             get_cfunc_name!();
+            get_class_name!();
+            is_jit_frame_unsupported!();
+            get_threads_2_5_0!();
+            get_vm_stats!();
+            get_gvl_owner_2_6_0!();
         }
     )
 );
@@ -220,7 +294,13 @@ macro_rules! ruby_version_v3_0_x(
             stack_field_2_5_0!();
             get_thread_status_2_6_0!();
             get_thread_id_2_5_0!();
+            get_thread_name_2_5_0!();
             get_cfunc_name!();
+            get_class_name!();
+            is_jit_frame_unsupported!();
+            get_threads_2_5_0!();
+            get_vm_stats!();
+            get_gvl_owner_3_0_0!();
 
             #[allow(non_upper_case_globals)]
             const ruby_fl_type_RUBY_FL_USHIFT: ruby_fl_type = ruby_fl_ushift_RUBY_FL_USHIFT as i32;
@@ -247,8 +327,14 @@ macro_rules! ruby_version_v3_1_x(
             get_stack_frame_2_5_0!();
             stack_field_2_5_0!();
             get_thread_status_2_6_0!();
-            get_thread_id_2_5_0!();
+            get_thread_id_3_1_0!();
+            get_thread_name_2_5_0!();
             get_cfunc_name!();
+            get_class_name!();
+            is_jit_frame_3_1_0!();
+            get_threads_2_5_0!();
+            get_vm_stats!();
+            get_gvl_owner_3_0_0!();
 
             #[allow(non_upper_case_globals)]
             const ruby_fl_type_RUBY_FL_USHIFT: ruby_fl_type = ruby_fl_ushift_RUBY_FL_USHIFT as i32;
@@ -275,7 +361,15 @@ macro_rules! ruby_version_v3_2_x(
             stack_field_2_5_0!();
             get_thread_status_2_6_0!();
             get_thread_id_3_2_0!();
+            get_thread_name_2_5_0!();
             get_cfunc_name!();
+            // Classes' instance variables (including `__classpath__`) are found through object
+            // shapes from 3.2, rather than the instance variable table that `get_class_name!` reads
+            get_class_name_unsupported!();
+            is_jit_frame_3_1_0!();
+            get_threads_2_5_0!();
+            get_vm_stats!();
+            get_gvl_owner_3_0_0!();
 
             #[allow(non_upper_case_globals)]
             const ruby_fl_type_RUBY_FL_USHIFT: ruby_fl_type = ruby_fl_ushift_RUBY_FL_USHIFT as i32;
@@ -303,14 +397,21 @@ macro_rules! get_execution_context_from_vm(
             ruby_vm_address_ptr: usize,
             source: &T
         ) -> Result<usize> {
-            // This is a roundabout way to get the execution context address, but it helps us
-            // avoid platform-specific structures in memory (e.g. pthread types) that would
-            // require us to maintain separate ruby-structs bindings for each platform due to
-            // their varying sizes and alignments.
             let vm_addr: usize = source.copy_struct(ruby_vm_address_ptr)
                 .context("couldn't read Ruby VM pointer")?;
             let vm: rb_vm_struct = source.copy_struct(vm_addr as usize)
                 .context("couldn't read Ruby VM struct")?;
+            let (_, execution_context) = find_running_ec(&vm, source)?;
+            Ok(execution_context)
+        }
+
+        /// Finds the main ractor's `running_ec` field, returning its address and the execution
+        /// context it points to
+        fn find_running_ec<T: ProcessMemory>(vm: &rb_vm_struct, source: &T) -> Result<(usize, usize)> {
+            // This is a roundabout way to get the execution context address, but it helps us
+            // avoid platform-specific structures in memory (e.g. pthread types) that would
+            // require us to maintain separate ruby-structs bindings for each platform due to
+            // their varying sizes and alignments.
 
             // Seek forward in the ractor struct, looking for the main thread's address. There
             // may be other copies of the main thread address in the ractor struct, so it's
@@ -328,12 +429,12 @@ macro_rules! get_execution_context_from_vm(
             .iter()
             .enumerate()
             .filter(|(_, &addr)| addr == vm.ractor.main_thread as usize)
-            .map(|(idx, _)| candidate_addresses[idx - 1])
-            .filter(|&addr| addr != 0)
-            .filter(|&addr| source.copy_struct::<rb_execution_context_struct>(addr as usize).is_ok())
-            .collect::<Vec<usize>>()
-            .first()
-            .map(|&addr| addr as usize)
+            .map(|(idx, _)| {
+                let field = main_ractor_address + initial_offset + (idx - 1) * std::mem::size_of::<usize>();
+                (field, candidate_addresses[idx - 1])
+            })
+            .filter(|&(_, addr)| addr != 0)
+            .find(|&(_, addr)| source.copy_struct::<rb_execution_context_struct>(addr as usize).is_ok())
             .ok_or_else(|| format_err!("couldn't find execution context"))
         }
     )
@@ -341,10 +442,11 @@ macro_rules! get_execution_context_from_vm(
 
 macro_rules! get_stack_trace(
     ($thread_type:ident) => (
-        use crate::core::process::Pid;
-        use crate::core::types::{StackFrame, StackTrace};
+        use crate::core::process::{Immutable, Pid, SampleMemory};
+        use crate::core::ruby_version::{locate_cfunc_frames, qualify_label};
+        use crate::core::types::{FrameKind, StackFrame, StackTrace, ThreadState};
 
-        pub fn get_stack_trace<T: ProcessMemory>(
+        pub fn get_stack_trace<T: SampleMemory>(
             ruby_current_thread_address_location: usize,
             ruby_vm_address_location: usize,
             ruby_global_symbols_address_location: Option<usize>,
@@ -354,29 +456,103 @@ macro_rules! get_stack_trace(
         ) -> Result<Option<StackTrace>, anyhow::Error> {
             let current_thread_addr: usize = get_execution_context(ruby_current_thread_address_location, ruby_vm_address_location, source)
                 .context("couldn't get execution context")?;
+            get_thread_stack_trace(current_thread_addr, ruby_global_symbols_address_location, source, pid, on_cpu)
+        }
+
+        /// Reads the stack traces of all of the VM's living threads, starting with the current
+        /// one. Versions of Ruby that rbspy can't find the other threads in just have the current
+        /// thread. Threads whose stacks can't be read (e.g. because they're exiting) are skipped.
+        pub fn get_all_stack_traces<T: SampleMemory>(
+            ruby_current_thread_address_location: usize,
+            ruby_vm_address_location: usize,
+            ruby_global_symbols_address_location: Option<usize>,
+            source: &T,
+            pid: Pid,
+            on_cpu: bool,
+        ) -> Result<Vec<StackTrace>, anyhow::Error> {
+            let current_thread_addr: usize = get_execution_context(ruby_current_thread_address_location, ruby_vm_address_location, source)
+                .context("couldn't get execution context")?;
+            let mut traces: Vec<StackTrace> =
+                get_thread_stack_trace(current_thread_addr, ruby_global_symbols_address_location, source, pid, on_cpu)?
+                    .into_iter()
+                    .collect();
+            let threads = match get_threads(current_thread_addr, source) {
+                Ok(threads) => threads,
+                Err(e) => {
+                    debug!("Couldn't find the VM's other threads: {:?}", e);
+                    return Ok(traces);
+                }
+            };
+            for thread_addr in threads.into_iter().filter(|&addr| addr != current_thread_addr) {
+                match get_thread_stack_trace(thread_addr, ruby_global_symbols_address_location, source, pid, on_cpu) {
+                    Ok(Some(trace)) => traces.push(trace),
+                    Ok(None) => {}
+                    Err(e) => debug!("Couldn't get stack trace of thread at {:#x}: {:?}", thread_addr, e),
+                }
+            }
+            Ok(traces)
+        }
+
+        fn get_thread_stack_trace<T: SampleMemory>(
+            current_thread_addr: usize,
+            ruby_global_symbols_address_location: Option<usize>,
+            source: &T,
+            pid: Pid,
+            on_cpu: bool,
+        ) -> Result<Option<StackTrace>, anyhow::Error> {
             let thread: $thread_type = source.copy_struct(current_thread_addr)
                 .context("couldn't get current thread")?;
 
             // testing the thread state in the interpreter.
-            if on_cpu && get_thread_status(&thread, source)? != rb_thread_status_THREAD_RUNNABLE /* THREAD_RUNNABLE */ {
+            let thread_state = match get_thread_status(&thread, source) {
+                Ok(status) if status == rb_thread_status_THREAD_RUNNABLE => Some(ThreadState::Running),
+                Ok(_) => Some(ThreadState::Blocked),
+                Err(e) if on_cpu => return Err(e),
+                Err(e) => {
+                    debug!("Couldn't get thread status: {}", e);
+                    None
+                },
+            };
+            if on_cpu && thread_state != Some(ThreadState::Running) {
                 /* This is in addition to any OS-specific checks for thread activity,
                  * and provides an extra measure of reliability for targets that haven't got them.
                  * Another added value for doing this is that it works for coredump targets. */
                 return Ok(None);
             }
 
+            let thread_id = match get_thread_id(&thread, source) {
+                Ok(tid) => Some(tid),
+                Err(e) => {
+                    debug!("Couldn't get thread ID: {}", e);
+                    None
+                },
+            };
+            let thread_address = Some(get_thread_address(&thread, current_thread_addr));
+            let thread_name = get_thread_name(&thread, source).ok();
+            let os_thread_id = match get_os_thread_id(&thread, source) {
+                Ok(tid) => Some(tid),
+                Err(e) => {
+                    debug!("Couldn't get OS thread ID: {}", e);
+                    None
+                },
+            };
+
             if stack_field(&thread) as usize == 0 {
                 return Ok(Some(StackTrace {
                     pid: Some(pid),
                     trace: vec!(StackFrame::unknown_c_function()),
-                    thread_id: match get_thread_id(&thread, source) {
-                        Ok(tid) => Some(tid),
-                        Err(e) => {
-                            debug!("Couldn't get thread ID: {}", e);
-                            None
-                        },
-                    },
-                    time: Some(SystemTime::now())
+                    thread_id,
+                    thread_address,
+                    thread_name,
+                    os_thread_id,
+                    thread_state,
+                    wait_channel: None,
+                    time: Some(SystemTime::now()),
+                    interval: None,
+                    vm_stats: None,
+                    labels: std::collections::BTreeMap::new(),
+                    repeats: 0,
+                    allocated: None,
                 }));
             }
 
@@ -388,11 +564,16 @@ macro_rules! get_stack_trace(
                     if let Some(global_symbols_addr) = ruby_global_symbols_address_location {
                         match get_cfunc_name(cfp, global_symbols_addr, source, pid) {
                             Ok(name) => {
+                                let name = match get_class_name(cfp, global_symbols_addr, source) {
+                                    Ok((class_name, singleton)) => qualify_label(&name, &class_name, singleton),
+                                    Err(_) => name,
+                                };
                                 frame = StackFrame{
                                     name: format!("{} [c function]", name),
                                     relative_path: "(unknown)".to_string(),
                                     absolute_path: None,
                                     lineno: None,
+                                    kind: FrameKind::CFunction,
                                 };
                             },
                             Err(e) => {
@@ -403,14 +584,24 @@ macro_rules! get_stack_trace(
                     trace.push(frame);
                     continue;
                 }
-                if cfp.pc as usize == 0 {
+                if cfp.pc as usize == 0 && !is_jit_frame(cfp) {
                     debug!("pc was 0. Not sure what that means, but skipping CFP");
                     continue;
                 }
-                let iseq_struct: rb_iseq_struct = source.copy_struct(cfp.iseq as usize)
+                // An iseq, and the names, paths and line tables it points to, don't change once
+                // it's compiled, so they can be kept from one sample to the next
+                let immutable = Immutable(source);
+                let iseq_struct: rb_iseq_struct = immutable.copy_struct(cfp.iseq as usize)
                     .context(cfp.iseq as usize)?;
 
-                let label_path  = get_stack_frame(&iseq_struct, &cfp, source);
+                let mut label_path  = get_stack_frame(&iseq_struct, &cfp, &immutable);
+                if let (Ok(frame), Some(global_symbols_addr)) = (label_path.as_mut(), ruby_global_symbols_address_location) {
+                    // Frames that aren't inside a method (e.g. `<main>` or class bodies) have no
+                    // owner, so they keep their plain label
+                    if let Ok((class_name, singleton)) = get_class_name(cfp, global_symbols_addr, source) {
+                        frame.name = qualify_label(&frame.name, &class_name, singleton);
+                    }
+                }
                 match label_path {
                     Ok(call)  => trace.push(call),
                     Err(x) => {
@@ -427,14 +618,23 @@ macro_rules! get_stack_trace(
                     }
                 }
             }
-            let thread_id = match get_thread_id(&thread, source) {
-                Ok(tid) => Some(tid),
-                Err(e) => {
-                    debug!("Couldn't get thread ID: {}", e);
-                    None
-                },
-            };
-            Ok(Some(StackTrace{trace, pid: Some(pid), thread_id, time: Some(SystemTime::now())}))
+            locate_cfunc_frames(&mut trace);
+            Ok(Some(StackTrace{
+                trace,
+                pid: Some(pid),
+                thread_id,
+                thread_address,
+                thread_name,
+                os_thread_id,
+                thread_state,
+                wait_channel: None,
+                time: Some(SystemTime::now()),
+                interval: None,
+                vm_stats: None,
+                labels: std::collections::BTreeMap::new(),
+                repeats: 0,
+                allocated: None,
+            }))
         }
 
         use proc_maps::{maps_contain_addr, MapRange};
@@ -461,7 +661,7 @@ macro_rules! get_stack_trace(
             stack_field(thread) + stack_size_field(thread) * std::mem::size_of::<VALUE>() as i64 - 1 * std::mem::size_of::<rb_control_frame_t>() as i64
         }
 
-        pub fn is_maybe_thread<T>(candidate_thread_addr: usize, candidate_thread_addr_ptr: usize, source: &T, all_maps: &[MapRange]) -> bool where T: ProcessMemory {
+        pub fn is_maybe_thread<T>(candidate_thread_addr: usize, candidate_thread_addr_ptr: usize, source: &T, all_maps: &[MapRange]) -> bool where T: SampleMemory {
             if !maps_contain_addr(candidate_thread_addr, all_maps) {
                 return false;
             }
@@ -546,6 +746,14 @@ macro_rules! get_thread_id_1_9_0(
         fn get_thread_id<T>(thread_struct: &rb_thread_struct, _source: &T) -> Result<usize> {
             Ok(thread_struct.thread_id as usize)
         }
+
+        fn get_thread_address(_thread_struct: &rb_thread_struct, thread_struct_address: usize) -> usize {
+            thread_struct_address
+        }
+
+        fn get_os_thread_id<T>(_thread_struct: &rb_thread_struct, _source: &T) -> Result<usize> {
+            Err(format_err!("OS thread IDs are not recorded by this version of Ruby"))
+        }
     )
 );
 
@@ -558,6 +766,41 @@ macro_rules! get_thread_id_2_5_0(
                 .context("couldn't copy thread struct")?;
             Ok(thread.thread_id as usize)
         }
+
+        fn get_thread_address(thread_struct: &rb_execution_context_struct, _execution_context_address: usize) -> usize {
+            thread_struct.thread_ptr as usize
+        }
+
+        fn get_os_thread_id<T>(_thread_struct: &rb_execution_context_struct, _source: &T) -> Result<usize> {
+            Err(format_err!("OS thread IDs are not recorded by this version of Ruby"))
+        }
+    )
+);
+
+macro_rules! get_thread_id_3_1_0(
+    () => (
+
+        fn get_thread_id<T>(thread_struct: &rb_execution_context_struct, source: &T)
+                            -> Result<usize> where T: ProcessMemory {
+            let thread: rb_thread_struct = source.copy_struct(thread_struct.thread_ptr as usize)
+                .context("couldn't copy thread struct")?;
+            Ok(thread.thread_id as usize)
+        }
+
+        fn get_thread_address(thread_struct: &rb_execution_context_struct, _execution_context_address: usize) -> usize {
+            thread_struct.thread_ptr as usize
+        }
+
+        fn get_os_thread_id<T>(thread_struct: &rb_execution_context_struct, source: &T)
+                            -> Result<usize> where T: ProcessMemory {
+            let thread: rb_thread_struct = source.copy_struct(thread_struct.thread_ptr as usize)
+                .context("couldn't copy thread struct")?;
+            // The TID is filled in by the thread itself once it starts running
+            if thread.tid == 0 {
+                return Err(format_err!("thread has no OS thread ID yet"));
+            }
+            Ok(thread.tid as usize)
+        }
     )
 );
 
@@ -574,6 +817,143 @@ macro_rules! get_thread_id_3_2_0(
                 .context("couldn't copy native thread struct")?;
             Ok(native_thread.thread_id as usize)
         }
+
+        fn get_thread_address(thread_struct: &rb_execution_context_struct, _execution_context_address: usize) -> usize {
+            thread_struct.thread_ptr as usize
+        }
+
+        fn get_os_thread_id<T>(thread_struct: &rb_execution_context_struct, source: &T)
+                            -> Result<usize> where T: ProcessMemory {
+            let thread: rb_thread_struct = source.copy_struct(thread_struct.thread_ptr as usize)
+                .context("couldn't copy thread struct")?;
+            if thread.nt.is_null() {
+                return Err(format_err!("native thread pointer is NULL"));
+            }
+            let native_thread: rb_native_thread = source.copy_struct(thread.nt as usize)
+                .context("couldn't copy native thread struct")?;
+            // The TID is filled in by the thread itself once it starts running
+            if native_thread.tid == 0 {
+                return Err(format_err!("thread has no OS thread ID yet"));
+            }
+            Ok(native_thread.tid as usize)
+        }
+    )
+);
+
+macro_rules! is_jit_frame_unsupported(
+    () => (
+        fn is_jit_frame(_cfp: &rb_control_frame_t) -> bool {
+            false
+        }
+    )
+);
+
+// YJIT (Ruby 3.1 and newer) sets `jit_return` in the frames it pushes, and only writes their
+// program counter when something needs it, so a frame running jitted code can have a PC of 0.
+// It's still a Ruby method: its name and path come from its iseq, but its line number is left
+// out until the PC is written.
+macro_rules! is_jit_frame_3_1_0(
+    () => (
+        fn is_jit_frame(cfp: &rb_control_frame_t) -> bool {
+            !cfp.jit_return.is_null()
+        }
+    )
+);
+
+macro_rules! get_threads_unsupported(
+    () => (
+        fn get_threads<T>(_current_thread_address: usize, _source: &T) -> Result<Vec<usize>> {
+            Err(format_err!("Finding all threads is not supported for this version of Ruby"))
+        }
+    )
+);
+
+// Before Ruby 2.5, the stack is read straight from the thread struct, which is both the list node
+// and what get_thread_stack_trace takes. A thread belongs to the same VM as the current thread,
+// which the list's head (a field of the VM struct) doesn't look like.
+macro_rules! get_threads_2_2_0(
+    () => (
+        fn get_threads<T>(current_thread_address: usize, source: &T)
+                          -> Result<Vec<usize>> where T: ProcessMemory {
+            let current: rb_thread_struct = source.copy_struct(current_thread_address)
+                .context("couldn't copy current thread struct")?;
+            crate::core::ruby_version::walk_thread_list(current_thread_address, source, |node| {
+                let thread: rb_thread_struct = source.copy_struct(node).ok()?;
+                if thread.vm == current.vm {
+                    Some(node)
+                } else {
+                    None
+                }
+            })
+        }
+    )
+);
+
+// Since Ruby 2.5, the stack is in the execution context that the thread is running (its root
+// fiber or another fiber), which points back to the thread.
+macro_rules! get_threads_2_5_0(
+    () => (
+        fn get_threads<T>(current_execution_context_address: usize, source: &T)
+                          -> Result<Vec<usize>> where T: ProcessMemory {
+            let current: rb_execution_context_struct = source.copy_struct(current_execution_context_address)
+                .context("couldn't copy current execution context")?;
+            crate::core::ruby_version::walk_thread_list(current.thread_ptr as usize, source, |node| {
+                let thread: rb_thread_struct = source.copy_struct(node).ok()?;
+                let ec: rb_execution_context_struct = source.copy_struct(thread.ec as usize).ok()?;
+                if ec.thread_ptr as usize == node {
+                    Some(thread.ec as usize)
+                } else {
+                    None
+                }
+            })
+        }
+    )
+);
+
+macro_rules! get_thread_name_unsupported(
+    () => (
+        fn get_thread_name<T, S>(_thread_struct: &S, _source: &T) -> Result<String> {
+            Err(format_err!("Thread names are not recorded by this version of Ruby"))
+        }
+    )
+);
+
+// Threads that were never given a name have a nil name rather than a string, and nil is the only
+// special constant that can be stored there. Heap objects are always 8-byte aligned and never 0 or
+// 8, which are false and (before Ruby 3.0) nil.
+macro_rules! read_thread_name(
+    () => (
+        fn read_thread_name<T>(name: VALUE, source: &T) -> Result<String> where T: ProcessMemory {
+            let name = name as usize;
+            if name == 0 || name == 8 || name & 7 != 0 {
+                return Err(format_err!("thread has no name"));
+            }
+            get_ruby_string(name, source)
+        }
+    )
+);
+
+macro_rules! get_thread_name_2_3_0(
+    () => (
+        read_thread_name!();
+
+        fn get_thread_name<T>(thread_struct: &rb_thread_struct, source: &T)
+                              -> Result<String> where T: ProcessMemory {
+            read_thread_name(thread_struct.name, source)
+        }
+    )
+);
+
+macro_rules! get_thread_name_2_5_0(
+    () => (
+        read_thread_name!();
+
+        fn get_thread_name<T>(thread_struct: &rb_execution_context_struct, source: &T)
+                              -> Result<String> where T: ProcessMemory {
+            let thread: rb_thread_struct = source.copy_struct(thread_struct.thread_ptr as usize)
+                .context("couldn't copy thread struct")?;
+            read_thread_name(thread.name, source)
+        }
     )
 );
 
@@ -734,6 +1114,8 @@ macro_rules! get_stack_frame_1_9_1(
                 name: get_ruby_string(iseq_struct.name as usize, source)?,
                 relative_path: get_ruby_string(iseq_struct.filename as usize, source)?,
                 absolute_path: None,
+                // ISEQ_TYPE_* are Fixnums starting from 1 in 1.9.1
+                kind: FrameKind::from_iseq_type(((iseq_struct.type_ >> 1) as u32).wrapping_sub(1)),
                 lineno: match get_lineno(iseq_struct, cfp, source) {
                     Ok(lineno) => Some(lineno),
                     Err(e) => {
@@ -757,6 +1139,7 @@ macro_rules! get_stack_frame_1_9_2(
                 name: get_ruby_string(iseq_struct.name as usize, source)?,
                 relative_path: get_ruby_string(iseq_struct.filename as usize, source)?,
                 absolute_path: Some(get_ruby_string(iseq_struct.filepath as usize, source)?),
+                kind: FrameKind::from_iseq_type(iseq_struct.type_ as u32),
                 lineno: match get_lineno(iseq_struct, cfp, source) {
                     Ok(lineno) => Some(lineno),
                     Err(e) => {
@@ -780,6 +1163,7 @@ macro_rules! get_stack_frame_2_0_0(
                 name: get_ruby_string(iseq_struct.location.label as usize, source)?,
                 relative_path: get_ruby_string(iseq_struct.location.path as usize, source)?,
                 absolute_path: Some(get_ruby_string(iseq_struct.location.absolute_path as usize, source)?),
+                kind: FrameKind::from_iseq_type(iseq_struct.type_ as u32),
                 lineno: match get_lineno(iseq_struct, cfp, source) {
                     Ok(lineno) => Some(lineno),
                     Err(e) => {
@@ -805,6 +1189,7 @@ macro_rules! get_stack_frame_2_3_0(
                 name: get_ruby_string(body.location.label as usize, source)?,
                 relative_path: get_ruby_string(body.location.path as usize, source)?,
                 absolute_path: Some(get_ruby_string(body.location.absolute_path as usize, source)?),
+                kind: FrameKind::from_iseq_type(body.type_ as u32),
                 lineno: match get_lineno(&body, cfp, source) {
                     Ok(lineno) => Some(lineno),
                     Err(e) => {
@@ -834,12 +1219,19 @@ macro_rules! get_stack_frame_2_5_0(
                 name: get_ruby_string(body.location.label as usize, source)?,
                 relative_path: path,
                 absolute_path: Some(absolute_path),
-                lineno: match get_lineno(&body, cfp, source) {
-                    Ok(lineno) => Some(lineno),
-                    Err(e) => {
-                        warn!("couldn't get lineno: {}", e);
-                        None
-                    },
+                kind: FrameKind::from_iseq_type(body.type_ as u32),
+                // Frames running jitted code (see `is_jit_frame_3_1_0!`) can have no PC, and the
+                // line can't be found without it
+                lineno: if cfp.pc as usize == 0 {
+                    None
+                } else {
+                    match get_lineno(&body, cfp, source) {
+                        Ok(lineno) => Some(lineno),
+                        Err(e) => {
+                            warn!("couldn't get lineno: {}", e);
+                            None
+                        },
+                    }
                 }
             })
         }
@@ -853,7 +1245,9 @@ macro_rules! get_pos(
             if (cfp.pc as usize) < (iseq_struct.iseq_encoded as usize) {
                 return Err(crate::core::types::MemoryCopyError::Message(format!("program counter and iseq are out of sync")).into());
             }
-            let mut pos = cfp.pc as usize - iseq_struct.iseq_encoded as usize;
+            // Like Ruby's `calc_pos`: the position is in instruction slots (`VALUE`s) rather than
+            // bytes, and the pc has already moved past the instruction that's running
+            let mut pos = (cfp.pc as usize - iseq_struct.iseq_encoded as usize) / std::mem::size_of::<VALUE>();
             if pos != 0 {
                 pos -= 1;
             }
@@ -864,6 +1258,8 @@ macro_rules! get_pos(
 
 macro_rules! get_lineno_1_9_0(
     () => (
+        use crate::core::ruby_version::insn_info_index;
+
         fn get_lineno<T>(
             iseq_struct: &rb_iseq_struct,
             cfp: &rb_control_frame_t,
@@ -880,14 +1276,8 @@ macro_rules! get_lineno_1_9_0(
             } else {
                 let table: Vec<iseq_insn_info_entry> = source.copy_vec(iseq_struct.insn_info_table as usize, t_size as usize)
                     .context("couldn't copy instruction table")?;
-                for i in 0..t_size {
-                    if pos == table[i].position as usize {
-                        return Ok(table[i].line_no as usize)
-                    } else if table[i].position as usize > pos {
-                        return Ok(table[i-1].line_no as usize)
-                    }
-                }
-                Ok(table[t_size-1].line_no as usize)
+                let positions: Vec<usize> = table.iter().map(|entry| entry.position as usize).collect();
+                Ok(table[insn_info_index(&positions, pos)].line_no as usize)
             }
         }
     )
@@ -895,6 +1285,8 @@ macro_rules! get_lineno_1_9_0(
 
 macro_rules! get_lineno_2_0_0(
     () => (
+        use crate::core::ruby_version::insn_info_index;
+
         fn get_lineno<T>(
             iseq_struct: &rb_iseq_struct,
             cfp: &rb_control_frame_t,
@@ -911,14 +1303,8 @@ macro_rules! get_lineno_2_0_0(
             } else {
                 let table: Vec<iseq_line_info_entry> = source.copy_vec(iseq_struct.line_info_table as usize, t_size as usize)
                     .context("couldn't copy instruction table")?;
-                for i in 0..t_size {
-                    if pos == table[i].position as usize {
-                        return Ok(table[i].line_no as usize)
-                    } else if table[i].position as usize > pos {
-                        return Ok(table[i-1].line_no as usize)
-                    }
-                }
-                Ok(table[t_size-1].line_no as usize)
+                let positions: Vec<usize> = table.iter().map(|entry| entry.position as usize).collect();
+                Ok(table[insn_info_index(&positions, pos)].line_no as usize)
             }
         }
     )
@@ -926,6 +1312,8 @@ macro_rules! get_lineno_2_0_0(
 
 macro_rules! get_lineno_2_3_0(
     () => (
+        use crate::core::ruby_version::insn_info_index;
+
         fn get_lineno<T>(
             iseq_struct: &rb_iseq_constant_body,
             cfp: &rb_control_frame_t,
@@ -942,14 +1330,8 @@ macro_rules! get_lineno_2_3_0(
             } else {
                 let table: Vec<iseq_line_info_entry> = source.copy_vec(iseq_struct.line_info_table as usize, t_size as usize)
                     .context("couldn't copy instruction table")?;
-                for i in 0..t_size {
-                    if pos == table[i].position as usize {
-                        return Ok(table[i].line_no as usize)
-                    } else if table[i].position as usize > pos {
-                        return Ok(table[i-1].line_no as usize)
-                    }
-                }
-                Ok(table[t_size-1].line_no as usize)
+                let positions: Vec<usize> = table.iter().map(|entry| entry.position as usize).collect();
+                Ok(table[insn_info_index(&positions, pos)].line_no as usize)
             }
         }
     )
@@ -957,6 +1339,8 @@ macro_rules! get_lineno_2_3_0(
 
 macro_rules! get_lineno_2_5_0(
     () => (
+        use crate::core::ruby_version::insn_info_index;
+
         fn get_lineno<T>(
             iseq_struct: &rb_iseq_constant_body,
             cfp: &rb_control_frame_t,
@@ -973,14 +1357,8 @@ macro_rules! get_lineno_2_5_0(
             } else {
                 let table: Vec<iseq_insn_info_entry> = source.copy_vec(iseq_struct.insns_info as usize, t_size as usize)
                     .context("couldn't copy instruction table")?;
-                for i in 0..t_size {
-                    if pos == table[i].position as usize {
-                        return Ok(table[i].line_no as usize)
-                    } else if table[i].position as usize > pos {
-                        return Ok(table[i-1].line_no as usize)
-                    }
-                }
-                Ok(table[t_size-1].line_no as usize)
+                let positions: Vec<usize> = table.iter().map(|entry| entry.position as usize).collect();
+                Ok(table[insn_info_index(&positions, pos)].line_no as usize)
             }
         }
     )
@@ -988,9 +1366,11 @@ macro_rules! get_lineno_2_5_0(
 
 macro_rules! get_lineno_2_6_0(
     () => (
+        use crate::core::ruby_version::{insn_info_index, succ_index_lookup};
+
         fn get_lineno<T>(
             iseq_struct: &rb_iseq_constant_body,
-            _cfp: &rb_control_frame_t,
+            cfp: &rb_control_frame_t,
             source: &T,
         ) -> Result<usize> where T: ProcessMemory {
             let t_size = iseq_struct.insns_info.size as usize;
@@ -1001,11 +1381,25 @@ macro_rules! get_lineno_2_6_0(
                     .context("couldn't copy instruction table")?;
                 Ok(table[0].line_no as usize)
             } else {
-                // TODO: To handle this properly, we need to imitate ruby's succinct bit vector lookup.
-                // See https://github.com/rbspy/rbspy/issues/213#issuecomment-826363857
-                let table: Vec<iseq_insn_info_entry> = source.copy_vec(iseq_struct.insns_info.body as usize, t_size as usize)
+                let pos = get_pos(iseq_struct, cfp)?;
+                // Since 2.6 the table's entries no longer have positions. Ruby keeps them in a
+                // separate array while it compiles an iseq, and usually swaps that array for a
+                // succinct bit vector once it's done.
+                let index = if iseq_struct.insns_info.positions as usize != 0 {
+                    let positions: Vec<std::os::raw::c_uint> = source.copy_vec(iseq_struct.insns_info.positions as usize, t_size)
+                        .context("couldn't copy instruction positions")?;
+                    let positions: Vec<usize> = positions.iter().map(|&position| position as usize).collect();
+                    insn_info_index(&positions, pos)
+                } else if iseq_struct.insns_info.succ_index_table as usize != 0 {
+                    succ_index_lookup(iseq_struct.insns_info.succ_index_table as usize, pos, source)?
+                        .saturating_sub(1)
+                        .min(t_size - 1)
+                } else {
+                    return Err(format_err!("instruction positions are not available"));
+                };
+                let entry: iseq_insn_info_entry = source.copy_struct(iseq_struct.insns_info.body as usize + index * std::mem::size_of::<iseq_insn_info_entry>())
                     .context(iseq_struct.insns_info.body as usize)?;
-                Ok(table[t_size-1].line_no as usize)
+                Ok(entry.line_no as usize)
             }
         }
     )
@@ -1109,6 +1503,17 @@ macro_rules! get_cfunc_name(
                 return Err(format_err!("Not a method entry").into());
             }
 
+            let def: rb_method_definition_struct = source.copy_struct(imemo.def as usize).context(imemo.def as usize)?;
+            get_symbol_name(def.original_id as usize, global_symbols_address, source)
+        }
+
+        // Resolves an ID (e.g. a method or instance variable name) to its string, using the VM's
+        // global symbol table.
+        fn get_symbol_name<T: ProcessMemory>(
+            id: usize,
+            global_symbols_address: usize,
+            source: &T,
+        ) -> Result<String> {
             #[allow(non_camel_case_types)]
             type rb_id_serial_t = u32;
 
@@ -1123,17 +1528,15 @@ macro_rules! get_cfunc_name(
             }
 
             let global_symbols: rb_symbols_t = source.copy_struct(global_symbols_address as usize).context(global_symbols_address as usize)?;
-            let def: rb_method_definition_struct = source.copy_struct(imemo.def as usize).context(imemo.def as usize)?;
-            let method_id = def.original_id as usize;
 
             // rb_id_to_serial
-            let mut serial = method_id;
-            if method_id > ruby_method_ids_tLAST_OP_ID as usize {
-                serial = method_id >> ruby_id_types_RUBY_ID_SCOPE_SHIFT;
+            let mut serial = id;
+            if id > ruby_method_ids_tLAST_OP_ID as usize {
+                serial = id >> ruby_id_types_RUBY_ID_SCOPE_SHIFT;
             }
 
             if serial > global_symbols.last_id as usize {
-                return Err(format_err!("Invalid method ID").into());
+                return Err(format_err!("Invalid symbol ID").into());
             }
 
             // ID_ENTRY_UNIT is defined in symbol.c, so not accessible by bindgen
@@ -1175,61 +1578,500 @@ macro_rules! get_cfunc_name(
     )
 );
 
-ruby_version_v_1_9_1!(ruby_1_9_1_0);
-ruby_version_v_1_9_2_to_3!(ruby_1_9_2_0);
-ruby_version_v_1_9_2_to_3!(ruby_1_9_3_0);
-ruby_version_v_2_0_to_2_2!(ruby_2_0_0_0);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_0);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_1);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_2);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_3);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_4);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_5);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_6);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_7);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_8);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_9);
-ruby_version_v_2_0_to_2_2!(ruby_2_1_10);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_0);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_1);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_2);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_3);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_4);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_5);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_6);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_7);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_8);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_9);
-ruby_version_v_2_0_to_2_2!(ruby_2_2_10);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_0);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_1);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_2);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_3);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_4);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_5);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_6);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_7);
-ruby_version_v_2_3_to_2_4!(ruby_2_3_8);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_0);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_1);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_2);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_3);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_4);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_5);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_6);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_7);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_8);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_9);
-ruby_version_v_2_3_to_2_4!(ruby_2_4_10);
-ruby_version_v2_5_x!(ruby_2_5_0);
-ruby_version_v2_5_x!(ruby_2_5_1);
-ruby_version_v2_5_x!(ruby_2_5_2);
-ruby_version_v2_5_x!(ruby_2_5_3);
-ruby_version_v2_5_x!(ruby_2_5_4);
-ruby_version_v2_5_x!(ruby_2_5_5);
-ruby_version_v2_5_x!(ruby_2_5_6);
-ruby_version_v2_5_x!(ruby_2_5_7);
-ruby_version_v2_5_x!(ruby_2_5_8);
+macro_rules! get_vm_stats_unsupported(
+    () => (
+        pub fn get_vm_stats<T: ProcessMemory>(_ruby_vm_address_ptr: usize, _source: &T) -> Result<crate::core::types::VmStats> {
+            return Err(format_err!("VM statistics are not supported for this version of Ruby").into());
+        }
+    )
+);
+
+macro_rules! get_vm_stats(
+    () => (
+        // The bindings treat rb_objspace as opaque because it's private to gc.c, but its first
+        // few fields have kept the same layout since 2.7, so only those are read here.
+        #[repr(C)]
+        #[derive(Copy, Clone)]
+        struct rb_objspace_head {
+            malloc_limit: usize,
+            malloc_increase: usize,
+            flags: u32,
+            hook_events: rb_event_flag_t,
+            total_allocated_objects: usize,
+        }
+
+        pub fn get_vm_stats<T: ProcessMemory>(ruby_vm_address_ptr: usize, source: &T) -> Result<crate::core::types::VmStats> {
+            let vm_addr: usize = source.copy_struct(ruby_vm_address_ptr)
+                .context("couldn't read Ruby VM pointer")?;
+            let vm: rb_vm_struct = source.copy_struct(vm_addr)
+                .context("couldn't read Ruby VM struct")?;
+            let objspace: rb_objspace_head = source.copy_struct(vm.objspace as usize)
+                .context("couldn't read Ruby object space")?;
+            Ok(crate::core::types::VmStats {
+                total_allocated_objects: objspace.total_allocated_objects as u64,
+                // `flags` is a bitfield that starts with `mode:2`, `immediate_sweep:1`,
+                // `dont_gc:1` and `dont_incremental:1`, followed by `during_gc:1`
+                during_gc: objspace.flags & (1 << 5) != 0,
+            })
+        }
+    )
+);
+
+macro_rules! check_gvl_owner(
+    () => (
+        /// Checks that the GVL's owner is one of the VM's threads, so that a GVL that was looked
+        /// for in the wrong place isn't trusted
+        fn check_gvl_owner<T: ProcessMemory>(owner: usize, vm_addr: usize, source: &T) -> Result<Option<usize>> {
+            if owner == 0 {
+                return Ok(None);
+            }
+            let thread: rb_thread_struct = source.copy_struct(owner)
+                .context("couldn't read the GVL owner's thread struct")?;
+            if thread.vm as usize != vm_addr {
+                return Err(format_err!("GVL owner {:#x} isn't one of the VM's threads", owner));
+            }
+            Ok(Some(owner))
+        }
+    )
+);
+
+macro_rules! get_gvl_owner_2_6_0(
+    () => (
+        /// The address of the thread that holds the GVL, or `None` if no thread does
+        pub fn get_gvl_owner<T: ProcessMemory>(ruby_vm_address_ptr: usize, source: &T) -> Result<Option<usize>> {
+            let vm_addr: usize = source.copy_struct(ruby_vm_address_ptr)
+                .context("couldn't read Ruby VM pointer")?;
+            let vm: rb_vm_struct = source.copy_struct(vm_addr)
+                .context("couldn't read Ruby VM struct")?;
+            check_gvl_owner(vm.gvl.owner as usize, vm_addr, source)
+        }
+
+        check_gvl_owner!();
+    )
+);
+
+macro_rules! get_gvl_owner_3_0_0(
+    () => (
+        // The GVL moved into the ractor, which the bindings treat as opaque, and from 3.2 it's
+        // called the thread scheduler (`rb_thread_sched`). Its fields are the same from 3.0 to
+        // 3.2, and it comes right before the ractor's `running_ec`, so it's found from there.
+        // The pthread types are sized as on Linux with glibc; elsewhere, the owner read from
+        // the wrong place is turned down by `check_gvl_owner`.
+        #[repr(C)]
+        #[derive(Copy, Clone)]
+        struct rb_global_vm_lock_head {
+            owner: usize,
+            lock: rb_nativethread_lock_t,
+            waitq: [usize; 2],
+            timer: usize,
+            timer_err: std::os::raw::c_int,
+            switch_cond: rb_nativethread_cond_t,
+            switch_wait_cond: rb_nativethread_cond_t,
+            need_yield: std::os::raw::c_int,
+            wait_yield: std::os::raw::c_int,
+        }
+
+        /// The address of the thread that holds the GVL, or `None` if no thread does
+        pub fn get_gvl_owner<T: ProcessMemory>(ruby_vm_address_ptr: usize, source: &T) -> Result<Option<usize>> {
+            let vm_addr: usize = source.copy_struct(ruby_vm_address_ptr)
+                .context("couldn't read Ruby VM pointer")?;
+            let vm: rb_vm_struct = source.copy_struct(vm_addr)
+                .context("couldn't read Ruby VM struct")?;
+            let (running_ec_field, _) = find_running_ec(&vm, source)?;
+            let gvl: rb_global_vm_lock_head = source
+                .copy_struct(running_ec_field - std::mem::size_of::<rb_global_vm_lock_head>())
+                .context("couldn't read the GVL")?;
+            check_gvl_owner(gvl.owner, vm_addr, source)
+        }
+
+        check_gvl_owner!();
+    )
+);
+
+macro_rules! get_class_name_unsupported(
+    () => (
+        fn get_class_name<T: crate::core::process::SampleMemory>(_cfp: &rb_control_frame_t, _global_symbols_address: usize, _source: &T) -> Result<(String, bool)> {
+            return Err(format_err!("Class name resolution is not supported for this version of Ruby").into());
+        }
+    )
+);
+
+// Needs `get_symbol_name` from `get_cfunc_name!`
+macro_rules! get_class_name(
+    () => (
+        // Finds the method entry that a control frame is executing, following block frames out
+        // to the method they were defined in. This is a port of rb_vm_frame_method_entry in
+        // vm_insnhelper.c.
+        fn get_method_entry_address<T: ProcessMemory>(
+            cfp: &rb_control_frame_t,
+            source: &T
+        ) -> Result<usize> {
+            let value_size = std::mem::size_of::<VALUE>();
+            let mut ep = cfp.ep as usize;
+            // Blocks can be nested, but not infinitely
+            for _ in 0..64 {
+                // ep[VM_ENV_DATA_INDEX_FLAGS]
+                let env_flags: usize = source.copy_struct(ep).context(ep)?;
+                // ep[VM_ENV_DATA_INDEX_ME_CREF]
+                let me_cref: usize = source.copy_struct(ep - 2 * value_size).context(ep - 2 * value_size)?;
+                if let Some(me) = method_entry_from_imemo(me_cref, source)? {
+                    return Ok(me);
+                }
+                // #define VM_ENV_FLAG_LOCAL 0x0002
+                if env_flags & 0x02 != 0 {
+                    break;
+                }
+                // VM_ENV_PREV_EP: ep[VM_ENV_DATA_INDEX_SPECVAL] with the guard bits cleared
+                let specval: usize = source.copy_struct(ep - value_size).context(ep - value_size)?;
+                ep = specval & !0x03;
+                if ep == 0 {
+                    break;
+                }
+            }
+            Err(format_err!("No method entry for frame").into())
+        }
+
+        fn method_entry_from_imemo<T: ProcessMemory>(
+            raw_imemo: usize,
+            source: &T
+        ) -> Result<Option<usize>> {
+            // Special constants (nil, false, etc) aren't heap objects
+            if raw_imemo == 0 || raw_imemo & 0x07 != 0 {
+                return Ok(None);
+            }
+            let basic: RBasic = source.copy_struct(raw_imemo).context(raw_imemo)?;
+            // #define RUBY_T_IMEMO 0x1a
+            if basic.flags as usize & 0x1f != 0x1a {
+                return Ok(None);
+            }
+            // These type constants are defined in ruby's internal/imemo.h
+            #[allow(non_upper_case_globals)]
+            match ((basic.flags >> 12) & 0x0f) as u32 {
+                imemo_type_imemo_ment => Ok(Some(raw_imemo)),
+                imemo_type_imemo_svar => {
+                    let svar: vm_svar = source.copy_struct(raw_imemo).context(raw_imemo)?;
+                    method_entry_from_imemo(svar.cref_or_me as usize, source)
+                },
+                _ => Ok(None),
+            }
+        }
+
+        // The first fields of struct RClass and rb_classext_t, which are declared in
+        // internal/class.h and so aren't accessible by bindgen. The layout is the same from 2.5
+        // through 3.1.
+        #[repr(C)]
+        #[derive(Debug, Copy, Clone)]
+        struct RClass {
+            basic: RBasic,
+            super_: VALUE,
+            ptr: usize,
+            m_tbl: usize,
+        }
+
+        #[repr(C)]
+        #[derive(Debug, Copy, Clone)]
+        struct rb_classext_head {
+            iv_index_tbl: *mut st_table,
+            iv_tbl: *mut st_table,
+        }
+
+        // st_table_entry is opaque in the bindings; this is its definition from st.c
+        #[repr(C)]
+        #[derive(Debug, Copy, Clone)]
+        struct st_entry {
+            hash: st_index_t,
+            key: st_data_t,
+            record: st_data_t,
+        }
+
+        // Looks up an instance variable on a class or module by name. Classes keep a handful of
+        // hidden instance variables, including their name (`__classpath__`) and, for singleton
+        // classes, the object that they're attached to (`__attached__`).
+        fn get_class_ivar<T: ProcessMemory>(
+            klass: usize,
+            name: &str,
+            global_symbols_address: usize,
+            source: &T
+        ) -> Result<VALUE> {
+            let rclass: RClass = source.copy_struct(klass).context(klass)?;
+            let ext: rb_classext_head = source.copy_struct(rclass.ptr).context(rclass.ptr)?;
+            if ext.iv_tbl.is_null() {
+                return Err(format_err!("class has no instance variables").into());
+            }
+            let table: st_table = source.copy_struct(ext.iv_tbl as usize).context(ext.iv_tbl as usize)?;
+            let num_entries = (table.entries_bound - table.entries_start) as usize;
+            if num_entries > 1024 {
+                return Err(format_err!("invalid instance variable table size: {}", num_entries).into());
+            }
+            let entries: Vec<st_entry> = source.copy_vec(
+                table.entries as usize + table.entries_start as usize * std::mem::size_of::<st_entry>(),
+                num_entries
+            ).context(table.entries as usize)?;
+            for entry in entries {
+                // Deleted entries are marked with a reserved hash value
+                if entry.hash == !0 {
+                    continue;
+                }
+                if let Ok(key) = get_symbol_name(entry.key as usize, global_symbols_address, source) {
+                    if key == name {
+                        return Ok(entry.record as VALUE);
+                    }
+                }
+            }
+            Err(format_err!("instance variable {} not found", name).into())
+        }
+
+        fn get_class_path<T: ProcessMemory>(
+            klass: usize,
+            global_symbols_address: usize,
+            source: &T
+        ) -> Result<String> {
+            if let Ok(path) = get_class_ivar(klass, "__classpath__", global_symbols_address, source) {
+                return get_ruby_string(path as usize, source);
+            }
+            // Classes created during VM boot (Object, Kernel, etc) are named with a static symbol
+            let id = get_class_ivar(klass, "__classid__", global_symbols_address, source)?;
+            // #define RUBY_SYMBOL_FLAG 0x0c
+            if id as usize & 0xff != 0x0c {
+                return Err(format_err!("class ID is not a static symbol").into());
+            }
+            get_symbol_name(id as usize >> 8, global_symbols_address, source)
+        }
+
+        // Returns the name of the class or module that owns the method executing in a control
+        // frame, and whether the method is a singleton method (e.g. `def self.find`). Names are
+        // kept by the owner's address, since finding one scans instance variable tables.
+        fn get_class_name<T: crate::core::process::SampleMemory>(
+            cfp: &rb_control_frame_t,
+            global_symbols_address: usize,
+            source: &T
+        ) -> Result<(String, bool)> {
+            let me_address = get_method_entry_address(cfp, source)?;
+            let me: rb_method_entry_struct = source.copy_struct(me_address).context(me_address)?;
+            let owner = me.owner as usize;
+            if let Some(name) = source.class_name(owner) {
+                return Ok(name);
+            }
+            let name = get_owner_name(owner, global_symbols_address, source)?;
+            source.remember_class_name(owner, &name);
+            Ok(name)
+        }
+
+        fn get_owner_name<T: ProcessMemory>(
+            owner: usize,
+            global_symbols_address: usize,
+            source: &T
+        ) -> Result<(String, bool)> {
+            let basic: RBasic = source.copy_struct(owner).context(owner)?;
+            if basic.flags as usize & ruby_fl_type_RUBY_FL_SINGLETON as usize == 0 {
+                return Ok((get_class_path(owner, global_symbols_address, source)?, false));
+            }
+
+            let attached = get_class_ivar(owner, "__attached__", global_symbols_address, source)? as usize;
+            let attached_basic: RBasic = source.copy_struct(attached).context(attached)?;
+            // #define RUBY_T_CLASS 0x02, RUBY_T_MODULE 0x03
+            let attached_class = match attached_basic.flags as usize & 0x1f {
+                0x02 | 0x03 => attached,
+                // A singleton method on a regular object; fall back to the object's class
+                _ => attached_basic.klass as usize,
+            };
+            Ok((get_class_path(attached_class, global_symbols_address, source)?, true))
+        }
+    )
+);
+
+/// The most threads to look for in a process. Unless the process is locked, Ruby can change the
+/// thread list while rbspy reads it, so the list might not lead back to where rbspy started.
+const MAX_THREADS: usize = 10_000;
+
+/// Follows a VM's circular list of living threads around from the thread at `start`. The list node
+/// is the first field of Ruby's thread struct, so a thread's address is its node's address, and
+/// the node's first field is the address of the next one. `thread` checks whether a node belongs
+/// to a thread (rather than being the list's head) and returns the address to read its stack from.
+pub(crate) fn walk_thread_list<T, F>(
+    start: usize,
+    source: &T,
+    mut thread: F,
+) -> anyhow::Result<Vec<usize>>
+where
+    T: crate::core::process::ProcessMemory,
+    F: FnMut(usize) -> Option<usize>,
+{
+    use anyhow::Context;
+
+    let mut threads = Vec::new();
+    let mut node = start;
+    for _ in 0..MAX_THREADS {
+        threads.extend(thread(node));
+        node = source
+            .copy_struct::<usize>(node)
+            .context("couldn't read thread list")?;
+        if node == start || node == 0 {
+            return Ok(threads);
+        }
+    }
+    Err(anyhow::format_err!(
+        "the thread list doesn't lead back to the current thread"
+    ))
+}
+
+/// Prefixes the method name in a frame label with the class or module that owns the method, the
+/// way Ruby 3.4 does in backtraces: `full_name` becomes `User#full_name`, a singleton method
+/// `find` becomes `User.find`, and `block (2 levels) in each` becomes
+/// `block (2 levels) in User#each`.
+pub(crate) fn qualify_label(label: &str, class_name: &str, singleton: bool) -> String {
+    let separator = if singleton { "." } else { "#" };
+    match label.rsplit_once(" in ") {
+        Some((prefix, method)) => format!("{} in {}{}{}", prefix, class_name, separator, method),
+        None => format!("{}{}{}", class_name, separator, label),
+    }
+}
+
+/// Gives C function frames the location of the Ruby frame that called them, the way Ruby's own
+/// backtraces do, so that time spent in e.g. `Kernel#sleep` is attributed to the line that called
+/// it. Traces are leaf-first, so a frame's caller comes after it.
+pub(crate) fn locate_cfunc_frames(trace: &mut [crate::core::types::StackFrame]) {
+    use crate::core::types::FrameKind;
+
+    let mut caller: Option<(String, Option<String>, Option<usize>)> = None;
+    for frame in trace.iter_mut().rev() {
+        if frame.kind != FrameKind::CFunction {
+            caller = Some((
+                frame.relative_path.clone(),
+                frame.absolute_path.clone(),
+                frame.lineno,
+            ));
+        } else if let Some((relative_path, absolute_path, lineno)) = &caller {
+            frame.relative_path = relative_path.clone();
+            frame.absolute_path = absolute_path.clone();
+            frame.lineno = *lineno;
+        }
+    }
+}
+
+/// Which entry of an iseq's line table covers the instruction at `pos`, given the positions that
+/// the table's entries start at: the last one that starts at or before it
+pub(crate) fn insn_info_index(positions: &[usize], pos: usize) -> usize {
+    match positions.binary_search(&pos) {
+        Ok(index) => index,
+        Err(index) => index.saturating_sub(1),
+    }
+}
+
+/// How many positions Ruby's succinct bit vector keeps the ranks of directly, 7 bits each
+const SUCC_IMMEDIATE_TABLE_SIZE: usize = 54;
+
+/// One of the succinct bit vector's blocks of 512 positions (`struct succ_dict_block`)
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct SuccDictBlock {
+    /// How many positions are set before the block
+    rank: u32,
+    /// How many positions are set before each of the block's 64-bit words but the first, 9 bits
+    /// each
+    small_block_ranks: u64,
+    bits: [u64; 8],
+}
+
+impl SuccDictBlock {
+    /// How many positions are set up to and including the block's `bit`th one
+    fn rank(&self, bit: usize) -> usize {
+        let word = bit / 64;
+        let small_block_rank = match word {
+            0 => 0,
+            _ => (self.small_block_ranks >> ((word - 1) * 9)) & 0x1ff,
+        };
+        let popcount = (self.bits[word] << (63 - bit % 64)).count_ones();
+        self.rank as usize + small_block_rank as usize + popcount as usize
+    }
+}
+
+/// How many of the positions that an iseq's line table entries start at are at or before `pos`,
+/// according to the succinct bit vector (`struct succ_index_table`) at `table_addr`. This is
+/// Ruby's `succ_index_lookup`, which 2.6+ uses to find an instruction's line.
+pub(crate) fn succ_index_lookup<T>(
+    table_addr: usize,
+    pos: usize,
+    source: &T,
+) -> anyhow::Result<usize>
+where
+    T: crate::core::process::ProcessMemory,
+{
+    use anyhow::Context;
+
+    type ImmediatePart = [u64; SUCC_IMMEDIATE_TABLE_SIZE / 9];
+    if pos < SUCC_IMMEDIATE_TABLE_SIZE {
+        let immediate: ImmediatePart = source
+            .copy_struct(table_addr)
+            .context("couldn't copy succinct bit vector")?;
+        Ok(((immediate[pos / 9] >> (pos % 9 * 7)) & 0x7f) as usize)
+    } else {
+        let bit = pos - SUCC_IMMEDIATE_TABLE_SIZE;
+        let block_addr = table_addr
+            + std::mem::size_of::<ImmediatePart>()
+            + bit / 512 * std::mem::size_of::<SuccDictBlock>();
+        let block: SuccDictBlock = source
+            .copy_struct(block_addr)
+            .context("couldn't copy succinct bit vector")?;
+        Ok(block.rank(bit % 512))
+    }
+}
+
+ruby_version_v_1_9_1!(ruby_1_9_1_0);
+ruby_version_v_1_9_2_to_3!(ruby_1_9_2_0);
+ruby_version_v_1_9_2_to_3!(ruby_1_9_3_0);
+ruby_version_v_2_0_to_2_1!(ruby_2_0_0_0);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_0);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_1);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_2);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_3);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_4);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_5);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_6);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_7);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_8);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_9);
+ruby_version_v_2_0_to_2_1!(ruby_2_1_10);
+ruby_version_v_2_2_x!(ruby_2_2_0);
+ruby_version_v_2_2_x!(ruby_2_2_1);
+ruby_version_v_2_2_x!(ruby_2_2_2);
+ruby_version_v_2_2_x!(ruby_2_2_3);
+ruby_version_v_2_2_x!(ruby_2_2_4);
+ruby_version_v_2_2_x!(ruby_2_2_5);
+ruby_version_v_2_2_x!(ruby_2_2_6);
+ruby_version_v_2_2_x!(ruby_2_2_7);
+ruby_version_v_2_2_x!(ruby_2_2_8);
+ruby_version_v_2_2_x!(ruby_2_2_9);
+ruby_version_v_2_2_x!(ruby_2_2_10);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_0);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_1);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_2);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_3);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_4);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_5);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_6);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_7);
+ruby_version_v_2_3_to_2_4!(ruby_2_3_8);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_0);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_1);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_2);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_3);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_4);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_5);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_6);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_7);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_8);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_9);
+ruby_version_v_2_3_to_2_4!(ruby_2_4_10);
+ruby_version_v2_5_x!(ruby_2_5_0);
+ruby_version_v2_5_x!(ruby_2_5_1);
+ruby_version_v2_5_x!(ruby_2_5_2);
+ruby_version_v2_5_x!(ruby_2_5_3);
+ruby_version_v2_5_x!(ruby_2_5_4);
+ruby_version_v2_5_x!(ruby_2_5_5);
+ruby_version_v2_5_x!(ruby_2_5_6);
+ruby_version_v2_5_x!(ruby_2_5_7);
+ruby_version_v2_5_x!(ruby_2_5_8);
 ruby_version_v2_5_x!(ruby_2_5_9);
 ruby_version_v2_6_x!(ruby_2_6_0);
 ruby_version_v2_6_x!(ruby_2_6_1);
@@ -1267,7 +2109,9 @@ ruby_version_v3_2_x!(ruby_3_2_0);
 ruby_version_v3_2_x!(ruby_3_2_1);
 ruby_version_v3_2_x!(ruby_3_2_2);
 
-pub fn get_execution_context(version: &Version) -> crate::core::types::GetExecutionContextFn {
+pub fn get_execution_context(
+    version: &Version,
+) -> anyhow::Result<crate::core::types::GetExecutionContextFn> {
     let function = match version {
         Version {
             major: 1,
@@ -1815,16 +2659,15 @@ pub fn get_execution_context(version: &Version) -> crate::core::types::GetExecut
             patch: 2,
             ..
         } => ruby_3_2_2::get_execution_context,
-        _ => panic!(
-            "Ruby version not supported yet: {}. In the meantime, we suggest trying `--force-version <prior version>`.",
-            version
-        ),
+        _ => return Err(unsupported_version(version)),
     };
     // function(thread_address, vm_address, &source)
-    Box::new(function)
+    Ok(Box::new(function))
 }
 
-pub fn is_maybe_thread_function(version: &Version) -> crate::core::types::IsMaybeThreadFn {
+pub fn is_maybe_thread_function(
+    version: &Version,
+) -> anyhow::Result<crate::core::types::IsMaybeThreadFn> {
     let function = match version {
         Version {
             major: 1,
@@ -2113,827 +2956,2049 @@ pub fn is_maybe_thread_function(version: &Version) -> crate::core::types::IsMayb
             minor: 5,
             patch: 1,
             ..
-        } => ruby_2_5_1::is_maybe_thread,
+        } => ruby_2_5_1::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 2,
+            ..
+        } => ruby_2_5_2::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 3,
+            ..
+        } => ruby_2_5_3::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 4,
+            ..
+        } => ruby_2_5_4::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 5,
+            ..
+        } => ruby_2_5_5::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 6,
+            ..
+        } => ruby_2_5_6::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 7,
+            ..
+        } => ruby_2_5_7::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 8,
+            ..
+        } => ruby_2_5_8::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 9,
+            ..
+        } => ruby_2_5_9::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 0,
+            ..
+        } => ruby_2_6_0::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 1,
+            ..
+        } => ruby_2_6_1::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 2,
+            ..
+        } => ruby_2_6_2::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 3,
+            ..
+        } => ruby_2_6_3::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 4,
+            ..
+        } => ruby_2_6_4::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 5,
+            ..
+        } => ruby_2_6_5::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 6,
+            ..
+        } => ruby_2_6_6::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 7,
+            ..
+        } => ruby_2_6_7::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 8,
+            ..
+        } => ruby_2_6_8::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 9,
+            ..
+        } => ruby_2_6_9::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 10,
+            ..
+        } => ruby_2_6_10::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 0,
+            ..
+        } => ruby_2_7_0::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 1,
+            ..
+        } => ruby_2_7_1::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 2,
+            ..
+        } => ruby_2_7_2::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 3,
+            ..
+        } => ruby_2_7_3::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 4,
+            ..
+        } => ruby_2_7_4::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 5,
+            ..
+        } => ruby_2_7_5::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 6,
+            ..
+        } => ruby_2_7_6::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 7,
+            ..
+        } => ruby_2_7_7::is_maybe_thread,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 8,
+            ..
+        } => ruby_2_7_8::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 0,
+            ..
+        } => ruby_3_0_0::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 1,
+            ..
+        } => ruby_3_0_1::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 2,
+            ..
+        } => ruby_3_0_2::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 3,
+            ..
+        } => ruby_3_0_3::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 4,
+            ..
+        } => ruby_3_0_4::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 5,
+            ..
+        } => ruby_3_0_5::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 6,
+            ..
+        } => ruby_3_0_6::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 0,
+            ..
+        } => ruby_3_1_0::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 1,
+            ..
+        } => ruby_3_1_1::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 2,
+            ..
+        } => ruby_3_1_2::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 3,
+            ..
+        } => ruby_3_1_3::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 4,
+            ..
+        } => ruby_3_1_4::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 2,
+            patch: 0,
+            ..
+        } => ruby_3_2_0::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 2,
+            patch: 1,
+            ..
+        } => ruby_3_2_1::is_maybe_thread,
+        Version {
+            major: 3,
+            minor: 2,
+            patch: 2,
+            ..
+        } => ruby_3_2_2::is_maybe_thread,
+        _ => return Err(unsupported_version(version)),
+    };
+    Ok(Box::new(function))
+}
+
+pub fn get_stack_trace_function(
+    version: &Version,
+) -> anyhow::Result<crate::core::types::StackTraceFn> {
+    stack_trace_function(version).ok_or_else(|| unsupported_version(version))
+}
+
+/// Whether rbspy knows how to read stack traces from this version of Ruby
+pub fn is_supported_version(version: &Version) -> bool {
+    stack_trace_function(version).is_some()
+}
+
+/// How far from an unknown patch release `compatible_version` looks for a supported one
+const MAX_PATCH_DISTANCE: u64 = 20;
+
+/// The version of Ruby to read a process running `version` as: `version` itself if it's
+/// supported, or else the nearest supported patch release of the same minor version, preferring
+/// earlier ones. Patch releases don't change the structs that rbspy reads, so e.g. a new 3.1.5 can
+/// be read as 3.1.4 until rbspy knows about it.
+pub(crate) fn compatible_version(version: &Version) -> Option<Version> {
+    if is_supported_version(version) {
+        return Some(version.clone());
+    }
+    (0..version.patch)
+        .rev()
+        .chain(version.patch + 1..=version.patch + MAX_PATCH_DISTANCE)
+        .map(|patch| Version::new(version.major, version.minor, patch))
+        .find(is_supported_version)
+}
+
+pub(crate) fn unsupported_version(version: &Version) -> anyhow::Error {
+    crate::core::errors::ErrorCode::UnsupportedVersion
+        .error(format!(
+            "Ruby version not supported yet: {}. In the meantime, we suggest setting `force_version` to the closest supported version.",
+            version
+        ))
+        .into()
+}
+
+fn stack_trace_function(version: &Version) -> Option<crate::core::types::StackTraceFn> {
+    stack_trace_function_for::<crate::core::memory::MemoryReader>(version)
+        .map(|stack_trace_function| Box::new(stack_trace_function) as crate::core::types::StackTraceFn)
+}
+
+/// Reads a stack trace out of any source of Ruby process memory, e.g. a fixture
+pub(crate) type StackTraceFnFor<T> = fn(
+    usize,
+    usize,
+    Option<usize>,
+    &T,
+    crate::core::process::Pid,
+    bool,
+) -> anyhow::Result<Option<crate::core::types::StackTrace>>;
+
+/// Like `stack_trace_function`, but for any source of memory rather than just live processes
+pub(crate) fn stack_trace_function_for<T: crate::core::process::SampleMemory>(
+    version: &Version,
+) -> Option<StackTraceFnFor<T>> {
+    let stack_trace_function: StackTraceFnFor<T> = match version {
+        Version {
+            major: 1,
+            minor: 9,
+            patch: 1,
+            ..
+        } => ruby_1_9_1_0::get_stack_trace,
+        Version {
+            major: 1,
+            minor: 9,
+            patch: 2,
+            ..
+        } => ruby_1_9_2_0::get_stack_trace,
+        Version {
+            major: 1,
+            minor: 9,
+            patch: 3,
+            ..
+        } => ruby_1_9_3_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 0,
+            patch: 0,
+            ..
+        } => ruby_2_0_0_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 0,
+            ..
+        } => ruby_2_1_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 1,
+            ..
+        } => ruby_2_1_1::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 2,
+            ..
+        } => ruby_2_1_2::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 3,
+            ..
+        } => ruby_2_1_3::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 4,
+            ..
+        } => ruby_2_1_4::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 5,
+            ..
+        } => ruby_2_1_5::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 6,
+            ..
+        } => ruby_2_1_6::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 7,
+            ..
+        } => ruby_2_1_7::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 8,
+            ..
+        } => ruby_2_1_8::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 9,
+            ..
+        } => ruby_2_1_9::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 10,
+            ..
+        } => ruby_2_1_10::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 0,
+            ..
+        } => ruby_2_2_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 1,
+            ..
+        } => ruby_2_2_1::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 2,
+            ..
+        } => ruby_2_2_2::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 3,
+            ..
+        } => ruby_2_2_3::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 4,
+            ..
+        } => ruby_2_2_4::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 5,
+            ..
+        } => ruby_2_2_5::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 6,
+            ..
+        } => ruby_2_2_6::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 7,
+            ..
+        } => ruby_2_2_7::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 8,
+            ..
+        } => ruby_2_2_8::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 9,
+            ..
+        } => ruby_2_2_9::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 10,
+            ..
+        } => ruby_2_2_10::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 0,
+            ..
+        } => ruby_2_3_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 1,
+            ..
+        } => ruby_2_3_1::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 2,
+            ..
+        } => ruby_2_3_2::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 3,
+            ..
+        } => ruby_2_3_3::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 4,
+            ..
+        } => ruby_2_3_4::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 5,
+            ..
+        } => ruby_2_3_5::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 6,
+            ..
+        } => ruby_2_3_6::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 7,
+            ..
+        } => ruby_2_3_7::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 8,
+            ..
+        } => ruby_2_3_8::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 0,
+            ..
+        } => ruby_2_4_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 1,
+            ..
+        } => ruby_2_4_1::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 2,
+            ..
+        } => ruby_2_4_2::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 3,
+            ..
+        } => ruby_2_4_3::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 4,
+            ..
+        } => ruby_2_4_4::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 5,
+            ..
+        } => ruby_2_4_5::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 6,
+            ..
+        } => ruby_2_4_6::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 7,
+            ..
+        } => ruby_2_4_7::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 8,
+            ..
+        } => ruby_2_4_8::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 9,
+            ..
+        } => ruby_2_4_9::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 10,
+            ..
+        } => ruby_2_4_10::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 0,
+            ..
+        } => ruby_2_5_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 1,
+            ..
+        } => ruby_2_5_1::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 2,
+            ..
+        } => ruby_2_5_2::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 3,
+            ..
+        } => ruby_2_5_3::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 4,
+            ..
+        } => ruby_2_5_4::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 5,
+            ..
+        } => ruby_2_5_5::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 6,
+            ..
+        } => ruby_2_5_6::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 7,
+            ..
+        } => ruby_2_5_7::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 8,
+            ..
+        } => ruby_2_5_8::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 9,
+            ..
+        } => ruby_2_5_9::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 0,
+            ..
+        } => ruby_2_6_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 1,
+            ..
+        } => ruby_2_6_1::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 2,
+            ..
+        } => ruby_2_6_2::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 3,
+            ..
+        } => ruby_2_6_3::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 4,
+            ..
+        } => ruby_2_6_4::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 5,
+            ..
+        } => ruby_2_6_5::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 6,
+            ..
+        } => ruby_2_6_6::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 7,
+            ..
+        } => ruby_2_6_7::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 8,
+            ..
+        } => ruby_2_6_8::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 9,
+            ..
+        } => ruby_2_6_9::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 6,
+            patch: 10,
+            ..
+        } => ruby_2_6_10::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 0,
+            ..
+        } => ruby_2_7_0::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 1,
+            ..
+        } => ruby_2_7_1::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 2,
+            ..
+        } => ruby_2_7_2::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 3,
+            ..
+        } => ruby_2_7_3::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 4,
+            ..
+        } => ruby_2_7_4::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 5,
+            ..
+        } => ruby_2_7_5::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 6,
+            ..
+        } => ruby_2_7_6::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 7,
+            ..
+        } => ruby_2_7_7::get_stack_trace,
+        Version {
+            major: 2,
+            minor: 7,
+            patch: 8,
+            ..
+        } => ruby_2_7_8::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 0,
+            ..
+        } => ruby_3_0_0::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 1,
+            ..
+        } => ruby_3_0_1::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 2,
+            ..
+        } => ruby_3_0_2::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 3,
+            ..
+        } => ruby_3_0_3::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 4,
+            ..
+        } => ruby_3_0_4::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 5,
+            ..
+        } => ruby_3_0_5::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 0,
+            patch: 6,
+            ..
+        } => ruby_3_0_6::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 0,
+            ..
+        } => ruby_3_1_0::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 1,
+            ..
+        } => ruby_3_1_1::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 2,
+            ..
+        } => ruby_3_1_2::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 3,
+            ..
+        } => ruby_3_1_3::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 1,
+            patch: 4,
+            ..
+        } => ruby_3_1_4::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 2,
+            patch: 0,
+            ..
+        } => ruby_3_2_0::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 2,
+            patch: 1,
+            ..
+        } => ruby_3_2_1::get_stack_trace,
+        Version {
+            major: 3,
+            minor: 2,
+            patch: 2,
+            ..
+        } => ruby_3_2_2::get_stack_trace,
+        _ => return None,
+    };
+    Some(stack_trace_function)
+}
+
+/// Like `StackTraceFnFor`, but reads the stack traces of all of the process's threads
+type AllStackTracesFnFor<T> = fn(
+    usize,
+    usize,
+    Option<usize>,
+    &T,
+    crate::core::process::Pid,
+    bool,
+) -> anyhow::Result<Vec<crate::core::types::StackTrace>>;
+
+/// Reads the stack traces of all of a Ruby process's threads. Before Ruby 2.2, rbspy can only find
+/// the current thread.
+pub fn get_all_stack_traces_function(
+    version: &Version,
+) -> anyhow::Result<crate::core::types::AllStackTracesFn> {
+    let function: AllStackTracesFnFor<crate::core::memory::MemoryReader> = match version {
+        Version {
+            major: 1,
+            minor: 9,
+            patch: 1,
+            ..
+        } => ruby_1_9_1_0::get_all_stack_traces,
+        Version {
+            major: 1,
+            minor: 9,
+            patch: 2,
+            ..
+        } => ruby_1_9_2_0::get_all_stack_traces,
+        Version {
+            major: 1,
+            minor: 9,
+            patch: 3,
+            ..
+        } => ruby_1_9_3_0::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 0,
+            patch: 0,
+            ..
+        } => ruby_2_0_0_0::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 0,
+            ..
+        } => ruby_2_1_0::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 1,
+            ..
+        } => ruby_2_1_1::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 2,
+            ..
+        } => ruby_2_1_2::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 3,
+            ..
+        } => ruby_2_1_3::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 4,
+            ..
+        } => ruby_2_1_4::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 5,
+            ..
+        } => ruby_2_1_5::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 6,
+            ..
+        } => ruby_2_1_6::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 7,
+            ..
+        } => ruby_2_1_7::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 8,
+            ..
+        } => ruby_2_1_8::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 9,
+            ..
+        } => ruby_2_1_9::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 1,
+            patch: 10,
+            ..
+        } => ruby_2_1_10::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 0,
+            ..
+        } => ruby_2_2_0::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 1,
+            ..
+        } => ruby_2_2_1::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 2,
+            ..
+        } => ruby_2_2_2::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 3,
+            ..
+        } => ruby_2_2_3::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 4,
+            ..
+        } => ruby_2_2_4::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 5,
+            ..
+        } => ruby_2_2_5::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 6,
+            ..
+        } => ruby_2_2_6::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 7,
+            ..
+        } => ruby_2_2_7::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 8,
+            ..
+        } => ruby_2_2_8::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 9,
+            ..
+        } => ruby_2_2_9::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 2,
+            patch: 10,
+            ..
+        } => ruby_2_2_10::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 0,
+            ..
+        } => ruby_2_3_0::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 1,
+            ..
+        } => ruby_2_3_1::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 2,
+            ..
+        } => ruby_2_3_2::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 3,
+            ..
+        } => ruby_2_3_3::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 4,
+            ..
+        } => ruby_2_3_4::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 5,
+            ..
+        } => ruby_2_3_5::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 6,
+            ..
+        } => ruby_2_3_6::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 7,
+            ..
+        } => ruby_2_3_7::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 3,
+            patch: 8,
+            ..
+        } => ruby_2_3_8::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 0,
+            ..
+        } => ruby_2_4_0::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 1,
+            ..
+        } => ruby_2_4_1::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 2,
+            ..
+        } => ruby_2_4_2::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 3,
+            ..
+        } => ruby_2_4_3::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 4,
+            ..
+        } => ruby_2_4_4::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 5,
+            ..
+        } => ruby_2_4_5::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 6,
+            ..
+        } => ruby_2_4_6::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 7,
+            ..
+        } => ruby_2_4_7::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 8,
+            ..
+        } => ruby_2_4_8::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 9,
+            ..
+        } => ruby_2_4_9::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 4,
+            patch: 10,
+            ..
+        } => ruby_2_4_10::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 0,
+            ..
+        } => ruby_2_5_0::get_all_stack_traces,
+        Version {
+            major: 2,
+            minor: 5,
+            patch: 1,
+            ..
+        } => ruby_2_5_1::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 2,
             ..
-        } => ruby_2_5_2::is_maybe_thread,
+        } => ruby_2_5_2::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 3,
             ..
-        } => ruby_2_5_3::is_maybe_thread,
+        } => ruby_2_5_3::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 4,
             ..
-        } => ruby_2_5_4::is_maybe_thread,
+        } => ruby_2_5_4::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 5,
             ..
-        } => ruby_2_5_5::is_maybe_thread,
+        } => ruby_2_5_5::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 6,
             ..
-        } => ruby_2_5_6::is_maybe_thread,
+        } => ruby_2_5_6::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 7,
             ..
-        } => ruby_2_5_7::is_maybe_thread,
+        } => ruby_2_5_7::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 8,
             ..
-        } => ruby_2_5_8::is_maybe_thread,
+        } => ruby_2_5_8::get_all_stack_traces,
         Version {
             major: 2,
             minor: 5,
             patch: 9,
             ..
-        } => ruby_2_5_9::is_maybe_thread,
+        } => ruby_2_5_9::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 0,
             ..
-        } => ruby_2_6_0::is_maybe_thread,
+        } => ruby_2_6_0::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 1,
             ..
-        } => ruby_2_6_1::is_maybe_thread,
+        } => ruby_2_6_1::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 2,
             ..
-        } => ruby_2_6_2::is_maybe_thread,
+        } => ruby_2_6_2::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 3,
             ..
-        } => ruby_2_6_3::is_maybe_thread,
+        } => ruby_2_6_3::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 4,
             ..
-        } => ruby_2_6_4::is_maybe_thread,
+        } => ruby_2_6_4::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 5,
             ..
-        } => ruby_2_6_5::is_maybe_thread,
+        } => ruby_2_6_5::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 6,
             ..
-        } => ruby_2_6_6::is_maybe_thread,
+        } => ruby_2_6_6::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 7,
             ..
-        } => ruby_2_6_7::is_maybe_thread,
+        } => ruby_2_6_7::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 8,
             ..
-        } => ruby_2_6_8::is_maybe_thread,
+        } => ruby_2_6_8::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 9,
             ..
-        } => ruby_2_6_9::is_maybe_thread,
+        } => ruby_2_6_9::get_all_stack_traces,
         Version {
             major: 2,
             minor: 6,
             patch: 10,
             ..
-        } => ruby_2_6_10::is_maybe_thread,
+        } => ruby_2_6_10::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 0,
             ..
-        } => ruby_2_7_0::is_maybe_thread,
+        } => ruby_2_7_0::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 1,
             ..
-        } => ruby_2_7_1::is_maybe_thread,
+        } => ruby_2_7_1::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 2,
             ..
-        } => ruby_2_7_2::is_maybe_thread,
+        } => ruby_2_7_2::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 3,
             ..
-        } => ruby_2_7_3::is_maybe_thread,
+        } => ruby_2_7_3::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 4,
             ..
-        } => ruby_2_7_4::is_maybe_thread,
+        } => ruby_2_7_4::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 5,
             ..
-        } => ruby_2_7_5::is_maybe_thread,
+        } => ruby_2_7_5::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 6,
             ..
-        } => ruby_2_7_6::is_maybe_thread,
+        } => ruby_2_7_6::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 7,
             ..
-        } => ruby_2_7_7::is_maybe_thread,
+        } => ruby_2_7_7::get_all_stack_traces,
         Version {
             major: 2,
             minor: 7,
             patch: 8,
             ..
-        } => ruby_2_7_8::is_maybe_thread,
+        } => ruby_2_7_8::get_all_stack_traces,
         Version {
             major: 3,
             minor: 0,
             patch: 0,
             ..
-        } => ruby_3_0_0::is_maybe_thread,
+        } => ruby_3_0_0::get_all_stack_traces,
         Version {
             major: 3,
             minor: 0,
             patch: 1,
             ..
-        } => ruby_3_0_1::is_maybe_thread,
+        } => ruby_3_0_1::get_all_stack_traces,
         Version {
             major: 3,
             minor: 0,
             patch: 2,
             ..
-        } => ruby_3_0_2::is_maybe_thread,
+        } => ruby_3_0_2::get_all_stack_traces,
         Version {
             major: 3,
             minor: 0,
             patch: 3,
             ..
-        } => ruby_3_0_3::is_maybe_thread,
+        } => ruby_3_0_3::get_all_stack_traces,
         Version {
             major: 3,
             minor: 0,
             patch: 4,
             ..
-        } => ruby_3_0_4::is_maybe_thread,
+        } => ruby_3_0_4::get_all_stack_traces,
         Version {
             major: 3,
             minor: 0,
             patch: 5,
             ..
-        } => ruby_3_0_5::is_maybe_thread,
+        } => ruby_3_0_5::get_all_stack_traces,
         Version {
             major: 3,
             minor: 0,
             patch: 6,
             ..
-        } => ruby_3_0_6::is_maybe_thread,
+        } => ruby_3_0_6::get_all_stack_traces,
         Version {
             major: 3,
             minor: 1,
             patch: 0,
             ..
-        } => ruby_3_1_0::is_maybe_thread,
+        } => ruby_3_1_0::get_all_stack_traces,
         Version {
             major: 3,
             minor: 1,
             patch: 1,
             ..
-        } => ruby_3_1_1::is_maybe_thread,
+        } => ruby_3_1_1::get_all_stack_traces,
         Version {
             major: 3,
             minor: 1,
             patch: 2,
             ..
-        } => ruby_3_1_2::is_maybe_thread,
+        } => ruby_3_1_2::get_all_stack_traces,
         Version {
             major: 3,
             minor: 1,
             patch: 3,
             ..
-        } => ruby_3_1_3::is_maybe_thread,
+        } => ruby_3_1_3::get_all_stack_traces,
         Version {
             major: 3,
             minor: 1,
             patch: 4,
             ..
-        } => ruby_3_1_4::is_maybe_thread,
+        } => ruby_3_1_4::get_all_stack_traces,
         Version {
             major: 3,
             minor: 2,
             patch: 0,
             ..
-        } => ruby_3_2_0::is_maybe_thread,
+        } => ruby_3_2_0::get_all_stack_traces,
         Version {
             major: 3,
             minor: 2,
             patch: 1,
             ..
-        } => ruby_3_2_1::is_maybe_thread,
+        } => ruby_3_2_1::get_all_stack_traces,
         Version {
             major: 3,
             minor: 2,
             patch: 2,
             ..
-        } => ruby_3_2_2::is_maybe_thread,
-        _ => panic!(
-            "Ruby version not supported yet: {}. In the meantime, we suggest trying `--force-version <prior version>`.",
-            version
-        ),
+        } => ruby_3_2_2::get_all_stack_traces,
+        _ => return Err(unsupported_version(version)),
     };
-    Box::new(function)
+    Ok(Box::new(function))
 }
 
-pub fn get_stack_trace_function(version: &Version) -> crate::core::types::StackTraceFn {
-    let stack_trace_function = match version {
+pub fn get_vm_stats_function(
+    version: &Version,
+) -> anyhow::Result<crate::core::types::VmStatsFn> {
+    let vm_stats_function = match version {
         Version {
             major: 1,
             minor: 9,
             patch: 1,
             ..
-        } => ruby_1_9_1_0::get_stack_trace,
+        } => ruby_1_9_1_0::get_vm_stats,
         Version {
             major: 1,
             minor: 9,
             patch: 2,
             ..
-        } => ruby_1_9_2_0::get_stack_trace,
+        } => ruby_1_9_2_0::get_vm_stats,
         Version {
             major: 1,
             minor: 9,
             patch: 3,
             ..
-        } => ruby_1_9_3_0::get_stack_trace,
+        } => ruby_1_9_3_0::get_vm_stats,
         Version {
             major: 2,
             minor: 0,
             patch: 0,
             ..
-        } => ruby_2_0_0_0::get_stack_trace,
+        } => ruby_2_0_0_0::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 0,
             ..
-        } => ruby_2_1_0::get_stack_trace,
+        } => ruby_2_1_0::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 1,
             ..
-        } => ruby_2_1_1::get_stack_trace,
+        } => ruby_2_1_1::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 2,
             ..
-        } => ruby_2_1_2::get_stack_trace,
+        } => ruby_2_1_2::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 3,
             ..
-        } => ruby_2_1_3::get_stack_trace,
+        } => ruby_2_1_3::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 4,
             ..
-        } => ruby_2_1_4::get_stack_trace,
+        } => ruby_2_1_4::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 5,
             ..
-        } => ruby_2_1_5::get_stack_trace,
+        } => ruby_2_1_5::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 6,
             ..
-        } => ruby_2_1_6::get_stack_trace,
+        } => ruby_2_1_6::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 7,
             ..
-        } => ruby_2_1_7::get_stack_trace,
+        } => ruby_2_1_7::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 8,
             ..
-        } => ruby_2_1_8::get_stack_trace,
+        } => ruby_2_1_8::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 9,
             ..
-        } => ruby_2_1_9::get_stack_trace,
+        } => ruby_2_1_9::get_vm_stats,
         Version {
             major: 2,
             minor: 1,
             patch: 10,
             ..
-        } => ruby_2_1_10::get_stack_trace,
+        } => ruby_2_1_10::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 0,
             ..
-        } => ruby_2_2_0::get_stack_trace,
+        } => ruby_2_2_0::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 1,
             ..
-        } => ruby_2_2_1::get_stack_trace,
+        } => ruby_2_2_1::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 2,
             ..
-        } => ruby_2_2_2::get_stack_trace,
+        } => ruby_2_2_2::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 3,
             ..
-        } => ruby_2_2_3::get_stack_trace,
+        } => ruby_2_2_3::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 4,
             ..
-        } => ruby_2_2_4::get_stack_trace,
+        } => ruby_2_2_4::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 5,
             ..
-        } => ruby_2_2_5::get_stack_trace,
+        } => ruby_2_2_5::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 6,
             ..
-        } => ruby_2_2_6::get_stack_trace,
+        } => ruby_2_2_6::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 7,
             ..
-        } => ruby_2_2_7::get_stack_trace,
+        } => ruby_2_2_7::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 8,
             ..
-        } => ruby_2_2_8::get_stack_trace,
+        } => ruby_2_2_8::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 9,
             ..
-        } => ruby_2_2_9::get_stack_trace,
+        } => ruby_2_2_9::get_vm_stats,
         Version {
             major: 2,
             minor: 2,
             patch: 10,
             ..
-        } => ruby_2_2_10::get_stack_trace,
+        } => ruby_2_2_10::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 0,
             ..
-        } => ruby_2_3_0::get_stack_trace,
+        } => ruby_2_3_0::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 1,
             ..
-        } => ruby_2_3_1::get_stack_trace,
+        } => ruby_2_3_1::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 2,
             ..
-        } => ruby_2_3_2::get_stack_trace,
+        } => ruby_2_3_2::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 3,
             ..
-        } => ruby_2_3_3::get_stack_trace,
+        } => ruby_2_3_3::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 4,
             ..
-        } => ruby_2_3_4::get_stack_trace,
+        } => ruby_2_3_4::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 5,
             ..
-        } => ruby_2_3_5::get_stack_trace,
+        } => ruby_2_3_5::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 6,
             ..
-        } => ruby_2_3_6::get_stack_trace,
+        } => ruby_2_3_6::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 7,
             ..
-        } => ruby_2_3_7::get_stack_trace,
+        } => ruby_2_3_7::get_vm_stats,
         Version {
             major: 2,
             minor: 3,
             patch: 8,
             ..
-        } => ruby_2_3_8::get_stack_trace,
+        } => ruby_2_3_8::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 0,
             ..
-        } => ruby_2_4_0::get_stack_trace,
+        } => ruby_2_4_0::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 1,
             ..
-        } => ruby_2_4_1::get_stack_trace,
+        } => ruby_2_4_1::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 2,
             ..
-        } => ruby_2_4_2::get_stack_trace,
+        } => ruby_2_4_2::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 3,
             ..
-        } => ruby_2_4_3::get_stack_trace,
+        } => ruby_2_4_3::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 4,
             ..
-        } => ruby_2_4_4::get_stack_trace,
+        } => ruby_2_4_4::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 5,
             ..
-        } => ruby_2_4_5::get_stack_trace,
+        } => ruby_2_4_5::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 6,
             ..
-        } => ruby_2_4_6::get_stack_trace,
+        } => ruby_2_4_6::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 7,
             ..
-        } => ruby_2_4_7::get_stack_trace,
+        } => ruby_2_4_7::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 8,
             ..
-        } => ruby_2_4_8::get_stack_trace,
+        } => ruby_2_4_8::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 9,
             ..
-        } => ruby_2_4_9::get_stack_trace,
+        } => ruby_2_4_9::get_vm_stats,
         Version {
             major: 2,
             minor: 4,
             patch: 10,
             ..
-        } => ruby_2_4_10::get_stack_trace,
+        } => ruby_2_4_10::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 0,
             ..
-        } => ruby_2_5_0::get_stack_trace,
+        } => ruby_2_5_0::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 1,
             ..
-        } => ruby_2_5_1::get_stack_trace,
+        } => ruby_2_5_1::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 2,
             ..
-        } => ruby_2_5_2::get_stack_trace,
+        } => ruby_2_5_2::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 3,
             ..
-        } => ruby_2_5_3::get_stack_trace,
+        } => ruby_2_5_3::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 4,
             ..
-        } => ruby_2_5_4::get_stack_trace,
+        } => ruby_2_5_4::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 5,
             ..
-        } => ruby_2_5_5::get_stack_trace,
+        } => ruby_2_5_5::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 6,
             ..
-        } => ruby_2_5_6::get_stack_trace,
+        } => ruby_2_5_6::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 7,
             ..
-        } => ruby_2_5_7::get_stack_trace,
+        } => ruby_2_5_7::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 8,
             ..
-        } => ruby_2_5_8::get_stack_trace,
+        } => ruby_2_5_8::get_vm_stats,
         Version {
             major: 2,
             minor: 5,
             patch: 9,
             ..
-        } => ruby_2_5_9::get_stack_trace,
+        } => ruby_2_5_9::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 0,
             ..
-        } => ruby_2_6_0::get_stack_trace,
+        } => ruby_2_6_0::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 1,
             ..
-        } => ruby_2_6_1::get_stack_trace,
+        } => ruby_2_6_1::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 2,
             ..
-        } => ruby_2_6_2::get_stack_trace,
+        } => ruby_2_6_2::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 3,
             ..
-        } => ruby_2_6_3::get_stack_trace,
+        } => ruby_2_6_3::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 4,
             ..
-        } => ruby_2_6_4::get_stack_trace,
+        } => ruby_2_6_4::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 5,
             ..
-        } => ruby_2_6_5::get_stack_trace,
+        } => ruby_2_6_5::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 6,
             ..
-        } => ruby_2_6_6::get_stack_trace,
+        } => ruby_2_6_6::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 7,
             ..
-        } => ruby_2_6_7::get_stack_trace,
+        } => ruby_2_6_7::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 8,
             ..
-        } => ruby_2_6_8::get_stack_trace,
+        } => ruby_2_6_8::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 9,
             ..
-        } => ruby_2_6_9::get_stack_trace,
+        } => ruby_2_6_9::get_vm_stats,
         Version {
             major: 2,
             minor: 6,
             patch: 10,
             ..
-        } => ruby_2_6_10::get_stack_trace,
+        } => ruby_2_6_10::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 0,
             ..
-        } => ruby_2_7_0::get_stack_trace,
+        } => ruby_2_7_0::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 1,
             ..
-        } => ruby_2_7_1::get_stack_trace,
+        } => ruby_2_7_1::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 2,
             ..
-        } => ruby_2_7_2::get_stack_trace,
+        } => ruby_2_7_2::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 3,
             ..
-        } => ruby_2_7_3::get_stack_trace,
+        } => ruby_2_7_3::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 4,
             ..
-        } => ruby_2_7_4::get_stack_trace,
+        } => ruby_2_7_4::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 5,
             ..
-        } => ruby_2_7_5::get_stack_trace,
+        } => ruby_2_7_5::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 6,
             ..
-        } => ruby_2_7_6::get_stack_trace,
+        } => ruby_2_7_6::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 7,
             ..
-        } => ruby_2_7_7::get_stack_trace,
+        } => ruby_2_7_7::get_vm_stats,
         Version {
             major: 2,
             minor: 7,
             patch: 8,
             ..
-        } => ruby_2_7_8::get_stack_trace,
+        } => ruby_2_7_8::get_vm_stats,
         Version {
             major: 3,
             minor: 0,
             patch: 0,
             ..
-        } => ruby_3_0_0::get_stack_trace,
+        } => ruby_3_0_0::get_vm_stats,
         Version {
             major: 3,
             minor: 0,
             patch: 1,
             ..
-        } => ruby_3_0_1::get_stack_trace,
+        } => ruby_3_0_1::get_vm_stats,
         Version {
             major: 3,
             minor: 0,
             patch: 2,
             ..
-        } => ruby_3_0_2::get_stack_trace,
+        } => ruby_3_0_2::get_vm_stats,
         Version {
             major: 3,
             minor: 0,
             patch: 3,
             ..
-        } => ruby_3_0_3::get_stack_trace,
+        } => ruby_3_0_3::get_vm_stats,
         Version {
             major: 3,
             minor: 0,
             patch: 4,
             ..
-        } => ruby_3_0_4::get_stack_trace,
+        } => ruby_3_0_4::get_vm_stats,
         Version {
             major: 3,
             minor: 0,
             patch: 5,
             ..
-        } => ruby_3_0_5::get_stack_trace,
+        } => ruby_3_0_5::get_vm_stats,
         Version {
             major: 3,
             minor: 0,
             patch: 6,
             ..
-        } => ruby_3_0_6::get_stack_trace,
+        } => ruby_3_0_6::get_vm_stats,
         Version {
             major: 3,
             minor: 1,
             patch: 0,
             ..
-        } => ruby_3_1_0::get_stack_trace,
+        } => ruby_3_1_0::get_vm_stats,
         Version {
             major: 3,
             minor: 1,
             patch: 1,
             ..
-        } => ruby_3_1_1::get_stack_trace,
+        } => ruby_3_1_1::get_vm_stats,
         Version {
             major: 3,
             minor: 1,
             patch: 2,
             ..
-        } => ruby_3_1_2::get_stack_trace,
+        } => ruby_3_1_2::get_vm_stats,
         Version {
             major: 3,
             minor: 1,
             patch: 3,
             ..
-        } => ruby_3_1_3::get_stack_trace,
+        } => ruby_3_1_3::get_vm_stats,
         Version {
             major: 3,
             minor: 1,
             patch: 4,
             ..
-        } => ruby_3_1_4::get_stack_trace,
+        } => ruby_3_1_4::get_vm_stats,
         Version {
             major: 3,
             minor: 2,
             patch: 0,
             ..
-        } => ruby_3_2_0::get_stack_trace,
+        } => ruby_3_2_0::get_vm_stats,
         Version {
             major: 3,
             minor: 2,
             patch: 1,
             ..
-        } => ruby_3_2_1::get_stack_trace,
+        } => ruby_3_2_1::get_vm_stats,
         Version {
             major: 3,
             minor: 2,
             patch: 2,
             ..
-        } => ruby_3_2_2::get_stack_trace,
-        _ => panic!(
-            "Ruby version not supported yet: {}. In the meantime, we suggest trying `--force-version <prior version>`.",
-            version
-        ),
+        } => ruby_3_2_2::get_vm_stats,
+        _ => return Err(unsupported_version(version)),
+    };
+    Ok(Box::new(vm_stats_function))
+}
+
+/// Reads which thread holds the GVL. Before 2.6, the VM only records whether the GVL is held,
+/// not by which thread, so this is only supported from 2.6.
+pub fn get_gvl_owner_function(version: &Version) -> anyhow::Result<crate::core::types::GvlOwnerFn> {
+    let gvl_owner_function = match (version.major, version.minor, version.patch) {
+        (2, 6, 0) => ruby_2_6_0::get_gvl_owner,
+        (2, 6, 1) => ruby_2_6_1::get_gvl_owner,
+        (2, 6, 2) => ruby_2_6_2::get_gvl_owner,
+        (2, 6, 3) => ruby_2_6_3::get_gvl_owner,
+        (2, 6, 4) => ruby_2_6_4::get_gvl_owner,
+        (2, 6, 5) => ruby_2_6_5::get_gvl_owner,
+        (2, 6, 6) => ruby_2_6_6::get_gvl_owner,
+        (2, 6, 7) => ruby_2_6_7::get_gvl_owner,
+        (2, 6, 8) => ruby_2_6_8::get_gvl_owner,
+        (2, 6, 9) => ruby_2_6_9::get_gvl_owner,
+        (2, 6, 10) => ruby_2_6_10::get_gvl_owner,
+        (2, 7, 0) => ruby_2_7_0::get_gvl_owner,
+        (2, 7, 1) => ruby_2_7_1::get_gvl_owner,
+        (2, 7, 2) => ruby_2_7_2::get_gvl_owner,
+        (2, 7, 3) => ruby_2_7_3::get_gvl_owner,
+        (2, 7, 4) => ruby_2_7_4::get_gvl_owner,
+        (2, 7, 5) => ruby_2_7_5::get_gvl_owner,
+        (2, 7, 6) => ruby_2_7_6::get_gvl_owner,
+        (2, 7, 7) => ruby_2_7_7::get_gvl_owner,
+        (2, 7, 8) => ruby_2_7_8::get_gvl_owner,
+        (3, 0, 0) => ruby_3_0_0::get_gvl_owner,
+        (3, 0, 1) => ruby_3_0_1::get_gvl_owner,
+        (3, 0, 2) => ruby_3_0_2::get_gvl_owner,
+        (3, 0, 3) => ruby_3_0_3::get_gvl_owner,
+        (3, 0, 4) => ruby_3_0_4::get_gvl_owner,
+        (3, 0, 5) => ruby_3_0_5::get_gvl_owner,
+        (3, 0, 6) => ruby_3_0_6::get_gvl_owner,
+        (3, 1, 0) => ruby_3_1_0::get_gvl_owner,
+        (3, 1, 1) => ruby_3_1_1::get_gvl_owner,
+        (3, 1, 2) => ruby_3_1_2::get_gvl_owner,
+        (3, 1, 3) => ruby_3_1_3::get_gvl_owner,
+        (3, 1, 4) => ruby_3_1_4::get_gvl_owner,
+        (3, 2, 0) => ruby_3_2_0::get_gvl_owner,
+        (3, 2, 1) => ruby_3_2_1::get_gvl_owner,
+        (3, 2, 2) => ruby_3_2_2::get_gvl_owner,
+        (1, _, _) | (2, 0..=5, _) => {
+            return Err(anyhow::format_err!(
+                "Ruby {} doesn't record which thread holds the GVL",
+                version
+            ))
+        }
+        _ => return Err(unsupported_version(version)),
     };
-    Box::new(stack_trace_function)
+    Ok(Box::new(gvl_owner_function))
 }
 
 #[cfg(not(debug_assertions))]
@@ -2941,12 +5006,23 @@ pub fn get_stack_trace_function(version: &Version) -> crate::core::types::StackT
 mod tests {
     use rbspy_testdata::*;
 
+    use crate::core::process::SampleMemory;
     use crate::core::ruby_version;
-    use crate::core::types::StackFrame;
+    use crate::core::types::{FrameKind, StackFrame};
+
+    impl SampleMemory for CoreDump {}
 
     fn real_stack_trace_1_9_3() -> Vec<StackFrame> {
         vec![
-            StackFrame::unknown_c_function(),
+            StackFrame {
+                name: "(unknown) [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(2),
+                kind: FrameKind::CFunction,
+            },
             StackFrame {
                 name: "aaa".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
@@ -2954,6 +5030,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(2),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "bbb".to_string(),
@@ -2962,6 +5039,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(6),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "ccc".to_string(),
@@ -2970,6 +5048,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(10),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "block in <main>".to_string(),
@@ -2978,9 +5057,26 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(14),
+                kind: FrameKind::Block,
+            },
+            StackFrame {
+                name: "(unknown) [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(13),
+                kind: FrameKind::CFunction,
+            },
+            StackFrame {
+                name: "(unknown) [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(13),
+                kind: FrameKind::CFunction,
             },
-            StackFrame::unknown_c_function(),
-            StackFrame::unknown_c_function(),
             StackFrame {
                 name: "<main>".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
@@ -2988,6 +5084,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(13),
+                kind: FrameKind::Main,
             },
             StackFrame::unknown_c_function(),
         ]
@@ -2996,40 +5093,46 @@ mod tests {
     fn real_stack_trace_2_7_2() -> Vec<StackFrame> {
         vec![
             StackFrame {
-                name: "sleep [c function]".to_string(),
-                relative_path: "(unknown)".to_string(),
-                absolute_path: None,
-                lineno: None,
+                name: "Kernel#sleep [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some("/vagrant/ci/ruby-programs/infinite.rb".to_string()),
+                lineno: Some(3),
+                kind: FrameKind::CFunction,
             },
             StackFrame {
-                name: "aaa".to_string(),
+                name: "Object#aaa".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
                 absolute_path: Some("/vagrant/ci/ruby-programs/infinite.rb".to_string()),
                 lineno: Some(3),
+                kind: FrameKind::Method,
             },
             StackFrame {
-                name: "bbb".to_string(),
+                name: "Object#bbb".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
                 absolute_path: Some("/vagrant/ci/ruby-programs/infinite.rb".to_string()),
                 lineno: Some(7),
+                kind: FrameKind::Method,
             },
             StackFrame {
-                name: "ccc".to_string(),
+                name: "Object#ccc".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
                 absolute_path: Some("/vagrant/ci/ruby-programs/infinite.rb".to_string()),
                 lineno: Some(11),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "block in <main>".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
                 absolute_path: Some("/vagrant/ci/ruby-programs/infinite.rb".to_string()),
                 lineno: Some(15),
+                kind: FrameKind::Block,
             },
             StackFrame {
-                name: "loop [c function]".to_string(),
+                name: "Kernel#loop [c function]".to_string(),
                 relative_path: "(unknown)".to_string(),
                 absolute_path: None,
                 lineno: None,
+                kind: FrameKind::CFunction,
             },
         ]
     }
@@ -3037,34 +5140,40 @@ mod tests {
     fn real_stack_trace_3_1_0() -> Vec<StackFrame> {
         vec![
             StackFrame {
-                name: "sleep [c function]".to_string(),
-                relative_path: "(unknown)".to_string(),
-                absolute_path: None,
-                lineno: None,
+                name: "Kernel#sleep [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/acj/workspace/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(3),
+                kind: FrameKind::CFunction,
             },
             StackFrame {
-                name: "aaa".to_string(),
+                name: "Object#aaa".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
                 absolute_path: Some(
                     "/home/acj/workspace/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(3),
+                kind: FrameKind::Method,
             },
             StackFrame {
-                name: "bbb".to_string(),
+                name: "Object#bbb".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
                 absolute_path: Some(
                     "/home/acj/workspace/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(7),
+                kind: FrameKind::Method,
             },
             StackFrame {
-                name: "ccc".to_string(),
+                name: "Object#ccc".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
                 absolute_path: Some(
                     "/home/acj/workspace/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(11),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "block in <main>".to_string(),
@@ -3073,12 +5182,14 @@ mod tests {
                     "/home/acj/workspace/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(15),
+                kind: FrameKind::Block,
             },
             StackFrame {
-                name: "loop [c function]".to_string(),
+                name: "Kernel#loop [c function]".to_string(),
                 relative_path: "(unknown)".to_string(),
                 absolute_path: None,
                 lineno: None,
+                kind: FrameKind::CFunction,
             },
         ]
     }
@@ -3087,9 +5198,12 @@ mod tests {
         vec![
             StackFrame {
                 name: "sleep [c function]".to_string(),
-                relative_path: "(unknown)".to_string(),
-                absolute_path: None,
-                lineno: None,
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/parallels/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(3),
+                kind: FrameKind::CFunction,
             },
             StackFrame {
                 name: "aaa".to_string(),
@@ -3098,6 +5212,7 @@ mod tests {
                     "/home/parallels/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(3),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "bbb".to_string(),
@@ -3106,6 +5221,7 @@ mod tests {
                     "/home/parallels/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(7),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "ccc".to_string(),
@@ -3114,6 +5230,7 @@ mod tests {
                     "/home/parallels/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(11),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "block in <main>".to_string(),
@@ -3122,12 +5239,16 @@ mod tests {
                     "/home/parallels/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(15),
+                kind: FrameKind::Block,
             },
             StackFrame {
                 name: "loop [c function]".to_string(),
-                relative_path: "(unknown)".to_string(),
-                absolute_path: None,
-                lineno: None,
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/parallels/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(13),
+                kind: FrameKind::CFunction,
             },
             StackFrame {
                 name: "<main>".to_string(),
@@ -3136,13 +5257,22 @@ mod tests {
                     "/home/parallels/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(13),
+                kind: FrameKind::Main,
             },
         ]
     }
 
     fn real_stack_trace_main() -> Vec<StackFrame> {
         vec![
-            StackFrame::unknown_c_function(),
+            StackFrame {
+                name: "(unknown) [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(2),
+                kind: FrameKind::CFunction,
+            },
             StackFrame {
                 name: "aaa".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
@@ -3150,6 +5280,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(2),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "bbb".to_string(),
@@ -3158,6 +5289,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(6),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "ccc".to_string(),
@@ -3166,6 +5298,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(10),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "block in <main>".to_string(),
@@ -3174,8 +5307,17 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(14),
+                kind: FrameKind::Block,
+            },
+            StackFrame {
+                name: "(unknown) [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(13),
+                kind: FrameKind::CFunction,
             },
-            StackFrame::unknown_c_function(),
             StackFrame {
                 name: "<main>".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
@@ -3183,13 +5325,22 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(13),
+                kind: FrameKind::Main,
             },
         ]
     }
 
     fn real_stack_trace() -> Vec<StackFrame> {
         vec![
-            StackFrame::unknown_c_function(),
+            StackFrame {
+                name: "(unknown) [c function]".to_string(),
+                relative_path: "ci/ruby-programs/infinite.rb".to_string(),
+                absolute_path: Some(
+                    "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
+                ),
+                lineno: Some(2),
+                kind: FrameKind::CFunction,
+            },
             StackFrame {
                 name: "aaa".to_string(),
                 relative_path: "ci/ruby-programs/infinite.rb".to_string(),
@@ -3197,6 +5348,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(2),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "bbb".to_string(),
@@ -3205,6 +5357,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(6),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "ccc".to_string(),
@@ -3213,6 +5366,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(10),
+                kind: FrameKind::Method,
             },
             StackFrame {
                 name: "block in <main>".to_string(),
@@ -3221,6 +5375,7 @@ mod tests {
                     "/home/bork/work/rbspy/ci/ruby-programs/infinite.rb".to_string(),
                 ),
                 lineno: Some(14),
+                kind: FrameKind::Block,
             },
             StackFrame::unknown_c_function(),
         ]
@@ -3674,4 +5829,123 @@ mod tests {
         .unwrap();
         assert_eq!(real_stack_trace_3_2_0(), stack_trace.trace);
     }
+
+    #[test]
+    fn test_qualify_label() {
+        assert_eq!(
+            ruby_version::qualify_label("full_name", "User", false),
+            "User#full_name"
+        );
+        assert_eq!(ruby_version::qualify_label("find", "User", true), "User.find");
+        assert_eq!(
+            ruby_version::qualify_label("block (2 levels) in each", "Admin::Report", false),
+            "block (2 levels) in Admin::Report#each"
+        );
+        assert_eq!(
+            ruby_version::qualify_label("rescue in call", "Rack::Lint", false),
+            "rescue in Rack::Lint#call"
+        );
+    }
+
+    #[test]
+    fn test_compatible_version() {
+        let compatible = |version: &str| {
+            ruby_version::compatible_version(&semver::Version::parse(version).unwrap())
+                .map(|version| version.to_string())
+        };
+        assert_eq!(compatible("3.1.4"), Some("3.1.4".to_string()));
+        assert_eq!(compatible("3.1.5"), Some("3.1.4".to_string()));
+        assert_eq!(compatible("3.2.9"), Some("3.2.2".to_string()));
+        assert_eq!(compatible("3.3.0"), None);
+    }
+
+    #[test]
+    fn test_locate_cfunc_frames() {
+        let mut trace = real_stack_trace_3_2_0();
+        for frame in trace
+            .iter_mut()
+            .filter(|frame| frame.kind == FrameKind::CFunction)
+        {
+            frame.relative_path = "(unknown)".to_string();
+            frame.absolute_path = None;
+            frame.lineno = None;
+        }
+        ruby_version::locate_cfunc_frames(&mut trace);
+        assert_eq!(trace, real_stack_trace_3_2_0());
+
+        let mut trace = vec![StackFrame::unknown_c_function()];
+        ruby_version::locate_cfunc_frames(&mut trace);
+        assert_eq!(trace, vec![StackFrame::unknown_c_function()]);
+    }
+
+    #[test]
+    fn test_insn_info_index() {
+        let positions = [0, 3, 7, 12];
+        let indexes: Vec<usize> = (0..15)
+            .map(|pos| ruby_version::insn_info_index(&positions, pos))
+            .collect();
+        assert_eq!(indexes, [0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 2, 3, 3, 3]);
+        assert_eq!(ruby_version::insn_info_index(&[2, 5], 0), 0);
+    }
+
+    /// Builds a succinct bit vector of `positions` the way Ruby's `succ_index_table_create` does
+    fn succ_index_table(positions: &[usize], size: usize) -> Vec<u8> {
+        let rank = |end: usize| positions.iter().filter(|&&pos| pos < end).count();
+        let mut table = Vec::new();
+        let mut immediate = [0u64; 6];
+        for pos in 0..54 {
+            immediate[pos / 9] |= (rank(pos + 1) as u64) << (pos % 9 * 7);
+        }
+        for word in immediate.iter() {
+            table.extend_from_slice(&word.to_ne_bytes());
+        }
+        for start in (54..size).step_by(512) {
+            let mut bits = [0u64; 8];
+            for &pos in positions
+                .iter()
+                .filter(|&&pos| pos >= start && pos < start + 512)
+            {
+                bits[(pos - start) / 64] |= 1 << ((pos - start) % 64);
+            }
+            let mut small_block_ranks = 0u64;
+            for word in 1..8 {
+                let popcount: u32 = bits[..word].iter().map(|bits| bits.count_ones()).sum();
+                small_block_ranks |= (popcount as u64) << ((word - 1) * 9);
+            }
+            table.extend_from_slice(&(rank(start) as u32).to_ne_bytes());
+            table.extend_from_slice(&[0; 4]);
+            table.extend_from_slice(&small_block_ranks.to_ne_bytes());
+            for word in bits.iter() {
+                table.extend_from_slice(&word.to_ne_bytes());
+            }
+        }
+        table
+    }
+
+    #[test]
+    fn test_succ_index_lookup() {
+        let positions = [0, 2, 9, 40, 53, 54, 60, 130, 565, 566, 900];
+        let memory = crate::core::fixture::Fixture {
+            ruby_version: "3.2.0".to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            pid: 0,
+            current_thread_addr_location: 0,
+            ruby_vm_addr_location: 0,
+            global_symbols_addr_location: None,
+            expected_trace: vec![],
+            regions: vec![crate::core::fixture::Region {
+                address: 0x1000,
+                data: succ_index_table(&positions, 1000),
+            }],
+        };
+        for pos in 0..1000 {
+            let rank = ruby_version::succ_index_lookup(0x1000, pos, &memory).unwrap();
+            assert_eq!(
+                rank - 1,
+                ruby_version::insn_info_index(&positions, pos),
+                "position {}",
+                pos
+            );
+        }
+    }
 }
@@ -1,7 +1,8 @@
 /// Core types used throughout rbspy: StackFrame and StackTrace
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::{self, convert::From};
 
 use anyhow::{Error, Result};
@@ -9,38 +10,388 @@ use clap::ArgEnum;
 use remoteprocess::Pid;
 use thiserror::Error;
 
-use crate::core::process::Process;
+use crate::core::memory::MemoryReader;
 use crate::ui::*;
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
-pub(crate) struct Header {
+/// Describes a raw recording session: written at the start of a recording, and again for each
+/// session that is appended to it. The fields after `start_time` are left out when they're empty,
+/// and older recordings don't have them.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct Header {
     pub sample_rate: Option<u32>,
+    /// The version of rbspy that made the recording
     pub rbspy_version: Option<String>,
     pub start_time: Option<SystemTime>,
+    /// The time on the system's monotonic clock when the recording started (see `Timestamp`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_monotonic: Option<Duration>,
+    /// The Ruby version of the profiled process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ruby_version: Option<String>,
+    /// The path of the profiled process's executable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exe: Option<String>,
+    /// The command line of the profiled process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+    /// The host the process ran on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// The options the recording was made with, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub flags: BTreeMap<String, String>,
+    /// The labels on the first trace of the session
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Header {
+    /// A header for a recording made by this version of rbspy, starting now
+    pub fn new(sample_rate: u32) -> Header {
+        let start = Timestamp::now();
+        Header {
+            sample_rate: Some(sample_rate),
+            rbspy_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            start_time: Some(start.time),
+            start_monotonic: start.monotonic,
+            ..Default::default()
+        }
+    }
+
+    /// When the recording started, on both clocks
+    pub fn start(&self) -> Option<Timestamp> {
+        self.start_time.map(|time| Timestamp {
+            time,
+            monotonic: self.start_monotonic,
+        })
+    }
+}
+
+/// A point in time on both the wall clock and the monotonic clock. Recordings have one for their
+/// start and for each checkpoint. Wall-clock times line up with logs, and monotonic times line up
+/// with other profilers' samples (e.g. `perf record -k CLOCK_MONOTONIC`), even when the wall clock
+/// is adjusted during the recording.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub time: SystemTime,
+    /// The time on the system's monotonic clock (`CLOCK_MONOTONIC` on Unix). Not recorded on
+    /// platforms without one.
+    pub monotonic: Option<Duration>,
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp {
+            time: SystemTime::now(),
+            monotonic: crate::core::process::monotonic_clock(),
+        }
+    }
+
+    /// The monotonic clock's time at a wall-clock time, e.g. a trace's `time`. The wall clock
+    /// can be adjusted at any time, so this is most precise with the nearest timestamp before the
+    /// time.
+    pub fn monotonic_at(&self, time: SystemTime) -> Option<Duration> {
+        let monotonic = self.monotonic?;
+        match time.duration_since(self.time) {
+            Ok(since) => Some(monotonic + since),
+            Err(e) => monotonic.checked_sub(e.duration()),
+        }
+    }
+}
+
+impl fmt::Display for Header {
+    /// Writes one `name: value` line for each field that is set
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(rbspy_version) = &self.rbspy_version {
+            writeln!(f, "rbspy version: {}", rbspy_version)?;
+        }
+        if let Some(start_time) = self.start_time {
+            let start_time: chrono::DateTime<chrono::Local> = start_time.into();
+            writeln!(f, "Start time: {}", start_time.to_rfc3339())?;
+        }
+        if let Some(start_monotonic) = self.start_monotonic {
+            writeln!(f, "Start time (monotonic clock): {:?}", start_monotonic)?;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            writeln!(f, "Sample rate: {} Hz", sample_rate)?;
+        }
+        let fields = [
+            ("Ruby version", &self.ruby_version),
+            ("Executable", &self.exe),
+            ("Command line", &self.cmdline),
+            ("Host", &self.host),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                writeln!(f, "{}: {}", name, value)?;
+            }
+        }
+        for (name, values) in [("Flags", &self.flags), ("Labels", &self.labels)] {
+            if !values.is_empty() {
+                writeln!(f, "{}:", name)?;
+                for (key, value) in values {
+                    writeln!(f, "  {}={}", key, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a profile was recorded from, shown in the formatted output so that a profile file is
+/// self-describing
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ProfileMetadata {
+    /// The command line of the profiled process
+    pub cmdline: Option<String>,
+    /// The path of the profiled process's executable
+    #[serde(default)]
+    pub exe: Option<String>,
+    /// The host the process ran on
+    pub host: Option<String>,
+    pub ruby_version: Option<String>,
+    /// The version of rbspy that made the recording
+    #[serde(default)]
+    pub rbspy_version: Option<String>,
+    pub sample_rate: Option<u32>,
+    /// When the recording started
+    #[serde(default)]
+    pub start_time: Option<SystemTime>,
+    /// How long the recording ran for
+    pub duration: Option<Duration>,
+    /// The options the recording was made with, by name. They're too long for `describe`, so
+    /// only formats with room for structured metadata show them.
+    #[serde(default)]
+    pub flags: BTreeMap<String, String>,
+    /// The labels that every trace in the profile had
+    pub labels: BTreeMap<String, String>,
+    /// The gems that the profile's frames were loaded from, with the versions that were loaded.
+    /// Like `flags`, they're left out of `describe`.
+    #[serde(default)]
+    pub gems: BTreeSet<Gem>,
+    /// The number of samples that were dropped because the output couldn't keep up (see
+    /// `Backpressure`)
+    #[serde(default)]
+    pub dropped_samples: usize,
+    /// The number of idle samples that were left out (see `RecordConfig::skip_idle`)
+    #[serde(default)]
+    pub idle_samples: usize,
+}
+
+impl ProfileMetadata {
+    /// A one-line description, e.g. for a subtitle
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(cmdline) = self.cmdline.as_ref().or(self.exe.as_ref()) {
+            parts.push(cmdline.clone());
+        }
+        if let Some(host) = &self.host {
+            parts.push(format!("on {}", host));
+        }
+        if let Some(version) = &self.ruby_version {
+            parts.push(format!("Ruby {}", version));
+        }
+        if let Some(rate) = self.sample_rate {
+            parts.push(format!("{} Hz", rate));
+        }
+        if let Some(start_time) = self.start_time {
+            let start_time: chrono::DateTime<chrono::Utc> = start_time.into();
+            parts.push(format!(
+                "started {}",
+                start_time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+            ));
+        }
+        if let Some(duration) = self.duration {
+            parts.push(format!("{}s", duration.as_secs()));
+        }
+        if self.dropped_samples > 0 {
+            parts.push(format!("{} samples dropped", self.dropped_samples));
+        }
+        if self.idle_samples > 0 {
+            parts.push(format!("{} idle samples skipped", self.idle_samples));
+        }
+        for (key, value) in &self.labels {
+            parts.push(format!("{}={}", key, value));
+        }
+        if let Some(rbspy_version) = &self.rbspy_version {
+            parts.push(format!("rbspy {}", rbspy_version));
+        }
+        parts.join(" | ")
+    }
+
+    /// Narrows down `labels` to the ones that `trace` also has. Start with the first trace's
+    /// labels to find the labels that every trace has.
+    pub(crate) fn keep_common_labels(&mut self, trace: &StackTrace) {
+        self.labels
+            .retain(|key, value| trace.labels.get(key) == Some(&*value));
+    }
+
+    /// Adds the gems that `trace`'s frames were loaded from to `gems`
+    pub(crate) fn add_gems(&mut self, trace: &StackTrace) {
+        self.gems
+            .extend(trace.trace.iter().filter_map(|frame| frame.gem()));
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct StackFrame {
+    /// The frame's label. From Ruby 2.5 through 3.1, methods are qualified with the class or
+    /// module that they're defined in (e.g. `Foo::Bar#baz`, or `Foo::Bar.baz` for singleton
+    /// methods). Ruby 3.2 keeps class names in object shapes, which rbspy doesn't read, so its
+    /// labels are left unqualified.
     pub name: String,
     pub relative_path: String,
     pub absolute_path: Option<String>,
     pub lineno: Option<usize>,
+    #[serde(default)]
+    pub kind: FrameKind,
+}
+
+/// The kind of code that a stack frame is executing, taken from the type of the frame's
+/// instruction sequence. Lets reports tell methods apart from the blocks, rescue clauses, etc.
+/// nested inside them, and from C functions.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameKind {
+    /// The top level of a required file (`<top (required)>`)
+    Top,
+    Method,
+    Block,
+    /// A class or module body (`<class:Foo>`)
+    Class,
+    Rescue,
+    Ensure,
+    Eval,
+    /// The top level of the main script (`<main>`)
+    Main,
+    CFunction,
+    /// Frames read from older recordings, or whose kind couldn't be determined
+    Unknown,
+}
+
+/// A gem that a stack frame's source file belongs to, e.g. `activerecord` version `7.0.4` for
+/// frames under `.../gems/activerecord-7.0.4/lib/...`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Gem {
+    pub name: String,
+    pub version: String,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub struct StackTrace {
     pub trace: Vec<StackFrame>,
     pub pid: Option<Pid>,
+    /// The native thread handle (e.g. a `pthread_t`) of the thread that the trace was taken from
     pub thread_id: Option<usize>,
+    /// The address of the Ruby VM's thread struct. Stays the same for as long as the Ruby thread
+    /// is alive, so together with `pid` it identifies a thread within a recording.
+    #[serde(default)]
+    pub thread_address: Option<usize>,
+    /// The name given to the Ruby thread with `Thread#name=`. Only recorded by Ruby 2.3 and newer.
+    #[serde(default)]
+    pub thread_name: Option<String>,
+    /// The operating system's ID for the thread (e.g. its Linux TID). Only recorded by Ruby 3.1
+    /// and newer.
+    #[serde(default)]
+    pub os_thread_id: Option<usize>,
+    /// What the thread was doing when the trace was taken. Missing from older recordings.
+    #[serde(default)]
+    pub thread_state: Option<ThreadState>,
+    /// Where a blocked thread was waiting according to the operating system, e.g. the kernel
+    /// function it was sleeping in (`futex_wait_queue`) or the system call it was in
+    /// (`syscall 7`). Only recorded on Linux, for Ruby versions that record OS thread IDs.
+    #[serde(default)]
+    pub wait_channel: Option<String>,
     pub time: Option<SystemTime>,
+    /// How long it had been since the previous sample of the same process was taken. Missing
+    /// from older recordings.
+    #[serde(default)]
+    pub interval: Option<Duration>,
+    /// Counters read from the Ruby VM when the sample was taken, if they were requested
+    #[serde(default)]
+    pub vm_stats: Option<VmStats>,
+    /// Extra metadata attached to the trace by the recorder's enrichment hooks
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// The number of identical samples of the same thread that directly followed this one and
+    /// were merged into it when the trace was stored. `interval` covers all of them.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub repeats: u32,
+    /// The number of objects the trace's process allocated since its previous sample, which the
+    /// trace is weighted by instead of its `interval` in the `alloc` view. Only set in reports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allocated: Option<u64>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// The state of a Ruby thread at the time it was sampled, according to the VM
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadState {
+    /// The thread was runnable, i.e. running Ruby code or in a C call that doesn't block
+    Running,
+    /// The thread was stopped, e.g. in `sleep`, waiting on a mutex or queue, or joining another
+    /// thread, or the operating system reported it as waiting, e.g. on IO
+    Blocked,
+}
+
+/// Cheap counters read from the Ruby VM alongside a stack trace, for correlating stack activity
+/// with allocation and GC pressure over time
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub struct VmStats {
+    /// The number of objects allocated since the process started
+    pub total_allocated_objects: u64,
+    /// Whether the garbage collector was running
+    #[serde(default)]
+    pub during_gc: bool,
+}
+
+/// Attributes the objects that each process allocated in between two of its samples to the
+/// later sample's stack, from the VM's allocation counter (see `VmStats`). The counter is read
+/// from outside the process, so this approximates what an allocation profiler inside it would
+/// record: the sampled stack stands in for every allocation since the previous sample.
+#[derive(Debug, Default)]
+pub(crate) struct Allocations {
+    /// The counter's value at each process's previous sample
+    last: std::collections::HashMap<Option<Pid>, u64>,
+}
+
+impl Allocations {
+    /// `trace`, weighted by the number of objects its process allocated since its previous
+    /// sample (see `StackTrace::allocated`). `None` when nothing was allocated, or it's not known
+    /// what was (e.g. for the first sample of each process).
+    pub fn weigh(&mut self, trace: &StackTrace) -> Option<StackTrace> {
+        let total = trace.vm_stats?.total_allocated_objects;
+        let last = self.last.insert(trace.pid, total)?;
+        // The counter starts over when the process execs another program
+        let allocated = total.checked_sub(last).filter(|&allocated| allocated > 0)?;
+        let mut trace = trace.clone();
+        trace.allocated = Some(allocated);
+        Some(trace)
+    }
 }
 
-pub type StackTraceFn =
-    Box<dyn Fn(usize, usize, Option<usize>, &Process, Pid, bool) -> Result<Option<StackTrace>>>;
+/// Called by the recorder with each trace before it's written, e.g. to attach labels
+pub type EnrichmentHook = Box<dyn Fn(&mut StackTrace) + Send + Sync>;
 
-pub type IsMaybeThreadFn = Box<dyn Fn(usize, usize, &Process, &[proc_maps::MapRange]) -> bool>;
+pub type StackTraceFn = Box<
+    dyn Fn(usize, usize, Option<usize>, &MemoryReader, Pid, bool) -> Result<Option<StackTrace>>,
+>;
 
-pub type GetExecutionContextFn = Box<dyn Fn(usize, usize, &Process) -> Result<usize>>;
+pub type AllStackTracesFn = Box<
+    dyn Fn(usize, usize, Option<usize>, &MemoryReader, Pid, bool) -> Result<Vec<StackTrace>>,
+>;
+
+pub type VmStatsFn = Box<dyn Fn(usize, &MemoryReader) -> Result<VmStats>>;
+
+pub type GvlOwnerFn = Box<dyn Fn(usize, &MemoryReader) -> Result<Option<usize>>>;
+
+pub type IsMaybeThreadFn = Box<dyn Fn(usize, usize, &MemoryReader, &[proc_maps::MapRange]) -> bool>;
+
+pub type GetExecutionContextFn = Box<dyn Fn(usize, usize, &MemoryReader) -> Result<usize>>;
 
 #[derive(Error, Debug)]
 pub enum MemoryCopyError {
@@ -66,6 +417,25 @@ impl StackFrame {
         }
     }
 
+    /// The gem that this frame's source file was loaded from, if any
+    pub fn gem(&self) -> Option<Gem> {
+        Gem::from_path(self.path())
+    }
+
+    /// Where this frame's code comes from. Gems are told apart by their install directory (see
+    /// `Gem::from_path`) and the standard library by Ruby's `lib/ruby/<version>` directory.
+    pub fn category(&self) -> FrameCategory {
+        if self.kind == FrameKind::CFunction || self.name.ends_with("[c function]") {
+            FrameCategory::Native
+        } else if self.gem().is_some() {
+            FrameCategory::Gem
+        } else if is_stdlib_path(self.path()) {
+            FrameCategory::Stdlib
+        } else {
+            FrameCategory::App
+        }
+    }
+
     // we use this stack frame when there's a C function that we don't recognize in the stack. This
     // would be a constant but it has strings in it so it can't be.
     pub fn unknown_c_function() -> StackFrame {
@@ -74,8 +444,100 @@ impl StackFrame {
             relative_path: "(unknown)".to_string(),
             absolute_path: None,
             lineno: None,
+            kind: FrameKind::CFunction,
         }
     }
+
+    // Stands in for the frames that were dropped from a stack that was too deep
+    pub fn truncated() -> StackFrame {
+        StackFrame {
+            name: "(truncated)".to_string(),
+            relative_path: "(unknown)".to_string(),
+            absolute_path: None,
+            lineno: None,
+            kind: FrameKind::Unknown,
+        }
+    }
+
+    // Goes on top of stacks that were sampled while the garbage collector was running
+    pub fn garbage_collection() -> StackFrame {
+        StackFrame {
+            name: "(garbage collection)".to_string(),
+            relative_path: "(unknown)".to_string(),
+            absolute_path: None,
+            lineno: None,
+            kind: FrameKind::Unknown,
+        }
+    }
+
+    // Goes on top of stacks whose thread was blocked, for flamegraphs that show off-CPU time
+    pub fn off_cpu() -> StackFrame {
+        StackFrame {
+            name: "(off-CPU)".to_string(),
+            relative_path: "(unknown)".to_string(),
+            absolute_path: None,
+            lineno: None,
+            kind: FrameKind::Unknown,
+        }
+    }
+}
+
+impl FrameKind {
+    /// Converts a Ruby `enum iseq_type` value. The values have been stable since Ruby 1.9.2.
+    pub(crate) fn from_iseq_type(iseq_type: u32) -> FrameKind {
+        match iseq_type {
+            0 => FrameKind::Top,
+            1 => FrameKind::Method,
+            2 => FrameKind::Block,
+            3 => FrameKind::Class,
+            4 => FrameKind::Rescue,
+            5 => FrameKind::Ensure,
+            6 => FrameKind::Eval,
+            7 => FrameKind::Main,
+            _ => FrameKind::Unknown,
+        }
+    }
+}
+
+impl Default for FrameKind {
+    fn default() -> FrameKind {
+        FrameKind::Unknown
+    }
+}
+
+/// Where the code that a stack frame is executing comes from, so that reports can tell the
+/// application's own code apart from its dependencies
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub enum FrameCategory {
+    /// The application's own code
+    App,
+    /// Code from an installed gem
+    Gem,
+    /// Ruby's standard library, including the VM's builtin methods (`<internal:kernel>`)
+    Stdlib,
+    /// C functions
+    Native,
+}
+
+impl FrameCategory {
+    pub const ALL: [FrameCategory; 4] = [
+        FrameCategory::App,
+        FrameCategory::Gem,
+        FrameCategory::Stdlib,
+        FrameCategory::Native,
+    ];
+}
+
+impl fmt::Display for FrameCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FrameCategory::App => "app",
+            FrameCategory::Gem => "gem",
+            FrameCategory::Stdlib => "stdlib",
+            FrameCategory::Native => "native",
+        };
+        write!(f, "{}", name)
+    }
 }
 
 impl fmt::Display for StackFrame {
@@ -94,6 +556,7 @@ impl Ord for StackFrame {
             .cmp(other.path())
             .then(self.name.cmp(&other.name))
             .then(self.lineno.cmp(&other.lineno))
+            .then(self.kind.cmp(&other.kind))
     }
 }
 
@@ -103,19 +566,229 @@ impl PartialOrd for StackFrame {
     }
 }
 
+/// Whether `path` is in Ruby's standard library, e.g. `/usr/lib/ruby/3.1.0/set.rb`, or is one of
+/// the VM's builtin files, e.g. `<internal:kernel>`
+fn is_stdlib_path(path: &str) -> bool {
+    if path.starts_with("<internal:") {
+        return true;
+    }
+    let components: Vec<&str> = path.split(|c| c == '/' || c == '\\').collect();
+    components.windows(3).any(|dirs| {
+        dirs[0] == "lib"
+            && dirs[1] == "ruby"
+            && dirs[2].starts_with(|c: char| c.is_ascii_digit())
+            && dirs[2].chars().all(|c| c.is_ascii_digit() || c == '.')
+    })
+}
+
+/// The shortest abbreviated git revision that bundler names a git-sourced gem's directory with
+const MIN_REVISION_LENGTH: usize = 7;
+
+impl Gem {
+    /// Parses the gem name and version out of a source path.
+    ///
+    /// Gems are installed into directories named `<name>-<version>` inside a `gems` directory,
+    /// e.g. `/usr/lib/ruby/gems/3.1.0/gems/activerecord-7.0.4/lib/active_record/base.rb`. Gems
+    /// with native extensions may carry a platform suffix (`nokogiri-1.13.8-x86_64-linux`), which
+    /// is kept as part of the version. Git-sourced bundler gems (`bundler/gems/rails-4f2a1c3d`)
+    /// use the revision as their version.
+    pub fn from_path(path: &str) -> Option<Gem> {
+        let mut components = path.split(|c| c == '/' || c == '\\').peekable();
+        let mut parent = None;
+        while let Some(component) = components.next() {
+            if component == "gems" {
+                let from_git = parent == Some("bundler");
+                if let Some(gem) = components
+                    .peek()
+                    .and_then(|dir| Gem::from_dir_name(dir, from_git))
+                {
+                    return Some(gem);
+                }
+            }
+            parent = Some(component);
+        }
+        None
+    }
+
+    /// Splits a gem's install directory into its name and version. Revisions are only accepted
+    /// as versions of git-sourced gems, since other directories can end in something that looks
+    /// like hex too (`my-cafe`).
+    fn from_dir_name(dir: &str, from_git: bool) -> Option<Gem> {
+        // The version starts at the first dash that is followed by a digit. Gem names can contain
+        // dashes too (`net-http-0.3.0`), so we can't just split on the first one.
+        let bytes = dir.as_bytes();
+        let split = (1..bytes.len())
+            .find(|&i| bytes[i - 1] == b'-' && bytes[i].is_ascii_digit())
+            .map(|i| i - 1)
+            .or_else(|| {
+                dir.rfind('-').filter(|&i| {
+                    let revision = &dir[i + 1..];
+                    from_git
+                        && revision.len() >= MIN_REVISION_LENGTH
+                        && revision.chars().all(|c| c.is_ascii_hexdigit())
+                })
+            })?;
+        let (name, version) = (&dir[..split], &dir[split + 1..]);
+        if name.is_empty() || version.is_empty() {
+            return None;
+        }
+        Some(Gem {
+            name: name.to_string(),
+            version: version.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Gem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.name, self.version)
+    }
+}
+
 impl StackTrace {
     pub fn new_empty() -> StackTrace {
         StackTrace {
             pid: None,
             trace: Vec::new(),
             thread_id: None,
+            thread_address: None,
+            thread_name: None,
+            os_thread_id: None,
+            thread_state: None,
+            wait_channel: None,
             time: None,
+            interval: None,
+            vm_stats: None,
+            labels: BTreeMap::new(),
+            repeats: 0,
+            allocated: None,
         }
     }
 
     pub fn iter(&self) -> std::slice::Iter<StackFrame> {
         self.trace.iter()
     }
+
+    /// Makes frames on different lines of the same function identical, for function granularity
+    pub fn strip_line_numbers(&mut self) {
+        for frame in self.trace.iter_mut() {
+            frame.lineno = None;
+        }
+    }
+
+    /// Rewrites the paths of the trace's frames with the first of `mappings` that applies to each
+    pub fn map_paths(&mut self, mappings: &[PathMapping]) {
+        for frame in self.trace.iter_mut() {
+            if let Some(path) = PathMapping::map(mappings, &frame.relative_path) {
+                frame.relative_path = path;
+            }
+            if let Some(path) = frame
+                .absolute_path
+                .as_deref()
+                .and_then(|path| PathMapping::map(mappings, path))
+            {
+                frame.absolute_path = Some(path);
+            }
+        }
+    }
+
+    /// Shortens the trace to at most `limit.max_depth` frames, including a `(truncated)` frame
+    /// in place of the frames that were dropped.
+    pub fn truncate(&mut self, limit: &DepthLimit) {
+        let len = self.trace.len();
+        if len <= limit.max_depth {
+            return;
+        }
+        let keep = limit.max_depth.saturating_sub(1);
+        // Frames are ordered leaf first
+        let (leaf, root) = match limit.strategy {
+            TruncationStrategy::leaf => (keep, 0),
+            TruncationStrategy::root => (0, keep),
+            TruncationStrategy::both => ((keep + 1) / 2, keep / 2),
+        };
+        self.trace.drain(leaf..len - root);
+        self.trace.insert(leaf, StackFrame::truncated());
+    }
+
+    /// How much this trace counts for when traces are aggregated: the number of microseconds
+    /// since the previous sample, so that late or delayed samples aren't underrepresented. Traces
+    /// without an interval count as 1 for each sample they stand for, and traces weighted by
+    /// allocations count as the number of objects allocated.
+    pub fn weight(&self) -> u64 {
+        match (self.allocated, self.interval) {
+            (Some(allocated), _) => allocated,
+            (None, Some(interval)) => std::cmp::max(interval.as_micros() as u64, 1),
+            (None, None) => 1 + u64::from(self.repeats),
+        }
+    }
+
+    /// What `weight` counts, for formats that label it
+    pub fn weight_unit(&self) -> &'static str {
+        match (self.allocated, self.interval) {
+            (Some(_), _) => "objects",
+            (None, Some(_)) => "µs",
+            (None, None) => "samples",
+        }
+    }
+
+    /// Whether the thread was running on a CPU (`Some(true)`) or blocked (`Some(false)`) when
+    /// the sample was taken, or `None` for recordings that don't have thread states
+    pub fn on_cpu(&self) -> Option<bool> {
+        self.thread_state.map(|state| state == ThreadState::Running)
+    }
+
+    /// Whether the thread was idle: blocked in a call that waits for work to arrive, like
+    /// `sleep`, `IO.select`, accepting a connection, popping from a `Queue` or joining a thread.
+    /// The sampled thread is the one that last held the GVL, so when it's idle, so is the rest of
+    /// the process. Threads blocked on anything else (e.g. reading a database's response) aren't
+    /// idle.
+    pub fn is_idle(&self) -> bool {
+        const IDLE_METHODS: [&str; 10] = [
+            "sleep",
+            "select",
+            "accept",
+            "accept_nonblock",
+            "__accept_nonblock",
+            "sysaccept",
+            "join",
+            "pop",
+            "deq",
+            "shift",
+        ];
+        if self.thread_state != Some(ThreadState::Blocked) {
+            return false;
+        }
+        let leaf = match self.trace.first() {
+            Some(leaf) => leaf.name.trim_end_matches(" [c function]"),
+            None => return false,
+        };
+        let method = leaf.rsplit(|c| c == '#' || c == '.').next().unwrap_or(leaf);
+        IDLE_METHODS.contains(&method)
+    }
+
+    /// Whether `other` is another sample of the same thread doing the same thing, so that it can
+    /// be merged into this trace with `add_repeat`
+    pub fn is_repeated_by(&self, other: &StackTrace) -> bool {
+        self.trace == other.trace
+            && self.pid == other.pid
+            && self.thread_id == other.thread_id
+            && self.thread_address == other.thread_address
+            && self.thread_name == other.thread_name
+            && self.os_thread_id == other.os_thread_id
+            && self.thread_state == other.thread_state
+            && self.wait_channel == other.wait_channel
+            && self.vm_stats == other.vm_stats
+            && self.labels == other.labels
+    }
+
+    /// Merges a repeated sample into this trace
+    pub fn add_repeat(&mut self, other: &StackTrace) {
+        self.repeats += 1 + other.repeats;
+        self.interval = match (self.interval, other.interval) {
+            (Some(interval), Some(other)) => Some(interval + other),
+            _ => None,
+        };
+    }
 }
 
 impl fmt::Display for StackTrace {
@@ -152,9 +825,23 @@ impl From<Error> for MemoryCopyError {
 #[allow(non_camel_case_types)]
 pub enum OutputFormat {
     flamegraph,
+    /// One `frame;frame;frame weight` line per stack. The weights are microseconds of sampled
+    /// time, or numbers of samples for recordings made before rbspy recorded sampling intervals.
     collapsed,
     callgrind,
     speedscope,
+    /// The samples of each thread in the order they were taken, as speedscope's evented
+    /// profiles, to see how a program's behavior changes over time
+    flamechart,
+    /// How many samples found each thread running or blocked in each second, as CSV
+    thread_timeline,
+    /// Chrome's trace event format, to open in Perfetto or chrome://tracing alongside other
+    /// traces, with each thread's frames as spans reconstructed from consecutive samples
+    chrometrace,
+    /// The Gecko profile format, to open in the Firefox Profiler (profiler.firefox.com) and
+    /// explore each thread's samples along a timeline
+    firefox,
+    /// Gzipped pprof protobuf, for `go tool pprof`, Parca, Pyroscope and Grafana
     pprof,
     summary,
     summary_by_line,
@@ -162,15 +849,35 @@ pub enum OutputFormat {
 
 impl OutputFormat {
     pub fn outputter(self, flame_min_width: f64) -> Box<dyn output::Outputter> {
-        match self {
+        self.outputter_with_granularity(flame_min_width, Granularity::default())
+    }
+
+    /// Like `outputter`, but tells frames apart by function rather than by line if `granularity`
+    /// says so
+    pub fn outputter_with_granularity(
+        self,
+        flame_min_width: f64,
+        granularity: Granularity,
+    ) -> Box<dyn output::Outputter> {
+        let outputter: Box<dyn output::Outputter> = match self {
             OutputFormat::flamegraph => Box::new(output::Flamegraph::new(flame_min_width)),
             OutputFormat::collapsed => Box::new(output::Collapsed::default()),
             OutputFormat::callgrind => Box::new(output::Callgrind(callgrind::Stats::new())),
             OutputFormat::speedscope => Box::new(output::Speedscope(speedscope::Stats::new())),
+            OutputFormat::flamechart => Box::new(output::Flamechart(speedscope::Flamechart::new())),
+            OutputFormat::thread_timeline => Box::new(output::Timeline(timeline::Stats::new())),
+            OutputFormat::chrometrace => Box::new(output::ChromeTrace(chrometrace::Stats::new())),
+            OutputFormat::firefox => Box::new(output::Firefox(firefox::Stats::new())),
             OutputFormat::pprof => Box::new(output::Pprof(pprof::Stats::new())),
-            OutputFormat::summary => Box::new(output::Summary(summary::Stats::new())),
-            OutputFormat::summary_by_line => Box::new(output::SummaryLine(summary::Stats::new())),
-        }
+            OutputFormat::summary => {
+                Box::new(output::Summary(summary::Stats::new(), Default::default()))
+            }
+            OutputFormat::summary_by_line => Box::new(output::SummaryLine(
+                summary::Stats::new(),
+                Default::default(),
+            )),
+        };
+        Box::new(output::Granular::new(outputter, granularity))
     }
 
     pub fn extension(&self) -> String {
@@ -179,6 +886,10 @@ impl OutputFormat {
             OutputFormat::collapsed => "collapsed.txt",
             OutputFormat::callgrind => "callgrind.txt",
             OutputFormat::speedscope => "speedscope.json",
+            OutputFormat::flamechart => "flamechart.json",
+            OutputFormat::thread_timeline => "thread_timeline.csv",
+            OutputFormat::chrometrace => "trace.json",
+            OutputFormat::firefox => "firefox.json",
             OutputFormat::pprof => "profile.pb.gz",
             OutputFormat::summary => "summary.txt",
             OutputFormat::summary_by_line => "summary_by_line.txt",
@@ -202,6 +913,10 @@ impl std::str::FromStr for OutputFormat {
             "collapsed" => Ok(OutputFormat::collapsed),
             "callgrind" => Ok(OutputFormat::callgrind),
             "speedscope" => Ok(OutputFormat::speedscope),
+            "flamechart" => Ok(OutputFormat::flamechart),
+            "thread-timeline" => Ok(OutputFormat::thread_timeline),
+            "chrometrace" => Ok(OutputFormat::chrometrace),
+            "firefox" => Ok(OutputFormat::firefox),
             "pprof" => Ok(OutputFormat::pprof),
             "summary" => Ok(OutputFormat::summary),
             "summary-by-line" => Ok(OutputFormat::summary_by_line),
@@ -209,3 +924,868 @@ impl std::str::FromStr for OutputFormat {
         }
     }
 }
+
+/// Which frames to keep from stacks that are deeper than a `DepthLimit`
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum TruncationStrategy {
+    /// The frames closest to the currently executing function
+    leaf,
+    /// The frames closest to the program's entry point
+    root,
+    /// Half of each, so that both what was running and where it was called from are visible
+    both,
+}
+
+impl std::str::FromStr for TruncationStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "leaf" => Ok(TruncationStrategy::leaf),
+            "root" => Ok(TruncationStrategy::root),
+            "both" => Ok(TruncationStrategy::both),
+            _ => Err(anyhow::format_err!("Unknown truncation strategy: {}", s)),
+        }
+    }
+}
+
+/// A limit on the number of frames that are kept from each stack trace
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct DepthLimit {
+    pub max_depth: usize,
+    pub strategy: TruncationStrategy,
+}
+
+impl DepthLimit {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.max_depth == 0 {
+            return Err(anyhow::format_err!(
+                "The stack depth limit must be at least 1 frame, which leaves room for the (truncated) frame"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// What the recorder does when its raw output reaches a `SizeLimit`
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum SizeLimitAction {
+    /// Move the full file aside (replacing the one moved aside before it) and start a new one,
+    /// so that at most twice the limit is used
+    rotate,
+    /// Stop recording
+    stop,
+}
+
+impl std::str::FromStr for SizeLimitAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rotate" => Ok(SizeLimitAction::rotate),
+            "stop" => Ok(SizeLimitAction::stop),
+            _ => Err(anyhow::format_err!("Unknown size limit action: {}", s)),
+        }
+    }
+}
+
+/// How the recorder compresses its raw output. Reports can be made from recordings in either.
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum RawCompression {
+    /// gzip, which any tool can decompress
+    gzip,
+    /// Zstandard, which uses less CPU and makes smaller files than gzip
+    zstd,
+}
+
+impl Default for RawCompression {
+    fn default() -> RawCompression {
+        RawCompression::gzip
+    }
+}
+
+impl std::str::FromStr for RawCompression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" => Ok(RawCompression::gzip),
+            "zstd" => Ok(RawCompression::zstd),
+            _ => Err(anyhow::format_err!("Unknown raw compression: {}", s)),
+        }
+    }
+}
+
+/// A limit on the size of the recorder's raw output
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct SizeLimit {
+    /// The largest the raw output may grow, in (compressed) bytes
+    pub max_bytes: u64,
+    pub action: SizeLimitAction,
+}
+
+/// A schedule that samples for `on` out of every `period`, e.g. 10 seconds out of every minute,
+/// which keeps the average overhead of always-on profiling low
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct DutyCycle {
+    pub on: Duration,
+    pub period: Duration,
+}
+
+impl DutyCycle {
+    /// Which burst of sampling a time since the schedule started falls in, counting from 0
+    pub fn cycle(&self, elapsed: Duration) -> u64 {
+        (elapsed.as_nanos() / self.period.as_nanos()) as u64
+    }
+
+    /// Whether samples are taken at a time since the schedule started
+    pub fn is_on(&self, elapsed: Duration) -> bool {
+        elapsed.as_nanos() % self.period.as_nanos() < self.on.as_nanos()
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.on.is_zero() || self.on > self.period {
+            return Err(anyhow::format_err!(
+                "The duty cycle's sampling time must be more than 0 and at most its period, not {:?}/{:?}",
+                self.on,
+                self.period
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DutyCycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}/{:?}", self.on, self.period)
+    }
+}
+
+/// Which samples a report is built from, based on what their thread was doing
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum ProfileView {
+    /// Every sample, regardless of thread state
+    wall,
+    /// Only samples whose thread was running
+    on_cpu,
+    /// Only samples whose thread was blocked
+    off_cpu,
+    /// Samples weighted by the number of objects that their process allocated since its previous
+    /// sample, rather than by time, to show which stacks allocate. Needs samples with VM stats,
+    /// which recording with this view turns on.
+    alloc,
+}
+
+impl ProfileView {
+    /// Whether a trace belongs in this view. Traces without a thread state (e.g. from older
+    /// recordings) only appear in the wall-clock view.
+    pub fn includes(&self, trace: &StackTrace) -> bool {
+        match self {
+            ProfileView::wall => true,
+            ProfileView::on_cpu => trace.on_cpu() == Some(true),
+            ProfileView::off_cpu => trace.on_cpu() == Some(false),
+            ProfileView::alloc => trace.vm_stats.is_some(),
+        }
+    }
+}
+
+impl Default for ProfileView {
+    fn default() -> ProfileView {
+        ProfileView::wall
+    }
+}
+
+impl std::str::FromStr for ProfileView {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "wall" => Ok(ProfileView::wall),
+            "on-cpu" => Ok(ProfileView::on_cpu),
+            "off-cpu" => Ok(ProfileView::off_cpu),
+            "alloc" => Ok(ProfileView::alloc),
+            _ => Err(anyhow::format_err!("Unknown profile view: {}", s)),
+        }
+    }
+}
+
+/// File formats that reports can be made from
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum InputFormat {
+    /// rbspy's raw recordings
+    raw,
+    /// Collapsed stacks (`frame;frame;frame weight` on each line), as made by Brendan Gregg's
+    /// `stackcollapse` scripts, other profilers, or rbspy's `collapsed` output format
+    collapsed,
+    /// speedscope JSON, from rbspy's `speedscope` output format or tools like stackprof
+    speedscope,
+    /// pprof protobuf (gzipped or not), from rbspy's `pprof` output format or other profilers
+    pprof,
+}
+
+impl Default for InputFormat {
+    fn default() -> InputFormat {
+        InputFormat::raw
+    }
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" => Ok(InputFormat::raw),
+            "collapsed" => Ok(InputFormat::collapsed),
+            "speedscope" => Ok(InputFormat::speedscope),
+            "pprof" => Ok(InputFormat::pprof),
+            _ => Err(anyhow::format_err!("Unknown input format: {}", s)),
+        }
+    }
+}
+
+/// Restricts sampling to the traces of one Ruby thread
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ThreadFilter {
+    /// A thread ID: either the native thread handle or the operating system's thread ID. On
+    /// Linux, the main thread's OS thread ID is the process ID.
+    Id(usize),
+    /// Part of a thread name, e.g. `processor` for Sidekiq's worker threads
+    Name(String),
+}
+
+impl ThreadFilter {
+    pub fn matches(&self, trace: &StackTrace) -> bool {
+        match self {
+            ThreadFilter::Id(id) => trace.thread_id == Some(*id) || trace.os_thread_id == Some(*id),
+            ThreadFilter::Name(pattern) => trace
+                .thread_name
+                .as_ref()
+                .map_or(false, |name| name.contains(pattern.as_str())),
+        }
+    }
+}
+
+impl std::str::FromStr for ThreadFilter {
+    type Err = Error;
+
+    /// Numbers are treated as thread IDs and anything else as part of a thread name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(anyhow::format_err!("Thread filter can't be empty"));
+        }
+        Ok(match s.parse::<usize>() {
+            Ok(id) => ThreadFilter::Id(id),
+            Err(_) => ThreadFilter::Name(s.to_string()),
+        })
+    }
+}
+
+/// The unprivileged account that rbspy switches to once it has attached to the target process
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub user: String,
+    /// Default: the user's primary group
+    pub group: Option<String>,
+}
+
+impl std::str::FromStr for Credentials {
+    type Err = Error;
+
+    /// Parses `user` or `user:group`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        if user.is_empty() || group == Some("") {
+            return Err(anyhow::format_err!(
+                "Expected a user or user:group, got \"{}\"",
+                s
+            ));
+        }
+        Ok(Credentials {
+            user: user.to_string(),
+            group: group.map(|group| group.to_string()),
+        })
+    }
+}
+
+/// Rewrites the start of frames' file paths, e.g. to strip a deploy directory or to turn the
+/// paths in a container into paths relative to the repository
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathMapping {
+    /// The prefix to replace. Only whole path components match, so `/app` matches `/app/x.rb`
+    /// but not `/application/x.rb`.
+    pub from: String,
+    /// What to replace it with. `.` leaves the rest of the path as a relative path.
+    pub to: String,
+}
+
+impl PathMapping {
+    /// The mapped path, if the mapping applies to `path`
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.from.as_str())?;
+        let rest = if rest.is_empty() || self.from.ends_with('/') {
+            rest
+        } else {
+            rest.strip_prefix('/')?
+        };
+        let to = self.to.trim_end_matches('/');
+        Some(match (to, rest) {
+            (".", "") => ".".to_string(),
+            (".", rest) => rest.to_string(),
+            (to, "") => to.to_string(),
+            (to, rest) => format!("{}/{}", to, rest),
+        })
+    }
+
+    /// Maps `path` with the first of `mappings` that applies to it
+    pub fn map(mappings: &[PathMapping], path: &str) -> Option<String> {
+        mappings.iter().find_map(|mapping| mapping.apply(path))
+    }
+}
+
+impl std::str::FromStr for PathMapping {
+    type Err = Error;
+
+    /// Parses `from=to`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(PathMapping {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(anyhow::format_err!(
+                "Expected a path mapping like /app/releases/1234=., got \"{}\"",
+                s
+            )),
+        }
+    }
+}
+
+/// How finely frames are told apart when traces are aggregated into a report
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum Granularity {
+    /// Frames in the same function are merged, regardless of which line was executing
+    function,
+    /// Frames on different lines of the same function are reported separately
+    line,
+}
+
+impl Default for Granularity {
+    fn default() -> Granularity {
+        Granularity::line
+    }
+}
+
+impl std::str::FromStr for Granularity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "function" => Ok(Granularity::function),
+            "line" => Ok(Granularity::line),
+            _ => Err(anyhow::format_err!("Unknown granularity: {}", s)),
+        }
+    }
+}
+
+/// What the sampler does when the recorder can't keep up with its traces, e.g. because the
+/// output is on a slow disk or a network filesystem
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum Backpressure {
+    /// Wait for the recorder to catch up. No samples are lost, but sampling falls behind
+    /// schedule, and with `lock_process` the process stays stopped while the sampler waits.
+    block,
+    /// Keep sampling, and drop the oldest traces that the recorder hasn't taken yet
+    drop_oldest,
+    /// Keep sampling, and drop new traces until the recorder catches up
+    drop_newest,
+}
+
+impl Default for Backpressure {
+    fn default() -> Backpressure {
+        Backpressure::block
+    }
+}
+
+impl std::str::FromStr for Backpressure {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "block" => Ok(Backpressure::block),
+            "drop_oldest" => Ok(Backpressure::drop_oldest),
+            "drop_newest" => Ok(Backpressure::drop_newest),
+            _ => Err(anyhow::format_err!("Unknown backpressure policy: {}", s)),
+        }
+    }
+}
+
+/// How the frames in a flamegraph are colored
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum FlamegraphPalette {
+    /// Warm colors picked from each function's name, like the original flamegraph scripts
+    hot,
+    /// One color for each `FrameCategory`, with a legend, so that the application's own code
+    /// stands out from its gems, the standard library and C functions
+    category,
+    /// Warm colors like `hot`, with an `(off-CPU)` frame in a color of its own on top of the
+    /// samples whose thread was blocked, so that a wall-clock profile shows where threads ran
+    /// and where they waited at the same time
+    state,
+}
+
+impl Default for FlamegraphPalette {
+    fn default() -> FlamegraphPalette {
+        FlamegraphPalette::hot
+    }
+}
+
+impl std::str::FromStr for FlamegraphPalette {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hot" => Ok(FlamegraphPalette::hot),
+            "category" => Ok(FlamegraphPalette::category),
+            "state" => Ok(FlamegraphPalette::state),
+            _ => Err(anyhow::format_err!("Unknown flamegraph palette: {}", s)),
+        }
+    }
+}
+
+/// How the functions in a summary are ordered
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum SummarySort {
+    /// The functions that were running the most come first
+    #[clap(name = "self")]
+    self_,
+    /// The functions that were on the stack the most come first
+    total,
+    /// Alphabetical order
+    name,
+}
+
+impl Default for SummarySort {
+    fn default() -> SummarySort {
+        SummarySort::self_
+    }
+}
+
+impl std::str::FromStr for SummarySort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "self" => Ok(SummarySort::self_),
+            "total" => Ok(SummarySort::total),
+            "name" => Ok(SummarySort::name),
+            _ => Err(anyhow::format_err!("Unknown summary sort order: {}", s)),
+        }
+    }
+}
+
+/// What each line of a summary adds up the time of
+#[derive(ArgEnum, Clone, Copy, Eq, PartialEq, Hash, Debug)]
+#[allow(non_camel_case_types)]
+pub enum SummaryGroup {
+    /// Each function
+    function,
+    /// Each source file
+    file,
+    /// Each gem, by the directory it's installed in (see `Gem::from_path`). Code that isn't in
+    /// a gem is grouped by where it comes from instead: `<app>`, `<stdlib>` or `<native>`.
+    gem,
+    /// Each directory of source files
+    directory,
+}
+
+impl std::str::FromStr for SummaryGroup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "function" => Ok(SummaryGroup::function),
+            "file" => Ok(SummaryGroup::file),
+            "gem" => Ok(SummaryGroup::gem),
+            "directory" => Ok(SummaryGroup::directory),
+            _ => Err(anyhow::format_err!("Unknown summary grouping: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::types::*;
+
+    fn gem(name: &str, version: &str) -> Option<Gem> {
+        Some(Gem {
+            name: name.to_string(),
+            version: version.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        let cycle = DutyCycle {
+            on: Duration::from_secs(10),
+            period: Duration::from_secs(60),
+        };
+        assert!(cycle.validate().is_ok());
+        assert!(cycle.is_on(Duration::from_secs(0)));
+        assert!(cycle.is_on(Duration::from_millis(9999)));
+        assert!(!cycle.is_on(Duration::from_secs(10)));
+        assert!(!cycle.is_on(Duration::from_secs(59)));
+        assert!(cycle.is_on(Duration::from_secs(125)));
+        assert_eq!(cycle.cycle(Duration::from_secs(59)), 0);
+        assert_eq!(cycle.cycle(Duration::from_secs(125)), 2);
+        assert_eq!(cycle.to_string(), "10s/60s");
+
+        let too_long = DutyCycle {
+            on: Duration::from_secs(61),
+            ..cycle
+        };
+        assert!(too_long.validate().is_err());
+        let never_on = DutyCycle {
+            on: Duration::from_secs(0),
+            ..cycle
+        };
+        assert!(never_on.validate().is_err());
+    }
+
+    #[test]
+    fn test_timestamp_monotonic_at() {
+        let start = Timestamp {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1000),
+            monotonic: Some(Duration::from_secs(50)),
+        };
+        assert_eq!(
+            start.monotonic_at(start.time + Duration::from_millis(1500)),
+            Some(Duration::from_millis(51500))
+        );
+        assert_eq!(
+            start.monotonic_at(start.time - Duration::from_secs(10)),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(
+            start.monotonic_at(start.time - Duration::from_secs(60)),
+            None
+        );
+        let without_monotonic = Timestamp {
+            monotonic: None,
+            ..start
+        };
+        assert_eq!(without_monotonic.monotonic_at(start.time), None);
+    }
+
+    #[test]
+    fn test_profile_metadata() {
+        let mut labels = BTreeMap::new();
+        labels.insert("deploy".to_string(), "abc123".to_string());
+        labels.insert("worker".to_string(), "1".to_string());
+        let mut metadata = ProfileMetadata {
+            cmdline: Some("puma 6.0.0".to_string()),
+            host: Some("web-1".to_string()),
+            ruby_version: Some("3.2.2".to_string()),
+            sample_rate: Some(100),
+            duration: Some(Duration::from_millis(61_500)),
+            labels,
+            ..Default::default()
+        };
+
+        let mut trace = StackTrace::new_empty();
+        trace
+            .labels
+            .insert("deploy".to_string(), "abc123".to_string());
+        trace.labels.insert("worker".to_string(), "2".to_string());
+        metadata.keep_common_labels(&trace);
+        trace.trace.push(StackFrame {
+            name: "call".to_string(),
+            relative_path: "rack.rb".to_string(),
+            absolute_path: Some("/gems/3.1.0/gems/rack-2.2.4/lib/rack.rb".to_string()),
+            lineno: Some(1),
+            kind: FrameKind::Method,
+        });
+        trace.trace.push(StackFrame::unknown_c_function());
+        metadata.add_gems(&trace);
+        assert_eq!(
+            metadata.gems.iter().collect::<Vec<_>>(),
+            vec![&gem("rack", "2.2.4").unwrap()]
+        );
+        assert_eq!(
+            metadata.describe(),
+            "puma 6.0.0 | on web-1 | Ruby 3.2.2 | 100 Hz | 61s | deploy=abc123"
+        );
+        metadata.dropped_samples = 12;
+        assert!(metadata
+            .describe()
+            .ends_with("| 61s | 12 samples dropped | deploy=abc123"));
+        metadata.start_time = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        metadata.rbspy_version = Some("0.12.1".to_string());
+        assert!(metadata
+            .describe()
+            .contains("| 100 Hz | started 2023-11-14T22:13:20Z | 61s |"));
+        assert!(metadata.describe().ends_with("| deploy=abc123 | rbspy 0.12.1"));
+        assert_eq!(ProfileMetadata::default().describe(), "");
+        assert_eq!(
+            ProfileMetadata {
+                exe: Some("/usr/bin/ruby".to_string()),
+                ..Default::default()
+            }
+            .describe(),
+            "/usr/bin/ruby"
+        );
+    }
+
+    #[test]
+    fn test_gem_from_path() {
+        assert_eq!(
+            Gem::from_path(
+                "/usr/lib/ruby/gems/3.1.0/gems/activerecord-7.0.4/lib/active_record/base.rb"
+            ),
+            gem("activerecord", "7.0.4")
+        );
+        assert_eq!(
+            Gem::from_path("/app/vendor/bundle/ruby/3.1.0/gems/net-http-0.3.0/lib/net/http.rb"),
+            gem("net-http", "0.3.0")
+        );
+        assert_eq!(
+            Gem::from_path("/gems/3.1.0/gems/nokogiri-1.13.8-x86_64-linux/lib/nokogiri.rb"),
+            gem("nokogiri", "1.13.8-x86_64-linux")
+        );
+        assert_eq!(
+            Gem::from_path(
+                "/app/vendor/bundle/ruby/3.1.0/bundler/gems/rails-4f2a1c3d5e6f/lib/rails.rb"
+            ),
+            gem("rails", "4f2a1c3d5e6f")
+        );
+        assert_eq!(
+            Gem::from_path("C:\\Ruby31\\lib\\ruby\\gems\\3.1.0\\gems\\rack-2.2.4\\lib\\rack.rb"),
+            gem("rack", "2.2.4")
+        );
+    }
+
+    #[test]
+    fn test_gem_from_path_outside_gems() {
+        assert_eq!(Gem::from_path("/app/app/models/user.rb"), None);
+        assert_eq!(Gem::from_path("/usr/lib/ruby/3.1.0/set.rb"), None);
+        assert_eq!(Gem::from_path("/app/lib/gems/my-project/file.rb"), None);
+        assert_eq!(Gem::from_path("/app/gems/my-cafe/lib/cafe.rb"), None);
+        assert_eq!(
+            Gem::from_path("/app/vendor/bundle/ruby/3.1.0/bundler/gems/rails-af2a1c/lib/rails.rb"),
+            None
+        );
+        assert_eq!(Gem::from_path("(unknown)"), None);
+    }
+
+    #[test]
+    fn test_frame_category() {
+        let frame = |name: &str, path: &str, kind: FrameKind| StackFrame {
+            name: name.to_string(),
+            relative_path: "(unknown)".to_string(),
+            absolute_path: Some(path.to_string()),
+            lineno: Some(1),
+            kind,
+        };
+        let categories: Vec<FrameCategory> = [
+            frame("show", "/app/app/controllers/users.rb", FrameKind::Method),
+            frame(
+                "execute",
+                "/app/vendor/bundle/ruby/3.1.0/gems/pg-1.4.5/lib/pg.rb",
+                FrameKind::Method,
+            ),
+            frame("add", "/usr/lib/ruby/3.1.0/set.rb", FrameKind::Block),
+            frame("tap", "<internal:kernel>", FrameKind::Method),
+            frame("sleep", "(unknown)", FrameKind::CFunction),
+            frame("read [c function]", "(unknown)", FrameKind::Unknown),
+            frame("run", "/app/lib/ruby/tasks.rb", FrameKind::Method),
+        ]
+        .iter()
+        .map(StackFrame::category)
+        .collect();
+        assert_eq!(
+            categories,
+            vec![
+                FrameCategory::App,
+                FrameCategory::Gem,
+                FrameCategory::Stdlib,
+                FrameCategory::Stdlib,
+                FrameCategory::Native,
+                FrameCategory::Native,
+                FrameCategory::App,
+            ]
+        );
+    }
+
+    fn numbered(depth: usize) -> StackTrace {
+        let mut trace = StackTrace::new_empty();
+        trace.trace = (0..depth)
+            .map(|i| StackFrame {
+                name: format!("f{}", i),
+                relative_path: "a.rb".to_string(),
+                absolute_path: None,
+                lineno: None,
+                kind: FrameKind::Method,
+            })
+            .collect();
+        trace
+    }
+
+    fn truncated_names(depth: usize, max_depth: usize, strategy: TruncationStrategy) -> String {
+        let mut trace = numbered(depth);
+        trace.truncate(&DepthLimit {
+            max_depth,
+            strategy,
+        });
+        trace
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(",")
+    }
+
+    #[test]
+    fn test_thread_filter() {
+        let mut trace = StackTrace::new_empty();
+        trace.thread_id = Some(140_000);
+        trace.os_thread_id = Some(1234);
+        trace.thread_name = Some("sidekiq.default/processor".to_string());
+
+        let matches = |filter: &str, trace: &StackTrace| {
+            filter.parse::<ThreadFilter>().unwrap().matches(trace)
+        };
+        assert!(matches("140000", &trace));
+        assert!(matches("1234", &trace));
+        assert!(matches("processor", &trace));
+        assert!(!matches("4321", &trace));
+        assert!(!matches("scheduler", &trace));
+
+        trace.thread_name = None;
+        assert!(!matches("processor", &trace));
+        assert!("".parse::<ThreadFilter>().is_err());
+    }
+
+    #[test]
+    fn test_parse_credentials() {
+        assert_eq!(
+            "nobody".parse::<Credentials>().unwrap(),
+            Credentials {
+                user: "nobody".to_string(),
+                group: None
+            }
+        );
+        assert_eq!(
+            "app:www-data".parse::<Credentials>().unwrap(),
+            Credentials {
+                user: "app".to_string(),
+                group: Some("www-data".to_string())
+            }
+        );
+        assert!("".parse::<Credentials>().is_err());
+        assert!(":wheel".parse::<Credentials>().is_err());
+        assert!("app:".parse::<Credentials>().is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncated_names(3, 5, TruncationStrategy::leaf), "f0,f1,f2");
+        assert_eq!(
+            truncated_names(8, 5, TruncationStrategy::leaf),
+            "f0,f1,f2,f3,(truncated)"
+        );
+        assert_eq!(
+            truncated_names(8, 5, TruncationStrategy::root),
+            "(truncated),f4,f5,f6,f7"
+        );
+        assert_eq!(
+            truncated_names(8, 6, TruncationStrategy::both),
+            "f0,f1,f2,(truncated),f6,f7"
+        );
+
+        let limit = |max_depth| DepthLimit {
+            max_depth,
+            strategy: TruncationStrategy::leaf,
+        };
+        assert!(limit(1).validate().is_ok());
+        assert!(limit(0).validate().is_err());
+    }
+
+    #[test]
+    fn test_path_mapping() {
+        let mappings: Vec<PathMapping> =
+            ["/app/releases/20240501=.", "/usr/src/app/=/home/dev/app"]
+                .iter()
+                .map(|mapping| mapping.parse().unwrap())
+                .collect();
+        let map = |path: &str| PathMapping::map(&mappings, path);
+        assert_eq!(
+            map("/app/releases/20240501/app/models/user.rb").as_deref(),
+            Some("app/models/user.rb")
+        );
+        assert_eq!(
+            map("/usr/src/app/lib/a.rb").as_deref(),
+            Some("/home/dev/app/lib/a.rb")
+        );
+        assert_eq!(map("/app/releases/20240501").as_deref(), Some("."));
+        assert_eq!(map("/app/releases/2024050199/a.rb"), None);
+        assert_eq!(map("(unknown)"), None);
+        assert!("=.".parse::<PathMapping>().is_err());
+        assert!("/app".parse::<PathMapping>().is_err());
+
+        let mut trace = StackTrace::new_empty();
+        trace.trace = vec![StackFrame {
+            name: "index".to_string(),
+            relative_path: "/app/releases/20240501/app/controllers/home.rb".to_string(),
+            absolute_path: Some("/app/releases/20240501/app/controllers/home.rb".to_string()),
+            lineno: Some(3),
+            kind: FrameKind::Method,
+        }];
+        trace.map_paths(&mappings);
+        assert_eq!(trace.trace[0].relative_path, "app/controllers/home.rb");
+        assert_eq!(
+            trace.trace[0].absolute_path.as_deref(),
+            Some("app/controllers/home.rb")
+        );
+    }
+
+    #[test]
+    fn test_is_idle() {
+        let trace = |leaf: &str, state| {
+            let mut trace = StackTrace::new_empty();
+            trace.trace = vec![StackFrame {
+                name: leaf.to_string(),
+                ..StackFrame::unknown_c_function()
+            }];
+            trace.thread_state = Some(state);
+            trace
+        };
+        assert!(trace("Kernel#sleep [c function]", ThreadState::Blocked).is_idle());
+        assert!(trace("IO.select [c function]", ThreadState::Blocked).is_idle());
+        assert!(trace("Thread::Queue#pop [c function]", ThreadState::Blocked).is_idle());
+        assert!(trace("sleep [c function]", ThreadState::Blocked).is_idle());
+        // Running, or waiting on something other than new work
+        assert!(!trace("Kernel#sleep [c function]", ThreadState::Running).is_idle());
+        assert!(!trace("IO#wait_readable [c function]", ThreadState::Blocked).is_idle());
+        assert!(!trace("block in select_rows", ThreadState::Blocked).is_idle());
+        assert!(!StackTrace::new_empty().is_idle());
+    }
+
+    #[test]
+    fn test_frame_kind_missing_from_old_recordings() {
+        let frame: StackFrame = serde_json::from_str(
+            r#"{"name":"aaa","relative_path":"a.rb","absolute_path":null,"lineno":2}"#,
+        )
+        .unwrap();
+        assert_eq!(frame.kind, FrameKind::Unknown);
+    }
+}
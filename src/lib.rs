@@ -5,6 +5,7 @@ extern crate chrono;
 extern crate clap;
 extern crate ctrlc;
 extern crate env_logger;
+extern crate hmac;
 extern crate inferno;
 extern crate libc;
 #[cfg(target_os = "macos")]
@@ -15,6 +16,7 @@ extern crate proc_maps;
 #[macro_use]
 extern crate log;
 extern crate rand;
+extern crate regex;
 #[cfg(test)]
 extern crate rbspy_testdata;
 extern crate remoteprocess;
@@ -24,11 +26,15 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sha2;
 extern crate term_size;
 #[cfg(windows)]
 extern crate winapi;
+extern crate zstd;
 
-use anyhow::{Error, Result};
+use anyhow::{format_err, Context, Error, Result};
+
+use crate::ui::output::Outputter;
 
 mod core;
 pub mod recorder;
@@ -36,10 +42,169 @@ pub mod sampler;
 mod storage;
 pub mod ui;
 
+pub use crate::core::audit::AuditTarget;
+pub use crate::core::check::run_checks;
+#[cfg(target_os = "linux")]
+pub use crate::core::check::setcap_self;
+pub use crate::core::check::CheckResult;
+pub use crate::core::check::CheckStatus;
+pub use crate::core::discovery::find_ruby_processes;
+pub use crate::core::discovery::list_ruby_processes;
+pub use crate::core::discovery::pick_process;
+pub use crate::core::discovery::write_process_list;
+pub use crate::core::discovery::RubyProcess;
+pub use crate::core::container::resolve_container;
+pub use crate::core::container::ContainerInfo;
+pub use crate::core::container::ContainerTarget;
+pub use crate::core::errors::error_code;
+pub use crate::core::errors::explain;
+pub use crate::core::errors::CodedError;
+pub use crate::core::errors::ErrorCode;
+pub use crate::core::fixture::generate_testdata;
+pub use crate::core::fixture::Fixture;
+pub use crate::core::inspect::inspect;
+pub use crate::core::inspect::Inspection;
+pub use crate::core::inspect::RubyLinkage;
+pub use crate::core::inspect::SymbolAddress;
+pub use crate::core::kubernetes::PodContainer;
+pub use crate::core::maps::memory_maps;
+pub use crate::core::maps::MapRegion;
+pub use crate::core::maps::MapsReport;
+pub use crate::core::maps::RegionKind;
+pub use crate::core::maps::VmLocation;
+pub use crate::core::memory::ReadMethod;
 pub use crate::core::process::Pid;
+pub use crate::core::process::read_pidfile;
+pub use crate::core::types::Backpressure;
+pub use crate::core::types::Credentials;
+pub use crate::core::types::DepthLimit;
+pub use crate::core::types::DutyCycle;
+pub use crate::core::types::EnrichmentHook;
+pub use crate::core::types::FlamegraphPalette;
+pub use crate::core::types::FrameCategory;
+pub use crate::core::types::FrameKind;
+pub use crate::core::types::Gem;
+pub use crate::core::types::Granularity;
+pub use crate::core::types::Header;
+pub use crate::core::types::InputFormat;
 pub use crate::core::types::OutputFormat;
+pub use crate::core::types::PathMapping;
+pub use crate::core::types::ProfileMetadata;
+pub use crate::core::types::ProfileView;
+pub use crate::core::types::RawCompression;
+pub use crate::core::types::SizeLimit;
+pub use crate::core::types::SizeLimitAction;
 pub use crate::core::types::StackFrame;
 pub use crate::core::types::StackTrace;
+pub use crate::core::types::SummaryGroup;
+pub use crate::core::types::SummarySort;
+pub use crate::core::types::ThreadFilter;
+pub use crate::core::types::ThreadState;
+pub use crate::core::types::Timestamp;
+pub use crate::core::types::TruncationStrategy;
+pub use crate::core::types::VmStats;
+pub use crate::ui::profile::diff;
+pub use crate::ui::profile::diff_flamegraph;
+pub use crate::ui::profile::merge;
+pub use crate::ui::profile::FrameDelta;
+pub use crate::ui::profile::FrameWeight;
+pub use crate::ui::profile::Profile;
+pub use crate::ui::profile::ProfileDiff;
+pub use crate::ui::summary::SummaryOptions;
+
+/// Options that control how previously recorded traces are turned into a report
+#[derive(Clone, Debug, Default)]
+pub struct ReportConfig {
+    /// Whether frames on different lines of the same function are reported separately.
+    /// Default: `line`.
+    pub granularity: Granularity,
+    /// Which samples to report on, based on what their thread was doing. A wall-clock recording
+    /// can be reported as a wall-clock, on-CPU, or off-CPU profile, and a recording with VM stats
+    /// as an allocation profile too. Default: `wall`.
+    pub view: ProfileView,
+    /// Only report on traces taken at or after this time. Default: none (from the start of the
+    /// recording).
+    pub from: Option<std::time::SystemTime>,
+    /// Only report on traces taken at or before this time. Default: none (to the end of the
+    /// recording).
+    pub to: Option<std::time::SystemTime>,
+    /// Only report on traces taken at least this long after the recording started, e.g. to look
+    /// at the half minute where latency spiked in a ten minute recording. If `from` is given too,
+    /// the later of the two applies. Default: none (from the start of the recording).
+    pub from_offset: Option<std::time::Duration>,
+    /// Only report on traces taken at most this long after the recording started. If `to` is
+    /// given too, the earlier of the two applies. Default: none (to the end of the recording).
+    pub to_offset: Option<std::time::Duration>,
+    /// Only report on traces of these processes. Default: none (every process).
+    pub pids: Vec<Pid>,
+    /// Only report on traces of these threads, given by their Ruby thread ID or their native
+    /// thread ID, as shown in the `thread_timeline` and `speedscope` formats. Default: none
+    /// (every thread).
+    pub threads: Vec<usize>,
+    /// The key that the recording was authenticated with (see `RecordConfig::raw_hmac_key`).
+    /// If given, reports are only made from recordings that were completed with the same key and
+    /// haven't been modified since. Recordings are authenticated as a whole, so an indexed
+    /// recording is read through once even if only part of it is reported on (see `from`).
+    /// Default: none (recordings are still checked against their checksums, if they have them).
+    pub hmac_key: Option<Vec<u8>>,
+    /// How the `summary` and `summary_by_line` formats order and cut down the functions they
+    /// show, e.g. to show only the top 10 functions by total time. Default: every function,
+    /// ordered by self time.
+    pub summary_options: SummaryOptions,
+    /// How the `flamegraph` format colors frames. Default: `hot`.
+    pub flame_palette: FlamegraphPalette,
+    /// The format of the input. Profiles from other tools (as collapsed stacks, speedscope or
+    /// pprof) can be reported on, or merged and diffed with `load_profile`, like rbspy's raw
+    /// recordings, though they lack the thread states and times that `view`, `from` and `to`
+    /// pick traces by. Default: `raw`.
+    pub input_format: InputFormat,
+    /// Rewrites the start of frames' file paths (see `RecordConfig::path_map`), so that
+    /// profiles recorded in different deploy directories or containers can be merged and
+    /// compared. Default: none.
+    pub path_map: Vec<PathMapping>,
+}
+
+impl ReportConfig {
+    /// The range of times to report on, as far as it's known before reading the recording
+    fn time_range(&self) -> storage::TimeRange {
+        storage::TimeRange {
+            from: self.from,
+            to: self.to,
+        }
+    }
+
+    /// The range of times to report on in a recording that started at `start`
+    fn time_range_from(&self, start: Option<std::time::SystemTime>) -> Result<storage::TimeRange> {
+        if self.from_offset.is_none() && self.to_offset.is_none() {
+            return Ok(self.time_range());
+        }
+        let start = start.ok_or_else(|| {
+            format_err!("The recording has no start time for the time range to be relative to")
+        })?;
+        let from = self.from_offset.map(|offset| start + offset);
+        let to = self.to_offset.map(|offset| start + offset);
+        Ok(storage::TimeRange {
+            from: match (self.from, from) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            },
+            to: match (self.to, to) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        })
+    }
+
+    /// Whether a trace is of one of the processes and threads to report on
+    fn selects(&self, trace: &StackTrace) -> bool {
+        let pid = trace.pid.map_or(false, |pid| self.pids.contains(&pid));
+        let thread = [trace.thread_id, trace.os_thread_id]
+            .iter()
+            .flatten()
+            .any(|thread| self.threads.contains(thread));
+        (self.pids.is_empty() || pid) && (self.threads.is_empty() || thread)
+    }
+}
 
 /// Generate visualization (e.g. a flamegraph) from raw data that was previously recorded by rbspy
 pub fn report(
@@ -47,11 +212,417 @@ pub fn report(
     input: &mut dyn std::io::Read,
     output: &mut dyn std::io::Write,
 ) -> Result<(), Error> {
-    let traces = storage::from_reader(input)?.traces;
-    let mut outputter = format.outputter(0.1);
-    for trace in traces {
-        outputter.record(&trace)?;
+    report_with_config(format, ReportConfig::default(), input, output)
+}
+
+/// Like `report`, but with options for which traces are reported on and how
+pub fn report_with_config(
+    format: OutputFormat,
+    config: ReportConfig,
+    input: &mut dyn std::io::Read,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    let data = storage::from_reader_in_format(input, config.input_format)?;
+    write_report(format, config, data, output)
+}
+
+/// Like `report`, but reads the raw data from a file. If the recording was indexed (see
+/// `RecordConfig::index_raw`) and a start time is given, only the part of the file around the
+/// requested time range is read.
+pub fn report_file(
+    format: OutputFormat,
+    config: ReportConfig,
+    path: &std::path::Path,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    let data = read_file(&config, path)?;
+    write_report(format, config, data, output)
+}
+
+/// Like `report_file`, but merges several recordings into one report, e.g. the recordings that
+/// each host in a fleet made of the same program. Paths can have `*` and `?` wildcards in their
+/// file name, e.g. `profiles/*.raw.gz`. Traces keep the PIDs and threads they were recorded with.
+/// The recordings have to agree on whether they're of on-CPU samples and of every thread, and
+/// each one is authenticated against `config.hmac_key` on its own.
+pub fn report_files(
+    format: OutputFormat,
+    config: ReportConfig,
+    paths: &[std::path::PathBuf],
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    let mut recordings = Vec::new();
+    for path in expand_paths(paths)? {
+        let data = read_file(&config, &path)?;
+        data.integrity
+            .verify(config.hmac_key.as_deref())
+            .context(format!("Failed to verify {}", path.display()))?;
+        recordings.push((path, data));
+    }
+    let data = storage::merge(recordings)?;
+    let config = ReportConfig {
+        hmac_key: None,
+        ..config
+    };
+    write_report(format, config, data, output)
+}
+
+fn read_file(config: &ReportConfig, path: &std::path::Path) -> Result<storage::v3::Data, Error> {
+    match config.input_format {
+        InputFormat::raw => {
+            storage::from_path_in_range(path, &config.time_range(), config.hmac_key.is_some())
+                .context(format!("Failed to read {}", path.display()))
+        }
+        format => {
+            let file = std::fs::File::open(path)
+                .context(format!("Failed to open {}", path.display()))?;
+            storage::from_reader_in_format(file, format)
+        }
+    }
+}
+
+/// Expands the wildcards in the paths' file names, in the order of the paths and then of the
+/// matching files' names
+fn expand_paths(paths: &[std::path::PathBuf]) -> Result<Vec<std::path::PathBuf>, Error> {
+    let mut expanded = Vec::new();
+    for path in paths {
+        let pattern = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.contains(['*', '?']) => name,
+            _ => {
+                expanded.push(path.clone());
+                continue;
+            }
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        let mut matches: Vec<std::path::PathBuf> = std::fs::read_dir(dir)
+            .context(format!("Failed to list {}", dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                name.to_str()
+                    .map_or(false, |name| matches_wildcards(pattern, name))
+            })
+            .map(|entry| path.with_file_name(entry.file_name()))
+            .collect();
+        if matches.is_empty() {
+            return Err(format_err!("No recordings match {}", path.display()));
+        }
+        matches.sort();
+        expanded.extend(matches);
+    }
+    Ok(expanded)
+}
+
+/// Whether a file name matches a pattern where `*` stands for any characters and `?` for any one
+/// character. Hidden files only match patterns that start with a `.`, as in a shell.
+fn matches_wildcards(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Matches greedily, and on a mismatch lets the last `*` take one more character. Earlier `*`s
+    // never need to be retried, which keeps this to O(pattern * name) steps.
+    let (mut p, mut n) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match last_star {
+                Some((star, skipped)) => {
+                    last_star = Some((star, skipped + 1));
+                    p = star + 1;
+                    n = skipped + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Reads the headers of a raw recording: the recording's own, followed by one for each session
+/// that was appended to it (see `RecordConfig::append`)
+pub fn read_metadata(input: &mut dyn std::io::Read) -> Result<Vec<Header>, Error> {
+    Ok(headers(storage::from_reader(input)?))
+}
+
+/// Reads when each checkpoint of a raw recording was written (see
+/// `RecordConfig::checkpoint_interval`), on both the wall clock and the monotonic clock. Together
+/// with the start of the recording in its header, these line traces up with the monotonic clock.
+pub fn read_checkpoints(input: &mut dyn std::io::Read) -> Result<Vec<Timestamp>, Error> {
+    Ok(storage::from_reader(input)?.checkpoints)
+}
+
+fn headers(data: storage::v3::Data) -> Vec<Header> {
+    let mut headers = vec![data.header];
+    headers.extend(data.epochs.into_iter().map(|epoch| epoch.header));
+    headers
+}
+
+/// Writes the headers and checkpoints of a raw recording in a human-readable form, e.g. to find
+/// out which process and options a recording was made with
+pub fn show_metadata(
+    input: &mut dyn std::io::Read,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    let mut data = storage::from_reader(input)?;
+    let checkpoints = std::mem::take(&mut data.checkpoints);
+    let headers = headers(data);
+    for (i, header) in headers.iter().enumerate() {
+        if headers.len() > 1 {
+            if i > 0 {
+                writeln!(output)?;
+            }
+            writeln!(output, "Session {}", i + 1)?;
+        }
+        write!(output, "{}", header)?;
+    }
+    if !checkpoints.is_empty() {
+        writeln!(output, "Checkpoints:")?;
+        for checkpoint in checkpoints {
+            let time: chrono::DateTime<chrono::Local> = checkpoint.time.into();
+            match checkpoint.monotonic {
+                Some(monotonic) => writeln!(output, "  {} ({:?})", time.to_rfc3339(), monotonic)?,
+                None => writeln!(output, "  {}", time.to_rfc3339())?,
+            }
+        }
     }
+    Ok(())
+}
+
+/// Replays a fixture made with `generate_testdata`, reading its stack trace the same way as from
+/// a live process, and reports on it like a recording. This reproduces a stack-walking bug from
+/// another machine without needing the process.
+pub fn replay(
+    format: OutputFormat,
+    config: ReportConfig,
+    input: &mut dyn std::io::Read,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    let fixture = Fixture::from_reader(input)?;
+    let data = storage::v3::Data {
+        header: Header {
+            ruby_version: Some(fixture.ruby_version.clone()),
+            ..Default::default()
+        },
+        traces: fixture.replay()?.into_iter().collect(),
+        epochs: vec![],
+        notes: vec![],
+        checkpoints: vec![],
+        gaps: vec![],
+        integrity: storage::integrity::Integrity::Unavailable,
+    };
+    write_report(format, config, data, output)
+}
+
+/// Adds up the traces in a raw recording into a profile, which can be merged with or compared to
+/// other profiles (see `merge` and `diff`). `config` picks the traces the same way as for
+/// `report`.
+pub fn load_profile(config: ReportConfig, input: &mut dyn std::io::Read) -> Result<Profile, Error> {
+    let data = storage::from_reader_in_format(input, config.input_format)?;
+    let mut profile = ui::output::Viewed::new(Profile::new(), config.view)
+        .with_granularity(config.granularity)
+        .with_path_map(config.path_map.clone());
+    record_report(&config, data, &mut |trace| profile.record(trace))?;
+    Ok(profile.into_inner())
+}
+
+/// Writes a differential flamegraph comparing two recordings, e.g. from before and after an
+/// optimization: the stacks of `after`, with each frame colored by whether it took a bigger (red)
+/// or smaller (blue) share of the time than in `before`. `config` picks the traces from both
+/// recordings the same way as for `report`.
+pub fn report_diff(
+    config: ReportConfig,
+    before: &mut dyn std::io::Read,
+    after: &mut dyn std::io::Read,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    let before = load_profile(config.clone(), before)?;
+    let after = load_profile(config, after)?;
+    diff_flamegraph(&before, &after, output)
+}
+
+fn write_report(
+    format: OutputFormat,
+    config: ReportConfig,
+    data: storage::v3::Data,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    let mut outputter = ui::output::Viewed::new(
+        format.outputter_with_granularity(0.1, config.granularity),
+        config.view,
+    )
+    .with_path_map(config.path_map.clone());
+    outputter.set_summary_options(&config.summary_options);
+    outputter.set_flamegraph_palette(config.flame_palette);
+    let metadata = record_report(&config, data, &mut |trace| outputter.record(trace))?;
+    outputter.set_metadata(&metadata);
     outputter.complete(output)?;
     Ok(())
 }
+
+/// Picks the traces that a report is on and weights them, and describes what they were
+/// recorded from
+fn record_report(
+    config: &ReportConfig,
+    data: storage::v3::Data,
+    record: &mut dyn FnMut(&StackTrace) -> Result<(), Error>,
+) -> Result<ProfileMetadata, Error> {
+    data.integrity.verify(config.hmac_key.as_deref())?;
+    for note in &data.notes {
+        warn!("{}", note);
+    }
+    // Recordings from before rbspy measured sample intervals are weighted by their nominal
+    // sample rate instead, so that all traces are weighted in the same units. Each appended
+    // recording session has its own sample rate. A rate of 0 (e.g. from a damaged header) doesn't
+    // give an interval.
+    let nominal_interval = |rate: Option<u32>| {
+        rate.filter(|&rate| rate > 0)
+            .map(|rate| std::time::Duration::from_secs(1) / rate)
+    };
+    let mut interval = nominal_interval(data.header.sample_rate);
+    let gaps = data.gaps;
+    let mut epochs = data.epochs.into_iter().peekable();
+    let start = data
+        .header
+        .start_time
+        .or_else(|| data.traces.iter().find_map(|trace| trace.time));
+    let range = config.time_range_from(start)?;
+    let mut metadata = ProfileMetadata {
+        cmdline: data.header.cmdline.clone(),
+        exe: data.header.exe.clone(),
+        host: data.header.host.clone(),
+        ruby_version: data.header.ruby_version.clone(),
+        rbspy_version: data.header.rbspy_version.clone(),
+        sample_rate: data.header.sample_rate,
+        start_time: data.header.start_time,
+        flags: data.header.flags.clone(),
+        ..Default::default()
+    };
+    let mut first_trace = true;
+    let mut first_time: Option<std::time::SystemTime> = None;
+    let mut last_time = None;
+    for (i, mut trace) in data.traces.into_iter().enumerate() {
+        while let Some(epoch) = epochs.next_if(|epoch| epoch.first_trace <= i) {
+            interval = nominal_interval(epoch.header.sample_rate);
+        }
+        if !range.contains(trace.time) || !config.selects(&trace) {
+            continue;
+        }
+        if trace.interval.is_none() {
+            trace.interval =
+                interval.map(|interval| interval.saturating_mul(trace.repeats.saturating_add(1)));
+        }
+        if first_trace {
+            metadata.labels = trace.labels.clone();
+            first_trace = false;
+        } else {
+            metadata.keep_common_labels(&trace);
+        }
+        metadata.add_gems(&trace);
+        // Merged recordings' traces aren't in order of time
+        first_time = match (first_time, trace.time) {
+            (Some(first), Some(time)) => Some(first.min(time)),
+            (first, time) => first.or(time),
+        };
+        last_time = last_time.max(trace.time);
+        record(&trace)?;
+    }
+    if let (Some(first), Some(last)) = (first_time, last_time) {
+        // Sampling was paused during gaps, so they aren't part of what the profile covers
+        let paused: std::time::Duration = gaps
+            .iter()
+            .filter(|gap| gap.from >= first && gap.to <= last)
+            .filter_map(|gap| gap.to.duration_since(gap.from).ok())
+            .sum();
+        metadata.duration = last
+            .duration_since(first)
+            .ok()
+            .map(|duration| duration.saturating_sub(paused));
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_report_filters() {
+        let start = SystemTime::now();
+        let config = ReportConfig {
+            from_offset: Some(Duration::from_secs(300)),
+            to_offset: Some(Duration::from_secs(330)),
+            to: Some(start + Duration::from_secs(320)),
+            pids: vec![1],
+            threads: vec![7],
+            ..Default::default()
+        };
+        let range = config.time_range_from(Some(start)).unwrap();
+        assert_eq!(range.from, Some(start + Duration::from_secs(300)));
+        assert_eq!(range.to, Some(start + Duration::from_secs(320)));
+        assert!(config.time_range_from(None).is_err());
+
+        let mut trace = StackTrace::new_empty();
+        trace.pid = Some(1);
+        trace.os_thread_id = Some(7);
+        assert!(config.selects(&trace));
+        trace.pid = Some(2);
+        assert!(!config.selects(&trace));
+        assert!(ReportConfig::default().selects(&trace));
+    }
+
+    #[test]
+    fn test_report_weights() {
+        let mut trace = StackTrace::new_empty();
+        trace.repeats = u32::MAX;
+        let mut data = storage::v3::Data {
+            header: Header::new(1),
+            traces: vec![trace.clone(), trace],
+            epochs: Vec::new(),
+            notes: Vec::new(),
+            checkpoints: Vec::new(),
+            gaps: Vec::new(),
+            integrity: storage::integrity::Integrity::Unavailable,
+        };
+        data.epochs.push(storage::v3::Epoch {
+            header: Header::new(0),
+            first_trace: 1,
+        });
+        let mut intervals = Vec::new();
+        record_report(&ReportConfig::default(), data, &mut |trace| {
+            intervals.push(trace.interval);
+            Ok(())
+        })
+        .unwrap();
+        let longest = Duration::from_secs(u64::from(u32::MAX));
+        assert_eq!(intervals, vec![Some(longest), None]);
+    }
+
+    #[test]
+    fn test_matches_wildcards() {
+        assert!(matches_wildcards("*.raw.gz", "web-1.raw.gz"));
+        assert!(matches_wildcards("web-?.raw.gz", "web-1.raw.gz"));
+        assert!(matches_wildcards("web-*", "web-"));
+        assert!(!matches_wildcards("web-?.raw.gz", "web-12.raw.gz"));
+        assert!(!matches_wildcards("*.raw.gz", "web-1.raw.gz.idx"));
+        assert!(!matches_wildcards("*", ".hidden.raw.gz"));
+        assert!(matches_wildcards(".*", ".hidden.raw.gz"));
+        assert!(matches_wildcards("*web*?.raw*", "app-web-1.raw.gz"));
+        assert!(!matches_wildcards(
+            &"*a".repeat(30),
+            &format!("{}b", "a".repeat(40))
+        ));
+    }
+}
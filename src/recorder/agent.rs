@@ -0,0 +1,381 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error, Result};
+
+use crate::core::kubernetes::{self, PodContainer};
+use crate::core::process::Pid;
+use crate::core::types::{DutyCycle, OutputFormat};
+use crate::recorder::record::{Config as RecordConfig, Recorder};
+
+/// A configuration bundle for profiling the Ruby containers on a Kubernetes node, e.g. from a
+/// DaemonSet. rbspy has to run in the host's PID namespace (`hostPID: true`) with the
+/// `SYS_PTRACE` capability to see and read the processes in other pods.
+pub struct Config {
+    /// Where the kubelet lists the pods on this node. Only plain `http://` URLs are supported,
+    /// so the authenticated HTTPS port (10250) can't be used. Most clusters turn off the
+    /// read-only port, and there this should be `None`: the pods are then found from the
+    /// container runtime's state on the host (Docker's, containerd's or CRI-O's), which has to
+    /// be mounted into rbspy's container at the same paths (`/var/lib/docker/containers`,
+    /// `/run/containerd` or `/run/containers/storage`). The runtime doesn't know the pods'
+    /// labels or annotations, so the selectors need the kubelet. Default:
+    /// `http://localhost:10255/pods` (the kubelet's read-only port).
+    pub pods_url: Option<String>,
+    /// Only profile pods that have all of these labels, with the same values, e.g.
+    /// `app=web`. Default: none (any labels).
+    pub label_selector: BTreeMap<String, String>,
+    /// Only profile pods that have all of these annotations, with the same values, e.g.
+    /// `rbspy.io/profile=true`. Default: none (any annotations).
+    pub annotation_selector: BTreeMap<String, String>,
+    /// How often to look for new pods and processes to profile. Default: 30 seconds.
+    pub discovery_interval: Duration,
+    /// How much of the time to sample each process for. Default: 10 seconds of every minute.
+    pub duty_cycle: DutyCycle,
+    /// The number of traces that should be collected each second. Default: `100`.
+    pub sample_rate: u32,
+    /// Whether to only keep samples of threads that are running on the CPU. Default: `false`.
+    pub on_cpu: bool,
+    /// The format of the profiles. Default: `flamegraph`.
+    pub format: OutputFormat,
+    /// Where to write the profiles. Each process gets a directory `<namespace>/<pod>`, with a
+    /// raw recording named after the container and the PID (`web-1234.raw.gz`) and a profile of
+    /// each burst of sampling named after the time it started. Traces are labelled with their
+    /// `namespace`, `pod` and `container`.
+    pub out_dir: PathBuf,
+}
+
+/// Finds the Ruby processes in the pods on this node that match the configured selectors, and
+/// profiles each of them on a duty cycle until it exits
+pub struct Agent {
+    config: Config,
+    recordings: Mutex<HashMap<Pid, Recording>>,
+    done: AtomicBool,
+}
+
+struct Recording {
+    container: PodContainer,
+    recorder: Arc<Recorder>,
+    thread: JoinHandle<()>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Agent {
+    pub fn new(config: Config) -> Result<Self> {
+        config.duty_cycle.validate()?;
+        match &config.pods_url {
+            Some(pods_url) if pods_url.starts_with("https://") => {
+                return Err(anyhow::format_err!(
+                    "The kubelet can only be asked for its pods over plain HTTP, not at {}. Leave the pods URL out to find the pods from the container runtime's state instead.",
+                    pods_url
+                ));
+            }
+            None if !config.label_selector.is_empty() || !config.annotation_selector.is_empty() => {
+                return Err(anyhow::format_err!(
+                    "Pods can only be selected by their labels or annotations with the kubelet's pod list, since the container runtime doesn't know them"
+                ));
+            }
+            _ => {}
+        }
+        Ok(Agent {
+            config,
+            recordings: Mutex::new(HashMap::new()),
+            done: AtomicBool::new(false),
+        })
+    }
+
+    /// Discovers and profiles processes until the stop function is called. Fails if the kubelet
+    /// (or the processes) can't be listed when the agent starts; later failures are logged and
+    /// retried.
+    pub fn run(&self) -> Result<(), Error> {
+        let mut first_discovery = true;
+        while !self.done.load(Ordering::Relaxed) {
+            self.remove_finished();
+            match self.discover() {
+                Ok(targets) => {
+                    for (pid, container) in targets {
+                        if let Err(e) = self.start(pid, container) {
+                            warn!("Failed to start profiling process {}: {:?}", pid, e);
+                        }
+                    }
+                }
+                Err(e) if first_discovery => return Err(e),
+                Err(e) => warn!("Failed to discover processes to profile: {:?}", e),
+            }
+            first_discovery = false;
+
+            let wait_start = Instant::now();
+            while wait_start.elapsed() < self.config.discovery_interval
+                && !self.done.load(Ordering::Relaxed)
+            {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+
+        let recordings: Vec<Recording> = {
+            let mut recordings = self.recordings.lock().unwrap();
+            recordings.drain().map(|(_, recording)| recording).collect()
+        };
+        for recording in &recordings {
+            recording.recorder.stop();
+        }
+        for recording in recordings {
+            let _ = recording.thread.join();
+        }
+        Ok(())
+    }
+
+    /// Stops the agent and every recording it started
+    pub fn stop(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+
+    /// The processes that are being profiled, and the containers they're in
+    pub fn profiled(&self) -> Vec<(Pid, PodContainer)> {
+        let recordings = self.recordings.lock().unwrap();
+        let mut profiled: Vec<(Pid, PodContainer)> = recordings
+            .iter()
+            .map(|(&pid, recording)| (pid, recording.container.clone()))
+            .collect();
+        profiled.sort_by_key(|(pid, _)| *pid);
+        profiled
+    }
+
+    /// Finds the Ruby processes in the containers of the pods that match the selectors
+    fn discover(&self) -> Result<Vec<(Pid, PodContainer)>> {
+        // Without the kubelet, each process's container is looked up in the runtime's state
+        let containers: Option<HashMap<String, PodContainer>> = match &self.config.pods_url {
+            Some(pods_url) => Some(
+                kubernetes::list_containers(pods_url)?
+                    .into_iter()
+                    .filter(|container| self.selects(container))
+                    .map(|container| (container.id.clone(), container))
+                    .collect(),
+            ),
+            None => None,
+        };
+        if containers
+            .as_ref()
+            .map_or(false, |containers| containers.is_empty())
+        {
+            return Ok(vec![]);
+        }
+        let mut targets = Vec::new();
+        for process in crate::core::discovery::list_ruby_processes()? {
+            if !process.profilable {
+                continue;
+            }
+            let container = match &containers {
+                Some(containers) => kubernetes::container_id(process.pid)
+                    .and_then(|id| containers.get(&id))
+                    .cloned(),
+                None => kubernetes::runtime_container(process.pid),
+            };
+            if let Some(container) = container {
+                targets.push((process.pid, container));
+            }
+        }
+        Ok(targets)
+    }
+
+    fn selects(&self, container: &PodContainer) -> bool {
+        let matches = |selector: &BTreeMap<String, String>, values: &BTreeMap<String, String>| {
+            selector
+                .iter()
+                .all(|(key, value)| values.get(key) == Some(value))
+        };
+        matches(&self.config.label_selector, &container.pod_labels)
+            && matches(&self.config.annotation_selector, &container.pod_annotations)
+    }
+
+    /// Starts profiling a process in a new thread, unless it's already being profiled
+    fn start(&self, pid: Pid, container: PodContainer) -> Result<()> {
+        let mut recordings = self.recordings.lock().unwrap();
+        if recordings.contains_key(&pid) {
+            return Ok(());
+        }
+        let dir = self
+            .config
+            .out_dir
+            .join(&container.namespace)
+            .join(&container.pod);
+        std::fs::create_dir_all(&dir).context(format!(
+            "Failed to create output directory {}",
+            dir.display()
+        ))?;
+        info!(
+            "Profiling process {} in {}/{} ({})",
+            pid, container.namespace, container.pod, container.name
+        );
+
+        let recorder = Arc::new(Recorder::new(self.record_config(pid, &container)));
+        let finished = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let recorder = recorder.clone();
+            let finished = finished.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = recorder.record() {
+                    warn!("Stopped profiling process {}: {:?}", pid, e);
+                }
+                finished.store(true, Ordering::Relaxed);
+            })
+        };
+        recordings.insert(
+            pid,
+            Recording {
+                container,
+                recorder,
+                thread,
+                finished,
+            },
+        );
+        Ok(())
+    }
+
+    /// Forgets the recordings of processes that have exited, so that a new process that reuses
+    /// the PID is profiled too
+    fn remove_finished(&self) {
+        let mut recordings = self.recordings.lock().unwrap();
+        let finished: Vec<Pid> = recordings
+            .iter()
+            .filter(|(_, recording)| recording.finished.load(Ordering::Relaxed))
+            .map(|(&pid, _)| pid)
+            .collect();
+        for pid in finished {
+            if let Some(recording) = recordings.remove(&pid) {
+                let _ = recording.thread.join();
+            }
+        }
+    }
+
+    fn record_config(&self, pid: Pid, container: &PodContainer) -> RecordConfig {
+        let dir = self
+            .config
+            .out_dir
+            .join(&container.namespace)
+            .join(&container.pod);
+        let name = format!("{}-{}", container.name, pid);
+        let labels = container.profile_labels();
+        RecordConfig {
+            format: self.config.format.clone(),
+            raw_path: Some(dir.join(format!("{}.raw.gz", name))),
+            append: true,
+            out_path: Some(dir.join(format!(
+                "{}-{{time}}.{}",
+                name,
+                self.config.format.extension()
+            ))),
+            pid,
+            sample_rate: self.config.sample_rate,
+            on_cpu: self.config.on_cpu,
+            on_cpu_fallback: true,
+            checkpoint_interval: Some(self.config.duty_cycle.period),
+            rotate_every: Some(self.config.duty_cycle.period),
+            enrichment_hooks: vec![Box::new(move |trace| {
+                trace.labels.extend(labels.clone());
+            })],
+            duty_cycle: Some(self.config.duty_cycle),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            pods_url: Some("http://localhost:10255/pods".to_string()),
+            label_selector: BTreeMap::new(),
+            annotation_selector: BTreeMap::new(),
+            discovery_interval: Duration::from_secs(30),
+            duty_cycle: DutyCycle {
+                on: Duration::from_secs(10),
+                period: Duration::from_secs(60),
+            },
+            sample_rate: 100,
+            on_cpu: false,
+            format: OutputFormat::flamegraph,
+            out_dir: PathBuf::from("."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selects_and_record_config() {
+        let mut config = Config {
+            out_dir: PathBuf::from("/profiles"),
+            ..Default::default()
+        };
+        config
+            .annotation_selector
+            .insert("rbspy.io/profile".to_string(), "true".to_string());
+        let agent = Agent::new(config).unwrap();
+
+        let mut container = PodContainer {
+            namespace: "shop".to_string(),
+            pod: "web-7d9f".to_string(),
+            pod_uid: "u1".to_string(),
+            name: "puma".to_string(),
+            id: "abc".to_string(),
+            pod_labels: BTreeMap::new(),
+            pod_annotations: BTreeMap::new(),
+        };
+        assert!(!agent.selects(&container));
+        container
+            .pod_annotations
+            .insert("rbspy.io/profile".to_string(), "true".to_string());
+        assert!(agent.selects(&container));
+
+        let config = agent.record_config(1234, &container);
+        assert_eq!(
+            config.raw_path,
+            Some(PathBuf::from("/profiles/shop/web-7d9f/puma-1234.raw.gz"))
+        );
+        assert_eq!(
+            config.out_path,
+            Some(PathBuf::from(
+                "/profiles/shop/web-7d9f/puma-1234-{time}.flamegraph.svg"
+            ))
+        );
+        assert_eq!(config.rotate_every, Some(Duration::from_secs(60)));
+
+        let mut trace = crate::core::types::StackTrace::new_empty();
+        (config.enrichment_hooks[0])(&mut trace);
+        assert_eq!(trace.labels, container.profile_labels());
+
+        assert!(Agent::new(Config {
+            duty_cycle: DutyCycle {
+                on: Duration::from_secs(0),
+                period: Duration::from_secs(60),
+            },
+            ..Default::default()
+        })
+        .is_err());
+
+        // The container runtime doesn't know the pods' labels or annotations
+        assert!(Agent::new(Config {
+            pods_url: None,
+            ..Default::default()
+        })
+        .is_ok());
+        let mut config = Config {
+            pods_url: None,
+            ..Default::default()
+        };
+        config
+            .annotation_selector
+            .insert("rbspy.io/profile".to_string(), "true".to_string());
+        assert!(Agent::new(config).is_err());
+        assert!(Agent::new(Config {
+            pods_url: Some("https://localhost:10250/pods".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
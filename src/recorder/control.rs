@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{format_err, Context, Error, Result};
+
+/// A command written to the control FIFO, one per line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Start (or resume) sampling
+    Start,
+    /// Pause sampling until the next `start`
+    Stop,
+    /// Write the formatted output and checkpoint the raw output now
+    Flush,
+    /// Label the traces from now on with `key=value`. An empty value removes the label.
+    Label(String, String),
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        match (command, argument) {
+            ("start", "") => Ok(Command::Start),
+            ("stop", "") => Ok(Command::Stop),
+            ("flush", "") => Ok(Command::Flush),
+            ("label", label) => match label.split_once('=') {
+                Some((key, value)) if !key.is_empty() => {
+                    Ok(Command::Label(key.to_string(), value.to_string()))
+                }
+                _ => Err(format_err!("Expected `label key=value`, got `{}`", line)),
+            },
+            _ => Err(format_err!("Unknown control command `{}`", line)),
+        }
+    }
+}
+
+/// The state that the control FIFO's commands change, shared between the thread that reads them
+/// and the recorder
+pub(crate) struct Control {
+    path: PathBuf,
+    /// Whether the FIFO was made by rbspy, and so should be removed when the recording ends
+    created: bool,
+    paused: Arc<AtomicBool>,
+    labels: Mutex<BTreeMap<String, String>>,
+    flush_requested: AtomicBool,
+    done: AtomicBool,
+}
+
+impl Control {
+    /// `paused` is the sampler's pause flag, which `start` and `stop` commands set
+    pub fn new(path: &Path, paused: Arc<AtomicBool>) -> Control {
+        Control {
+            path: path.to_path_buf(),
+            created: false,
+            paused,
+            labels: Mutex::new(BTreeMap::new()),
+            flush_requested: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts reading commands in a new thread, creating the FIFO if there's nothing at its path.
+    /// Reading stops when the handle is dropped.
+    pub fn listen(mut self) -> Result<ControlHandle> {
+        if !self.path.exists() {
+            make_fifo(&self.path)?;
+            self.created = true;
+        }
+        let control = Arc::new(self);
+        let listener = control.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = listener.read_commands() {
+                warn!("Stopped reading control commands: {:?}", e);
+            }
+        });
+        Ok(ControlHandle(control))
+    }
+
+    fn read_commands(&self) -> Result<()> {
+        let is_fifo = is_fifo(&self.path);
+        while !self.done.load(Ordering::Relaxed) {
+            let mut file = File::open(&self.path)?;
+            if !is_fifo {
+                // Only commands written to a regular file after recording starts apply
+                file.seek(SeekFrom::End(0))?;
+            }
+            let mut reader = BufReader::new(file);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    if is_fifo || self.done.load(Ordering::Relaxed) {
+                        // Every writer closed the FIFO, so wait for the next one
+                        break;
+                    }
+                    // Regular files are followed like `tail -f`
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match line.parse() {
+                    Ok(command) => self.apply(command),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, command: Command) {
+        debug!("Control command: {:?}", command);
+        match command {
+            Command::Start => self.paused.store(false, Ordering::Relaxed),
+            Command::Stop => self.paused.store(true, Ordering::Relaxed),
+            Command::Flush => self.flush_requested.store(true, Ordering::Relaxed),
+            Command::Label(key, value) => {
+                let mut labels = self.labels.lock().unwrap();
+                if value.is_empty() {
+                    labels.remove(&key);
+                } else {
+                    labels.insert(key, value);
+                }
+            }
+        }
+    }
+
+    /// The labels that traces should have from now on
+    pub fn labels(&self) -> BTreeMap<String, String> {
+        self.labels.lock().unwrap().clone()
+    }
+
+    /// Whether a flush was requested since the last time this was called
+    pub fn take_flush_request(&self) -> bool {
+        self.flush_requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Stops reading commands, and removes the FIFO if rbspy made it
+    fn close(&self) {
+        self.done.store(true, Ordering::Relaxed);
+        // Unblock the reading thread if it's waiting for a writer
+        wake_reader(&self.path);
+        if self.created {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!(
+                    "Failed to remove control FIFO {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// A control FIFO that commands are being read from
+pub(crate) struct ControlHandle(Arc<Control>);
+
+impl std::ops::Deref for ControlHandle {
+    type Target = Control;
+
+    fn deref(&self) -> &Control {
+        &self.0
+    }
+}
+
+impl Drop for ControlHandle {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(unix)]
+fn make_fifo(path: &Path) -> Result<()> {
+    use nix::sys::stat::Mode;
+    nix::unistd::mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)
+        .context(format!("Failed to create control FIFO {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn make_fifo(path: &Path) -> Result<()> {
+    Err(format_err!(
+        "FIFOs aren't supported on this platform; create {} as a regular file to control recording with",
+        path.display()
+    ))
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata(path).map_or(false, |metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn wake_reader(path: &Path) {
+    use std::os::unix::fs::OpenOptionsExt;
+    if is_fifo(path) {
+        // Fails without blocking if nothing is reading the FIFO
+        let _ = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path);
+    }
+}
+
+#[cfg(not(unix))]
+fn wake_reader(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("start".parse::<Command>().unwrap(), Command::Start);
+        assert_eq!(" stop \n".parse::<Command>().unwrap(), Command::Stop);
+        assert_eq!("flush".parse::<Command>().unwrap(), Command::Flush);
+        assert_eq!(
+            "label phase=report generation".parse::<Command>().unwrap(),
+            Command::Label("phase".to_string(), "report generation".to_string())
+        );
+        assert_eq!(
+            "label phase=".parse::<Command>().unwrap(),
+            Command::Label("phase".to_string(), "".to_string())
+        );
+        assert!("label phase".parse::<Command>().is_err());
+        assert!("label =x".parse::<Command>().is_err());
+        assert!("start now".parse::<Command>().is_err());
+        assert!("restart".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_apply_commands() {
+        let paused = Arc::new(AtomicBool::new(true));
+        let control = Control::new(Path::new("control"), paused.clone());
+        control.apply(Command::Start);
+        assert!(!paused.load(Ordering::Relaxed));
+        control.apply(Command::Stop);
+        assert!(paused.load(Ordering::Relaxed));
+
+        control.apply(Command::Flush);
+        assert!(control.take_flush_request());
+        assert!(!control.take_flush_request());
+
+        control.apply(Command::Label("phase".to_string(), "report".to_string()));
+        control.apply(Command::Label("job".to_string(), "1".to_string()));
+        control.apply(Command::Label("job".to_string(), "".to_string()));
+        let mut labels = BTreeMap::new();
+        labels.insert("phase".to_string(), "report".to_string());
+        assert_eq!(control.labels(), labels);
+    }
+}
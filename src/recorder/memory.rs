@@ -0,0 +1,100 @@
+use anyhow::{Error, Result};
+use std::collections::HashMap;
+
+use crate::core::audit::AuditEvent;
+use crate::core::types::{Granularity, StackTrace};
+use crate::recorder::record::{audit_options, new_audit_log, new_sampler, Config};
+use crate::sampler::sampler_result;
+use crate::ui::output::{Outputter, Viewed};
+use crate::ui::{flamegraph, summary};
+
+/// A profile aggregated in memory by `record_in_memory`
+#[derive(Clone, Debug)]
+pub struct Profile {
+    /// The weight of each distinct stack, keyed by the stack in collapsed format: frames from the
+    /// root to the leaf, separated by `;`
+    pub folded: HashMap<String, u64>,
+    /// The stats for each function (or line, at line granularity), with the most self weight
+    /// first
+    pub functions: Vec<summary::FunctionStats>,
+    /// The combined weight of every trace in the profile
+    pub total_weight: u64,
+    /// The number of traces in the profile
+    pub traces: usize,
+}
+
+/// Records a profile without writing anything to the filesystem. The output and raw output
+/// options in the config are ignored, and the sampling options are used as they are by
+/// `Recorder`.
+///
+/// Recording continues until the process exits, so set `maybe_duration` to bound it.
+pub fn record_in_memory(config: Config) -> Result<Profile, Error> {
+    let audit_log = new_audit_log(&config);
+    let sampler = new_sampler(&config, audit_log.clone());
+    let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(100);
+    let (result_sender, result_receiver) = std::sync::mpsc::channel();
+    if let Some(audit_log) = &audit_log {
+        audit_log.record(
+            AuditEvent::Start,
+            Some(config.pid),
+            Some(&audit_options(&config)),
+        )?;
+    }
+    sampler.start(trace_sender, result_sender)?;
+
+    let mut profile = Viewed::new(InMemory::new(config.granularity), config.view)
+        .with_granularity(config.granularity)
+        .with_path_map(config.path_map.clone());
+    for trace in trace_receiver {
+        profile.record(&trace)?;
+    }
+    let result = sampler_result(result_receiver);
+    if let Some(audit_log) = &audit_log {
+        audit_log.record(AuditEvent::Stop, Some(config.pid), None)?;
+    }
+    result?;
+
+    let profile = profile.into_inner();
+    Ok(Profile {
+        folded: profile.folded.counts,
+        functions: profile.functions.functions(),
+        total_weight: profile.functions.total_weight(),
+        traces: profile.traces,
+    })
+}
+
+/// Adds up the traces that `record_in_memory` keeps
+struct InMemory {
+    granularity: Granularity,
+    folded: flamegraph::Stats,
+    functions: summary::Stats,
+    traces: usize,
+}
+
+impl InMemory {
+    fn new(granularity: Granularity) -> InMemory {
+        InMemory {
+            granularity,
+            folded: flamegraph::Stats::default(),
+            functions: summary::Stats::new(),
+            traces: 0,
+        }
+    }
+}
+
+impl Outputter for InMemory {
+    fn record(&mut self, trace: &StackTrace) -> Result<()> {
+        self.traces += 1;
+        match self.granularity {
+            Granularity::function => self
+                .functions
+                .add_function_name(&trace.trace, trace.weight()),
+            Granularity::line => self.functions.add_lineno(&trace.trace, trace.weight()),
+        }
+        self.folded.record(&trace.trace, trace.weight())
+    }
+
+    fn complete(&mut self, _write: &mut dyn std::io::Write) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,6 +1,27 @@
+mod agent;
+mod control;
+mod memory;
+mod pyroscope;
 mod record;
+mod serve;
+mod signals;
 mod snapshot;
+#[cfg(unix)]
+mod top;
+mod watch;
+mod window;
 
+pub use agent::Agent;
+pub use agent::Config as AgentConfig;
+pub use memory::{record_in_memory, Profile};
+pub use pyroscope::Config as PyroscopeConfig;
 pub use record::Config as RecordConfig;
 pub use record::Recorder;
+pub use serve::Server;
 pub use snapshot::snapshot;
+#[cfg(unix)]
+pub use snapshot::snapshot_on_signal;
+#[cfg(unix)]
+pub use top::Top;
+pub use watch::Config as WatchConfig;
+pub use watch::{Alert, WatchAction, Watcher};
@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::core::http;
+use crate::core::types::{Granularity, OutputFormat, PathMapping, ProfileView, StackTrace};
+use crate::ui::output::{Outputter, Viewed};
+
+/// How many pushes can wait for the server before new ones are dropped
+const CAPACITY: usize = 16;
+/// Traces are weighted by the time since the previous sample in microseconds, so that's the unit
+/// that Pyroscope is told the samples are taken at. In the `alloc` view, they're weighted by
+/// allocated objects instead, which Pyroscope is told with the series' units.
+const SAMPLE_RATE: u32 = 1_000_000;
+
+/// Where and how to push profiles to a Pyroscope server while recording
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The server's URL, e.g. `http://pyroscope:4040`. Only plain `http://` URLs are supported.
+    pub url: String,
+    /// The application that the profiles are stored under, e.g. `myapp`
+    pub app_name: String,
+    /// Tags for every profile, e.g. `env=prod`. Default: none.
+    pub tags: BTreeMap<String, String>,
+    /// How often to push the samples taken since the last push. Default: 10 seconds.
+    pub interval: Duration,
+    /// Whether to tag samples with the Ruby thread they were taken from (`thread`), as well as
+    /// their process (`pid`) and the traces' labels. Every thread is a separate series, so this
+    /// is best left off for processes that start a thread per request. Default: `false`.
+    pub thread_labels: bool,
+}
+
+impl Config {
+    pub fn new(url: &str, app_name: &str) -> Config {
+        Config {
+            url: url.to_string(),
+            app_name: app_name.to_string(),
+            tags: BTreeMap::new(),
+            interval: Duration::from_secs(10),
+            thread_labels: false,
+        }
+    }
+}
+
+/// Aggregates traces into folded stacks, one series per set of tags, and pushes them to
+/// Pyroscope's `/ingest` endpoint on an interval. Pushes are sent from a thread of their own, so
+/// that a slow or unreachable server doesn't hold up the recorder; pushes that fail are logged and
+/// dropped.
+pub(crate) struct Exporter {
+    config: Config,
+    view: ProfileView,
+    granularity: Granularity,
+    path_map: Vec<PathMapping>,
+    series: BTreeMap<BTreeMap<String, String>, Viewed>,
+    period_start: SystemTime,
+    last_push: Instant,
+    pushes: Option<SyncSender<Push>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Push {
+    url: String,
+    body: Vec<u8>,
+}
+
+impl Exporter {
+    pub fn spawn(
+        config: Config,
+        view: ProfileView,
+        granularity: Granularity,
+        path_map: Vec<PathMapping>,
+    ) -> Result<Exporter> {
+        let (pushes, receiver) = mpsc::sync_channel::<Push>(CAPACITY);
+        let thread = std::thread::Builder::new()
+            .name("pyroscope".to_string())
+            .spawn(move || {
+                for push in receiver {
+                    if let Err(e) = http::post(&push.url, "text/plain", &push.body) {
+                        warn!("Failed to push a profile to Pyroscope: {:#}", e);
+                    }
+                }
+            })?;
+        Ok(Exporter {
+            config,
+            view,
+            granularity,
+            path_map,
+            series: BTreeMap::new(),
+            period_start: SystemTime::now(),
+            last_push: Instant::now(),
+            pushes: Some(pushes),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn record(&mut self, trace: &StackTrace) -> Result<()> {
+        if !self.view.includes(trace) {
+            return Ok(());
+        }
+        let mut tags = trace.labels.clone();
+        if let Some(pid) = trace.pid {
+            tags.insert("pid".to_string(), pid.to_string());
+        }
+        if let (true, Some(thread_id)) = (self.config.thread_labels, trace.thread_id) {
+            tags.insert("thread".to_string(), thread_id.to_string());
+        }
+        let (granularity, view, path_map) = (self.granularity, self.view, &self.path_map);
+        self.series
+            .entry(tags)
+            .or_insert_with(|| {
+                Viewed::new(
+                    OutputFormat::collapsed.outputter_with_granularity(0.0, granularity),
+                    view,
+                )
+                .with_path_map(path_map.clone())
+            })
+            .record(trace)
+    }
+
+    /// Pushes the samples taken since the last push, if it's been at least the interval
+    pub fn push_if_due(&mut self) -> Result<()> {
+        if self.last_push.elapsed() >= self.config.interval {
+            self.push()?;
+        }
+        Ok(())
+    }
+
+    /// Pushes the samples taken since the last push
+    pub fn push(&mut self) -> Result<()> {
+        let until = SystemTime::now();
+        let from = unix_seconds(self.period_start);
+        for (tags, mut series) in std::mem::take(&mut self.series) {
+            let mut body = Vec::new();
+            series.complete(&mut body)?;
+            let url = ingest_url(&self.config, self.view, &tags, from, unix_seconds(until));
+            let sent = self
+                .pushes
+                .as_ref()
+                .map(|pushes| pushes.try_send(Push { url, body }));
+            if let Some(Err(TrySendError::Full(_))) = sent {
+                warn!("Dropped a profile because Pyroscope isn't keeping up with the pushes");
+            }
+        }
+        self.period_start = until;
+        self.last_push = Instant::now();
+        Ok(())
+    }
+
+    /// Pushes the remaining samples and waits for every push to be sent
+    pub fn finish(mut self) -> Result<()> {
+        self.push()
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.pushes = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The URL to push a series to, which names it after the application and its tags, e.g.
+/// `myapp{env=prod,pid=1234}`
+fn ingest_url(
+    config: &Config,
+    view: ProfileView,
+    tags: &BTreeMap<String, String>,
+    from: u64,
+    until: u64,
+) -> String {
+    let mut tags = tags.clone();
+    tags.extend(config.tags.clone());
+    let tags: Vec<String> = tags
+        .iter()
+        .map(|(key, value)| format!("{}={}", tag_key(key), tag_value(value)))
+        .collect();
+    let name = format!("{}{{{}}}", config.app_name, tags.join(","));
+    format!(
+        "{}/ingest?name={}&from={}&until={}&format=folded&sampleRate={}&spyName=rbspy&units={}&aggregationType=sum",
+        config.url.trim_end_matches('/'),
+        encode(&name),
+        from,
+        until,
+        SAMPLE_RATE,
+        match view {
+            ProfileView::alloc => "objects",
+            _ => "samples",
+        }
+    )
+}
+
+/// Pyroscope's tag names are limited to ASCII letters, digits, `_` and `.`, and can't start with
+/// a digit
+fn tag_key(key: &str) -> String {
+    key.chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'a'..='z' | 'A'..='Z' | '_' => c,
+            '0'..='9' | '.' if i > 0 => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Tag values can't contain the characters that delimit the tags in a series' name
+fn tag_value(value: &str) -> String {
+    value.replace(|c| matches!(c, '{' | '}' | ',' | '='), "_")
+}
+
+/// Percent-encodes a query parameter
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{FrameKind, StackFrame};
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    fn frame(name: &str, lineno: usize) -> StackFrame {
+        StackFrame {
+            name: name.to_string(),
+            relative_path: "a.rb".to_string(),
+            absolute_path: None,
+            lineno: Some(lineno),
+            kind: FrameKind::Method,
+        }
+    }
+
+    #[test]
+    fn test_ingest_url() {
+        let mut config = Config::new("http://pyroscope:4040/", "myapp");
+        config.tags.insert("env".to_string(), "prod".to_string());
+        let mut tags = BTreeMap::new();
+        tags.insert("pid".to_string(), "1234".to_string());
+        tags.insert("2nd-key".to_string(), "a,b".to_string());
+        assert_eq!(
+            ingest_url(&config, ProfileView::wall, &tags, 10, 20),
+            "http://pyroscope:4040/ingest?name=myapp%7B_nd_key%3Da_b%2Cenv%3Dprod%2Cpid%3D1234%7D\
+             &from=10&until=20&format=folded&sampleRate=1000000&spyName=rbspy&units=samples\
+             &aggregationType=sum"
+        );
+        assert!(ingest_url(&config, ProfileView::alloc, &tags, 10, 20).contains("&units=objects&"));
+    }
+
+    #[test]
+    fn test_push() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            while !request.ends_with("\r\n\r\n") {
+                reader.read_line(&mut request).unwrap();
+            }
+            let length: usize = request
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            (request, String::from_utf8(body).unwrap())
+        });
+
+        let config = Config::new(&format!("http://127.0.0.1:{}", port), "myapp");
+        let mut exporter =
+            Exporter::spawn(config, ProfileView::wall, Granularity::line, vec![]).unwrap();
+        let mut trace = StackTrace::new_empty();
+        trace.pid = Some(1234);
+        trace.interval = Some(Duration::from_millis(10));
+        trace.trace = vec![frame("inner", 2), frame("outer", 1)];
+        exporter.record(&trace).unwrap();
+        exporter.record(&trace).unwrap();
+        exporter.finish().unwrap();
+
+        let (request, body) = server.join().unwrap();
+        assert!(request.starts_with("POST /ingest?name=myapp%7Bpid%3D1234%7D&from="));
+        assert_eq!(body, "outer - a.rb:1;inner - a.rb:2 20000\n");
+    }
+}
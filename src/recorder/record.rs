@@ -1,35 +1,82 @@
 use anyhow::{Context, Error, Result};
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Mutex};
 
-use crate::storage::Store;
+use crate::core::audit::{AuditEvent, AuditLog};
+use crate::core::process::Process;
+use crate::core::types::{Header, ProfileMetadata, SizeLimitAction, StackTrace};
+use crate::recorder::control::Control;
+use crate::recorder::pyroscope::Exporter;
+use crate::recorder::signals::PauseSignals;
+use crate::recorder::window::Window;
+use crate::storage::{Store, StoreWriter};
+use crate::ui::output::{Outputter, Viewed};
+use crate::ui::progress::Progress;
 use crate::ui::summary;
 
-/// A configuration bundle for the recorder
+/// A configuration bundle for the recorder. `Config::default()` has the defaults below, and
+/// writes a flamegraph; `pid` (0 by default) has to be set.
 pub struct Config {
     /// The format to use for recorded traces. See `OutputFormat` for a list of available options.
     pub format: crate::core::types::OutputFormat,
     /// Where to write rbspy's raw trace output, which can be used for later processing.
     pub raw_path: Option<PathBuf>,
+    /// Whether to continue the recording at `raw_path` if there already is one, instead of
+    /// replacing it. Each recording session is marked in the raw output. Default: `false`.
+    pub append: bool,
     /// Where to write rbspy's output. If `-` is given, output is written to standard output.
     pub out_path: Option<PathBuf>,
+    /// Where to write rbspy's output when `out_path` isn't given. The file is named after the
+    /// process and the time the recording started, e.g.
+    /// `rbspy-puma-1234-2023-01-02T150405.flamegraph.svg`, and its path is printed when the
+    /// recording finishes. Default: none (no output unless `out_path` is given).
+    pub out_dir: Option<PathBuf>,
     /// The process ID (PID) of the process to profile. This is usually a ruby process, but rbspy
     /// will locate and profile any ruby subprocesses of the target process if `with_subprocesses`
-    /// is enabled.
+    /// is enabled. To profile a container, `resolve_container` finds the PID of its init process.
     pub pid: crate::core::process::Pid,
+    /// Other processes to profile along with `pid`, e.g. several app servers that don't share a
+    /// parent. Each one is sampled by a thread of its own, and their traces are merged into the
+    /// same output, told apart by PID. With `with_subprocesses`, their child processes are
+    /// profiled too, and the recording runs until all of them have exited. Default: none.
+    pub extra_pids: Vec<crate::core::process::Pid>,
+    /// The pidfile that the target process writes its PID to, e.g. Puma's `--pidfile`.
+    /// `read_pidfile` reads `pid` from it. Default: none.
+    pub pidfile: Option<PathBuf>,
+    /// Whether to keep recording when the target process exits, by waiting for `pidfile` to name
+    /// the process that replaced it (e.g. after systemd or a deploy restarts Puma) and attaching
+    /// to that one. Its samples go into the same recording, and each restart is noted in the raw
+    /// output. The recording runs until it's stopped or `maybe_duration` passes. Requires
+    /// `pidfile`, and isn't supported with `with_subprocesses`. Default: `false`.
+    pub reattach: bool,
     /// Whether to profile the target process (given by `pid`) as well as its child processes, and
-    /// their child processes, and so on. Default: `false`.
+    /// their child processes, and so on, e.g. the workers that a Puma or Unicorn master forks.
+    /// New processes are found within a second, and their traces are merged into the same output,
+    /// told apart by PID. A process that execs another program (e.g. a shell or `bundle exec`
+    /// that execs Ruby) is attached to again. Default: `false`.
     pub with_subprocesses: bool,
     /// The number of traces that should be collected each second. Default: `100`.
     pub sample_rate: u32,
     /// The length of time that the recorder should run before stopping. Default: none (run until
     /// interrupted).
     pub maybe_duration: Option<std::time::Duration>,
+    /// The number of samples to record before stopping, e.g. to bound unattended recordings.
+    /// Samples left out by `skip_idle` don't count. The output is written as usual when the
+    /// limit is reached. To bound the size of the raw output instead, see `raw_size_limit`.
+    /// Default: none (no limit).
+    pub max_samples: Option<usize>,
     /// Minimum flame width. Applies to flamegraph output only. If your sample has many small
     /// functions in it and is difficult to read, then consider increasing this value.
     /// Default: 0.1.
     pub flame_min_width: f64,
+    /// How to color the frames of flamegraph output. The `category` palette colors the
+    /// application's own code, gems, the standard library and C functions differently.
+    /// Default: `hot`.
+    pub flame_palette: crate::core::types::FlamegraphPalette,
     /// Locks the process when a sample is being taken.
     ///
     /// You should enable this option for the most accurate samples. However, it briefly
@@ -37,43 +84,362 @@ pub struct Config {
     /// is most noticeable in CPU-bound ruby programs or when a high sampling rate is used.
     pub lock_process: bool,
     /// Forces the recorder to use the given Ruby version. If not given, rbspy will attempt to
-    /// determine the Ruby version from the running process.
+    /// determine the Ruby version from the running process. A patch release that rbspy doesn't
+    /// know about yet is read as the nearest one it does, with a warning.
     ///
     /// This option shouldn't be needed unless you're testing a pre-release Ruby version.
     pub force_version: Option<String>,
+    /// Whether to only keep samples of threads that are running on the CPU, rather than blocked
+    /// (e.g. waiting for IO or a lock). Blocked samples are dropped as they're taken; to keep them
+    /// in the raw output, leave this off and pick the samples with `view`, or show both with the
+    /// `state` flamegraph palette. Default: `false`.
     pub on_cpu: bool,
+    /// What to do when `on_cpu` is set but on-CPU samples can't be told apart reliably, e.g. on
+    /// macOS or in `confined` mode. If `true`, wall-clock samples are recorded instead, with a
+    /// note in the raw output; if `false`, recording fails to start. Default: `false`.
+    pub on_cpu_fallback: bool,
+    /// Whether frames on different lines of the same function are reported separately. Applies
+    /// to the formatted output only; the raw output always keeps line numbers. Default: `line`.
+    pub granularity: crate::core::types::Granularity,
+    /// Which samples go into the formatted output, based on what their thread was doing. Every
+    /// sample is written to the raw output along with its thread state, so a wall-clock recording
+    /// can later be reported as an on-CPU or off-CPU profile too. The `alloc` view turns on
+    /// `vm_stats`, so its raw output can be reported by time as well. Default: `wall`.
+    pub view: crate::core::types::ProfileView,
+    /// The maximum number of frames to keep from each stack trace. Deeper stacks are cut down to
+    /// this size and a "(truncated)" frame marks where frames were dropped. Default: none (keep
+    /// every frame).
+    pub depth_limit: Option<crate::core::types::DepthLimit>,
+    /// Whether to read allocation and GC counters from the Ruby VM with each sample and store
+    /// them in the raw output. The summaries then estimate the time spent in GC and which stacks
+    /// started GC pauses. Requires Ruby 2.7 or newer. Default: `false`.
+    pub vm_stats: bool,
+    /// Whether to put a `(garbage collection)` frame on top of the stacks that were sampled
+    /// while the garbage collector was running, so that flamegraphs show the time spent in GC
+    /// separately from the Ruby code that triggered it. The GC state is read from the Ruby VM,
+    /// which requires Ruby 2.7 or newer. Default: `false`.
+    pub gc_frames: bool,
+    /// Whether to label each sample with the ID of the request that its thread was working on,
+    /// as published by the process in a shared memory region (see `core::marker` for the
+    /// protocol). Processes that don't publish one are sampled as usual. Default: `false`.
+    pub request_marker: bool,
+    /// Whether to sample every Ruby thread in the process each time, rather than only the
+    /// thread that holds the GVL, e.g. to see what the background threads in Puma or Sidekiq are
+    /// doing. Each thread's samples are weighted by the full sample interval, so a report covers
+    /// the threads' time added together. Requires Ruby 2.2 or newer; with older versions only the
+    /// current thread is sampled. Default: `false`.
+    pub all_threads: bool,
+    /// Whether to label each sample with what its thread was doing with the GVL: `gvl: held`
+    /// for the thread that holds it, `gvl: waiting` for runnable threads that are waiting to get
+    /// it, and `gvl: released` for threads that gave it up to sleep, wait or do IO. Only the
+    /// sampled threads are labelled, so set `all_threads` as well to see the waiting threads,
+    /// e.g. so that a flamegraph of the `waiting` samples shows where the threads of a
+    /// multi-threaded Puma worker contend for the GVL. The owner is read from the VM with Ruby
+    /// 2.6 or newer; otherwise it's taken to be the VM's current thread, which may have released
+    /// the GVL since, and the recording's notes say so. Waiting threads are told apart from
+    /// those doing IO with the OS's view of the threads, which needs Linux and Ruby 3.1 or
+    /// newer, and otherwise every runnable thread but the owner counts as waiting. Default:
+    /// `false`.
+    pub gvl_labels: bool,
+    /// Only keep samples taken from the matching Ruby thread. Unless `all_threads` is set, only
+    /// the thread that's running Ruby code can be sampled, so this skips the samples taken while
+    /// other threads were running. Default: none (keep samples from every thread).
+    pub thread_filter: Option<crate::core::types::ThreadFilter>,
+    /// Switch to this unprivileged account once the target process has been attached to, so
+    /// that the rest of the recording doesn't run as root. The account needs to be able to read
+    /// the target's memory, e.g. by being the account the target runs as. Can't be combined with
+    /// `with_subprocesses`. Unix only. Default: none.
+    pub drop_privileges: Option<crate::core::types::Credentials>,
+    /// Where to keep a record of who started each recording, with which options, and when each
+    /// process was attached to and detached from. Recording fails if the records can't be
+    /// written. Default: none.
+    pub audit_log: Option<crate::core::audit::AuditTarget>,
+    /// Guarantees that rbspy never stops, signals or attaches to the target process with ptrace,
+    /// only reading its memory. Recording fails to start if another option (e.g. `lock_process`)
+    /// would break that guarantee. Linux only. Default: `false`.
+    pub read_only: bool,
+    /// Avoids the reads that security modules like SELinux and AppArmor commonly deny, for
+    /// recording under a confined profile. Only the target's memory is read, so samples aren't
+    /// refined with what the OS reports each thread as doing (e.g. blocked in IO) and don't
+    /// record wait channels. Default: `false`.
+    pub confined: bool,
+    /// Keep the traces from this much recent time in memory, so that a profile of the last few
+    /// minutes can be written with `Recorder::write_window` at any time, e.g. when an incident is
+    /// noticed. Default: none.
+    pub window: Option<std::time::Duration>,
+    /// How often to flush the raw output to disk in a form that can be read back, so that
+    /// stopping rbspy abruptly (e.g. with `SIGKILL`) loses at most this much of the recording.
+    /// Default: none (the raw output is only readable once the recording is complete).
+    pub checkpoint_interval: Option<std::time::Duration>,
+    /// Whether to keep an index of the raw output's checkpoints in a file next to it (with an
+    /// `.idx` extension), so that `report_file` can skip to the requested time range in a long
+    /// recording. Indexing implies a checkpoint every minute unless `checkpoint_interval` is set.
+    /// Default: `false`.
+    pub index_raw: bool,
+    /// A secret key for authenticating the raw output. The raw output always ends with a
+    /// checksum, which reports use to detect truncated or modified recordings; with a key, it's
+    /// also signed with an HMAC, so that it can't be modified without the key either. Default:
+    /// none.
+    pub raw_hmac_key: Option<Vec<u8>>,
+    /// Start a new formatted output file at this interval, e.g. to write one flamegraph per hour.
+    /// `{n}` in `out_path` is replaced with the number of the interval, counting from 0, and
+    /// `{time}` with the time the interval started (e.g. `20230102-150405`). If `out_path`
+    /// contains neither, the number is added before the file extension. Default: none (write a
+    /// single output file at the end of the recording).
+    pub rotate_every: Option<std::time::Duration>,
+    /// Limits the size of the raw output, to protect the disk from a recording that was left
+    /// running. The formatted output is only written at the end of the recording, and isn't
+    /// limited. Default: none.
+    pub raw_size_limit: Option<crate::core::types::SizeLimit>,
+    /// How to compress the raw output. The raw output is compressed and written to disk in a
+    /// thread of its own either way, so that it doesn't hold up sampling. When appending, an
+    /// existing recording keeps the compression it was started with. Default: `gzip`.
+    pub raw_compression: crate::core::types::RawCompression,
+    /// Called in order with each trace before it's written, e.g. to label traces with the
+    /// current deploy or the state of a feature flag. Hooks run on the recorder's thread, so slow
+    /// hooks hold up recording. Default: none.
+    pub enrichment_hooks: Vec<crate::core::types::EnrichmentHook>,
+    /// Whether to label traces with the container that their process runs in, found from the
+    /// process's cgroup: `container_id`, `container_runtime`, and where the container runtime's
+    /// state can be read on the host, `image`, `container`, `pod`, `namespace` and `pod_uid`.
+    /// Labels set by `enrichment_hooks` take precedence. Default: `false`.
+    pub container_labels: bool,
+    /// Environment variables to read from each process's `/proc/<pid>/environ` (on Linux, where
+    /// permitted) and label its traces with, e.g. `RAILS_ENV` or `GIT_SHA`. Only the environment
+    /// that the process started with is seen. Labels set by `enrichment_hooks` take precedence.
+    /// Default: none.
+    pub capture_env: Vec<String>,
+    /// Rewrite the formatted output at this interval while recording, so that it can be watched
+    /// or served as the profile builds up. Each update replaces the file atomically. Doesn't
+    /// apply when writing to standard output. Default: none.
+    pub live_update_interval: Option<std::time::Duration>,
+    /// Whether to show a line on standard error with the elapsed and remaining time, the number
+    /// of samples collected, the current sample rate and the share of samples that failed, which
+    /// is updated in place while recording. Only shown when standard error is a terminal; turn it
+    /// off for scripts that capture it. Default: `false`.
+    pub progress: bool,
+    /// How `Recorder::write_summary` and the `summary` and `summary_by_line` output formats
+    /// order, cut down and color the functions they show. Default: every function (the top 20 in
+    /// `write_summary`), ordered by self time, without color.
+    pub summary_options: crate::ui::summary::SummaryOptions,
+    /// Whether to lower the sample rate if samples can't be taken as often as requested (e.g.
+    /// because the process's stacks are very deep). Either way, a shortfall is noted in the raw
+    /// output and the summary. Default: `false`.
+    pub adapt_rate: bool,
+    /// Keeps the time that rbspy spends taking samples of each process under this fraction of
+    /// one CPU (e.g. `0.01` for 1%), by lowering the sample rate when samples get expensive and
+    /// raising it again, up to `sample_rate`, when they get cheaper. Samples are weighted by the
+    /// time between them, so the profile stays accurate, and big drops in the rate are noted in
+    /// the raw output. Default: none (no limit).
+    pub max_overhead: Option<f64>,
+    /// A FIFO that the profiled application (or an operator) can write commands to, one per
+    /// line, to bracket the interesting parts of a run: `start` and `stop` resume and pause
+    /// sampling, `flush` writes the formatted output and checkpoints the raw output, and
+    /// `label key=value` labels the traces from then on (an empty value removes the label).
+    /// Sampling starts paused, and pauses are marked in the raw output like `pause_signals`'s
+    /// are. The FIFO is created if there's nothing at the path, and removed when the recording
+    /// ends; an existing regular file is followed for new commands instead. Default: none.
+    pub control_fifo: Option<PathBuf>,
+    /// Whether to pause sampling when rbspy receives SIGUSR1 and resume it on SIGUSR2, without
+    /// detaching from the process or closing the output, e.g. to only profile the interesting
+    /// windows of a long-lived daemon. Pauses are marked in the raw output, so that reports don't
+    /// count them towards its length. Unix only. Default: `false`.
+    pub pause_signals: bool,
+    /// Only sample for part of every period, e.g. 10 seconds out of every minute, indefinitely.
+    /// This keeps the average overhead of always-on profiling low. The raw output is a single
+    /// recording, with the gaps between bursts marked so that reports don't count them towards
+    /// its length. For a formatted output file per burst, set `rotate_every` to the period.
+    /// Default: none (sample all the time).
+    pub duty_cycle: Option<crate::core::types::DutyCycle>,
+    /// What to do when writing the output can't keep up with sampling, e.g. on a slow disk:
+    /// make the sampler wait (`block`), or keep sampling and drop the oldest (`drop_oldest`) or
+    /// newest (`drop_newest`) samples that haven't been written yet. Dropped samples are counted
+    /// in the summary, the formatted output's metadata and a note in the raw output.
+    /// Default: `block`.
+    pub backpressure: crate::core::types::Backpressure,
+    /// Whether to leave out samples where the process was idle, i.e. the thread that last ran
+    /// Ruby code was waiting for work in `sleep`, `IO.select`, `accept`, `Queue#pop` or the like
+    /// (see `StackTrace::is_idle`). This keeps wall-clock recordings of mostly idle servers small
+    /// without changing what they say about the time spent working. Skipped samples are counted
+    /// in the summary, the formatted output's metadata and a note in the raw output. Has no
+    /// effect with `on_cpu`, which leaves out every sample of a blocked thread. Default: `false`.
+    pub skip_idle: bool,
+    /// Rewrites the start of frames' file paths in the formatted output and the summary, e.g.
+    /// `/app/releases/20240501=.` to strip a deploy directory, or `/usr/src/app=/home/me/app` to
+    /// map the paths in a container to a checkout. The first mapping whose prefix matches whole
+    /// path components wins. The raw output keeps the paths as they were. Default: none.
+    pub path_map: Vec<crate::core::types::PathMapping>,
+    /// Push the samples to a Pyroscope server on an interval while recording, as folded stacks
+    /// tagged with their process and labels, so that rbspy can run as a sidecar agent without
+    /// writing any files. `view`, `granularity` and `path_map` apply to the pushed profiles as
+    /// they do to the formatted output. Default: none.
+    pub pyroscope: Option<crate::recorder::PyroscopeConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            format: crate::core::types::OutputFormat::flamegraph,
+            raw_path: None,
+            append: false,
+            out_path: None,
+            out_dir: None,
+            pid: 0,
+            extra_pids: vec![],
+            pidfile: None,
+            reattach: false,
+            with_subprocesses: false,
+            sample_rate: 100,
+            maybe_duration: None,
+            max_samples: None,
+            flame_min_width: 0.1,
+            flame_palette: Default::default(),
+            lock_process: false,
+            force_version: None,
+            on_cpu: false,
+            on_cpu_fallback: false,
+            granularity: Default::default(),
+            view: Default::default(),
+            depth_limit: None,
+            vm_stats: false,
+            gc_frames: false,
+            request_marker: false,
+            all_threads: false,
+            gvl_labels: false,
+            thread_filter: None,
+            drop_privileges: None,
+            audit_log: None,
+            read_only: false,
+            confined: false,
+            window: None,
+            checkpoint_interval: None,
+            index_raw: false,
+            raw_hmac_key: None,
+            rotate_every: None,
+            raw_size_limit: None,
+            raw_compression: Default::default(),
+            enrichment_hooks: Vec::new(),
+            container_labels: false,
+            capture_env: Vec::new(),
+            live_update_interval: None,
+            progress: false,
+            summary_options: Default::default(),
+            adapt_rate: false,
+            max_overhead: None,
+            control_fifo: None,
+            pause_signals: false,
+            duty_cycle: None,
+            backpressure: Default::default(),
+            skip_idle: false,
+            path_map: Vec::new(),
+            pyroscope: None,
+        }
+    }
 }
 
 pub struct Recorder {
     format: crate::core::types::OutputFormat,
     flame_min_width: f64,
+    flame_palette: crate::core::types::FlamegraphPalette,
+    granularity: crate::core::types::Granularity,
+    view: crate::core::types::ProfileView,
     out_path: Option<PathBuf>,
+    generated_out_path: bool,
     raw_path: Option<PathBuf>,
+    append: bool,
     sample_rate: u32,
+    checkpoint_interval: Option<std::time::Duration>,
+    index_raw: bool,
+    raw_hmac_key: Option<Vec<u8>>,
+    rotate_every: Option<std::time::Duration>,
+    raw_size_limit: Option<crate::core::types::SizeLimit>,
+    raw_compression: crate::core::types::RawCompression,
+    enrichment_hooks: Vec<crate::core::types::EnrichmentHook>,
+    live_update_interval: Option<std::time::Duration>,
+    progress: bool,
+    duration: Option<std::time::Duration>,
+    max_samples: Option<usize>,
+    summary_options: summary::SummaryOptions,
     sampler: crate::sampler::Sampler,
     summary: Arc<Mutex<summary::Stats>>,
+    window: Option<Arc<Mutex<Window>>>,
+    audit_log: Option<Arc<AuditLog>>,
+    audit_options: BTreeMap<&'static str, String>,
+    control_fifo: Option<PathBuf>,
+    pause_signals: bool,
+    backpressure: crate::core::types::Backpressure,
+    skip_idle: bool,
+    idle_samples: AtomicUsize,
+    path_map: Vec<crate::core::types::PathMapping>,
+    pyroscope: Option<crate::recorder::PyroscopeConfig>,
 }
 
 impl Recorder {
     pub fn new(config: Config) -> Self {
-        let sampler = crate::sampler::Sampler::new(
-            config.pid,
-            config.sample_rate,
-            config.lock_process,
-            config.maybe_duration,
-            config.with_subprocesses,
-            config.force_version,
-            config.on_cpu,
-        );
+        let audit_log = new_audit_log(&config);
+        let audit_options = audit_options(&config);
+        let sampler = new_sampler(&config, audit_log.clone());
+        let generated_out_path = config.out_path.is_none() && config.out_dir.is_some();
+        let mut enrichment_hooks = config.enrichment_hooks;
+        if !config.capture_env.is_empty() {
+            enrichment_hooks.insert(
+                0,
+                crate::core::environ::capture_env_hook(config.capture_env),
+            );
+        }
+        if config.container_labels {
+            enrichment_hooks.insert(0, crate::core::container::container_labels_hook());
+        }
+        let out_path = config.out_path.or_else(|| {
+            config.out_dir.map(|dir| {
+                dir.join(generated_out_name(
+                    config.pid,
+                    &config.format,
+                    std::time::SystemTime::now(),
+                ))
+            })
+        });
 
         Recorder {
             format: config.format,
             flame_min_width: config.flame_min_width,
-            out_path: config.out_path,
+            flame_palette: config.flame_palette,
+            granularity: config.granularity,
+            view: config.view,
+            out_path,
+            generated_out_path,
             raw_path: config.raw_path,
+            append: config.append,
             sample_rate: config.sample_rate,
+            checkpoint_interval: match config.checkpoint_interval {
+                None if config.index_raw => Some(std::time::Duration::from_secs(60)),
+                interval => interval,
+            },
+            index_raw: config.index_raw,
+            raw_hmac_key: config.raw_hmac_key,
+            rotate_every: config.rotate_every,
+            raw_size_limit: config.raw_size_limit,
+            raw_compression: config.raw_compression,
+            enrichment_hooks,
+            live_update_interval: config.live_update_interval,
+            progress: config.progress,
+            duration: config.maybe_duration,
+            max_samples: config.max_samples,
+            summary_options: config.summary_options,
             sampler,
             summary: Arc::new(Mutex::new(summary::Stats::new())),
+            window: config
+                .window
+                .map(|length| Arc::new(Mutex::new(Window::new(length)))),
+            audit_log,
+            audit_options,
+            control_fifo: config.control_fifo,
+            pause_signals: config.pause_signals,
+            backpressure: config.backpressure,
+            skip_idle: config.skip_idle,
+            idle_samples: AtomicUsize::new(0),
+            path_map: config.path_map,
+            pyroscope: config.pyroscope,
         }
     }
 
@@ -85,6 +451,34 @@ impl Recorder {
         // traces, but not an unbounded buffer.
         let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(100);
         let (result_sender, result_receiver) = std::sync::mpsc::channel();
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(
+                AuditEvent::Start,
+                Some(self.sampler.root_pid()),
+                Some(&self.audit_options),
+            )?;
+        }
+        let control = match &self.control_fifo {
+            Some(path) => {
+                self.sampler.pause();
+                Some(Control::new(path, self.sampler.paused_flag()).listen()?)
+            }
+            None => None,
+        };
+        let pause_signals = if self.pause_signals {
+            Some(PauseSignals::install(self.sampler.paused_flag())?)
+        } else {
+            None
+        };
+        let mut exporter = match &self.pyroscope {
+            Some(config) => Some(Exporter::spawn(
+                config.clone(),
+                self.view,
+                self.granularity,
+                self.path_map.clone(),
+            )?),
+            None => None,
+        };
         self.sampler.start(trace_sender, result_sender)?;
 
         // Aggregate stack traces as we receive them from the threads that are collecting them
@@ -92,57 +486,288 @@ impl Recorder {
         // and the formatted output (a flamegraph or something)
         let mut out = None;
         if self.out_path.is_some() {
-            out = Some(self.format.clone().outputter(self.flame_min_width));
-        }
-        let mut raw_store = None;
-        if let Some(raw_path) = &self.raw_path {
-            raw_store = Some(Store::new(&raw_path, self.sample_rate)?);
+            out = Some(self.new_outputter());
         }
+        let start = std::time::Instant::now();
+        let start_time = std::time::SystemTime::now();
+        let process = Process::new(self.sampler.root_pid()).ok();
+        let mut metadata = ProfileMetadata {
+            cmdline: process
+                .as_ref()
+                .and_then(|process| process.cmdline().ok())
+                .map(|cmdline| cmdline.join(" ")),
+            exe: process.as_ref().and_then(|process| process.exe().ok()),
+            host: crate::core::process::hostname(),
+            rbspy_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            sample_rate: Some(self.sample_rate),
+            start_time: Some(start_time),
+            flags: self.raw_flags(),
+            ..Default::default()
+        };
+        let mut first_trace = true;
+        let describe = |metadata: &ProfileMetadata| ProfileMetadata {
+            ruby_version: self.sampler.ruby_version(),
+            duration: Some(start.elapsed()),
+            dropped_samples: self.sampler.dropped_traces(),
+            idle_samples: self.idle_samples(),
+            ..metadata.clone()
+        };
+        let mut period = 0;
+        let output_path = |out_path: &Path, period: u32| match self.rotate_every {
+            Some(rotate_every) => {
+                rotated_path(out_path, period, start_time + rotate_every * period)
+            }
+            None => out_path.to_path_buf(),
+        };
+        let mut last_live_update = std::time::Instant::now();
+        // The raw output is started when the first trace arrives, so that its header can say
+        // which Ruby version the process runs
+        let mut raw_store: Option<StoreWriter> = None;
+        let mut raw_header = None;
+        let mut last_checkpoint = std::time::Instant::now();
+        let mut progress = self.progress.then(|| Progress::new(self.duration));
+        let mut notes_written = 0;
+        // The duty cycle's burst that the last trace was taken in, and when
+        let mut last_burst = None;
+        // When sampling was last paused, while it stays paused
+        let mut paused_at = None;
+        let mut samples = 0;
 
-        for trace in trace_receiver {
+        loop {
+            // Traces stop arriving while sampling is paused, so control commands, signals and
+            // pushes to Pyroscope are checked for in between
+            let received = if control.is_some() || pause_signals.is_some() || exporter.is_some() {
+                trace_receiver.recv_timeout(std::time::Duration::from_millis(100))
+            } else {
+                trace_receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)
+            };
+            if let Some(control) = &control {
+                if control.take_flush_request() {
+                    if let (Some(out), Some(out_path)) = (&mut out, &self.out_path) {
+                        if out_path.as_os_str() != "-" {
+                            out.set_metadata(&describe(&metadata));
+                            write_output_atomically(out, &output_path(out_path, period))?;
+                        }
+                    }
+                    if let Some(raw_store) = &mut raw_store {
+                        raw_store.checkpoint()?;
+                    }
+                }
+            }
+            if let Some(pause_signals) = &pause_signals {
+                pause_signals.apply();
+            }
+            match (self.sampler.is_paused(), paused_at) {
+                (true, None) => paused_at = Some(std::time::SystemTime::now()),
+                (false, Some(from)) => {
+                    if let Some(raw_store) = &mut raw_store {
+                        raw_store.gap(from, std::time::SystemTime::now())?;
+                    }
+                    paused_at = None;
+                }
+                _ => {}
+            }
+            if let Some(exporter) = &mut exporter {
+                exporter.push_if_due()?;
+            }
+            let mut trace = match received {
+                Ok(trace) => trace,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if self.skip_idle && trace.is_idle() {
+                self.idle_samples.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            // Samples that were already on their way when the limit was reached are dropped
+            if self.max_samples.map_or(false, |max| samples >= max) {
+                continue;
+            }
+            samples += 1;
+            if let Some(control) = &control {
+                trace.labels.extend(control.labels());
+            }
+            for hook in &self.enrichment_hooks {
+                hook(&mut trace);
+            }
+            if first_trace {
+                metadata.labels = trace.labels.clone();
+                first_trace = false;
+            } else {
+                metadata.keep_common_labels(&trace);
+            }
+            metadata.add_gems(&trace);
+            if let (Some(raw_path), None) = (&self.raw_path, &raw_header) {
+                let header = self.raw_header(&metadata);
+                raw_store = Some(self.open_raw_store(raw_path, header.clone(), self.append)?);
+                raw_header = Some(header);
+            }
+            if let (Some(rotate_every), Some(out_path)) = (self.rotate_every, &self.out_path) {
+                let current_period = (start.elapsed().as_nanos() / rotate_every.as_nanos()) as u32;
+                if current_period != period {
+                    if let Some(out) = &mut out {
+                        out.set_metadata(&describe(&metadata));
+                        write_output(out, &output_path(out_path, period))?;
+                    }
+                    out = Some(self.new_outputter());
+                    period = current_period;
+                }
+            }
             if let Some(out) = &mut out {
                 out.record(&trace)?;
             }
+            if let Some(exporter) = &mut exporter {
+                exporter.record(&trace)?;
+            }
+            if let (Some(interval), Some(out), Some(out_path)) =
+                (self.live_update_interval, &mut out, &self.out_path)
+            {
+                if last_live_update.elapsed() >= interval && out_path.as_os_str() != "-" {
+                    out.set_metadata(&describe(&metadata));
+                    write_output_atomically(out, &output_path(out_path, period))?;
+                    last_live_update = std::time::Instant::now();
+                }
+            }
             if let Some(raw_store) = &mut raw_store {
+                for note in self.sampler.new_notes(notes_written) {
+                    raw_store.note(&note)?;
+                    notes_written += 1;
+                }
+                if let Some(burst) = self.sampler.duty_cycle_burst() {
+                    let time = trace.time.unwrap_or_else(std::time::SystemTime::now);
+                    if let Some((last, last_time)) = last_burst {
+                        if last != burst {
+                            raw_store.gap(last_time, time)?;
+                        }
+                    }
+                    last_burst = Some((burst, time));
+                }
                 raw_store.write(&trace)?;
+                if let Some(interval) = self.checkpoint_interval {
+                    if last_checkpoint.elapsed() >= interval {
+                        raw_store.checkpoint()?;
+                        last_checkpoint = std::time::Instant::now();
+                    }
+                }
+            }
+            if let (Some(limit), Some(raw_path)) = (self.raw_size_limit, &self.raw_path) {
+                if let Some(store) = raw_store.take() {
+                    if store.size() < limit.max_bytes {
+                        raw_store = Some(store);
+                    } else {
+                        store.complete();
+                        match limit.action {
+                            SizeLimitAction::rotate => {
+                                let mut rotated = raw_path.clone().into_os_string();
+                                rotated.push(".1");
+                                std::fs::rename(raw_path, &rotated).context(format!(
+                                    "Failed to move full raw output file {}",
+                                    raw_path.display()
+                                ))?;
+                                if self.index_raw {
+                                    let index = crate::storage::index_path(raw_path);
+                                    let mut rotated_index = index.clone().into_os_string();
+                                    rotated_index.push(".1");
+                                    std::fs::rename(&index, rotated_index)?;
+                                }
+                                let header = raw_header.clone().unwrap_or_default();
+                                raw_store = Some(self.open_raw_store(raw_path, header, false)?);
+                            }
+                            SizeLimitAction::stop => {
+                                warn!(
+                                    "Raw output reached {} bytes; stopping the recording",
+                                    limit.max_bytes
+                                );
+                                self.stop();
+                            }
+                        }
+                    }
+                }
             }
 
             let mut summary = self.summary.lock().unwrap();
-            summary.add_function_name(&trace.trace);
+            let mapped;
+            let frames = if self.path_map.is_empty() {
+                &trace.trace
+            } else {
+                let mut trace = trace.clone();
+                trace.map_paths(&self.path_map);
+                mapped = trace.trace;
+                &mapped
+            };
+            match self.summary_options.group_by {
+                Some(group) => summary.add_grouped(frames, trace.weight(), group),
+                None => summary.add_function_name(frames, trace.weight()),
+            }
+            summary.add_vm_stats(&trace);
+            drop(summary);
+
+            if let Some(window) = &self.window {
+                window.lock().unwrap().push(trace);
+            }
+
+            if let Some(progress) = &mut progress {
+                progress.update(self.sampler.total_traces(), self.sampler.error_traces());
+            }
+
+            if self.max_samples == Some(samples) {
+                info!("Recorded {} samples; stopping the recording", samples);
+                self.stop();
+            }
+        }
+        if let Some(progress) = &progress {
+            progress.finish();
+        }
+        if let Some(exporter) = exporter {
+            exporter.finish()?;
         }
 
         // Finish writing all data to disk
         if let (Some(out), Some(out_path)) = (&mut out, self.out_path.as_ref()) {
-            if out_path.display().to_string() == "-" {
-                out.complete(&mut std::io::stdout())?;
-            } else {
-                let mut out_file = File::create(&out_path).context(format!(
-                    "Failed to create output file {}",
-                    &out_path.display()
-                ))?;
-                out.complete(&mut out_file)?;
+            out.set_metadata(&describe(&metadata));
+            let out_path = output_path(out_path, period);
+            write_output(out, &out_path)?;
+            if self.generated_out_path {
+                let out_path = std::fs::canonicalize(&out_path).unwrap_or(out_path);
+                info!("Wrote the profile to {}", out_path.display());
             }
         }
-        if let Some(raw_store) = raw_store {
-            raw_store.complete();
+        if let (Some(raw_path), None) = (&self.raw_path, &raw_header) {
+            // Nothing was recorded, but the raw output is still expected to exist
+            let header = Header {
+                flags: self.raw_flags(),
+                ..Header::new(self.sample_rate)
+            };
+            raw_store = Some(self.open_raw_store(raw_path, header, self.append)?);
         }
-
-        // Check for errors from the child threads. Ignore errors unless every single thread
-        // returned an error. If that happens, return the last error. This lets rbspy successfully
-        // record processes even if the parent thread isn't a Ruby process.
-        let mut num_ok = 0;
-        let mut last_result = Ok(());
-        for result in result_receiver {
-            if result.is_ok() {
-                num_ok += 1;
+        if let Some(mut raw_store) = raw_store {
+            let dropped = self.sampler.dropped_traces();
+            if dropped > 0 {
+                raw_store.note(&format!(
+                    "Dropped {} samples because the output couldn't keep up (backpressure: {:?})",
+                    dropped, self.backpressure
+                ))?;
             }
-            last_result = result;
+            let idle = self.idle_samples();
+            if idle > 0 {
+                raw_store.note(&format!("Skipped {} idle samples", idle))?;
+            }
+            raw_store.complete();
         }
 
-        match num_ok {
-            0 => last_result,
-            _ => Ok(()),
+        let result = crate::sampler::sampler_result(result_receiver);
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(AuditEvent::Stop, Some(self.sampler.root_pid()), None)?;
         }
+        result
+    }
+
+    /// Where the formatted output is written, including a name generated for `out_dir`, e.g. for
+    /// telling the user where to find the profile
+    pub fn out_path(&self) -> Option<&Path> {
+        self.out_path.as_deref()
     }
 
     /// Stops the recorder
@@ -150,6 +775,143 @@ impl Recorder {
         self.sampler.stop();
     }
 
+    /// Stops taking samples until `resume` is called. Can be called from another thread while
+    /// the recorder is running.
+    pub fn pause(&self) {
+        self.sampler.pause();
+    }
+
+    pub fn resume(&self) {
+        self.sampler.resume();
+    }
+
+    /// Writes the traces in the recording window in the recorder's output format. Can be called
+    /// from another thread while the recorder is running.
+    pub fn write_window(&self, w: &mut dyn std::io::Write) -> Result<(), Error> {
+        let mut out = self.new_outputter();
+        for trace in &self.window_traces(None)? {
+            out.record(trace)?;
+        }
+        out.complete(w)
+    }
+
+    /// Writes the traces in the recording window that were taken at or after `since` in
+    /// `format`, and returns how many there were. Nothing is written if there weren't any.
+    pub(crate) fn write_recent(
+        &self,
+        format: crate::core::types::OutputFormat,
+        since: Option<std::time::SystemTime>,
+        w: &mut dyn std::io::Write,
+    ) -> Result<usize, Error> {
+        let traces = self.window_traces(since)?;
+        if traces.is_empty() {
+            return Ok(0);
+        }
+        let mut out = self.outputter_for(format);
+        for trace in &traces {
+            out.record(trace)?;
+        }
+        out.complete(w)?;
+        Ok(traces.len())
+    }
+
+    /// Copies the traces in the recording window that were taken at or after `since`. They're
+    /// rendered from the copy, since the recorder can't add to the window while it's locked.
+    fn window_traces(
+        &self,
+        since: Option<std::time::SystemTime>,
+    ) -> Result<Vec<StackTrace>, Error> {
+        let window = self
+            .window
+            .as_ref()
+            .ok_or_else(|| anyhow::format_err!("The recorder wasn't configured with a window"))?;
+        let window = window.lock().unwrap();
+        Ok(window
+            .iter()
+            .filter(|trace| match (since, trace.time) {
+                (Some(since), Some(time)) => time >= since,
+                _ => true,
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Describes the process and the recording options in the raw output's header, given the
+    /// metadata as of the first trace
+    fn raw_header(&self, metadata: &ProfileMetadata) -> Header {
+        Header {
+            ruby_version: self.sampler.ruby_version(),
+            exe: metadata.exe.clone(),
+            cmdline: metadata.cmdline.clone(),
+            host: metadata.host.clone(),
+            flags: self.raw_flags(),
+            labels: metadata.labels.clone(),
+            ..Header::new(self.sample_rate)
+        }
+    }
+
+    fn raw_flags(&self) -> BTreeMap<String, String> {
+        self.audit_options
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn open_raw_store(&self, raw_path: &Path, header: Header, append: bool) -> Result<StoreWriter> {
+        let mut store = if append {
+            Store::append(raw_path, header, self.raw_compression)?
+        } else {
+            Store::new(raw_path, header, self.raw_compression)?
+        };
+        if self.index_raw {
+            store = store.with_index(raw_path, append)?;
+        }
+        if let Some(key) = &self.raw_hmac_key {
+            store = store.with_hmac_key(key.clone());
+        }
+        StoreWriter::spawn(store)
+    }
+
+    fn new_outputter(&self) -> Viewed {
+        self.outputter_for(self.format.clone())
+    }
+
+    fn outputter_for(&self, format: crate::core::types::OutputFormat) -> Viewed {
+        let mut out = Viewed::new(
+            format.outputter_with_granularity(self.flame_min_width, self.granularity),
+            self.view,
+        )
+        .with_path_map(self.path_map.clone());
+        out.set_summary_options(&self.summary_options);
+        out.set_flamegraph_palette(self.flame_palette);
+        out
+    }
+
+    /// The number of samples that were left out because the process was idle (see
+    /// `RecordConfig::skip_idle`)
+    pub fn idle_samples(&self) -> usize {
+        self.idle_samples.load(Ordering::Relaxed)
+    }
+
+    /// The number of samples that have been taken so far, including the ones that failed
+    pub fn total_traces(&self) -> usize {
+        self.sampler.total_traces()
+    }
+
+    /// Writes the summary table of the traces collected so far, without the notes that
+    /// `write_summary` adds. If `width` is given, lines are cut down to that many characters.
+    pub(crate) fn write_summary_table(
+        &self,
+        w: &mut dyn std::io::Write,
+        options: &summary::SummaryOptions,
+        width: Option<usize>,
+    ) -> Result<(), Error> {
+        self.summary
+            .lock()
+            .unwrap()
+            .write_with_options(w, options, width)
+    }
+
     /// Writes a summary of collected traces
     pub fn write_summary(&self, w: &mut dyn std::io::Write) -> Result<(), Error> {
         let width = match term_size::dimensions() {
@@ -168,19 +930,295 @@ impl Recorder {
         )?;
 
         writeln!(w, "Summary of profiling data so far:")?;
-        summary.write_top_n(w, 20, width)?;
+        let options = summary::SummaryOptions {
+            top: self.summary_options.top.or(Some(20)),
+            ..self.summary_options.clone()
+        };
+        summary.write_with_options(w, &options, width)?;
 
         if total_traces > 100 && percent_timing_error > 0.5 {
             // Only include this warning if timing errors are more than 0.5% of total traces. rbspy
             // is a statistical profiler, so smaller differences don't really matter.
             writeln!(w, "{:.1}% ({}/{}) of stack traces were sampled late because we couldn't sample at expected rate; results may be inaccurate. Current rate: {}. Try sampling at a lower rate with `--rate`.", percent_timing_error, timing_error_traces, total_traces, self.sample_rate)?;
         }
+        let dropped = self.sampler.dropped_traces();
+        if dropped > 0 {
+            writeln!(
+                w,
+                "{} stack traces were dropped because the output couldn't keep up (backpressure: {:?}).",
+                dropped, self.backpressure
+            )?;
+        }
+        let idle = self.idle_samples();
+        if idle > 0 {
+            writeln!(w, "{} idle samples were skipped.", idle)?;
+        }
+        for note in self.sampler.notes() {
+            writeln!(w, "Note: {}", note)?;
+        }
         Ok(())
     }
 }
 
+/// Creates a sampler for the process and sampling options in the config
+pub(crate) fn new_sampler(
+    config: &Config,
+    audit_log: Option<Arc<AuditLog>>,
+) -> crate::sampler::Sampler {
+    crate::sampler::Sampler::new(
+        config.pid,
+        crate::sampler::SampleOptions {
+            sample_rate: config.sample_rate,
+            lock_process: config.lock_process,
+            time_limit: config.maybe_duration,
+            with_subprocesses: config.with_subprocesses,
+            extra_pids: config.extra_pids.clone(),
+            reattach_pidfile: config.pidfile.clone().filter(|_| config.reattach),
+            force_version: config.force_version.clone(),
+            on_cpu: config.on_cpu,
+            on_cpu_fallback: config.on_cpu_fallback,
+            depth_limit: config.depth_limit,
+            vm_stats: config.vm_stats || config.view == crate::core::types::ProfileView::alloc,
+            request_marker: config.request_marker,
+            all_threads: config.all_threads,
+            gvl_labels: config.gvl_labels,
+            gc_frames: config.gc_frames,
+            max_overhead: config.max_overhead,
+            thread_filter: config.thread_filter.clone(),
+            drop_privileges: config.drop_privileges.clone(),
+            audit_log,
+            read_only: config.read_only,
+            confined: config.confined,
+            adapt_rate: config.adapt_rate,
+            duty_cycle: config.duty_cycle,
+            backpressure: config.backpressure,
+        },
+    )
+}
+
+pub(crate) fn new_audit_log(config: &Config) -> Option<Arc<AuditLog>> {
+    config
+        .audit_log
+        .clone()
+        .map(|target| Arc::new(AuditLog::new(target)))
+}
+
+/// The options that go into the audit log's record of a recording starting
+pub(crate) fn audit_options(config: &Config) -> BTreeMap<&'static str, String> {
+    let mut options = BTreeMap::new();
+    options.insert("sample_rate", config.sample_rate.to_string());
+    if !config.extra_pids.is_empty() {
+        let pids: Vec<String> = config
+            .extra_pids
+            .iter()
+            .map(|pid| pid.to_string())
+            .collect();
+        options.insert("extra_pids", pids.join(","));
+    }
+    if let Some(pidfile) = &config.pidfile {
+        options.insert("pidfile", pidfile.display().to_string());
+    }
+    options.insert("reattach", config.reattach.to_string());
+    options.insert("with_subprocesses", config.with_subprocesses.to_string());
+    options.insert("lock_process", config.lock_process.to_string());
+    options.insert("on_cpu", config.on_cpu.to_string());
+    options.insert("vm_stats", config.vm_stats.to_string());
+    options.insert("request_marker", config.request_marker.to_string());
+    options.insert("all_threads", config.all_threads.to_string());
+    options.insert("gvl_labels", config.gvl_labels.to_string());
+    options.insert("gc_frames", config.gc_frames.to_string());
+    options.insert("read_only", config.read_only.to_string());
+    options.insert("confined", config.confined.to_string());
+    options.insert("adapt_rate", config.adapt_rate.to_string());
+    if let Some(max_overhead) = config.max_overhead {
+        options.insert("max_overhead", max_overhead.to_string());
+    }
+    options.insert("backpressure", format!("{:?}", config.backpressure));
+    options.insert("raw_compression", format!("{:?}", config.raw_compression));
+    options.insert("container_labels", config.container_labels.to_string());
+    options.insert("skip_idle", config.skip_idle.to_string());
+    if !config.capture_env.is_empty() {
+        options.insert("capture_env", config.capture_env.join(","));
+    }
+    if !config.path_map.is_empty() {
+        let mappings: Vec<String> = config
+            .path_map
+            .iter()
+            .map(|mapping| format!("{}={}", mapping.from, mapping.to))
+            .collect();
+        options.insert("path_map", mappings.join(","));
+    }
+    if let Some(duration) = config.maybe_duration {
+        options.insert("duration", format!("{:?}", duration));
+    }
+    if let Some(max_samples) = config.max_samples {
+        options.insert("max_samples", max_samples.to_string());
+    }
+    if let Some(version) = &config.force_version {
+        options.insert("force_version", version.clone());
+    }
+    if let Some(path) = &config.raw_path {
+        options.insert("raw_path", path.display().to_string());
+    }
+    if let Some(path) = &config.out_path {
+        options.insert("out_path", path.display().to_string());
+    }
+    if let Some(path) = &config.out_dir {
+        options.insert("out_dir", path.display().to_string());
+    }
+    if let Some(credentials) = &config.drop_privileges {
+        options.insert("drop_privileges", credentials.user.clone());
+    }
+    if let Some(path) = &config.control_fifo {
+        options.insert("control_fifo", path.display().to_string());
+    }
+    options.insert("pause_signals", config.pause_signals.to_string());
+    if let Some(duty_cycle) = &config.duty_cycle {
+        options.insert("duty_cycle", duty_cycle.to_string());
+    }
+    if let Some(pyroscope) = &config.pyroscope {
+        options.insert("pyroscope_url", pyroscope.url.clone());
+    }
+    options
+}
+
+fn write_output(out: &mut dyn Outputter, out_path: &Path) -> Result<(), Error> {
+    if out_path.display().to_string() == "-" {
+        out.complete(&mut std::io::stdout())
+    } else {
+        let mut out_file = File::create(out_path).context(format!(
+            "Failed to create output file {}",
+            &out_path.display()
+        ))?;
+        out.complete(&mut out_file)
+    }
+}
+
+/// Writes the output to a temporary file that then replaces the output file, so that readers of
+/// the output file never see it half-written
+fn write_output_atomically(out: &mut dyn Outputter, out_path: &Path) -> Result<(), Error> {
+    let mut temp_path = out_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    write_output(out, &temp_path)?;
+    std::fs::rename(&temp_path, out_path).context(format!(
+        "Failed to replace output file {}",
+        out_path.display()
+    ))?;
+    Ok(())
+}
+
+/// Names an output file after the process it profiles and the time the recording started, e.g.
+/// `rbspy-puma-1234-2023-01-02T150405.flamegraph.svg`
+fn generated_out_name(
+    pid: crate::core::process::Pid,
+    format: &crate::core::types::OutputFormat,
+    start_time: std::time::SystemTime,
+) -> String {
+    let comm = Process::new(pid)
+        .and_then(|process| process.exe())
+        .ok()
+        .and_then(|exe| {
+            Path::new(&exe)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        });
+    let time = chrono::DateTime::<chrono::Local>::from(start_time);
+    format!(
+        "rbspy-{}-{}-{}.{}",
+        sanitized_comm(comm.as_deref().unwrap_or("ruby")),
+        pid,
+        time.format("%Y-%m-%dT%H%M%S"),
+        format.extension()
+    )
+}
+
+/// Keeps a process name safe to use in a file name
+fn sanitized_comm(comm: &str) -> String {
+    let comm: String = comm
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    match comm.trim_matches('.') {
+        "" => "ruby".to_string(),
+        comm => comm.to_string(),
+    }
+}
+
+/// Fills in the name of the output file for one interval of a rotated recording
+fn rotated_path(template: &Path, period: u32, start_time: std::time::SystemTime) -> PathBuf {
+    let template = template.display().to_string();
+    let time = chrono::DateTime::<chrono::Local>::from(start_time);
+    if template == "-" {
+        return PathBuf::from(template);
+    }
+    if template.contains("{n}") || template.contains("{time}") {
+        return PathBuf::from(
+            template
+                .replace("{n}", &period.to_string())
+                .replace("{time}", &time.format("%Y%m%d-%H%M%S").to_string()),
+        );
+    }
+    let path = Path::new(&template);
+    let stem = path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, period, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, period),
+    };
+    path.with_file_name(name)
+}
+
 impl Drop for Recorder {
     fn drop(&mut self) {
         self.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotated_path() {
+        let start_time = std::time::SystemTime::now();
+        let rotated = |template: &str| rotated_path(Path::new(template), 3, start_time);
+        assert_eq!(
+            rotated("out/profile.svg"),
+            PathBuf::from("out/profile-3.svg")
+        );
+        assert_eq!(rotated("profile"), PathBuf::from("profile-3"));
+        assert_eq!(rotated("profile-{n}.svg"), PathBuf::from("profile-3.svg"));
+        let time = chrono::DateTime::<chrono::Local>::from(start_time);
+        assert_eq!(
+            rotated("{time}.svg"),
+            PathBuf::from(format!("{}.svg", time.format("%Y%m%d-%H%M%S")))
+        );
+        assert_eq!(rotated("-"), PathBuf::from("-"));
+    }
+
+    #[test]
+    fn test_generated_out_name() {
+        assert_eq!(sanitized_comm("puma"), "puma");
+        assert_eq!(sanitized_comm("ruby 3.2/x"), "ruby_3.2_x");
+        assert_eq!(sanitized_comm(".."), "ruby");
+
+        let start_time = std::time::SystemTime::now();
+        let time = chrono::DateTime::<chrono::Local>::from(start_time);
+        let name = generated_out_name(
+            std::process::id() as crate::core::process::Pid,
+            &crate::core::types::OutputFormat::speedscope,
+            start_time,
+        );
+        assert!(name.starts_with("rbspy-"));
+        assert!(name.ends_with(&format!(
+            "-{}-{}.speedscope.json",
+            std::process::id(),
+            time.format("%Y-%m-%dT%H%M%S")
+        )));
+    }
+}
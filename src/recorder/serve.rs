@@ -0,0 +1,351 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{format_err, Context, Error, Result};
+
+use crate::core::types::OutputFormat;
+use crate::recorder::record::{Config as RecordConfig, Recorder};
+
+/// How much recent time the endpoints cover when the recorder isn't configured with a window
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+/// How often to check whether the recording has finished while waiting for requests
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a client has to send its whole request, and to take each part of the response
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest request line that's accepted, which is 8 KiB
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+/// The biggest request line and headers that are accepted, which is 32 KiB
+const MAX_HEAD_SIZE: usize = 32 * 1024;
+/// The most requests that are answered at once. Connections beyond that are closed.
+const MAX_WORKERS: usize = 8;
+
+/// The profiles that the server serves, by path
+const ENDPOINTS: &[(&str, OutputFormat, &str)] = &[
+    ("/flamegraph.svg", OutputFormat::flamegraph, "image/svg+xml"),
+    (
+        "/speedscope.json",
+        OutputFormat::speedscope,
+        "application/json",
+    ),
+    ("/pprof", OutputFormat::pprof, "application/octet-stream"),
+];
+
+/// Records a process continuously and serves profiles of the last stretch of the recording over
+/// plain HTTP, so that a browser (or `go tool pprof`) can be pointed at a production machine
+/// instead of copying files off it. The endpoints are `/flamegraph.svg`, `/speedscope.json` and
+/// `/pprof`, each covering the recorder's `window` (a minute if it isn't set). A `seconds` query
+/// parameter narrows that down, e.g. `/flamegraph.svg?seconds=10` for the last ten seconds.
+///
+/// The server doesn't authenticate requests, so it should listen on a loopback or otherwise
+/// trusted address.
+pub struct Server {
+    recorder: Arc<Recorder>,
+    listener: TcpListener,
+    done: AtomicBool,
+}
+
+impl Server {
+    /// Starts listening on `address`, e.g. `127.0.0.1:8080`. Nothing is recorded or served until
+    /// `run` is called.
+    pub fn new<A: ToSocketAddrs>(mut config: RecordConfig, address: A) -> Result<Self, Error> {
+        config.window.get_or_insert(DEFAULT_WINDOW);
+        let listener = TcpListener::bind(address).context("Failed to listen for HTTP requests")?;
+        // Waiting for requests can't block, so that the server notices when it's stopped
+        listener.set_nonblocking(true)?;
+        Ok(Server {
+            recorder: Arc::new(Recorder::new(config)),
+            listener,
+            done: AtomicBool::new(false),
+        })
+    }
+
+    /// The address that the server listens on, e.g. to find the port that was picked when
+    /// listening on port 0
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Records the process and answers requests until the process exits or `stop` is called.
+    /// Each request is answered on a thread of its own, up to `MAX_WORKERS` at once.
+    pub fn run(&self) -> Result<(), Error> {
+        let finished = Arc::new(AtomicBool::new(false));
+        let recording = {
+            let recorder = self.recorder.clone();
+            let finished = finished.clone();
+            std::thread::spawn(move || {
+                let result = recorder.record();
+                finished.store(true, Ordering::Relaxed);
+                result
+            })
+        };
+
+        let workers = Arc::new(AtomicUsize::new(0));
+        let mut result = Ok(());
+        while !self.done.load(Ordering::Relaxed) && !finished.load(Ordering::Relaxed) {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    if workers.load(Ordering::SeqCst) >= MAX_WORKERS {
+                        debug!("Closed HTTP connection from {}: too many requests", peer);
+                        continue;
+                    }
+                    let worker = Worker::start(&workers);
+                    let recorder = self.recorder.clone();
+                    std::thread::spawn(move || {
+                        let _worker = worker;
+                        if let Err(e) = answer(&recorder, stream) {
+                            debug!("Failed to answer HTTP request from {}: {:?}", peer, e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(POLL_INTERVAL)
+                }
+                Err(e) => {
+                    result = Err(Error::from(e).context("Failed to accept HTTP connection"));
+                    break;
+                }
+            }
+        }
+        self.recorder.stop();
+        // Requests that are still being answered read from the recorder
+        while workers.load(Ordering::SeqCst) > 0 {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        let recorded = recording
+            .join()
+            .map_err(|_| format_err!("The recording thread panicked"))?;
+        result.and(recorded)
+    }
+
+    /// Stops the recording and the server. Can be called from another thread while the server
+    /// is running.
+    pub fn stop(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Counts a request that's being answered until it's dropped
+struct Worker(Arc<AtomicUsize>);
+
+impl Worker {
+    fn start(workers: &Arc<AtomicUsize>) -> Worker {
+        workers.fetch_add(1, Ordering::SeqCst);
+        Worker(workers.clone())
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn answer(recorder: &Recorder, mut stream: TcpStream) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let response = match read_head(&mut stream)? {
+        Ok(head) => match parse_request(head.lines().next().unwrap_or_default()) {
+            Ok(request) => respond(recorder, &request),
+            Err(e) => Response::error(400, "Bad Request", &e.to_string()),
+        },
+        Err(response) => response,
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads the request line and headers, which the client has `TIMEOUT` to send in all. The headers
+/// don't matter, but are read so that the client sees a clean close. Requests that are too big are
+/// answered with the returned response instead.
+fn read_head(stream: &mut TcpStream) -> Result<Result<String, Response>> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        let end = find_head_end(&head);
+        let request_line = head.iter().position(|&b| b == b'\n').unwrap_or(head.len());
+        if request_line > MAX_REQUEST_LINE {
+            return Ok(Err(Response::error(
+                414,
+                "URI Too Long",
+                "The request line is too long",
+            )));
+        }
+        if end.unwrap_or(head.len()) > MAX_HEAD_SIZE {
+            return Ok(Err(Response::error(
+                431,
+                "Request Header Fields Too Large",
+                "The request's headers are too big",
+            )));
+        }
+        if let Some(end) = end {
+            head.truncate(end);
+            return Ok(Ok(String::from_utf8_lossy(&head).into_owned()));
+        }
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| format_err!("Timed out waiting for the request"))?;
+        stream.set_read_timeout(Some(remaining))?;
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            // The client stopped sending, so answer what it sent
+            return Ok(Ok(String::from_utf8_lossy(&head).into_owned()));
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+}
+
+/// Where the blank line that ends a request's headers starts
+fn find_head_end(head: &[u8]) -> Option<usize> {
+    let crlf = head.windows(4).position(|window| window == b"\r\n\r\n");
+    let lf = head.windows(2).position(|window| window == b"\n\n");
+    match (crlf, lf) {
+        (Some(crlf), Some(lf)) => Some(crlf.min(lf)),
+        (crlf, lf) => crlf.or(lf),
+    }
+}
+
+fn respond(recorder: &Recorder, request: &Request) -> Response {
+    if request.method != "GET" {
+        return Response::error(405, "Method Not Allowed", "Only GET requests are supported");
+    }
+    if request.path == "/" {
+        let paths: Vec<&str> = ENDPOINTS.iter().map(|(path, _, _)| *path).collect();
+        return Response::text(200, "OK", &format!("{}\n", paths.join("\n")));
+    }
+    let (format, content_type) = match ENDPOINTS.iter().find(|(path, _, _)| *path == request.path) {
+        Some((_, format, content_type)) => (format.clone(), *content_type),
+        None => return Response::error(404, "Not Found", "No such profile"),
+    };
+    let since = request
+        .seconds
+        .and_then(|seconds| SystemTime::now().checked_sub(Duration::from_secs(seconds)));
+    let mut body = Vec::new();
+    match recorder.write_recent(format, since, &mut body) {
+        Ok(0) => Response::error(
+            503,
+            "Service Unavailable",
+            "No samples have been taken in the window yet",
+        ),
+        Ok(_) => Response {
+            status: 200,
+            reason: "OK",
+            content_type,
+            body,
+        },
+        Err(e) => Response::error(500, "Internal Server Error", &format!("{:#}", e)),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    /// How many of the most recent seconds of the window to profile
+    seconds: Option<u64>,
+}
+
+fn parse_request(line: &str) -> Result<Request> {
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(format_err!("Invalid HTTP request line: {}", line.trim())),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut seconds = None;
+    for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+        if let Some(("seconds", value)) = parameter.split_once('=') {
+            seconds = Some(
+                value
+                    .parse()
+                    .context(format!("Invalid number of seconds: {}", value))?,
+            );
+        }
+    }
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        seconds,
+    })
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: u16, reason: &'static str, text: &str) -> Response {
+        Response {
+            status,
+            reason,
+            content_type: "text/plain; charset=utf-8",
+            body: text.as_bytes().to_vec(),
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: &str) -> Response {
+        Response::text(status, reason, &format!("{}\n", message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request("GET /flamegraph.svg?seconds=10&x=y HTTP/1.1\r\n").unwrap(),
+            Request {
+                method: "GET".to_string(),
+                path: "/flamegraph.svg".to_string(),
+                seconds: Some(10),
+            }
+        );
+        let request = parse_request("GET /pprof HTTP/1.1\r\n").unwrap();
+        assert_eq!((request.path.as_str(), request.seconds), ("/pprof", None));
+        assert!(parse_request("GET /pprof?seconds=ten HTTP/1.1\r\n").is_err());
+        assert!(parse_request("\r\n").is_err());
+    }
+
+    fn read_sent(request: &[u8]) -> Result<Result<String, Response>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        read_head(&mut stream)
+    }
+
+    #[test]
+    fn test_read_head() {
+        let head = read_sent(b"GET /pprof HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(head.ok().unwrap(), "GET /pprof HTTP/1.1\r\nHost: a");
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_REQUEST_LINE));
+        let response = read_sent(long_line.as_bytes()).unwrap().err().unwrap();
+        assert_eq!(response.status, 414);
+
+        let mut big_head = b"GET /pprof HTTP/1.1\r\n".to_vec();
+        for _ in 0..MAX_HEAD_SIZE / 16 {
+            big_head.extend_from_slice(b"X-Padding: 123\r\n");
+        }
+        let response = read_sent(&big_head).unwrap().err().unwrap();
+        assert_eq!(response.status, 431);
+    }
+}
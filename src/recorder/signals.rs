@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+/// No signal was received since the last time the requests were applied
+const NO_REQUEST: usize = 0;
+const PAUSE: usize = 1;
+const RESUME: usize = 2;
+
+/// What the last signal that was received asked for. When several arrive in between two checks,
+/// the last one wins.
+static REQUEST: AtomicUsize = AtomicUsize::new(NO_REQUEST);
+
+/// Pauses sampling when rbspy receives SIGUSR1, and resumes it on SIGUSR2. The signals' previous
+/// handlers are restored when this is dropped.
+pub(crate) struct PauseSignals {
+    paused: Arc<AtomicBool>,
+    #[cfg(unix)]
+    previous: Vec<(nix::sys::signal::Signal, nix::sys::signal::SigAction)>,
+}
+
+#[cfg(unix)]
+extern "C" fn handle(signal: std::os::raw::c_int) {
+    let request = if signal == libc::SIGUSR1 {
+        PAUSE
+    } else {
+        RESUME
+    };
+    REQUEST.store(request, Ordering::Relaxed);
+}
+
+impl PauseSignals {
+    /// `paused` is the sampler's pause flag, which the signals set
+    #[cfg(unix)]
+    pub fn install(paused: Arc<AtomicBool>) -> Result<PauseSignals> {
+        use anyhow::Context;
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+        let action = SigAction::new(
+            SigHandler::Handler(handle),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        REQUEST.store(NO_REQUEST, Ordering::Relaxed);
+        let mut signals = PauseSignals {
+            paused,
+            previous: Vec::new(),
+        };
+        for signal in [Signal::SIGUSR1, Signal::SIGUSR2] {
+            // The handler only sets an atomic, which is async-signal-safe
+            let previous = unsafe { sigaction(signal, &action) }
+                .context(format!("Failed to handle {}", signal))?;
+            signals.previous.push((signal, previous));
+        }
+        Ok(signals)
+    }
+
+    #[cfg(not(unix))]
+    pub fn install(_paused: Arc<AtomicBool>) -> Result<PauseSignals> {
+        Err(anyhow::format_err!(
+            "Pausing on signals isn't supported on this platform"
+        ))
+    }
+
+    /// Pauses or resumes sampling if a signal asked for it since the last time this was called
+    pub fn apply(&self) {
+        match REQUEST.swap(NO_REQUEST, Ordering::Relaxed) {
+            PAUSE if !self.paused.swap(true, Ordering::Relaxed) => {
+                info!("Pausing sampling (received SIGUSR1)")
+            }
+            RESUME if self.paused.swap(false, Ordering::Relaxed) => {
+                info!("Resuming sampling (received SIGUSR2)")
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for PauseSignals {
+    fn drop(&mut self) {
+        for (signal, previous) in &self.previous {
+            if let Err(e) = unsafe { nix::sys::signal::sigaction(*signal, previous) } {
+                warn!("Failed to restore the handler for {}: {}", signal, e);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_signals() {
+        // The handler is called directly, since other tests handle the same signals
+        let signals = PauseSignals {
+            paused: Arc::new(AtomicBool::new(false)),
+            previous: Vec::new(),
+        };
+        signals.apply();
+        assert!(!signals.paused.load(Ordering::Relaxed));
+        handle(libc::SIGUSR1);
+        signals.apply();
+        assert!(signals.paused.load(Ordering::Relaxed));
+        // Only the last signal counts
+        handle(libc::SIGUSR1);
+        handle(libc::SIGUSR2);
+        signals.apply();
+        assert!(!signals.paused.load(Ordering::Relaxed));
+        // Requests are only applied once
+        signals.paused.store(true, Ordering::Relaxed);
+        signals.apply();
+        assert!(signals.paused.load(Ordering::Relaxed));
+    }
+}
@@ -12,3 +12,196 @@ pub fn snapshot(
 ) -> Result<Option<StackTrace>, Error> {
     RubySpy::retry_new(pid, 10, force_version)?.get_stack_trace(lock_process, on_cpu)
 }
+
+/// Stays attached to the process belonging to `pid` without sampling it, and writes a snapshot
+/// of its threads' stacks to `output` each time rbspy receives `signal` (e.g. `libc::SIGUSR1`),
+/// like `jstack` does for the JVM. Each snapshot starts with a line giving the time it was taken,
+/// so `output` can be a file opened for appending, followed by every Ruby thread with its name,
+/// TID and state, starting with the one that holds the GVL. Before Ruby 2.2, only that thread
+/// can be found. Returns when the process exits or `done` is set.
+#[cfg(unix)]
+pub fn snapshot_on_signal(
+    pid: Pid,
+    lock_process: bool,
+    force_version: Option<String>,
+    on_cpu: bool,
+    signal: i32,
+    done: &std::sync::atomic::AtomicBool,
+    output: &mut dyn std::io::Write,
+) -> Result<(), Error> {
+    use crate::core::types::MemoryCopyError;
+    use std::sync::atomic::Ordering;
+
+    let mut spy = RubySpy::retry_new(pid, 10, force_version)?;
+    let _handler = signal_handler::install(signal)?;
+    while !done.load(Ordering::Relaxed) {
+        if !signal_handler::take_signal() {
+            if !crate::core::process::is_running(pid) {
+                debug!("Process {} ended", pid);
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            continue;
+        }
+        let time: chrono::DateTime<chrono::Local> = std::time::SystemTime::now().into();
+        match spy.get_all_stack_traces(lock_process, on_cpu) {
+            Ok(traces) if !traces.is_empty() => {
+                writeln!(
+                    output,
+                    "Snapshot at {}: process {}, {} thread{}\n",
+                    time.to_rfc3339(),
+                    pid,
+                    traces.len(),
+                    if traces.len() == 1 { "" } else { "s" }
+                )?;
+                for trace in &traces {
+                    write_thread(output, trace)?;
+                }
+            }
+            Ok(_) => writeln!(
+                output,
+                "Snapshot at {}: process {} wasn't running Ruby code\n",
+                time.to_rfc3339(),
+                pid
+            )?,
+            Err(e) => {
+                if let Some(MemoryCopyError::ProcessEnded) = e.downcast_ref() {
+                    debug!("Process {} ended", pid);
+                    return Ok(());
+                }
+                writeln!(
+                    output,
+                    "Snapshot at {}: failed to read the stack of process {}: {}\n",
+                    time.to_rfc3339(),
+                    pid,
+                    e
+                )?;
+            }
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Writes a thread's heading, e.g. `Thread "worker-1" (TID 4242): blocked in futex_wait_queue`,
+/// followed by its stack
+#[cfg(unix)]
+fn write_thread(output: &mut dyn std::io::Write, trace: &StackTrace) -> Result<(), Error> {
+    use crate::core::types::ThreadState;
+
+    write!(output, "Thread")?;
+    match (&trace.thread_name, trace.thread_id) {
+        (Some(name), _) => write!(output, " \"{}\"", name)?,
+        (None, Some(id)) => write!(output, " {:#x}", id)?,
+        (None, None) => {}
+    }
+    if let Some(id) = trace.os_thread_id {
+        write!(output, " (TID {})", id)?;
+    }
+    match trace.thread_state {
+        Some(ThreadState::Running) => write!(output, ": running")?,
+        Some(ThreadState::Blocked) => write!(output, ": blocked")?,
+        None => {}
+    }
+    if let Some(channel) = &trace.wait_channel {
+        write!(output, " in {}", channel)?;
+    }
+    writeln!(output, "\n{}\n", trace)?;
+    Ok(())
+}
+
+/// Notes when rbspy receives a signal, which `snapshot_on_signal` then takes snapshots for
+#[cfg(unix)]
+mod signal_handler {
+    use anyhow::{Context, Result};
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn handle(_signal: std::os::raw::c_int) {
+        SIGNALLED.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the signal was received since the last time this was called
+    pub fn take_signal() -> bool {
+        SIGNALLED.swap(false, Ordering::Relaxed)
+    }
+
+    /// Restores the signal's previous handler when dropped
+    pub struct Installed {
+        signal: Signal,
+        previous: SigAction,
+    }
+
+    pub fn install(signal: i32) -> Result<Installed> {
+        let signal = Signal::try_from(signal).context("Invalid snapshot signal")?;
+        let action = SigAction::new(
+            SigHandler::Handler(handle),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        SIGNALLED.store(false, Ordering::Relaxed);
+        // The handler only sets an atomic flag, which is async-signal-safe
+        let previous = unsafe { sigaction(signal, &action) }
+            .context(format!("Failed to handle {}", signal))?;
+        Ok(Installed { signal, previous })
+    }
+
+    impl Drop for Installed {
+        fn drop(&mut self) {
+            if let Err(e) = unsafe { sigaction(self.signal, &self.previous) } {
+                warn!("Failed to restore the handler for {}: {}", self.signal, e);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{signal_handler, write_thread};
+    use crate::core::types::{FrameKind, StackFrame, StackTrace, ThreadState};
+
+    #[test]
+    fn test_write_thread() {
+        let mut trace = StackTrace::new_empty();
+        trace.trace = vec![StackFrame {
+            name: "pop".to_string(),
+            relative_path: "worker.rb".to_string(),
+            absolute_path: None,
+            lineno: Some(7),
+            kind: FrameKind::Method,
+        }];
+        trace.thread_name = Some("worker-1".to_string());
+        trace.os_thread_id = Some(4242);
+        trace.thread_state = Some(ThreadState::Blocked);
+        trace.wait_channel = Some("futex_wait_queue".to_string());
+        let mut output = Vec::new();
+        write_thread(&mut output, &trace).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Thread \"worker-1\" (TID 4242): blocked in futex_wait_queue\npop - worker.rb:7\n\n"
+        );
+
+        trace.thread_name = None;
+        trace.thread_id = Some(0x7f00);
+        trace.thread_state = Some(ThreadState::Running);
+        trace.wait_channel = None;
+        let mut output = Vec::new();
+        write_thread(&mut output, &trace).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .starts_with("Thread 0x7f00 (TID 4242): running\n"));
+    }
+
+    #[test]
+    fn test_signal_handler() {
+        let handler = signal_handler::install(libc::SIGUSR2).unwrap();
+        assert!(!signal_handler::take_signal());
+        nix::sys::signal::raise(nix::sys::signal::Signal::SIGUSR2).unwrap();
+        assert!(signal_handler::take_signal());
+        assert!(!signal_handler::take_signal());
+        drop(handler);
+    }
+}
@@ -0,0 +1,386 @@
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{format_err, Context, Error, Result};
+use nix::sys::termios::{self, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+
+use crate::core::types::SummarySort;
+use crate::recorder::record::{Config as RecordConfig, Recorder};
+use crate::ui::summary::SummaryOptions;
+
+/// How often the table is redrawn when the recorder isn't configured with a live update interval
+const DEFAULT_REFRESH: Duration = Duration::from_secs(1);
+/// The lines above the table: the status line, the help line and a blank line
+const HEADER_LINES: usize = 3;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+/// Records a process and shows its hottest functions in the terminal, redrawn in place like
+/// `top`. Keys change what's shown while it runs:
+///
+/// * `s` cycles the order between self time, total time and name
+/// * `/` starts typing a filter, which only shows the functions whose name contains it (Enter
+///   applies it, Escape cancels it); Escape on its own clears the filter
+/// * `p` or space pauses and resumes sampling
+/// * `q` or Ctrl+C stops the recording
+///
+/// The percentages cover the whole recording so far, like `write_summary`'s.
+pub struct Top {
+    recorder: Arc<Recorder>,
+    refresh: Duration,
+    view: View,
+    done: AtomicBool,
+}
+
+impl Top {
+    /// `config.live_update_interval` is how often the table is redrawn (every second if it isn't
+    /// set), and `config.summary_options` is how it's ordered and filtered to start with.
+    pub fn new(mut config: RecordConfig) -> Top {
+        let refresh = config
+            .live_update_interval
+            .take()
+            .unwrap_or(DEFAULT_REFRESH);
+        let view = View::new(&config.summary_options);
+        Top {
+            recorder: Arc::new(Recorder::new(config)),
+            refresh,
+            view,
+            done: AtomicBool::new(false),
+        }
+    }
+
+    /// Records the process and redraws the table until the process exits, `q` is pressed or
+    /// `stop` is called. Standard input and output have to be a terminal.
+    pub fn run(&self) -> Result<(), Error> {
+        let stdin = std::io::stdin();
+        let terminal = RawTerminal::enter(stdin.as_raw_fd())
+            .context("Failed to set up the terminal; is standard input a terminal?")?;
+        let finished = Arc::new(AtomicBool::new(false));
+        let recording = {
+            let recorder = self.recorder.clone();
+            let finished = finished.clone();
+            std::thread::spawn(move || {
+                let result = recorder.record();
+                finished.store(true, Ordering::Relaxed);
+                result
+            })
+        };
+
+        let result = self.show(&finished, &mut stdin.lock(), &mut std::io::stdout());
+        self.recorder.stop();
+        drop(terminal);
+        let recorded = recording
+            .join()
+            .map_err(|_| format_err!("The recording thread panicked"))?;
+        result.and(recorded)
+    }
+
+    /// Stops the recording. Can be called from another thread while `run` is running.
+    pub fn stop(&self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+
+    /// Draws the table and reads keys until the recording has `finished` or is stopped
+    fn show(
+        &self,
+        finished: &AtomicBool,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let mut view = self.view.clone();
+        let mut last_draw = None;
+        write!(output, "\x1b[?1049h\x1b[?25l")?;
+        let result = loop {
+            if self.done.load(Ordering::Relaxed) || finished.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            let due = last_draw.map_or(true, |time: Instant| time.elapsed() >= self.refresh);
+            if due {
+                let (width, height) = term_size::dimensions_stdout().unwrap_or((80, 24));
+                let status = Status {
+                    elapsed: start.elapsed(),
+                    samples: self.recorder.total_traces(),
+                };
+                let mut screen = Vec::new();
+                view.draw(&self.recorder, &status, width, height, &mut screen)?;
+                output.write_all(&screen)?;
+                output.flush()?;
+                last_draw = Some(Instant::now());
+            }
+            // The terminal is set up so that this waits at most a tenth of a second
+            let mut keys = [0; 16];
+            let read = match input.read(&mut keys) {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => 0,
+                Err(e) => break Err(e.into()),
+            };
+            for key in &keys[..read] {
+                match view.key(*key) {
+                    Action::Quit => self.stop(),
+                    Action::Pause => self.recorder.pause(),
+                    Action::Resume => self.recorder.resume(),
+                    Action::Redraw => last_draw = None,
+                    Action::Nothing => {}
+                }
+            }
+        };
+        write!(output, "\x1b[?25h\x1b[?1049l")?;
+        output.flush()?;
+        result
+    }
+}
+
+/// Puts the terminal in non-canonical mode without echo, so that keys are read as they're
+/// pressed, until it's dropped
+struct RawTerminal {
+    fd: i32,
+    original: Termios,
+}
+
+impl RawTerminal {
+    fn enter(fd: i32) -> Result<RawTerminal> {
+        let original = termios::tcgetattr(fd)?;
+        let mut raw = original.clone();
+        // Ctrl+C is read as a key rather than raising SIGINT, so the terminal is always restored
+        raw.local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG);
+        // Reads return after a tenth of a second, whether or not a key was pressed
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw)?;
+        Ok(RawTerminal { fd, original })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(self.fd, SetArg::TCSANOW, &self.original);
+    }
+}
+
+struct Status {
+    elapsed: Duration,
+    samples: usize,
+}
+
+/// What a key press asks `Top` to do
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Quit,
+    Pause,
+    Resume,
+    Redraw,
+    Nothing,
+}
+
+/// How the table is ordered and filtered, as changed by key presses
+#[derive(Clone, Debug)]
+struct View {
+    sort: SummarySort,
+    filter: Option<String>,
+    /// The filter that's being typed, after `/` was pressed
+    editing: Option<String>,
+    paused: bool,
+    min_percent: Option<f64>,
+}
+
+impl View {
+    fn new(options: &SummaryOptions) -> View {
+        View {
+            sort: options.sort,
+            filter: options.filter.clone(),
+            editing: None,
+            paused: false,
+            min_percent: options.min_percent,
+        }
+    }
+
+    fn key(&mut self, key: u8) -> Action {
+        if let Some(editing) = &mut self.editing {
+            match key {
+                b'\r' | b'\n' => {
+                    self.filter = Some(editing.clone()).filter(|filter| !filter.is_empty());
+                    self.editing = None;
+                }
+                ESCAPE => self.editing = None,
+                BACKSPACE | DELETE => {
+                    editing.pop();
+                }
+                CTRL_C => return Action::Quit,
+                key if key == b' ' || key.is_ascii_graphic() => editing.push(key as char),
+                _ => return Action::Nothing,
+            }
+            return Action::Redraw;
+        }
+        match key {
+            b'q' | b'Q' | CTRL_C => Action::Quit,
+            b's' | b'S' => {
+                self.sort = match self.sort {
+                    SummarySort::self_ => SummarySort::total,
+                    SummarySort::total => SummarySort::name,
+                    SummarySort::name => SummarySort::self_,
+                };
+                Action::Redraw
+            }
+            b'/' => {
+                self.editing = Some(String::new());
+                Action::Redraw
+            }
+            ESCAPE if self.filter.is_some() => {
+                self.filter = None;
+                Action::Redraw
+            }
+            b'p' | b'P' | b' ' => {
+                self.paused = !self.paused;
+                if self.paused {
+                    Action::Pause
+                } else {
+                    Action::Resume
+                }
+            }
+            _ => Action::Nothing,
+        }
+    }
+
+    /// Draws the whole screen over what was there, with the table cut down to fit in `width`
+    /// by `height` characters
+    fn draw(
+        &self,
+        recorder: &Recorder,
+        status: &Status,
+        width: usize,
+        height: usize,
+        w: &mut dyn Write,
+    ) -> Result<()> {
+        let rows = height.saturating_sub(HEADER_LINES + 1);
+        let options = SummaryOptions {
+            sort: self.sort,
+            top: Some(rows),
+            min_percent: self.min_percent,
+            filter: self.filter.clone(),
+            color: true,
+            group_by: None,
+        };
+        let mut table = Vec::new();
+        recorder.write_summary_table(&mut table, &options, Some(width))?;
+        let table = String::from_utf8_lossy(&table);
+
+        write!(w, "\x1b[H")?;
+        for line in self.header(status, width) {
+            writeln!(w, "{}\x1b[K", line)?;
+        }
+        writeln!(w, "\x1b[K")?;
+        // Garbage collection estimates follow the table, and are left off if they don't fit
+        for line in table.lines().take(rows + 1) {
+            writeln!(w, "{}\x1b[K", line)?;
+        }
+        write!(w, "\x1b[J")?;
+        Ok(())
+    }
+
+    /// The status line and the help line, which shows the filter that's being typed instead
+    /// while there is one
+    fn header(&self, status: &Status, width: usize) -> [String; 2] {
+        let sort = match self.sort {
+            SummarySort::self_ => "self",
+            SummarySort::total => "total",
+            SummarySort::name => "name",
+        };
+        let mut line = format!(
+            "rbspy top - {}s elapsed, {} samples, sorted by {}",
+            status.elapsed.as_secs(),
+            status.samples,
+            sort
+        );
+        if let Some(filter) = &self.filter {
+            line.push_str(&format!(", matching \"{}\"", filter));
+        }
+        if self.paused {
+            line.push_str(" [paused]");
+        }
+        let help = match &self.editing {
+            Some(editing) => format!("Filter: {}_", editing),
+            None => "s: sort  /: filter  Esc: clear filter  p: pause  q: quit".to_string(),
+        };
+        [truncate(line, width), truncate(help, width)]
+    }
+}
+
+fn truncate(mut line: String, width: usize) -> String {
+    if let Some((end, _)) = line.char_indices().nth(width) {
+        line.truncate(end);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let mut view = View::new(&SummaryOptions::default());
+        assert_eq!(view.key(b's'), Action::Redraw);
+        assert_eq!(view.sort, SummarySort::total);
+        view.key(b's');
+        view.key(b's');
+        assert_eq!(view.sort, SummarySort::self_);
+
+        for key in b"/ab\x7fc\r" {
+            assert_eq!(view.key(*key), Action::Redraw);
+        }
+        assert_eq!(view.filter.as_deref(), Some("ac"));
+        assert_eq!(view.editing, None);
+        // Cancelling a new filter keeps the old one, and Escape on its own clears it
+        for key in b"/x\x1b" {
+            view.key(*key);
+        }
+        assert_eq!(view.filter.as_deref(), Some("ac"));
+        assert_eq!(view.key(ESCAPE), Action::Redraw);
+        assert_eq!(view.filter, None);
+        assert_eq!(view.key(ESCAPE), Action::Nothing);
+
+        assert_eq!(view.key(b'p'), Action::Pause);
+        assert_eq!(view.key(b' '), Action::Resume);
+        // Keys typed into the filter don't act as commands
+        view.key(b'/');
+        assert_eq!(view.key(b'q'), Action::Redraw);
+        assert_eq!(view.editing.as_deref(), Some("q"));
+        assert_eq!(view.key(CTRL_C), Action::Quit);
+        assert_eq!(
+            View::new(&SummaryOptions::default()).key(b'q'),
+            Action::Quit
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        let mut view = View::new(&SummaryOptions {
+            sort: SummarySort::total,
+            filter: Some("puma".to_string()),
+            ..Default::default()
+        });
+        view.paused = true;
+        let status = Status {
+            elapsed: Duration::from_secs(12),
+            samples: 1200,
+        };
+        let [line, help] = view.header(&status, 200);
+        assert_eq!(
+            line,
+            "rbspy top - 12s elapsed, 1200 samples, sorted by total, matching \"puma\" [paused]"
+        );
+        assert!(help.starts_with("s: sort"));
+        view.key(b'/');
+        view.key(b'x');
+        assert_eq!(view.header(&status, 10)[1], "Filter: x_");
+        assert_eq!(view.header(&status, 5)[0], "rbspy");
+    }
+}
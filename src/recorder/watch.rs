@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Error, Result};
+use regex::Regex;
+
+use crate::core::process::Pid;
+use crate::core::types::StackTrace;
+use crate::recorder::window::Window;
+
+/// A configuration bundle for watching a process for a code path
+pub struct Config {
+    /// The process ID (PID) of the process to watch
+    pub pid: Pid,
+    /// Whether to watch the process's child processes too, and their child processes, and so
+    /// on. Default: `false`.
+    pub with_subprocesses: bool,
+    /// The number of traces that should be collected each second. Default: `100`.
+    pub sample_rate: u32,
+    /// Locks the process when a sample is being taken. See `RecordConfig::lock_process`.
+    pub lock_process: bool,
+    /// Forces the watcher to use the given Ruby version. Default: none (detect it).
+    pub force_version: Option<String>,
+    /// Whether to only count samples of threads that are running on the CPU. Default: `false`.
+    pub on_cpu: bool,
+    /// A regular expression for the frames to watch for, e.g. `ActiveRecord::.*#execute`. It's
+    /// matched against each frame in its `name - path:line` form, so it can match paths too.
+    pub pattern: String,
+    /// The share of recent samples, as a percentage, that have to contain a matching frame for an
+    /// alert to fire, e.g. `30.0`
+    pub threshold: f64,
+    /// How far back samples count as recent. No alert fires until the watcher has been sampling
+    /// for this long. Default: 10 seconds.
+    pub window: Duration,
+    /// What to do when an alert fires, e.g. `vec![WatchAction::Log]`. Once it has fired, an alert
+    /// doesn't fire again until the share of matching samples has dropped below the threshold.
+    pub actions: Vec<WatchAction>,
+}
+
+/// Something to do when a watched code path takes up more than its share of recent samples
+pub enum WatchAction {
+    /// Write a warning to rbspy's log
+    Log,
+    /// POST the alert as JSON to a plain `http://` URL
+    Webhook(String),
+    /// Append the alert and the matching stacks to a file
+    Dump(PathBuf),
+    /// Call a function with the alert
+    Hook(Box<dyn Fn(&Alert) + Send + Sync>),
+}
+
+/// A watched code path taking up more than its share of recent samples
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Alert {
+    pub time: SystemTime,
+    pub pid: Pid,
+    pub pattern: String,
+    /// The share of recent samples that contained a matching frame, as a percentage
+    pub share: f64,
+    pub threshold: f64,
+    /// The number of recent samples
+    pub samples: usize,
+    /// The recent samples that contained a matching frame, as collapsed stacks (outermost frame
+    /// first), with how many samples had each stack
+    pub stacks: BTreeMap<String, usize>,
+}
+
+pub struct Watcher {
+    pid: Pid,
+    pattern: Regex,
+    threshold: f64,
+    window_length: Duration,
+    actions: Vec<WatchAction>,
+    sampler: crate::sampler::Sampler,
+}
+
+impl Watcher {
+    pub fn new(config: Config) -> Result<Self> {
+        let pattern = Regex::new(&config.pattern)
+            .context(format!("Invalid frame pattern {}", config.pattern))?;
+        let sampler = crate::sampler::Sampler::new(
+            config.pid,
+            crate::sampler::SampleOptions {
+                sample_rate: config.sample_rate,
+                lock_process: config.lock_process,
+                with_subprocesses: config.with_subprocesses,
+                force_version: config.force_version,
+                on_cpu: config.on_cpu,
+                ..Default::default()
+            },
+        );
+        Ok(Watcher {
+            pid: config.pid,
+            pattern,
+            threshold: config.threshold,
+            window_length: config.window,
+            actions: config.actions,
+            sampler,
+        })
+    }
+
+    /// Samples the process until it exits or the stop function is called, firing alerts along
+    /// the way. Fails if an alert's actions fail.
+    pub fn watch(&self) -> Result<(), Error> {
+        let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(100);
+        let (result_sender, result_receiver) = std::sync::mpsc::channel();
+        self.sampler.start(trace_sender, result_sender)?;
+
+        let start = Instant::now();
+        let mut window = Window::new(self.window_length);
+        let mut fired = false;
+        for trace in trace_receiver {
+            window.push(trace);
+            if start.elapsed() < self.window_length {
+                continue;
+            }
+            let alert = self.check(&window);
+            match (alert, fired) {
+                (Some(alert), false) => {
+                    self.fire(&alert)?;
+                    fired = true;
+                }
+                (None, true) => fired = false,
+                _ => {}
+            }
+        }
+        crate::sampler::sampler_result(result_receiver)
+    }
+
+    /// Stops the watcher
+    pub fn stop(&self) {
+        self.sampler.stop();
+    }
+
+    /// An alert, if matching frames are in more than the threshold's share of the window
+    fn check(&self, window: &Window) -> Option<Alert> {
+        let mut samples = 0;
+        let mut stacks = BTreeMap::new();
+        for trace in window.iter() {
+            samples += 1;
+            if self.matches(trace) {
+                *stacks.entry(collapsed(trace)).or_insert(0) += 1;
+            }
+        }
+        let matching: usize = stacks.values().sum();
+        let share = match samples {
+            0 => 0.0,
+            _ => 100.0 * matching as f64 / samples as f64,
+        };
+        if matching == 0 || share < self.threshold {
+            return None;
+        }
+        Some(Alert {
+            time: SystemTime::now(),
+            pid: self.pid,
+            pattern: self.pattern.to_string(),
+            share,
+            threshold: self.threshold,
+            samples,
+            stacks,
+        })
+    }
+
+    fn matches(&self, trace: &StackTrace) -> bool {
+        trace
+            .trace
+            .iter()
+            .any(|frame| self.pattern.is_match(&frame.to_string()))
+    }
+
+    fn fire(&self, alert: &Alert) -> Result<()> {
+        for action in &self.actions {
+            match action {
+                WatchAction::Log => warn!(
+                    "{} matched {:.1}% of the last {} samples of process {}, over the {}% threshold",
+                    alert.pattern, alert.share, alert.samples, alert.pid, alert.threshold
+                ),
+                WatchAction::Webhook(url) => {
+                    let body = serde_json::to_vec(alert)?;
+                    crate::core::http::post(url, "application/json", &body)
+                        .context("Failed to send the alert to its webhook")?;
+                }
+                WatchAction::Dump(path) => {
+                    let mut file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .context(format!("Failed to open {}", path.display()))?;
+                    write_dump(alert, &mut file)?;
+                }
+                WatchAction::Hook(hook) => hook(alert),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn collapsed(trace: &StackTrace) -> String {
+    let frames: Vec<String> = trace.iter().rev().map(|frame| frame.to_string()).collect();
+    frames.join(";")
+}
+
+fn write_dump(alert: &Alert, w: &mut dyn Write) -> Result<()> {
+    let time: chrono::DateTime<chrono::Local> = alert.time.into();
+    writeln!(
+        w,
+        "Alert at {}: {} matched {:.1}% of the last {} samples of process {}",
+        time.to_rfc3339(),
+        alert.pattern,
+        alert.share,
+        alert.samples,
+        alert.pid
+    )?;
+    for (stack, count) in &alert.stacks {
+        writeln!(w, "{} {}", stack, count)?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{FrameKind, StackFrame};
+
+    fn trace(names: &[&str]) -> StackTrace {
+        let mut trace = StackTrace::new_empty();
+        trace.time = Some(SystemTime::now());
+        trace.trace = names
+            .iter()
+            .map(|name| StackFrame {
+                name: name.to_string(),
+                relative_path: "app.rb".to_string(),
+                absolute_path: None,
+                lineno: Some(1),
+                kind: FrameKind::Method,
+            })
+            .collect();
+        trace
+    }
+
+    #[test]
+    fn test_check() {
+        let watcher = Watcher::new(Config {
+            pattern: "ActiveRecord::.*#execute".to_string(),
+            ..watcher_config()
+        })
+        .unwrap();
+
+        let mut window = Window::new(Duration::from_secs(10));
+        let slow = trace(&["ActiveRecord::Base#execute", "<main>"]);
+        window.push(slow.clone());
+        for _ in 0..3 {
+            window.push(trace(&["work", "<main>"]));
+        }
+        assert_eq!(watcher.check(&window), None);
+
+        window.push(slow);
+        let alert = watcher.check(&window).unwrap();
+        assert_eq!((alert.share, alert.samples), (40.0, 5));
+        let mut stacks = BTreeMap::new();
+        stacks.insert(
+            "<main> - app.rb:1;ActiveRecord::Base#execute - app.rb:1".to_string(),
+            2,
+        );
+        assert_eq!(alert.stacks, stacks);
+
+        let mut dump = Vec::new();
+        write_dump(&alert, &mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.contains("ActiveRecord::.*#execute matched 40.0% of the last 5 samples"));
+        assert!(dump.ends_with("ActiveRecord::Base#execute - app.rb:1 2\n\n"));
+
+        assert!(Watcher::new(Config {
+            pattern: "(".to_string(),
+            ..watcher_config()
+        })
+        .is_err());
+    }
+
+    fn watcher_config() -> Config {
+        Config {
+            pid: 1,
+            with_subprocesses: false,
+            sample_rate: 100,
+            lock_process: false,
+            force_version: None,
+            on_cpu: false,
+            pattern: ".".to_string(),
+            threshold: 30.0,
+            window: Duration::from_secs(10),
+            actions: vec![],
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use crate::core::types::StackTrace;
+
+/// Holds the traces from the most recent stretch of a recording, dropping older traces as new ones
+/// arrive
+pub(crate) struct Window {
+    length: Duration,
+    traces: VecDeque<StackTrace>,
+}
+
+impl Window {
+    pub fn new(length: Duration) -> Self {
+        Window {
+            length,
+            traces: VecDeque::new(),
+        }
+    }
+
+    /// Adds a trace, evicting any traces that were taken more than the window's length before it
+    pub fn push(&mut self, trace: StackTrace) {
+        let newest = trace.time.unwrap_or_else(SystemTime::now);
+        while let Some(oldest) = self.traces.front() {
+            let expired = match oldest.time.map(|time| newest.duration_since(time)) {
+                Some(Ok(age)) => age > self.length,
+                // The clock was set back since the oldest trace was taken, so its age is unknown
+                Some(Err(_)) => false,
+                None => true,
+            };
+            if !expired {
+                break;
+            }
+            self.traces.pop_front();
+        }
+        self.traces.push_back(trace);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &StackTrace> {
+        self.traces.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace_at(secs: u64) -> StackTrace {
+        let mut trace = StackTrace::new_empty();
+        trace.time = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        trace
+    }
+
+    fn times(window: &Window) -> Vec<u64> {
+        window
+            .iter()
+            .map(|trace| {
+                trace
+                    .time
+                    .unwrap()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_window_evicts_old_traces() {
+        let mut window = Window::new(Duration::from_secs(60));
+        for secs in &[0, 30, 60] {
+            window.push(trace_at(*secs));
+        }
+        assert_eq!(times(&window), vec![0, 30, 60]);
+
+        window.push(trace_at(61));
+        assert_eq!(times(&window), vec![30, 60, 61]);
+
+        window.push(trace_at(200));
+        assert_eq!(times(&window), vec![200]);
+    }
+}
@@ -1,53 +1,161 @@
 use anyhow::{Context, Error, Result};
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::mpsc::{Sender, SyncSender};
-use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 #[cfg(windows)]
 use winapi::um::timeapi;
 
+use crate::core::audit::{AuditEvent, AuditLog};
+use crate::core::marker::RequestMarker;
+use crate::core::memory::ReadMethod;
 use crate::core::process::{Pid, Process, ProcessRetry};
-use crate::core::types::{MemoryCopyError, StackTrace};
+use crate::core::types::{
+    Backpressure, Credentials, DepthLimit, DutyCycle, MemoryCopyError, StackFrame, StackTrace,
+    ThreadFilter,
+};
 
+mod queue;
+
+use queue::TraceSender;
+
+/// Samples a process, and optionally its subprocesses, in threads of its own. `start` sets one up
+/// from a `Config`, for programs that embed rbspy.
 #[derive(Debug)]
 pub struct Sampler {
     done: Arc<AtomicBool>,
-    lock_process: bool,
+    paused: Arc<AtomicBool>,
     root_pid: Pid,
-    sample_rate: u32,
-    time_limit: Option<Duration>,
+    options: SampleOptions,
     timing_error_traces: Arc<AtomicUsize>,
     total_traces: Arc<AtomicUsize>,
-    with_subprocesses: bool,
-    force_version: Option<String>,
-    on_cpu: bool,
+    error_traces: Arc<AtomicUsize>,
+    dropped_traces: Arc<AtomicUsize>,
+    /// When the duty cycle's schedule started
+    schedule_start: Mutex<Option<Instant>>,
+    notes: Arc<Mutex<Vec<String>>>,
+    ruby_version: Arc<Mutex<Option<String>>>,
+}
+
+/// How a `Sampler` samples its processes. `RecordConfig` has the details of most of these.
+#[derive(Clone, Debug)]
+pub struct SampleOptions {
+    /// The number of traces that should be collected each second. Default: `100`.
+    pub sample_rate: u32,
+    /// Locks the process when a sample is being taken. Default: `false`.
+    pub lock_process: bool,
+    /// How long to sample for. Default: none (until the process exits or sampling is stopped).
+    pub time_limit: Option<Duration>,
+    /// Whether to sample the process's descendents too. Default: `false`.
+    pub with_subprocesses: bool,
+    /// Other processes to sample along with the root process. Default: none.
+    pub extra_pids: Vec<Pid>,
+    /// The pidfile to find the root process in again after it restarts. Default: none (stop
+    /// when the root process exits).
+    pub reattach_pidfile: Option<PathBuf>,
+    /// Forces the sampler to use the given Ruby version. Default: none (detect it).
+    pub force_version: Option<String>,
+    /// Whether to only take samples of threads that are running on the CPU. Default: `false`.
+    pub on_cpu: bool,
+    /// Whether to take wall-clock samples instead when on-CPU samples can't be told apart
+    /// reliably, rather than failing to start. Default: `false`.
+    pub on_cpu_fallback: bool,
+    /// Limits how many frames each trace keeps. Default: none (keep every frame).
+    pub depth_limit: Option<DepthLimit>,
+    /// Whether to read the VM's counters along with each trace. Default: `false`.
+    pub vm_stats: bool,
+    /// Whether to label traces with the request that their thread was working on. Default:
+    /// `false`.
+    pub request_marker: bool,
+    /// Whether to sample every Ruby thread, not only the current one. Default: `false`.
+    pub all_threads: bool,
+    /// Whether to label traces with their thread's GVL state. Default: `false`.
+    pub gvl_labels: bool,
+    /// Whether to add a frame for the garbage collector while it runs, to the trace of the thread
+    /// that holds the GVL. Default: `false`.
+    pub gc_frames: bool,
+    /// The share of a CPU that sampling each process may take. Default: none (no limit).
+    pub max_overhead: Option<f64>,
+    /// Which threads to keep the traces of. Default: none (every thread).
+    pub thread_filter: Option<ThreadFilter>,
+    /// The user to switch to once the process has been attached to. Default: none.
+    pub drop_privileges: Option<Credentials>,
+    /// Where to record attaching to and detaching from processes. Default: none.
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Whether to refuse anything that would affect the sampled process. Default: `false`.
+    pub read_only: bool,
+    /// Whether to avoid the reads that security modules often deny. Default: `false`.
+    pub confined: bool,
+    /// Whether to lower the sample rate when samples can't keep up with it. Default: `false`.
+    pub adapt_rate: bool,
+    /// When to take samples, in bursts. Default: none (all the time).
+    pub duty_cycle: Option<DutyCycle>,
+    /// What to do when the traces aren't received as fast as they're taken. Default: `block`.
+    pub backpressure: Backpressure,
+}
+
+impl Default for SampleOptions {
+    fn default() -> SampleOptions {
+        SampleOptions {
+            sample_rate: 100,
+            lock_process: false,
+            time_limit: None,
+            with_subprocesses: false,
+            extra_pids: vec![],
+            reattach_pidfile: None,
+            force_version: None,
+            on_cpu: false,
+            on_cpu_fallback: false,
+            depth_limit: None,
+            vm_stats: false,
+            request_marker: false,
+            all_threads: false,
+            gvl_labels: false,
+            gc_frames: false,
+            max_overhead: None,
+            thread_filter: None,
+            drop_privileges: None,
+            audit_log: None,
+            read_only: false,
+            confined: false,
+            adapt_rate: false,
+            duty_cycle: None,
+            backpressure: Backpressure::default(),
+        }
+    }
 }
 
 impl Sampler {
-    pub fn new(
-        pid: Pid,
-        sample_rate: u32,
-        lock_process: bool,
-        time_limit: Option<Duration>,
-        with_subprocesses: bool,
-        force_version: Option<String>,
-        on_cpu: bool,
-    ) -> Self {
+    pub fn new(pid: Pid, options: SampleOptions) -> Self {
         Sampler {
             done: Arc::new(AtomicBool::new(false)),
-            lock_process,
+            paused: Arc::new(AtomicBool::new(false)),
             root_pid: pid,
-            sample_rate,
-            time_limit,
+            options,
             timing_error_traces: Arc::new(AtomicUsize::new(0)),
             total_traces: Arc::new(AtomicUsize::new(0)),
-            with_subprocesses,
-            force_version,
-            on_cpu,
+            error_traces: Arc::new(AtomicUsize::new(0)),
+            dropped_traces: Arc::new(AtomicUsize::new(0)),
+            schedule_start: Mutex::new(None),
+            notes: Arc::new(Mutex::new(Vec::new())),
+            ruby_version: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// The process that's being sampled (along with its subprocesses, if enabled)
+    pub fn root_pid(&self) -> Pid {
+        self.root_pid
+    }
+
+    /// Every process that was asked to be sampled: the root process, then the other processes
+    pub fn target_pids(&self) -> Vec<Pid> {
+        let mut pids = vec![self.root_pid];
+        pids.extend(&self.options.extra_pids);
+        pids
+    }
+
     pub fn total_traces(&self) -> usize {
         self.total_traces.load(Ordering::Relaxed)
     }
@@ -56,6 +164,35 @@ impl Sampler {
         self.timing_error_traces.load(Ordering::Relaxed)
     }
 
+    /// Things that happened while sampling that affect how the profile should be read, e.g. that
+    /// the sample rate was lowered
+    pub fn notes(&self) -> Vec<String> {
+        self.notes.lock().unwrap().clone()
+    }
+
+    /// The Ruby version of the first process that was attached to, once one has been
+    pub fn ruby_version(&self) -> Option<String> {
+        self.ruby_version.lock().unwrap().clone()
+    }
+
+    /// The notes after the first `seen` of them
+    pub(crate) fn new_notes(&self, seen: usize) -> Vec<String> {
+        let notes = self.notes.lock().unwrap();
+        notes.get(seen..).unwrap_or_default().to_vec()
+    }
+
+    /// The number of traces that were dropped because the recorder couldn't keep up with them
+    /// (see `Backpressure`)
+    pub fn dropped_traces(&self) -> usize {
+        self.dropped_traces.load(Ordering::Relaxed)
+    }
+
+    /// The number of samples that failed, e.g. because the process's memory changed while it was
+    /// being read
+    pub fn error_traces(&self) -> usize {
+        self.error_traces.load(Ordering::Relaxed)
+    }
+
     /// Start thread(s) recording a PID and possibly its children. Tracks new processes
     /// Returns a pair of Receivers from which you can consume recorded stacktraces and errors
     pub fn start(
@@ -63,75 +200,159 @@ impl Sampler {
         trace_sender: SyncSender<StackTrace>,
         result_sender: Sender<Result<(), Error>>,
     ) -> Result<(), Error> {
-        let done = self.done.clone();
-        let root_pid = self.root_pid.clone();
-        let sample_rate = self.sample_rate.clone();
-        let maybe_stop_time = match self.time_limit {
-            Some(duration) => Some(std::time::Instant::now() + duration),
-            None => None,
+        let options = &self.options;
+        if options.read_only {
+            check_read_only(options.lock_process)?;
+        }
+        if options.with_subprocesses && options.drop_privileges.is_some() {
+            // Attaching to subprocesses that start later needs the privileges that were dropped
+            return Err(anyhow::format_err!(
+                "Privileges can't be dropped when recording subprocesses"
+            ));
+        }
+        if !options.extra_pids.is_empty() && options.drop_privileges.is_some() {
+            // Privileges are dropped for all of rbspy, so the first process to be attached to
+            // would keep the others from being attached to
+            return Err(anyhow::format_err!(
+                "Privileges can't be dropped when recording several processes"
+            ));
+        }
+        if options.reattach_pidfile.is_some() && options.with_subprocesses {
+            return Err(anyhow::format_err!(
+                "Re-attaching from a pidfile isn't supported when recording subprocesses"
+            ));
+        }
+        if options.reattach_pidfile.is_some() && options.drop_privileges.is_some() {
+            // The same goes for the process that replaces the root process
+            return Err(anyhow::format_err!(
+                "Privileges can't be dropped when re-attaching from a pidfile"
+            ));
+        }
+        if let Some(duty_cycle) = &options.duty_cycle {
+            duty_cycle.validate()?;
+        }
+        if let Some(depth_limit) = &options.depth_limit {
+            depth_limit.validate()?;
+        }
+        if let Some(max_overhead) = options.max_overhead {
+            if !(max_overhead > 0.0 && max_overhead <= 1.0) {
+                return Err(anyhow::format_err!(
+                    "The overhead budget must be more than 0 and at most 1 (one CPU), not {}",
+                    max_overhead
+                ));
+            }
+        }
+        let mut options = options.clone();
+        if let (true, Some(limitation)) = (options.on_cpu, on_cpu_limitation(options.confined)) {
+            if !options.on_cpu_fallback {
+                return Err(anyhow::format_err!(
+                    "On-CPU samples can't be told apart reliably here: {}. Record wall-clock samples instead, or allow falling back to them.",
+                    limitation
+                ));
+            }
+            let note = format!(
+                "Recorded wall-clock samples instead of on-CPU samples, since they can't be told apart reliably here: {}",
+                limitation
+            );
+            warn!("{}", note);
+            self.notes.lock().unwrap().push(note);
+            options.on_cpu = false;
+        }
+        // Every process is sampled on the same schedule, including subprocesses that start later
+        let schedule = options
+            .duty_cycle
+            .map(|duty_cycle| (duty_cycle, std::time::Instant::now()));
+        *self.schedule_start.lock().unwrap() = schedule.map(|(_, start)| start);
+        let context = SampleContext {
+            maybe_stop_time: options
+                .time_limit
+                .map(|duration| std::time::Instant::now() + duration),
+            schedule,
+            done: self.done.clone(),
+            paused: self.paused.clone(),
+            timing_error_traces: self.timing_error_traces.clone(),
+            total_traces: self.total_traces.clone(),
+            error_traces: self.error_traces.clone(),
+            sender: TraceSender::new(
+                trace_sender,
+                options.backpressure,
+                QUEUE_CAPACITY,
+                self.dropped_traces.clone(),
+            ),
+            notes: self.notes.clone(),
+            ruby_version: self.ruby_version.clone(),
+            options: Arc::new(options),
         };
-        let lock_process = self.lock_process.clone();
-        let force_version = self.force_version.clone();
-        let on_cpu = self.on_cpu.clone();
-        let result_sender = result_sender.clone();
-        let timing_error_traces = self.timing_error_traces.clone();
-        let total_traces = self.total_traces.clone();
-
-        if self.with_subprocesses {
+        let target_pids = self.target_pids();
+        let root_pid = self.root_pid;
+        let done = self.done.clone();
+
+        if context.options.with_subprocesses {
             // Start a thread which watches for new descendents and starts new recorders when they
             // appear
-            let done_clone = self.done.clone();
             std::thread::spawn(move || {
-                let process = Process::new_with_retry(root_pid).unwrap();
-                let mut pids: HashSet<Pid> = HashSet::new();
+                let roots: Vec<Process> = target_pids
+                    .iter()
+                    .map(|&pid| Process::new_with_retry(pid).unwrap())
+                    .collect();
+                // The program that each process was running when it was attached to. A process
+                // that execs another program (e.g. a shell wrapper that execs Ruby) is attached
+                // to again.
+                let mut attached: HashMap<Pid, String> = HashMap::new();
                 // we need to exit this loop when the process we're monitoring exits, otherwise the
                 // sender channels won't get closed and rbspy will hang. So we check the done
                 // mutex.
-                while !done_clone.load(Ordering::Relaxed) {
-                    let mut descendents: Vec<Pid> = process
-                        .child_processes()
-                        .expect("Error finding descendents of pid")
-                        .into_iter()
-                        .map(|tuple| tuple.0)
-                        .collect();
-                    descendents.push(root_pid);
+                while !done.load(Ordering::Relaxed) {
+                    let mut descendents: Vec<Pid> = Vec::new();
+                    for process in &roots {
+                        match process.child_processes() {
+                            Ok(children) => {
+                                descendents.extend(children.into_iter().map(|tuple| tuple.0))
+                            }
+                            Err(e) => debug!(
+                                "Couldn't find descendents of process {}: {:?}",
+                                process.pid, e
+                            ),
+                        }
+                        descendents.push(process.pid);
+                    }
+                    // Forget processes that have exited, in case their PIDs are reused
+                    attached.retain(|pid, _| descendents.contains(pid));
 
                     for pid in descendents {
-                        if pids.contains(&pid) {
+                        let exe = match program(pid) {
+                            Some(exe) => exe,
+                            // It exited already
+                            None => continue,
+                        };
+                        if attached.get(&pid) == Some(&exe) {
                             // already recording it, no need to start a new recording thread
                             continue;
                         }
-                        pids.insert(pid);
+                        if attached.contains_key(&pid) {
+                            debug!("Process {} is now running {}", pid, exe);
+                        }
+                        attached.insert(pid, exe);
                         let done_root = done.clone();
-                        let done_thread = done.clone();
                         let result_sender = result_sender.clone();
-                        let timing_error_traces = timing_error_traces.clone();
-                        let total_traces = total_traces.clone();
-                        let trace_sender_clone = trace_sender.clone();
-                        let force_version = force_version.clone();
-                        let on_cpu = on_cpu.clone();
+                        let context = context.clone();
+                        let target_pids = target_pids.clone();
 
                         std::thread::spawn(move || {
-                            let result = sample(
-                                pid,
-                                sample_rate,
-                                maybe_stop_time,
-                                done_thread,
-                                timing_error_traces,
-                                total_traces,
-                                trace_sender_clone,
-                                lock_process,
-                                force_version,
-                                on_cpu,
-                            );
+                            let result = sample(pid, context);
                             result_sender.send(result).expect("couldn't send error");
                             drop(result_sender);
 
-                            if pid == root_pid {
+                            // The root process might have exec'd another program, which is
+                            // attached to separately. Recording goes on until every root process
+                            // has ended.
+                            if target_pids.contains(&pid) && program(pid).is_none() {
                                 debug!("Root process {} ended", pid);
-                                // we need to store done = true here to signal the other threads here that we
-                                // should stop profiling
-                                done_root.store(true, Ordering::Relaxed);
+                                if target_pids.iter().all(|&pid| program(pid).is_none()) {
+                                    // we need to store done = true here to signal the other threads here that we
+                                    // should stop profiling
+                                    done_root.store(true, Ordering::Relaxed);
+                                }
                             }
                         });
                     }
@@ -140,23 +361,55 @@ impl Sampler {
                 }
             });
         } else {
-            // Start a single recorder thread
-            std::thread::spawn(move || {
-                let result = sample(
-                    root_pid,
-                    sample_rate,
-                    maybe_stop_time,
-                    done,
-                    timing_error_traces,
-                    total_traces,
-                    trace_sender,
-                    lock_process,
-                    force_version,
-                    on_cpu,
-                );
-                result_sender.send(result).unwrap();
-                drop(result_sender);
-            });
+            // Start a recorder thread for each process
+            for pid in target_pids {
+                let context = context.clone();
+                let result_sender = result_sender.clone();
+                let reattach_pidfile = context
+                    .options
+                    .reattach_pidfile
+                    .clone()
+                    .filter(|_| pid == root_pid);
+                std::thread::spawn(move || {
+                    let mut pid = pid;
+                    loop {
+                        let result = sample(pid, context.clone());
+                        // Sampling failed for another reason than the process exiting
+                        let failed = result.is_err() && program(pid).is_some();
+                        result_sender.send(result).unwrap();
+                        let pidfile = match &reattach_pidfile {
+                            Some(pidfile) if !failed && !context.done.load(Ordering::Relaxed) => {
+                                pidfile
+                            }
+                            _ => break,
+                        };
+                        info!(
+                            "Process {} exited; waiting for {} to name its replacement",
+                            pid,
+                            pidfile.display()
+                        );
+                        let new_pid = match wait_for_restart(
+                            pidfile,
+                            pid,
+                            &context.done,
+                            context.maybe_stop_time,
+                        ) {
+                            Some(new_pid) => new_pid,
+                            None => break,
+                        };
+                        let note = format!(
+                            "Process {} exited and was restarted as process {}, which was re-attached to from {}",
+                            pid,
+                            new_pid,
+                            pidfile.display()
+                        );
+                        info!("{}", note);
+                        context.notes.lock().unwrap().push(note);
+                        pid = new_pid;
+                    }
+                    drop(result_sender);
+                });
+            }
         }
 
         return Ok(());
@@ -165,28 +418,350 @@ impl Sampler {
     pub fn stop(&self) {
         self.done.store(true, Ordering::Relaxed);
     }
+
+    /// Stops taking samples until `resume` is called, without detaching from the processes
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Which burst of the duty cycle is under way, if there's a duty cycle with gaps between its
+    /// bursts
+    pub(crate) fn duty_cycle_burst(&self) -> Option<u64> {
+        let duty_cycle = self
+            .options
+            .duty_cycle
+            .filter(|cycle| cycle.on < cycle.period)?;
+        let start = (*self.schedule_start.lock().unwrap())?;
+        Some(duty_cycle.cycle(start.elapsed()))
+    }
+
+    /// The flag that `pause` and `resume` set, for pausing from another thread
+    pub(crate) fn paused_flag(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
 }
 
-/// Samples stack traces and sends them to a channel in another thread where they can be aggregated
-fn sample(
+/// What `start` samples and how
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The process ID (PID) of the process to sample
+    pub pid: Pid,
+    /// Whether to sample the process's child processes too, and their child processes, and so
+    /// on. New processes are found within a second. Default: `false`.
+    pub with_subprocesses: bool,
+    /// The number of traces that should be collected each second. Default: `100`.
+    pub sample_rate: u32,
+    /// How long to sample for. Default: none (until the process exits or sampling is stopped).
+    pub time_limit: Option<Duration>,
+    /// Whether to only take samples of threads that are running on the CPU, rather than blocked
+    /// (e.g. waiting for IO or a lock). Default: `false`.
+    pub on_cpu: bool,
+    /// Locks the process when a sample is being taken. See `RecordConfig::lock_process`.
+    /// Default: `false`.
+    pub lock_process: bool,
+    /// Forces the sampler to use the given Ruby version. Default: none (detect it).
+    pub force_version: Option<String>,
+    /// Limits how many frames each trace keeps. Default: none (keep every frame).
+    pub depth_limit: Option<DepthLimit>,
+    /// Which threads to sample. Default: none (sample the thread that holds the GVL).
+    pub thread_filter: Option<ThreadFilter>,
+    /// What to do when the traces aren't received as fast as they're taken. Default: `block`.
+    pub backpressure: Backpressure,
+}
+
+impl Config {
+    pub fn new(pid: Pid) -> Config {
+        Config {
+            pid,
+            with_subprocesses: false,
+            sample_rate: 100,
+            time_limit: None,
+            on_cpu: false,
+            lock_process: false,
+            force_version: None,
+            depth_limit: None,
+            thread_filter: None,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+/// Starts sampling a process, returning the traces as they're taken and a handle that controls
+/// the sampling. The receiver is closed once sampling has ended, which is when the process exits,
+/// the time limit passes or the handle is stopped (or dropped).
+pub fn start(config: Config) -> Result<(Receiver<StackTrace>, SamplerHandle), Error> {
+    let sampler = Sampler::new(
+        config.pid,
+        SampleOptions {
+            sample_rate: config.sample_rate,
+            lock_process: config.lock_process,
+            time_limit: config.time_limit,
+            with_subprocesses: config.with_subprocesses,
+            force_version: config.force_version,
+            on_cpu: config.on_cpu,
+            depth_limit: config.depth_limit,
+            thread_filter: config.thread_filter,
+            backpressure: config.backpressure,
+            ..SampleOptions::default()
+        },
+    );
+    let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(QUEUE_CAPACITY);
+    let (result_sender, result_receiver) = std::sync::mpsc::channel();
+    sampler.start(trace_sender, result_sender)?;
+    Ok((
+        trace_receiver,
+        SamplerHandle {
+            sampler,
+            results: Some(result_receiver),
+        },
+    ))
+}
+
+/// Controls the sampling that `start` started. Dropping the handle stops sampling.
+pub struct SamplerHandle {
+    sampler: Sampler,
+    results: Option<Receiver<Result<(), Error>>>,
+}
+
+impl SamplerHandle {
+    /// Stops sampling. The traces that were already taken can still be received.
+    pub fn stop(&self) {
+        self.sampler.stop();
+    }
+
+    /// Stops taking samples until `resume` is called, without detaching from the processes
+    pub fn pause(&self) {
+        self.sampler.pause();
+    }
+
+    pub fn resume(&self) {
+        self.sampler.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.sampler.is_paused()
+    }
+
+    /// The number of samples that have been taken so far, including the ones that failed
+    pub fn total_traces(&self) -> usize {
+        self.sampler.total_traces()
+    }
+
+    /// The number of samples that failed, e.g. because the process's memory changed while it was
+    /// being read
+    pub fn error_traces(&self) -> usize {
+        self.sampler.error_traces()
+    }
+
+    /// The number of traces that were dropped because they weren't received fast enough (see
+    /// `Config::backpressure`)
+    pub fn dropped_traces(&self) -> usize {
+        self.sampler.dropped_traces()
+    }
+
+    /// The Ruby version of the first process that was attached to, once one has been
+    pub fn ruby_version(&self) -> Option<String> {
+        self.sampler.ruby_version()
+    }
+
+    /// Things that happened while sampling that affect how the traces should be read, e.g. that
+    /// the sample rate was lowered
+    pub fn notes(&self) -> Vec<String> {
+        self.sampler.notes()
+    }
+
+    /// Waits for sampling to end, and returns an error if no process could be sampled. Errors
+    /// from some of the subprocesses (e.g. ones that aren't Ruby processes) are ignored. Unless
+    /// the backpressure drops traces, the traces have to keep being received (or the receiver
+    /// dropped) for sampling to end.
+    pub fn wait(mut self) -> Result<(), Error> {
+        match self.results.take() {
+            Some(results) => sampler_result(results),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for SamplerHandle {
+    fn drop(&mut self) {
+        self.sampler.stop();
+    }
+}
+
+/// Checks for errors from the sampler's threads. Errors are ignored unless every single thread
+/// returned an error. If that happens, the last error is returned. This lets rbspy successfully
+/// record processes even if the parent thread isn't a Ruby process.
+pub(crate) fn sampler_result(result_receiver: Receiver<Result<(), Error>>) -> Result<(), Error> {
+    let mut num_ok = 0;
+    let mut last_result = Ok(());
+    for result in result_receiver {
+        if result.is_ok() {
+            num_ok += 1;
+        }
+        last_result = result;
+    }
+
+    match num_ok {
+        0 => last_result,
+        _ => Ok(()),
+    }
+}
+
+/// Refuses options that would affect the target process in read-only mode. On Linux, memory is
+/// read with `process_vm_readv`, which doesn't stop or attach to the target; other platforms
+/// aren't supported.
+fn check_read_only(lock_process: bool) -> Result<(), Error> {
+    if lock_process {
+        return Err(anyhow::format_err!(
+            "Processes can't be locked in read-only mode, since locking stops them with ptrace"
+        ));
+    }
+    if !cfg!(target_os = "linux") {
+        return Err(anyhow::format_err!(
+            "Read-only mode is only supported on Linux"
+        ));
+    }
+    Ok(())
+}
+
+/// Why threads that are on the CPU can't be told apart reliably from ones that are blocked, if
+/// they can't. Ruby considers threads that are blocked in IO or other system calls to be
+/// runnable, so rbspy asks the OS what each sampled thread is doing, which is only possible on
+/// Linux.
+fn on_cpu_limitation(confined: bool) -> Option<&'static str> {
+    if !cfg!(target_os = "linux") {
+        Some("only Linux reports whether a thread that Ruby considers runnable is blocked in a system call")
+    } else if confined {
+        Some("confined mode doesn't ask the OS what each thread is doing")
+    } else {
+        None
+    }
+}
+
+/// Writes an audit record when sampling of a process ends, however it ends
+struct AuditDetach {
+    audit_log: Arc<AuditLog>,
     pid: Pid,
-    sample_rate: u32,
+}
+
+impl Drop for AuditDetach {
+    fn drop(&mut self) {
+        if let Err(e) = self
+            .audit_log
+            .record(AuditEvent::Detach, Some(self.pid), None)
+        {
+            warn!("Failed to write to the audit log: {:?}", e);
+        }
+    }
+}
+
+/// What the threads that sample each process share
+#[derive(Clone)]
+struct SampleContext {
+    /// The sampler's options, with `on_cpu` turned off if it fell back to wall-clock samples
+    options: Arc<SampleOptions>,
     maybe_stop_time: Option<Instant>,
+    schedule: Option<(DutyCycle, Instant)>,
     done: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
     timing_error_traces: Arc<AtomicUsize>,
     total_traces: Arc<AtomicUsize>,
-    sender: SyncSender<StackTrace>,
-    lock_process: bool,
-    force_version: Option<String>,
-    on_cpu: bool,
-) -> Result<(), Error> {
-    let mut process =
-        crate::core::ruby_spy::RubySpy::retry_new(pid, 10, force_version).context("new spy")?;
+    error_traces: Arc<AtomicUsize>,
+    sender: TraceSender,
+    notes: Arc<Mutex<Vec<String>>>,
+    ruby_version: Arc<Mutex<Option<String>>>,
+}
+
+/// Samples stack traces and sends them to a channel in another thread where they can be aggregated
+fn sample(pid: Pid, context: SampleContext) -> Result<(), Error> {
+    let SampleContext {
+        options,
+        maybe_stop_time,
+        schedule,
+        done,
+        paused,
+        timing_error_traces,
+        total_traces,
+        error_traces,
+        sender,
+        notes,
+        ruby_version,
+    } = context;
+    let SampleOptions {
+        sample_rate,
+        lock_process,
+        ref force_version,
+        on_cpu,
+        depth_limit,
+        vm_stats,
+        request_marker,
+        all_threads,
+        gvl_labels,
+        gc_frames,
+        max_overhead,
+        ref thread_filter,
+        ref drop_privileges,
+        ref audit_log,
+        confined,
+        adapt_rate,
+        ..
+    } = *options;
+    let mut process = crate::core::ruby_spy::RubySpy::retry_new(pid, 10, force_version.clone())
+        .context("new spy")?;
+    process.set_os_thread_activity(!confined);
+    process.set_gvl_labels(gvl_labels);
+    process.set_gvl_owner_tracking(gc_frames);
+    ruby_version
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| process.ruby_version().to_string());
+    if process.read_method() != ReadMethod::Native {
+        notes.lock().unwrap().push(format!(
+            "Process {}'s memory was read through {} because {} isn't allowed here",
+            pid,
+            process.read_method(),
+            ReadMethod::Native
+        ));
+    }
+    let _detach = match audit_log {
+        Some(audit_log) => {
+            audit_log.record(AuditEvent::Attach, Some(pid), None)?;
+            Some(AuditDetach {
+                audit_log: audit_log.clone(),
+                pid,
+            })
+        }
+        None => None,
+    };
+    if let Some(credentials) = drop_privileges {
+        crate::core::process::drop_privileges(credentials).context("drop privileges")?;
+    }
+
+    let mut marker = if request_marker {
+        Some(RequestMarker::new(pid))
+    } else {
+        None
+    };
 
     let mut total = 0;
     let mut errors = 0;
 
+    let mut current_rate = sample_rate;
     let mut sample_time = SampleTime::new(sample_rate);
+    let mut nominal_interval = Duration::from_nanos(BILLION / u64::from(sample_rate));
+    let mut rate_check = Some(RateCheck::new(sample_rate));
+    let mut overhead_budget =
+        max_overhead.map(|max_overhead| OverheadBudget::new(max_overhead, sample_rate));
+    let mut last_sample_time: Option<Instant> = None;
+    let mut gvl_owner_noted = false;
     #[cfg(windows)]
     {
         // This changes a system-wide setting on Windows so that the OS wakes up every 1ms
@@ -201,13 +776,103 @@ fn sample(
     }
 
     while !done.load(Ordering::Relaxed) {
+        let off_duty = schedule.map_or(false, |(duty_cycle, started)| {
+            !duty_cycle.is_on(started.elapsed())
+        });
+        if paused.load(Ordering::Relaxed) || off_duty {
+            if maybe_stop_time.map_or(false, |stop_time| Instant::now() > stop_time) {
+                done.store(true, Ordering::Relaxed);
+                break;
+            }
+            std::thread::sleep(nominal_interval);
+            // The time spent paused isn't part of the next sample's interval, and the sampling
+            // schedule starts over when sampling resumes, as does measuring the sample rate
+            last_sample_time = None;
+            sample_time = SampleTime::new(current_rate);
+            if let Some(check) = &mut rate_check {
+                check.restart();
+            }
+            continue;
+        }
         total += 1;
-        let trace = process.get_stack_trace(lock_process, on_cpu);
-        match trace {
-            Ok(Some(ok_trace)) => {
-                sender.send(ok_trace).context("send trace")?;
+        // The first sample stands in for one nominal interval
+        let now = Instant::now();
+        let interval = last_sample_time.map_or(nominal_interval, |last| now - last);
+        last_sample_time = Some(now);
+        let traces = if all_threads {
+            process.get_all_stack_traces(lock_process, on_cpu)
+        } else {
+            process
+                .get_stack_trace(lock_process, on_cpu)
+                .map(|trace| trace.into_iter().collect())
+        };
+        if gvl_labels && !gvl_owner_noted && process.gvl_owner_guessed() {
+            notes.lock().unwrap().push(format!(
+                "Process {}'s GVL owner couldn't be read from Ruby {}, so its samples' gvl labels take the thread that last acquired the GVL to still hold it",
+                pid,
+                process.ruby_version()
+            ));
+            gvl_owner_noted = true;
+        }
+        match traces {
+            Ok(traces) => {
+                // Whether each trace's thread holds the GVL. If the owner couldn't be read, it's
+                // taken to be the VM's current thread, whose trace comes first.
+                let gvl_owner = process.sampled_gvl_owner();
+                let traces: Vec<(bool, StackTrace)> = traces
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, trace)| match (gvl_owner, trace.thread_address) {
+                        (Some(owner), Some(thread_address)) => {
+                            (owner == Some(thread_address), trace)
+                        }
+                        _ => (i == 0, trace),
+                    })
+                    .filter(|(_, trace)| {
+                        thread_filter
+                            .as_ref()
+                            .map_or(true, |filter| filter.matches(trace))
+                    })
+                    .collect();
+                // The VM's counters are shared by all of its threads
+                let stats = if (vm_stats || gc_frames) && !traces.is_empty() {
+                    match process.get_vm_stats() {
+                        Ok(stats) => Some(stats),
+                        Err(e) => {
+                            debug!("Couldn't read VM stats: {:?}", e);
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                for (owner, mut ok_trace) in traces {
+                    ok_trace.interval = Some(interval);
+                    // The GC runs on the thread that holds the GVL, not the others
+                    let in_gc = gc_frames && owner && stats.map_or(false, |stats| stats.during_gc);
+                    if let Some(limit) = &depth_limit {
+                        // Leaves room for the GC frame, so that it's kept whichever end is cut
+                        let reserved = usize::from(in_gc && limit.max_depth > 1);
+                        ok_trace.truncate(&DepthLimit {
+                            max_depth: limit.max_depth - reserved,
+                            ..*limit
+                        });
+                    }
+                    if in_gc {
+                        ok_trace.trace.insert(0, StackFrame::garbage_collection());
+                        if let Some(limit) = &depth_limit {
+                            ok_trace.trace.truncate(limit.max_depth);
+                        }
+                    }
+                    if vm_stats {
+                        ok_trace.vm_stats = stats;
+                    }
+                    if let Some(marker) = &mut marker {
+                        marker.label(process.memory(), &mut ok_trace);
+                    }
+                    sender.send(ok_trace).context("send trace")?;
+                }
             }
-            Ok(None) => {}
             Err(e) => {
                 if let Some(MemoryCopyError::ProcessEnded) = e.downcast_ref() {
                     debug!("Process {} ended", pid);
@@ -215,6 +880,7 @@ fn sample(
                 }
 
                 errors += 1;
+                error_traces.fetch_add(1, Ordering::Relaxed);
                 if errors > 20 && (errors as f64) / (total as f64) > 0.5 {
                     // TODO: Return error type instead of printing here
                     print_errors(errors, total);
@@ -222,6 +888,54 @@ fn sample(
                 }
             }
         }
+        let sample_cost = now.elapsed();
+        if let Some(check) = &mut rate_check {
+            if let Some(shortfall) = check.sample(sample_cost) {
+                let mut note = format!(
+                    "Sampled process {} at {:.0} samples per second instead of the requested {}, since each sample took {:?} on average",
+                    pid, shortfall.achieved_rate, sample_rate, shortfall.sample_cost
+                );
+                if adapt_rate {
+                    note.push_str(&format!(
+                        ". Lowered the sample rate to {}.",
+                        shortfall.attainable_rate
+                    ));
+                    current_rate = shortfall.attainable_rate;
+                    sample_time = SampleTime::new(current_rate);
+                    nominal_interval =
+                        Duration::from_nanos(BILLION / u64::from(shortfall.attainable_rate));
+                } else {
+                    note.push_str(". Samples are weighted by the time between them, so the profile is still accurate, but has fewer samples than expected.");
+                }
+                warn!("{}", note);
+                notes.lock().unwrap().push(note);
+            }
+            if check.is_done() {
+                rate_check = None;
+            }
+        }
+        if let Some(budget) = &mut overhead_budget {
+            if let Some(adjustment) = budget.sample(sample_cost, current_rate) {
+                debug!(
+                    "Changed process {}'s sample rate from {} to {}, since each sample took {:?} on average",
+                    pid, current_rate, adjustment.rate, adjustment.sample_cost
+                );
+                if adjustment.noteworthy {
+                    let note = format!(
+                        "Lowered process {}'s sample rate to {} samples per second to keep sampling under {:.1}% of a CPU, since each sample took {:?} on average",
+                        pid,
+                        adjustment.rate,
+                        100.0 * budget.max_overhead,
+                        adjustment.sample_cost
+                    );
+                    warn!("{}", note);
+                    notes.lock().unwrap().push(note);
+                }
+                current_rate = adjustment.rate;
+                sample_time = SampleTime::new(current_rate);
+                nominal_interval = Duration::from_nanos(BILLION / u64::from(current_rate));
+            }
+        }
         if let Some(stop_time) = maybe_stop_time {
             if std::time::Instant::now() > stop_time {
                 // need to store done for same reason as above
@@ -251,6 +965,35 @@ fn sample(
     Ok(())
 }
 
+/// Waits for the pidfile to name a running process other than `old_pid`, which replaced it when
+/// it restarted. Gives up when sampling is stopped or its time limit passes.
+fn wait_for_restart(
+    pidfile: &Path,
+    old_pid: Pid,
+    done: &AtomicBool,
+    maybe_stop_time: Option<Instant>,
+) -> Option<Pid> {
+    while !done.load(Ordering::Relaxed) {
+        if maybe_stop_time.map_or(false, |stop_time| Instant::now() > stop_time) {
+            done.store(true, Ordering::Relaxed);
+            break;
+        }
+        match crate::core::process::read_pidfile(pidfile) {
+            Ok(pid) if pid != old_pid && program(pid).is_some() => return Some(pid),
+            Ok(_) => {}
+            // The pidfile is usually removed while the process restarts
+            Err(e) => debug!("{:#}", e),
+        }
+        std::thread::sleep(REATTACH_INTERVAL);
+    }
+    None
+}
+
+/// The program that a process is running, or none if it has exited
+fn program(pid: Pid) -> Option<String> {
+    Process::new(pid).and_then(|process| process.exe()).ok()
+}
+
 fn print_errors(errors: usize, total: usize) {
     if errors > 0 {
         eprintln!(
@@ -272,6 +1015,13 @@ struct SampleTime {
     num_samples: u64,
 }
 
+/// How many traces can wait for the recorder before `Backpressure` applies, on top of the
+/// recorder's own channel
+const QUEUE_CAPACITY: usize = 100;
+
+/// How often the pidfile is checked for a new process while waiting to re-attach
+const REATTACH_INTERVAL: Duration = Duration::from_millis(100);
+
 const BILLION: u64 = 1000 * 1000 * 1000; // for nanosleep
 
 impl SampleTime {
@@ -298,6 +1048,156 @@ impl SampleTime {
     }
 }
 
+/// Finds out early in a recording whether samples are taken as often as requested, since when
+/// each sample takes too long (e.g. for very deep stacks), fewer samples are taken
+struct RateCheck {
+    requested_rate: u32,
+    start_time: Instant,
+    samples: u32,
+    sample_cost: Duration,
+    done: bool,
+}
+
+/// A sample rate that couldn't be kept up
+#[derive(Debug, PartialEq)]
+struct RateShortfall {
+    achieved_rate: f64,
+    /// How long each sample took, on average
+    sample_cost: Duration,
+    /// A rate that leaves some time between samples
+    attainable_rate: u32,
+}
+
+impl RateCheck {
+    /// How long the rate is measured for before it's checked
+    const MEASURE_FOR: Duration = Duration::from_secs(2);
+
+    fn new(requested_rate: u32) -> RateCheck {
+        RateCheck {
+            requested_rate,
+            start_time: Instant::now(),
+            samples: 0,
+            sample_cost: Duration::from_secs(0),
+            done: false,
+        }
+    }
+
+    /// Starts measuring again, e.g. once sampling resumes after a pause, which would otherwise
+    /// count as time in which no samples were taken
+    fn restart(&mut self) {
+        *self = RateCheck::new(self.requested_rate);
+    }
+
+    /// Counts a sample that took `cost` to take. Returns the shortfall once, if the rate that's
+    /// achieved is well below the requested rate.
+    fn sample(&mut self, cost: Duration) -> Option<RateShortfall> {
+        self.samples += 1;
+        self.sample_cost += cost;
+        let elapsed = self.start_time.elapsed();
+        if elapsed < RateCheck::MEASURE_FOR {
+            return None;
+        }
+        self.done = true;
+        self.shortfall(elapsed)
+    }
+
+    fn shortfall(&self, elapsed: Duration) -> Option<RateShortfall> {
+        let achieved_rate = f64::from(self.samples) / elapsed.as_secs_f64();
+        if achieved_rate >= f64::from(self.requested_rate) / 2.0 {
+            return None;
+        }
+        let sample_cost = self.sample_cost / self.samples;
+        // Leave a quarter of the time between samples for the process to run
+        let attainable_rate = (0.75 / sample_cost.as_secs_f64().max(1e-9)) as u32;
+        Some(RateShortfall {
+            achieved_rate,
+            sample_cost,
+            attainable_rate: attainable_rate.clamp(1, self.requested_rate),
+        })
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/// Keeps the time spent taking samples under a fraction of one CPU, by lowering the sample rate
+/// when samples get expensive and raising it again (up to the requested rate) when they get
+/// cheaper
+struct OverheadBudget {
+    /// The fraction of one CPU that sampling may use
+    max_overhead: f64,
+    requested_rate: u32,
+    period_start: Instant,
+    samples: u32,
+    sample_cost: Duration,
+    /// The lowest rate that was noted, so that only big drops are noted
+    noted_rate: Option<u32>,
+}
+
+/// A new sample rate that fits the overhead budget
+#[derive(Debug, PartialEq)]
+struct RateAdjustment {
+    rate: u32,
+    /// How long each sample took, on average
+    sample_cost: Duration,
+    /// Whether the rate dropped far enough to be noted in the recording
+    noteworthy: bool,
+}
+
+impl OverheadBudget {
+    /// How long the cost of samples is measured for before the rate is adjusted
+    const MEASURE_FOR: Duration = Duration::from_secs(1);
+
+    fn new(max_overhead: f64, requested_rate: u32) -> OverheadBudget {
+        OverheadBudget {
+            max_overhead,
+            requested_rate,
+            period_start: Instant::now(),
+            samples: 0,
+            sample_cost: Duration::from_secs(0),
+            noted_rate: None,
+        }
+    }
+
+    /// Counts a sample that took `cost` to take at `current_rate`. Returns a new rate at the end
+    /// of each measurement period, if the current one is over the budget or well under it.
+    fn sample(&mut self, cost: Duration, current_rate: u32) -> Option<RateAdjustment> {
+        self.samples += 1;
+        self.sample_cost += cost;
+        if self.period_start.elapsed() < OverheadBudget::MEASURE_FOR {
+            return None;
+        }
+        let sample_cost = self.sample_cost / self.samples;
+        self.period_start = Instant::now();
+        self.samples = 0;
+        self.sample_cost = Duration::from_secs(0);
+        self.adjust(sample_cost, current_rate)
+    }
+
+    fn adjust(&mut self, sample_cost: Duration, current_rate: u32) -> Option<RateAdjustment> {
+        let affordable = (self.max_overhead / sample_cost.as_secs_f64().max(1e-9))
+            .min(f64::from(self.requested_rate))
+            .max(1.0) as u32;
+        // Raising the rate a little isn't worth starting the sampling schedule over for
+        if affordable >= current_rate && affordable <= current_rate + current_rate / 4 {
+            return None;
+        }
+        let noteworthy = affordable < current_rate
+            && self
+                .noted_rate
+                .map_or(true, |noted_rate| affordable <= noted_rate / 2);
+        if noteworthy {
+            self.noted_rate = Some(affordable);
+        }
+        Some(RateAdjustment {
+            rate: affordable,
+            sample_cost,
+            noteworthy,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[cfg(not(target_os = "windows"))]
@@ -306,7 +1206,117 @@ mod tests {
     use std::process::Command;
 
     use crate::core::process::{tests::RubyScript, Pid};
-    use crate::sampler::Sampler;
+    use crate::sampler::{
+        check_read_only, on_cpu_limitation, start, wait_for_restart, Config, OverheadBudget,
+        RateAdjustment, RateCheck, RateShortfall, SampleOptions, Sampler,
+    };
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_read_only_refuses_locking() {
+        assert!(check_read_only(true).is_err());
+        #[cfg(target_os = "linux")]
+        assert!(check_read_only(false).is_ok());
+    }
+
+    #[test]
+    fn test_on_cpu_limitation() {
+        assert!(on_cpu_limitation(true).is_some());
+        #[cfg(target_os = "linux")]
+        assert_eq!(on_cpu_limitation(false), None);
+        #[cfg(not(target_os = "linux"))]
+        assert!(on_cpu_limitation(false).is_some());
+    }
+
+    #[test]
+    fn test_rate_check() {
+        let mut check = RateCheck::new(100);
+        check.samples = 150;
+        check.sample_cost = Duration::from_secs(1);
+        assert_eq!(check.shortfall(Duration::from_secs(2)), None);
+
+        check.samples = 40;
+        check.sample_cost = Duration::from_secs(2);
+        assert_eq!(
+            check.shortfall(Duration::from_secs(2)),
+            Some(RateShortfall {
+                achieved_rate: 20.0,
+                sample_cost: Duration::from_millis(50),
+                attainable_rate: 15,
+            })
+        );
+    }
+
+    #[test]
+    fn test_rate_check_after_pause() {
+        // Sampling started paused, and was paused for longer than the rate is measured for
+        let mut check = RateCheck::new(100);
+        check.start_time = Instant::now() - RateCheck::MEASURE_FOR * 2;
+        check.restart();
+        // The first sample once it resumes isn't taken for a rate of 0 samples per second
+        assert_eq!(check.sample(Duration::from_millis(1)), None);
+        assert!(!check.is_done());
+    }
+
+    #[test]
+    fn test_overhead_budget() {
+        let mut budget = OverheadBudget::new(0.01, 100);
+        // 100µs samples at 100 per second are exactly 1% of a CPU
+        assert_eq!(budget.adjust(Duration::from_micros(100), 100), None);
+        assert_eq!(
+            budget.adjust(Duration::from_micros(400), 100),
+            Some(RateAdjustment {
+                rate: 25,
+                sample_cost: Duration::from_micros(400),
+                noteworthy: true,
+            })
+        );
+        // Small drops after the first aren't noted again
+        assert_eq!(
+            budget.adjust(Duration::from_micros(500), 25),
+            Some(RateAdjustment {
+                rate: 20,
+                sample_cost: Duration::from_micros(500),
+                noteworthy: false,
+            })
+        );
+        // Slightly cheaper samples don't raise the rate, but much cheaper ones do, up to the
+        // requested rate
+        assert_eq!(budget.adjust(Duration::from_micros(450), 20), None);
+        assert_eq!(
+            budget.adjust(Duration::from_micros(10), 20).map(|adjustment| adjustment.rate),
+            Some(100)
+        );
+        // The rate never drops to 0
+        assert_eq!(
+            budget.adjust(Duration::from_secs(1), 100).map(|adjustment| adjustment.rate),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_wait_for_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("puma.pid");
+        let own_pid = std::process::id() as Pid;
+        let done = AtomicBool::new(false);
+        std::fs::write(&pidfile, format!("{}\n", own_pid)).unwrap();
+        assert_eq!(
+            wait_for_restart(&pidfile, own_pid + 1, &done, None),
+            Some(own_pid)
+        );
+
+        // The pidfile still names the process that exited, until the time limit passes
+        let stop_time = Instant::now() + Duration::from_millis(300);
+        assert_eq!(
+            wait_for_restart(&pidfile, own_pid, &done, Some(stop_time)),
+            None
+        );
+        assert!(done.load(Ordering::Relaxed));
+        std::fs::remove_file(&pidfile).unwrap();
+        assert_eq!(wait_for_restart(&pidfile, own_pid + 1, &done, None), None);
+    }
 
     #[test]
     fn test_sample_single_process() {
@@ -319,7 +1329,13 @@ mod tests {
         let mut process = RubyScript::new("ci/ruby-programs/infinite.rb");
         let pid = process.id() as Pid;
 
-        let sampler = Sampler::new(pid, 100, true, None, false, None);
+        let sampler = Sampler::new(
+            pid,
+            SampleOptions {
+                lock_process: true,
+                ..SampleOptions::default()
+            },
+        );
         let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(100);
         let (result_sender, result_receiver) = std::sync::mpsc::channel();
         sampler
@@ -348,11 +1364,11 @@ mod tests {
 
         let sampler = Sampler::new(
             pid,
-            100,
-            true,
-            Some(std::time::Duration::from_millis(500)),
-            false,
-            None,
+            SampleOptions {
+                lock_process: true,
+                time_limit: Some(std::time::Duration::from_millis(500)),
+                ..SampleOptions::default()
+            },
         );
         let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(100);
         let (result_sender, result_receiver) = std::sync::mpsc::channel();
@@ -371,6 +1387,73 @@ mod tests {
         result.expect("unexpected error");
     }
 
+    #[test]
+    fn test_sample_several_processes() {
+        #[cfg(target_os = "macos")]
+        if !nix::unistd::Uid::effective().is_root() {
+            println!("Skipping test because we're not running as root");
+            return;
+        }
+
+        let mut first = RubyScript::new("ci/ruby-programs/infinite.rb");
+        let mut second = RubyScript::new("ci/ruby-programs/infinite.rb");
+        let pids = [first.id() as Pid, second.id() as Pid];
+
+        let sampler = Sampler::new(
+            pids[0],
+            SampleOptions {
+                lock_process: true,
+                time_limit: Some(std::time::Duration::from_millis(500)),
+                extra_pids: vec![pids[1]],
+                ..SampleOptions::default()
+            },
+        );
+        assert_eq!(sampler.target_pids(), pids);
+        let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(100);
+        let (result_sender, result_receiver) = std::sync::mpsc::channel();
+        sampler
+            .start(trace_sender, result_sender)
+            .expect("sampler failed to start");
+
+        let mut sampled = HashSet::<Pid>::new();
+        for trace in trace_receiver {
+            sampled.insert(trace.pid.unwrap());
+        }
+        assert_eq!(sampled, pids.iter().cloned().collect());
+
+        first.kill().expect("failed to kill process");
+        second.kill().expect("failed to kill process");
+
+        for result in result_receiver.iter().take(2) {
+            result.expect("unexpected error");
+        }
+    }
+
+    #[test]
+    fn test_start() {
+        #[cfg(target_os = "macos")]
+        if !nix::unistd::Uid::effective().is_root() {
+            println!("Skipping test because we're not running as root");
+            return;
+        }
+
+        let mut process = RubyScript::new("ci/ruby-programs/infinite.rb");
+        let pid = process.id() as Pid;
+
+        let mut config = Config::new(pid);
+        config.lock_process = true;
+        let (traces, handle) = start(config).expect("sampler failed to start");
+        let trace = traces.recv().expect("failed to receive trace");
+        assert_eq!(trace.pid.unwrap(), pid);
+        assert!(handle.total_traces() > 0);
+
+        handle.stop();
+        // Sampling ends once the traces that were already taken have been received
+        for _ in traces {}
+        handle.wait().expect("unexpected error");
+        process.kill().expect("failed to kill process");
+    }
+
     // TODO: Find a more reliable way to test this on Windows hosts
     #[cfg(not(target_os = "windows"))]
     #[test]
@@ -409,7 +1492,15 @@ mod tests {
             .unwrap();
         let pid = process.id() as Pid;
 
-        let sampler = Sampler::new(pid, 5, true, None, true, None);
+        let sampler = Sampler::new(
+            pid,
+            SampleOptions {
+                sample_rate: 5,
+                lock_process: true,
+                with_subprocesses: true,
+                ..SampleOptions::default()
+            },
+        );
         let (trace_sender, trace_receiver) = std::sync::mpsc::sync_channel(100);
         let (result_sender, result_receiver) = std::sync::mpsc::channel();
         sampler
@@ -0,0 +1,199 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::{format_err, Context, Result};
+
+use crate::core::types::{Backpressure, StackTrace};
+
+/// Hands traces from the sampling threads to the recorder, following a backpressure policy when
+/// the recorder falls behind. With `block`, traces go straight into the recorder's channel, and
+/// sampling waits whenever it's full. Otherwise, they're queued up and passed on to the channel
+/// by another thread, and traces are dropped when the queue is full too.
+pub(crate) enum TraceSender {
+    Channel(SyncSender<StackTrace>),
+    Queue(Arc<Queue>),
+}
+
+impl TraceSender {
+    /// Counts dropped traces in `dropped`
+    pub fn new(
+        sender: SyncSender<StackTrace>,
+        backpressure: Backpressure,
+        capacity: usize,
+        dropped: Arc<AtomicUsize>,
+    ) -> TraceSender {
+        if backpressure == Backpressure::block {
+            return TraceSender::Channel(sender);
+        }
+        let queue = Arc::new(Queue::new(backpressure, capacity, dropped));
+        let forwarder = queue.clone();
+        std::thread::spawn(move || {
+            while let Some(trace) = forwarder.pop() {
+                if sender.send(trace).is_err() {
+                    forwarder.close();
+                    break;
+                }
+            }
+        });
+        TraceSender::Queue(queue)
+    }
+
+    pub fn send(&self, trace: StackTrace) -> Result<()> {
+        match self {
+            TraceSender::Channel(sender) => sender.send(trace).context("send trace"),
+            TraceSender::Queue(queue) => queue.push(trace),
+        }
+    }
+}
+
+impl Clone for TraceSender {
+    fn clone(&self) -> TraceSender {
+        match self {
+            TraceSender::Channel(sender) => TraceSender::Channel(sender.clone()),
+            TraceSender::Queue(queue) => {
+                queue.state.lock().unwrap().senders += 1;
+                TraceSender::Queue(queue.clone())
+            }
+        }
+    }
+}
+
+impl Drop for TraceSender {
+    fn drop(&mut self) {
+        if let TraceSender::Queue(queue) = self {
+            queue.state.lock().unwrap().senders -= 1;
+            // The forwarding thread stops once the queue is empty and every sender is gone
+            queue.changed.notify_all();
+        }
+    }
+}
+
+pub(crate) struct Queue {
+    backpressure: Backpressure,
+    capacity: usize,
+    dropped: Arc<AtomicUsize>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    traces: VecDeque<StackTrace>,
+    senders: usize,
+    /// Whether the recorder stopped receiving traces
+    closed: bool,
+}
+
+impl Queue {
+    fn new(backpressure: Backpressure, capacity: usize, dropped: Arc<AtomicUsize>) -> Queue {
+        Queue {
+            backpressure,
+            capacity,
+            dropped,
+            state: Mutex::new(State {
+                traces: VecDeque::with_capacity(capacity),
+                senders: 1,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn push(&self, trace: StackTrace) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(format_err!(
+                "send trace: the recorder stopped receiving traces"
+            ));
+        }
+        if state.traces.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.backpressure {
+                Backpressure::drop_newest => return Ok(()),
+                _ => {
+                    state.traces.pop_front();
+                }
+            }
+        }
+        state.traces.push_back(trace);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// The oldest trace, once there is one. `None` once every sender is gone and the queue has
+    /// been emptied.
+    fn pop(&self) -> Option<StackTrace> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(trace) = state.traces.pop_front() {
+                return Some(trace);
+            }
+            if state.senders == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.traces.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(i: usize) -> StackTrace {
+        let mut trace = StackTrace::new_empty();
+        trace.thread_id = Some(i);
+        trace
+    }
+
+    fn ids(queue: &Queue) -> Vec<usize> {
+        let state = queue.state.lock().unwrap();
+        state
+            .traces
+            .iter()
+            .filter_map(|trace| trace.thread_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_queue_policies() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let oldest = Queue::new(Backpressure::drop_oldest, 2, dropped.clone());
+        let newest = Queue::new(Backpressure::drop_newest, 2, dropped.clone());
+        for i in 0..4 {
+            oldest.push(numbered(i)).unwrap();
+            newest.push(numbered(i)).unwrap();
+        }
+        assert_eq!(ids(&oldest), vec![2, 3]);
+        assert_eq!(ids(&newest), vec![0, 1]);
+        assert_eq!(dropped.load(Ordering::Relaxed), 4);
+
+        assert_eq!(oldest.pop().and_then(|trace| trace.thread_id), Some(2));
+        oldest.close();
+        assert!(oldest.push(numbered(4)).is_err());
+    }
+
+    #[test]
+    fn test_sender_delivers_or_counts_every_trace() {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let sender = TraceSender::new(sender, Backpressure::drop_newest, 4, dropped.clone());
+        let other_sender = sender.clone();
+        for i in 0..50 {
+            sender.send(numbered(i)).unwrap();
+            other_sender.send(numbered(i)).unwrap();
+        }
+        drop(sender);
+        drop(other_sender);
+        let received = receiver.iter().count();
+        assert!(received >= 4);
+        assert_eq!(received + dropped.load(Ordering::Relaxed), 100);
+    }
+}
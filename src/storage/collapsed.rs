@@ -0,0 +1,95 @@
+use std::io::{BufRead, BufReader, Read};
+use std::time::Duration;
+
+use anyhow::{format_err, Context, Result};
+
+use crate::core::types::{FrameKind, StackFrame, StackTrace};
+
+/// Reads collapsed stacks (`frame;frame;frame weight` on each line, outermost frame first), e.g.
+/// from Brendan Gregg's `stackcollapse` scripts or rbspy's own `collapsed` output. Frames in
+/// rbspy's `name - path:line` form keep their path and line number. Each stack becomes a trace
+/// whose weight is the stack's weight.
+pub(crate) fn read_traces<R: Read>(r: R) -> Result<Vec<StackTrace>> {
+    let mut traces = Vec::new();
+    for (i, line) in BufReader::new(r).lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let (stack, weight) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| format_err!("Line {} has no weight: {}", i + 1, line))?;
+        let weight: u64 =
+            weight
+                .parse()
+                .context(format!("Line {} has an invalid weight: {}", i + 1, line))?;
+        let mut trace = StackTrace::new_empty();
+        trace.trace = stack.split(';').rev().map(parse_frame).collect();
+        // Traces with an interval are weighted by its length in microseconds
+        trace.interval = Some(Duration::from_micros(weight));
+        traces.push(trace);
+    }
+    Ok(traces)
+}
+
+fn parse_frame(frame: &str) -> StackFrame {
+    let (name, location) = match frame.rsplit_once(" - ") {
+        Some((name, location)) => (name, Some(location)),
+        None => (frame, None),
+    };
+    let (path, lineno) = match location {
+        Some(location) => match location.rsplit_once(':') {
+            Some((path, lineno)) if lineno.parse::<usize>().is_ok() => {
+                (path.to_string(), lineno.parse().ok())
+            }
+            _ => (location.to_string(), None),
+        },
+        None => ("(unknown)".to_string(), None),
+    };
+    StackFrame {
+        name: name.to_string(),
+        relative_path: path,
+        absolute_path: None,
+        lineno,
+        kind: FrameKind::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_reader() {
+        let collapsed = "<main> - a.rb:10;aaa - a.rb:2 30\nmain;read [c function] 5\n\n";
+        let traces = read_traces(collapsed.as_bytes()).unwrap();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(
+            traces[0].trace,
+            vec![
+                StackFrame {
+                    name: "aaa".to_string(),
+                    relative_path: "a.rb".to_string(),
+                    absolute_path: None,
+                    lineno: Some(2),
+                    kind: FrameKind::Unknown,
+                },
+                StackFrame {
+                    name: "<main>".to_string(),
+                    relative_path: "a.rb".to_string(),
+                    absolute_path: None,
+                    lineno: Some(10),
+                    kind: FrameKind::Unknown,
+                },
+            ]
+        );
+        assert_eq!(traces[0].weight(), 30);
+        assert_eq!(traces[1].trace[0].name, "read [c function]");
+        assert_eq!(traces[1].trace[1].relative_path, "(unknown)");
+        assert_eq!(traces[1].weight(), 5);
+
+        assert!(read_traces("aaa;bbb".as_bytes()).is_err());
+        assert!(read_traces("aaa;bbb x".as_bytes()).is_err());
+    }
+}
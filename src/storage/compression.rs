@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+
+use crate::core::types::RawCompression;
+
+/// The first bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compresses a recording as a series of gzip members or zstd frames, each of which can be
+/// decompressed on its own
+pub(crate) enum Encoder {
+    Gzip(flate2::write::GzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Encoder {
+    pub fn new(file: File, compression: RawCompression) -> io::Result<Encoder> {
+        Ok(match compression {
+            RawCompression::gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            RawCompression::zstd => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+        })
+    }
+
+    pub fn compression(&self) -> RawCompression {
+        match self {
+            Encoder::Gzip(_) => RawCompression::gzip,
+            Encoder::Zstd(_) => RawCompression::zstd,
+        }
+    }
+
+    /// Ends the current gzip member or zstd frame, writing everything in it to the file
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.try_finish(),
+            Encoder::Zstd(encoder) => encoder.do_finish(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &File {
+        match self {
+            Encoder::Gzip(encoder) => encoder.get_ref(),
+            Encoder::Zstd(encoder) => encoder.get_ref(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Which compression a recording that starts with `start` was written with
+pub(crate) fn compression_of(start: &[u8]) -> RawCompression {
+    if start.starts_with(&ZSTD_MAGIC) {
+        RawCompression::zstd
+    } else {
+        RawCompression::gzip
+    }
+}
+
+/// Decompresses a recording, whichever compression it was written with, carrying on from each
+/// gzip member or zstd frame into the next
+pub(crate) fn decompress<'a, R: Read + 'a>(r: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut reader = io::BufReader::new(r);
+    Ok(match compression_of(reader.fill_buf()?) {
+        RawCompression::gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        RawCompression::zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+    })
+}
+
+/// Decompresses only the gzip member or zstd frame at the start of `reader`, leaving `reader` at
+/// the start of the next one
+pub(crate) fn decompress_part<'a, R: BufRead + 'a>(
+    reader: R,
+    compression: RawCompression,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        RawCompression::gzip => Box::new(flate2::bufread::GzDecoder::new(reader)),
+        RawCompression::zstd => Box::new(zstd::Decoder::with_buffer(reader)?.single_frame()),
+    })
+}
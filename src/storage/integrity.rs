@@ -0,0 +1,206 @@
+use std::io;
+
+use anyhow::{format_err, Error, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Written at the end of each recording session. `sha256` covers everything in the recording
+/// before the footer (after the version tag), including the footers of earlier sessions, so the
+/// last footer vouches for the whole recording.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Footer {
+    pub sha256: String,
+    /// An HMAC-SHA256 of `sha256`, if the recording was made with a key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmac_sha256: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FooterMarker {
+    pub footer: Footer,
+}
+
+// Traces are serialized starting with their `trace` field, so they can't be mistaken for this
+pub(crate) const FOOTER_MARKER_PREFIX: &str = "{\"footer\":";
+
+/// Hashes the lines of a recording as they're written or read
+#[derive(Clone)]
+pub(crate) struct Checksum {
+    hasher: Sha256,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum {
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn line(&mut self, line: &str) {
+        self.hasher.update(line.as_bytes());
+        self.hasher.update(b"\n");
+    }
+
+    /// Makes a footer for everything that's been hashed so far
+    pub fn footer(&self, hmac_key: Option<&[u8]>) -> Result<Footer> {
+        let sha256 = hex(&self.hasher.clone().finalize());
+        let hmac_sha256 = match hmac_key {
+            Some(key) => {
+                let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+                mac.update(sha256.as_bytes());
+                Some(hex(&mac.finalize().into_bytes()))
+            }
+            None => None,
+        };
+        Ok(Footer {
+            sha256,
+            hmac_sha256,
+        })
+    }
+}
+
+impl io::Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Checks the lines of a recording against its footers as they're read
+pub(crate) struct Seal {
+    checksum: Checksum,
+    /// The last footer and the checksum of the lines before it, if no lines have followed it
+    sealed: Option<(Footer, String)>,
+}
+
+impl Seal {
+    /// Starts checking after lines that `checksum` has hashed, e.g. the header
+    pub fn new(checksum: Checksum) -> Seal {
+        Seal {
+            checksum,
+            sealed: None,
+        }
+    }
+
+    pub fn line(&mut self, line: &str) -> Result<()> {
+        self.sealed = if line.starts_with(FOOTER_MARKER_PREFIX) {
+            let marker: FooterMarker = serde_json::from_str(line)?;
+            Some((marker.footer, self.checksum.footer(None)?.sha256))
+        } else {
+            None
+        };
+        self.checksum.line(line);
+        Ok(())
+    }
+
+    /// Notes that the recording ended in the middle of a line, so it isn't sealed by any footer
+    /// that came before
+    pub fn truncated(&mut self) {
+        self.sealed = None;
+    }
+
+    pub fn integrity(self) -> Integrity {
+        match self.sealed {
+            Some((footer, sha256)) => Integrity::Sealed { footer, sha256 },
+            None => Integrity::Unsealed,
+        }
+    }
+}
+
+/// What a reader found out about a recording's checksums
+pub(crate) enum Integrity {
+    /// Checksums can't be checked, e.g. because the recording is in an older format or only part
+    /// of it was read
+    Unavailable,
+    /// The recording doesn't end with a footer, e.g. because the recording didn't finish
+    Unsealed,
+    /// The last footer, and the checksum of everything before it
+    Sealed { footer: Footer, sha256: String },
+}
+
+impl Integrity {
+    /// Fails if the recording was modified after it was written, or if it can't be
+    /// authenticated with `hmac_key`
+    pub fn verify(&self, hmac_key: Option<&[u8]>) -> Result<(), Error> {
+        let (footer, sha256) = match self {
+            Integrity::Sealed { footer, sha256 } => (footer, sha256),
+            Integrity::Unavailable | Integrity::Unsealed if hmac_key.is_some() => {
+                return Err(format_err!(
+                    "Can't authenticate the recording, since it has no checksum. It may be incomplete, or from an older version of rbspy."
+                ));
+            }
+            Integrity::Unavailable => return Ok(()),
+            Integrity::Unsealed => {
+                warn!("The recording doesn't end with a checksum, so it may be incomplete");
+                return Ok(());
+            }
+        };
+        if &footer.sha256 != sha256 {
+            return Err(format_err!(
+                "The recording doesn't match its checksum, so it was truncated or modified after it was written"
+            ));
+        }
+        if let Some(key) = hmac_key {
+            let expected = footer.hmac_sha256.as_ref().ok_or_else(|| {
+                format_err!("Can't authenticate the recording, since it was made without a key")
+            })?;
+            let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+            mac.update(sha256.as_bytes());
+            mac.verify_slice(&unhex(expected)?).map_err(|_| {
+                format_err!("The recording's HMAC doesn't match, so it was made with a different key or modified after it was written")
+            })?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn unhex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return Err(format_err!("Invalid hex string: {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format_err!("Invalid hex string: {}", s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_footer() {
+        let mut checksum = Checksum::new();
+        checksum.line("abc");
+        let footer = checksum.footer(Some(b"key")).unwrap();
+        assert_eq!(
+            footer.sha256,
+            "edeaaff3f1774ad2888673770c6d64097e391bc362d7d6fb34982ddf0efd18cb"
+        );
+
+        let sealed = Integrity::Sealed {
+            sha256: footer.sha256.clone(),
+            footer,
+        };
+        assert!(sealed.verify(None).is_ok());
+        assert!(sealed.verify(Some(b"key")).is_ok());
+        assert!(sealed.verify(Some(b"other key")).is_err());
+
+        assert!(Integrity::Unsealed.verify(None).is_ok());
+        assert!(Integrity::Unsealed.verify(Some(b"key")).is_err());
+    }
+}
@@ -14,53 +14,332 @@
 extern crate anyhow;
 extern crate flate2;
 
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
-use std::time::SystemTime;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use crate::core::types::Header;
+use crate::core::types::InputFormat;
+use crate::core::types::RawCompression;
 use crate::core::types::StackTrace;
+use crate::core::types::Timestamp;
 
-use self::flate2::Compression;
+use self::compression::{compression_of, decompress, decompress_part, Encoder};
+use self::integrity::{Checksum, FooterMarker, Integrity, Seal, FOOTER_MARKER_PREFIX};
 
 use anyhow::{Error, Result};
 use thiserror::Error;
 
+mod collapsed;
+mod compression;
+pub(crate) mod integrity;
 mod v0;
 mod v1;
 mod v2;
+pub(crate) mod v3;
+mod writer;
+
+pub(crate) use self::writer::StoreWriter;
 
 pub struct Store {
-    encoder: flate2::write::GzEncoder<File>,
+    encoder: Encoder,
+    index: Option<File>,
+    /// Where the current gzip member (or zstd frame) starts, if it still needs to be added to the
+    /// index
+    unindexed_member: Option<u64>,
+    /// The last trace that was written, which hasn't been stored yet in case the samples that
+    /// follow it are repeats of it
+    pending: Option<StackTrace>,
+    checksum: Checksum,
+    hmac_key: Option<Vec<u8>>,
+}
+
+/// An entry in a raw recording's index, which is kept in a separate file next to the recording.
+/// Each gzip member (or zstd frame) after the first gets an entry, so that readers can skip to the
+/// traces from a given time.
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    /// When the first trace in the gzip member was taken
+    time: SystemTime,
+    /// Where the gzip member starts in the recording
+    offset: u64,
+}
+
+pub(crate) fn index_path(raw_path: &Path) -> PathBuf {
+    let mut path = raw_path.as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// The times that traces are read from, for reading part of a recording
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TimeRange {
+    pub from: Option<SystemTime>,
+    pub to: Option<SystemTime>,
+}
+
+impl TimeRange {
+    // Traces from different processes can be written slightly out of order
+    const SLACK: Duration = Duration::from_secs(1);
+
+    /// Whether a trace taken at `time` is in the range. Traces without a time are always included.
+    pub fn contains(&self, time: Option<SystemTime>) -> bool {
+        match time {
+            Some(time) => {
+                self.from.map_or(true, |from| time >= from) && self.to.map_or(true, |to| time <= to)
+            }
+            None => true,
+        }
+    }
+
+    /// Whether a trace taken at `time` is far enough past the end of the range that no more
+    /// traces from the range are expected after it
+    pub fn is_past(&self, time: Option<SystemTime>) -> bool {
+        match (time, self.to) {
+            (Some(time), Some(to)) => time > to + TimeRange::SLACK,
+            _ => false,
+        }
+    }
 }
 
 impl Store {
-    pub fn new(out_path: &Path, sample_rate: u32) -> Result<Store, io::Error> {
+    /// Starts a recording, with a header that describes it and the process it's of
+    pub fn new(
+        out_path: &Path,
+        header: Header,
+        compression: RawCompression,
+    ) -> Result<Store, io::Error> {
         let file = File::create(out_path)?;
-        let mut encoder = flate2::write::GzEncoder::new(file, Compression::default());
-        encoder.write_all("rbspy02\n".as_bytes())?;
+        let mut encoder = Encoder::new(file, compression)?;
+        encoder.write_all("rbspy03\n".as_bytes())?;
 
-        let json = serde_json::to_string(&Header {
-            sample_rate: Some(sample_rate),
-            rbspy_version: Some(env!("CARGO_PKG_VERSION").to_string()),
-            start_time: Some(SystemTime::now()),
-        })?;
-        writeln!(&mut encoder, "{}", json)?;
+        let mut store = Store {
+            encoder,
+            index: None,
+            unindexed_member: None,
+            pending: None,
+            checksum: Checksum::new(),
+            hmac_key: None,
+        };
+        let json = serde_json::to_string(&header)?;
+        store.write_line(&json)?;
+        Ok(store)
+    }
 
-        Ok(Store { encoder })
+    /// Continues an existing recording, or starts a new one if there's no file at `out_path`.
+    /// The new traces are preceded by an epoch marker with their own header, so that readers can
+    /// tell the recording sessions apart. Anything after the last complete gzip member (e.g. from
+    /// a recording that was killed between checkpoints) is discarded. An existing recording is
+    /// continued with the compression it was started with, rather than `compression`.
+    pub fn append(
+        out_path: &Path,
+        header: Header,
+        compression: RawCompression,
+    ) -> Result<Store, Error> {
+        if !out_path.exists() {
+            return Ok(Store::new(out_path, header, compression)?);
+        }
+        let (length, checksum, compression) = complete_part(out_path)?;
+        let mut file = OpenOptions::new().write(true).open(out_path)?;
+        file.set_len(length)?;
+        file.seek(io::SeekFrom::End(0))?;
+        let encoder = Encoder::new(file, compression)?;
+
+        let mut store = Store {
+            encoder,
+            index: None,
+            unindexed_member: Some(length),
+            pending: None,
+            checksum,
+            hmac_key: None,
+        };
+        let json = serde_json::to_string(&v3::EpochMarker { epoch: header })?;
+        store.write_line(&json)?;
+        Ok(store)
+    }
+
+    /// Authenticates the recording with an HMAC in its footer, so that readers with the same key
+    /// can tell that it hasn't been modified
+    pub fn with_hmac_key(mut self, key: Vec<u8>) -> Store {
+        self.hmac_key = Some(key);
+        self
+    }
+
+    /// Starts (or, when appending, continues) an index of the recording in a file next to it, with
+    /// an entry for each checkpoint. The index lets readers skip to the part of a long recording
+    /// that they need.
+    pub fn with_index(mut self, out_path: &Path, append: bool) -> Result<Store, Error> {
+        let index = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(index_path(out_path))?;
+        self.index = Some(index);
+        Ok(self)
     }
 
+    /// Writes a trace. Consecutive identical samples of a thread (e.g. of a thread that's blocked
+    /// for a while) are stored as one trace with a repeat count.
     pub fn write(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        match &mut self.pending {
+            Some(pending) if pending.is_repeated_by(trace) => pending.add_repeat(trace),
+            _ => {
+                if let Some(pending) = self.pending.replace(trace.clone()) {
+                    self.store(&pending)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, trace: &StackTrace) -> Result<(), Error> {
+        if let (Some(index), Some(offset)) = (&mut self.index, self.unindexed_member.take()) {
+            if let Some(time) = trace.time {
+                let json = serde_json::to_string(&IndexEntry { time, offset })?;
+                writeln!(index, "{}", json)?;
+            }
+        }
         let json = serde_json::to_string(trace)?;
-        writeln!(&mut self.encoder, "{}", json)?;
+        Ok(self.write_line(&json)?)
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), io::Error> {
+        writeln!(&mut self.encoder, "{}", line)?;
+        self.checksum.line(line);
         Ok(())
     }
 
-    pub fn complete(self) {
+    /// Writes a note about the recording, e.g. that the sample rate had to be lowered. Reports
+    /// show the notes along with the profile.
+    pub fn note(&mut self, note: &str) -> Result<(), Error> {
+        if let Some(pending) = self.pending.take() {
+            self.store(&pending)?;
+        }
+        let json = serde_json::to_string(&v3::NoteMarker {
+            note: note.to_string(),
+        })?;
+        Ok(self.write_line(&json)?)
+    }
+
+    /// Marks a time between two traces when sampling was paused on purpose (e.g. by a duty
+    /// cycle), so that reports don't count it towards the length of the recording
+    pub fn gap(&mut self, from: SystemTime, to: SystemTime) -> Result<(), Error> {
+        if let Some(pending) = self.pending.take() {
+            self.store(&pending)?;
+        }
+        let json = serde_json::to_string(&v3::GapMarker {
+            gap: v3::Gap { from, to },
+        })?;
+        Ok(self.write_line(&json)?)
+    }
+
+    /// Ends the current gzip member (or zstd frame) and flushes it to disk, so that everything
+    /// written so far can be read back even if the recording is never completed (e.g. because
+    /// rbspy was killed). Readers carry on into the gzip member that follows.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        if let Some(pending) = self.pending.take() {
+            self.store(&pending)?;
+        }
+        let json = serde_json::to_string(&v3::CheckpointMarker {
+            checkpoint: Timestamp::now(),
+        })?;
+        self.write_line(&json)?;
+        self.encoder.try_finish()?;
+        let mut file = self.encoder.get_ref().try_clone()?;
+        file.sync_data()?;
+        self.unindexed_member = Some(file.stream_position()?);
+        self.encoder = Encoder::new(file, self.encoder.compression())?;
+        Ok(())
+    }
+
+    /// How many bytes have been written to disk so far. Lags behind what's been written to the
+    /// store by up to the size of the compressor's buffer.
+    pub fn size(&self) -> Result<u64, io::Error> {
+        Ok(self.encoder.get_ref().metadata()?.len())
+    }
+
+    /// Writes any remaining traces, and a footer with a checksum of the recording. The footer gets
+    /// a gzip member (or zstd frame) of its own, so that appending to the recording can drop it
+    /// and the footer at the end vouches for every session.
+    pub fn complete(mut self) {
+        if let Err(err) = self.finish() {
+            warn!("Failed to finish writing the raw output: {}", err);
+        }
         drop(self.encoder)
     }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if let Some(pending) = self.pending.take() {
+            self.store(&pending)?;
+        }
+        self.encoder.try_finish()?;
+        let file = self.encoder.get_ref().try_clone()?;
+        self.encoder = Encoder::new(file, self.encoder.compression())?;
+        let footer = self.checksum.footer(self.hmac_key.as_deref())?;
+        let json = serde_json::to_string(&FooterMarker { footer })?;
+        self.write_line(&json)?;
+        // Unlike gzip, zstd doesn't end the last frame when the encoder is dropped
+        Ok(self.encoder.try_finish()?)
+    }
+}
+
+/// Finds the length of the complete gzip members (or zstd frames) at the start of a version 3
+/// recording, the checksum of their contents, and which of the two the recording uses. A footer
+/// at the end, in a member of its own, is left out: once more is appended, it no longer vouches
+/// for the whole recording, and a recording that was cut back to it would still verify.
+fn complete_part(path: &Path) -> Result<(u64, Checksum, RawCompression), Error> {
+    let mut reader = io::BufReader::new(File::open(path)?);
+    let compression = compression_of(reader.fill_buf()?);
+    let mut length = 0;
+    let mut checksum = Checksum::new();
+    let mut footer_member = None;
+    while !reader.fill_buf()?.is_empty() {
+        let mut member = decompress_part(&mut reader, compression)?;
+        if length == 0 {
+            match read_version(&mut member) {
+                Ok(Version(3)) => {}
+                Ok(v) => return Err(anyhow::format_err!("Can't append to rbspy format {}", v)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut member_checksum = checksum.clone();
+        // Footers are much shorter than this, so a member that fits is checked for being one
+        let mut start = Vec::new();
+        let copied = member
+            .by_ref()
+            .take(1024)
+            .read_to_end(&mut start)
+            .and_then(|_| {
+                member_checksum.write_all(&start)?;
+                io::copy(&mut member, &mut member_checksum)
+            });
+        match copied {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        drop(member);
+        let is_footer = start.len() < 1024
+            && start.starts_with(FOOTER_MARKER_PREFIX.as_bytes())
+            && start.iter().filter(|&&byte| byte == b'\n').count() == 1;
+        footer_member = Some((length, checksum)).filter(|_| is_footer);
+        checksum = member_checksum;
+        length = reader.stream_position()?;
+    }
+    if let Some((footer_start, before_footer)) = footer_member {
+        return Ok((footer_start, before_footer, compression));
+    }
+    if length == 0 {
+        return Err(anyhow::format_err!(
+            "{} doesn't contain any complete rbspy data to append to",
+            path.display()
+        ));
+    }
+    Ok((length, checksum, compression))
 }
 
 #[derive(Clone, Debug, Copy, Eq, PartialEq, Ord, PartialOrd)]
@@ -85,6 +364,8 @@ impl Version {
             Ok(Version(1))
         } else if &b[0..3] == "02\n".as_bytes() {
             Ok(Version(2))
+        } else if &b[0..3] == "03\n".as_bytes() {
+            Ok(Version(3))
         } else {
             Err(StorageError::Invalid)
         }
@@ -106,7 +387,7 @@ pub(crate) enum StorageError {
 
 /// Types that can be deserialized from an `io::Read` into something convertible
 /// to the current internal form.
-pub(crate) trait Storage: Into<v2::Data> {
+pub(crate) trait Storage: Into<v3::Data> {
     fn from_reader<R: Read>(r: R) -> Result<Self>;
     fn version() -> Version;
 }
@@ -121,10 +402,109 @@ fn read_version(r: &mut dyn Read) -> Result<Version, StorageError> {
     }
 }
 
-pub(crate) fn from_reader<R: Read>(r: R) -> Result<v2::Data, Error> {
+/// Reads the traces from part of a recording. If the recording has an index, reading starts at
+/// the last checkpoint before the start of the range rather than at the start of the recording.
+/// Since the footer's checksum covers the whole recording, the rest of it is only checked against
+/// the footer if `verify` is set.
+pub(crate) fn from_path_in_range(
+    path: &Path,
+    range: &TimeRange,
+    verify: bool,
+) -> Result<v3::Data, Error> {
+    let offset = match (range.from, std::fs::read_to_string(index_path(path))) {
+        (Some(from), Ok(index)) => {
+            // The index can outlive part of the recording it describes, when an unfinished
+            // recording was appended to
+            let length = std::fs::metadata(path)?.len();
+            let mut offset = 0;
+            for line in index.lines() {
+                let entry: IndexEntry = serde_json::from_str(line)?;
+                if entry.time <= from && entry.offset < length {
+                    offset = offset.max(entry.offset);
+                }
+            }
+            offset
+        }
+        _ => 0,
+    };
+
+    if offset == 0 {
+        return from_reader(File::open(path)?);
+    }
+
+    // The header is in the first gzip member
+    let mut file = File::open(path)?;
+    let mut first_member = decompress(&mut file)?;
+    match read_version(&mut first_member)? {
+        Version(3) => {}
+        v => return Err(StorageError::UnknownVersion(v).into()),
+    }
+    let mut header_line = String::new();
+    io::BufReader::new(first_member).read_line(&mut header_line)?;
+    let mut data = v3::Data {
+        header: serde_json::from_str(&header_line)?,
+        traces: Vec::new(),
+        epochs: Vec::new(),
+        notes: Vec::new(),
+        checkpoints: Vec::new(),
+        gaps: Vec::new(),
+        integrity: Integrity::Unavailable,
+    };
+
+    file.seek(io::SeekFrom::Start(offset))?;
+    let reader = io::BufReader::new(decompress(file)?);
+    data.read_traces(reader.lines(), range, None)?;
+    if verify {
+        data.integrity = integrity_of(path)?;
+    }
+    Ok(data)
+}
+
+/// Checks the whole of a version 3 recording against its footer, without keeping its traces
+fn integrity_of(path: &Path) -> Result<Integrity, Error> {
+    let mut reader = io::BufReader::new(decompress(File::open(path)?)?);
+    read_version(&mut reader)?;
+    let mut seal = Seal::new(Checksum::new());
+    for line in reader.lines() {
+        match line {
+            Ok(line) => seal.line(&line)?,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                seal.truncated();
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(seal.integrity())
+}
+
+/// Reads the traces from an input in any format that reports can be made from
+pub(crate) fn from_reader_in_format<R: Read>(
+    mut r: R,
+    format: InputFormat,
+) -> Result<v3::Data, Error> {
+    let traces = match format {
+        InputFormat::raw => return from_reader(r),
+        InputFormat::collapsed => collapsed::read_traces(r)?,
+        InputFormat::speedscope => crate::ui::speedscope::read_traces(&mut r)?,
+        InputFormat::pprof => crate::ui::pprof::read_traces(&mut r)?,
+    };
+    // Other tools' profiles don't have rbspy's header or checksums
+    Ok(v3::Data {
+        header: Header::default(),
+        traces,
+        epochs: vec![],
+        notes: vec![],
+        checkpoints: vec![],
+        gaps: vec![],
+        integrity: Integrity::Unavailable,
+    })
+}
+
+pub(crate) fn from_reader<R: Read>(r: R) -> Result<v3::Data, Error> {
     // This will read 8 bytes, leaving the reader's cursor at the start of the
     // "real" data.
-    let mut reader = flate2::read::GzDecoder::new(r);
+    let mut reader = decompress(r)?;
     let version = read_version(&mut reader)?;
     match version {
         Version(0) => {
@@ -137,8 +517,516 @@ pub(crate) fn from_reader<R: Read>(r: R) -> Result<v2::Data, Error> {
         }
         Version(2) => {
             let intermediate = v2::Data::from_reader(reader)?;
+            Ok(intermediate.into())
+        }
+        Version(3) => {
+            let intermediate = v3::Data::from_reader(reader)?;
             Ok(intermediate)
         }
         v => Err(StorageError::UnknownVersion(v).into()),
     }
 }
+
+/// The recording options that change what a sample stands for, which recordings have to agree on
+/// to be merged: on-CPU samples leave out the time that wall-clock samples include, and sampling
+/// every thread adds up the threads' time.
+const MERGED_FLAGS: [&str; 2] = ["on_cpu", "all_threads"];
+
+/// Merges recordings, e.g. of the same program on each host in a fleet, into one whose traces
+/// keep the PIDs and threads they were recorded with. Each recording becomes a session of the
+/// merged one (as if it had been appended), so that its traces are weighted by its own sample
+/// rate. The merged header keeps what all of the recordings have in common.
+pub(crate) fn merge(recordings: Vec<(PathBuf, v3::Data)>) -> Result<v3::Data, Error> {
+    let mut merged = match recordings.first() {
+        Some((_, data)) => v3::Data {
+            header: data.header.clone(),
+            traces: Vec::new(),
+            epochs: Vec::new(),
+            notes: Vec::new(),
+            checkpoints: Vec::new(),
+            // A gap in one recording isn't one in the others
+            gaps: Vec::new(),
+            // Each recording is verified before it's merged
+            integrity: Integrity::Unavailable,
+        },
+        None => return Err(anyhow::format_err!("There are no recordings to merge")),
+    };
+    // The value of each of the `MERGED_FLAGS` and the first recording it was seen in. Older
+    // recordings don't have their flags.
+    let mut seen_flags: BTreeMap<&str, (&String, PathBuf)> = BTreeMap::new();
+    for (path, data) in &recordings {
+        for flag in MERGED_FLAGS {
+            let value = match data.header.flags.get(flag) {
+                Some(value) => value,
+                None => continue,
+            };
+            match seen_flags.get(flag) {
+                Some((seen, seen_path)) if *seen != value => {
+                    return Err(anyhow::format_err!(
+                        "Can't merge {} with {}: they were recorded with {} set to {} and {}",
+                        seen_path.display(),
+                        path.display(),
+                        flag,
+                        seen,
+                        value
+                    ));
+                }
+                Some(_) => {}
+                None => {
+                    seen_flags.insert(flag, (value, path.clone()));
+                }
+            }
+        }
+    }
+    for (path, data) in recordings {
+        merged.header = common_header(&merged.header, &data.header);
+        let offset = merged.traces.len();
+        merged.epochs.push(v3::Epoch {
+            header: data.header,
+            first_trace: offset,
+        });
+        merged
+            .epochs
+            .extend(data.epochs.into_iter().map(|epoch| v3::Epoch {
+                first_trace: offset + epoch.first_trace,
+                ..epoch
+            }));
+        merged.traces.extend(data.traces);
+        merged.notes.extend(
+            data.notes
+                .into_iter()
+                .map(|note| format!("{}: {}", path.display(), note)),
+        );
+    }
+    Ok(merged)
+}
+
+/// The parts of two headers that are the same, and the earliest start
+fn common_header(a: &Header, b: &Header) -> Header {
+    fn same<T: Clone + PartialEq>(a: &Option<T>, b: &Option<T>) -> Option<T> {
+        a.clone().filter(|_| a == b)
+    }
+    let earliest = match (a.start_time, b.start_time) {
+        (Some(a_time), Some(b_time)) if b_time < a_time => b,
+        (None, _) => b,
+        _ => a,
+    };
+    let common = |a: &BTreeMap<String, String>, b: &BTreeMap<String, String>| {
+        a.iter()
+            .filter(|(name, value)| b.get(*name) == Some(*value))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    };
+    Header {
+        sample_rate: same(&a.sample_rate, &b.sample_rate),
+        rbspy_version: same(&a.rbspy_version, &b.rbspy_version),
+        start_time: earliest.start_time,
+        // Each host has a monotonic clock of its own
+        start_monotonic: earliest.start_monotonic.filter(|_| a.host == b.host),
+        ruby_version: same(&a.ruby_version, &b.ruby_version),
+        exe: same(&a.exe, &b.exe),
+        cmdline: same(&a.cmdline, &b.cmdline),
+        host: same(&a.host, &b.host),
+        flags: common(&a.flags, &b.flags),
+        labels: common(&a.labels, &b.labels),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::StackFrame;
+
+    fn trace(lineno: usize) -> StackTrace {
+        let mut trace = StackTrace::new_empty();
+        trace.trace.push(StackFrame {
+            name: "work".to_string(),
+            relative_path: "work.rb".to_string(),
+            absolute_path: None,
+            lineno: Some(lineno),
+            kind: Default::default(),
+        });
+        trace
+    }
+
+    fn linenos(data: &v3::Data) -> Vec<usize> {
+        data.traces
+            .iter()
+            .map(|trace| trace.trace[0].lineno.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_checkpointed_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip).unwrap();
+        store.write(&trace(1)).unwrap();
+        store.checkpoint().unwrap();
+        store.write(&trace(2)).unwrap();
+        store.checkpoint().unwrap();
+        store.write(&trace(3)).unwrap();
+        store.complete();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(data.header.sample_rate, Some(100));
+        assert_eq!(linenos(&data), vec![1, 2, 3]);
+        assert_eq!(data.checkpoints.len(), 2);
+        let start = data.header.start().unwrap();
+        assert!(data.checkpoints[0].time >= start.time);
+        if let (Some(start), Some(checkpoint)) = (start.monotonic, data.checkpoints[1].monotonic) {
+            assert!(checkpoint >= start);
+        }
+    }
+
+    #[test]
+    fn test_append_to_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip).unwrap();
+        store.write(&trace(1)).unwrap();
+        store.checkpoint().unwrap();
+        store.write(&trace(2)).unwrap();
+        // The unfinished part of an interrupted recording is dropped
+        std::mem::forget(store);
+
+        let mut store = Store::append(&path, Header::new(50), RawCompression::gzip).unwrap();
+        store.write(&trace(3)).unwrap();
+        store.complete();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(data.header.sample_rate, Some(100));
+        assert_eq!(linenos(&data), vec![1, 3]);
+        assert_eq!(data.epochs.len(), 1);
+        assert_eq!(data.epochs[0].first_trace, 1);
+        assert_eq!(data.epochs[0].header.sample_rate, Some(50));
+    }
+
+    #[test]
+    fn test_zstd_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.zst");
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut store = Store::new(&path, Header::new(100), RawCompression::zstd)
+            .unwrap()
+            .with_index(&path, false)
+            .unwrap();
+        for secs in 0..4 {
+            let mut trace = trace(secs as usize);
+            trace.time = Some(at(secs));
+            store.write(&trace).unwrap();
+            store.checkpoint().unwrap();
+        }
+        store.write(&trace(4)).unwrap();
+        // The unfinished zstd frame is dropped, like an unfinished gzip member
+        std::mem::forget(store);
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(linenos(&data), vec![0, 1, 2, 3]);
+
+        // Appending keeps the recording's compression
+        let mut store = Store::append(&path, Header::new(50), RawCompression::gzip).unwrap();
+        let mut last = trace(5);
+        last.time = Some(at(10));
+        store.write(&last).unwrap();
+        store.complete();
+        let mut magic = [0u8; 4];
+        File::open(&path).unwrap().read_exact(&mut magic).unwrap();
+        assert_eq!(compression_of(&magic), RawCompression::zstd);
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(linenos(&data), vec![0, 1, 2, 3, 5]);
+        assert_eq!(data.epochs.len(), 1);
+        data.integrity.verify(None).unwrap();
+
+        let range = TimeRange {
+            from: Some(at(2)),
+            to: Some(at(2)),
+        };
+        let data = from_path_in_range(&path, &range, false).unwrap();
+        assert_eq!(data.header.sample_rate, Some(100));
+        assert_eq!(linenos(&data), vec![2]);
+    }
+
+    #[test]
+    fn test_recording_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut header = Header {
+            ruby_version: Some("3.2.2".to_string()),
+            exe: Some("/usr/bin/ruby".to_string()),
+            cmdline: Some("puma 6.0.0".to_string()),
+            host: Some("web-1".to_string()),
+            ..Header::new(100)
+        };
+        header
+            .flags
+            .insert("on_cpu".to_string(), "true".to_string());
+        header
+            .labels
+            .insert("deploy".to_string(), "abc123".to_string());
+        let mut store = Store::new(&path, header.clone(), RawCompression::gzip).unwrap();
+        store.write(&trace(1)).unwrap();
+        store.complete();
+
+        let appended = Header {
+            ruby_version: Some("3.3.0".to_string()),
+            ..Header::new(50)
+        };
+        let mut store = Store::append(&path, appended.clone(), RawCompression::gzip).unwrap();
+        store.write(&trace(2)).unwrap();
+        store.complete();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(data.header, header);
+        assert_eq!(data.epochs[0].header, appended);
+    }
+
+    #[test]
+    fn test_gaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip).unwrap();
+        store.write(&trace(1)).unwrap();
+        let from = SystemTime::UNIX_EPOCH + Duration::from_secs(10);
+        let to = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        store.gap(from, to).unwrap();
+        store.write(&trace(2)).unwrap();
+        store.complete();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(linenos(&data), vec![1, 2]);
+        assert_eq!(data.gaps, vec![v3::Gap { from, to }]);
+        data.integrity.verify(None).unwrap();
+    }
+
+    #[test]
+    fn test_unfinished_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip).unwrap();
+        store.write(&trace(1)).unwrap();
+        store.checkpoint().unwrap();
+        store.write(&trace(2)).unwrap();
+        // Simulate rbspy being killed before it could finish the last gzip member
+        std::mem::forget(store);
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(linenos(&data), vec![1]);
+    }
+
+    #[test]
+    fn test_version_2_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        );
+        writeln!(encoder, "rbspy02").unwrap();
+        let header = serde_json::to_string(&Header::new(100)).unwrap();
+        writeln!(encoder, "{}", header).unwrap();
+        for lineno in 1..3 {
+            let json = serde_json::to_string(&trace(lineno)).unwrap();
+            writeln!(encoder, "{}", json).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(data.header.sample_rate, Some(100));
+        assert_eq!(linenos(&data), vec![1, 2]);
+        assert!(matches!(data.integrity, Integrity::Unavailable));
+        // Sessions can only be appended to recordings that readers will look for markers in
+        assert!(Store::append(&path, Header::new(100), RawCompression::gzip).is_err());
+    }
+
+    #[test]
+    fn test_indexed_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip)
+            .unwrap()
+            .with_index(&path, false)
+            .unwrap();
+        for secs in 0..10 {
+            let mut trace = trace(secs as usize);
+            trace.time = Some(at(secs));
+            store.write(&trace).unwrap();
+            if secs % 3 == 2 {
+                store.checkpoint().unwrap();
+            }
+        }
+        store.complete();
+
+        let index = std::fs::read_to_string(index_path(&path)).unwrap();
+        assert_eq!(index.lines().count(), 3);
+
+        let range = TimeRange {
+            from: Some(at(4)),
+            to: Some(at(5)),
+        };
+        let data = from_path_in_range(&path, &range, false).unwrap();
+        assert_eq!(data.header.sample_rate, Some(100));
+        // Reading starts at the checkpoint before 4s, and stops a second after the range ends
+        assert_eq!(linenos(&data), vec![4, 5]);
+        assert!(matches!(data.integrity, Integrity::Unavailable));
+        // The rest of the recording is only read to check it against the footer when asked to
+        let data = from_path_in_range(&path, &range, true).unwrap();
+        assert_eq!(linenos(&data), vec![4, 5]);
+        assert!(matches!(data.integrity, Integrity::Sealed { .. }));
+        data.integrity.verify(None).unwrap();
+
+        let data = from_path_in_range(&path, &TimeRange::default(), false).unwrap();
+        assert_eq!(linenos(&data), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_repeated_samples() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip).unwrap();
+        for lineno in &[1, 1, 1, 2, 1, 1] {
+            let mut trace = trace(*lineno);
+            trace.interval = Some(Duration::from_millis(10));
+            store.write(&trace).unwrap();
+        }
+        store.complete();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(linenos(&data), vec![1, 2, 1]);
+        let repeats: Vec<u32> = data.traces.iter().map(|trace| trace.repeats).collect();
+        assert_eq!(repeats, vec![2, 0, 1]);
+        assert_eq!(data.traces[0].interval, Some(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn test_notes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip).unwrap();
+        store.write(&trace(1)).unwrap();
+        store.note("Lowered the sample rate to 50.").unwrap();
+        store.write(&trace(2)).unwrap();
+        store.complete();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(linenos(&data), vec![1, 2]);
+        assert_eq!(
+            data.notes,
+            vec!["Lowered the sample rate to 50.".to_string()]
+        );
+        assert!(data.integrity.verify(None).is_ok());
+    }
+
+    #[test]
+    fn test_merge() {
+        let recording = |host: &str, sample_rate: u32, on_cpu: &str, linenos: &[usize]| {
+            let mut header = Header::new(sample_rate);
+            header.host = Some(host.to_string());
+            header
+                .flags
+                .insert("on_cpu".to_string(), on_cpu.to_string());
+            header
+                .flags
+                .insert("lock_process".to_string(), host.to_string());
+            v3::Data {
+                header,
+                traces: linenos.iter().map(|&lineno| trace(lineno)).collect(),
+                epochs: vec![],
+                notes: vec!["Dropped 3 samples".to_string()],
+                checkpoints: vec![],
+                gaps: vec![],
+                integrity: Integrity::Unavailable,
+            }
+        };
+        let merged = merge(vec![
+            (
+                PathBuf::from("web-1.raw.gz"),
+                recording("web-1", 100, "false", &[1, 2]),
+            ),
+            (
+                PathBuf::from("web-2.raw.gz"),
+                recording("web-2", 50, "false", &[3]),
+            ),
+        ])
+        .unwrap();
+        assert_eq!(linenos(&merged), vec![1, 2, 3]);
+        let sessions: Vec<(Option<u32>, usize)> = merged
+            .epochs
+            .iter()
+            .map(|epoch| (epoch.header.sample_rate, epoch.first_trace))
+            .collect();
+        assert_eq!(sessions, vec![(Some(100), 0), (Some(50), 2)]);
+        // Only what the recordings have in common is kept
+        assert_eq!(merged.header.host, None);
+        assert_eq!(merged.header.sample_rate, None);
+        assert_eq!(
+            merged.header.rbspy_version.as_deref(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(merged.header.flags.keys().collect::<Vec<_>>(), ["on_cpu"]);
+        assert_eq!(merged.notes[1], "web-2.raw.gz: Dropped 3 samples");
+
+        let error = merge(vec![
+            (
+                PathBuf::from("web-1.raw.gz"),
+                recording("web-1", 100, "false", &[1]),
+            ),
+            (
+                PathBuf::from("web-2.raw.gz"),
+                recording("web-2", 100, "true", &[2]),
+            ),
+        ])
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Can't merge web-1.raw.gz with web-2.raw.gz: they were recorded with on_cpu set to false and true"
+        );
+        assert!(merge(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_recording_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.gz");
+        let mut store = Store::new(&path, Header::new(100), RawCompression::gzip)
+            .unwrap()
+            .with_hmac_key(b"key".to_vec());
+        store.write(&trace(1)).unwrap();
+        store.checkpoint().unwrap();
+        store.write(&trace(2)).unwrap();
+        store.complete();
+        let mut store = Store::append(&path, Header::new(100), RawCompression::gzip)
+            .unwrap()
+            .with_hmac_key(b"key".to_vec());
+        store.write(&trace(3)).unwrap();
+        store.complete();
+
+        let data = from_reader(File::open(&path).unwrap()).unwrap();
+        assert_eq!(linenos(&data), vec![1, 2, 3]);
+        assert!(data.integrity.verify(Some(b"key")).is_ok());
+        assert!(data.integrity.verify(Some(b"other key")).is_err());
+
+        // Change a line number in the recording, keeping the footer
+        let mut contents = String::new();
+        flate2::read::MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        let tampered = contents.replace("\"lineno\":2", "\"lineno\":5");
+        assert_ne!(tampered, contents);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(tampered.as_bytes()).unwrap();
+        let data = from_reader(&encoder.finish().unwrap()[..]).unwrap();
+        assert_eq!(linenos(&data), vec![1, 5, 3]);
+        assert!(data.integrity.verify(None).is_err());
+
+        // Cut the recording back to the end of the first session
+        let first_session = &contents[..contents.find(v3::EPOCH_MARKER_PREFIX).unwrap()];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(first_session.as_bytes()).unwrap();
+        let data = from_reader(&encoder.finish().unwrap()[..]).unwrap();
+        assert_eq!(linenos(&data), vec![1, 2]);
+        assert!(data.integrity.verify(Some(b"key")).is_err());
+    }
+}
@@ -21,16 +21,17 @@ impl Storage for Data {
     }
 }
 
-impl From<Data> for v2::Data {
-    fn from(d: Data) -> v2::Data {
+impl From<Data> for v3::Data {
+    fn from(d: Data) -> v3::Data {
         let x: Vec<StackTrace> = d.0.into_iter().map(std::convert::Into::into).collect();
-        v2::Data {
-            header: Header {
-                sample_rate: None,
-                rbspy_version: None,
-                start_time: None,
-            },
+        v3::Data {
+            header: Header::default(),
             traces: x,
+            epochs: Vec::new(),
+            notes: Vec::new(),
+            checkpoints: Vec::new(),
+            gaps: Vec::new(),
+            integrity: Integrity::Unavailable,
         }
     }
 }
@@ -41,7 +42,17 @@ impl From<Vec<StackFrame>> for StackTrace {
             pid: None,
             trace,
             thread_id: None,
+            thread_address: None,
+            thread_name: None,
+            os_thread_id: None,
+            thread_state: None,
+            wait_channel: None,
             time: None,
+            interval: None,
+            vm_stats: None,
+            labels: std::collections::BTreeMap::new(),
+            repeats: 0,
+            allocated: None,
         }
     }
 }
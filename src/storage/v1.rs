@@ -21,15 +21,16 @@ impl Storage for Data {
     }
 }
 
-impl From<Data> for v2::Data {
-    fn from(d: Data) -> v2::Data {
-        v2::Data {
-            header: Header {
-                sample_rate: None,
-                rbspy_version: None,
-                start_time: None,
-            },
+impl From<Data> for v3::Data {
+    fn from(d: Data) -> v3::Data {
+        v3::Data {
+            header: Header::default(),
             traces: d.0,
+            epochs: Vec::new(),
+            notes: Vec::new(),
+            checkpoints: Vec::new(),
+            gaps: Vec::new(),
+            integrity: Integrity::Unavailable,
         }
     }
 }
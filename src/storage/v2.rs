@@ -5,7 +5,6 @@ use std::io::BufReader;
 use super::*;
 
 pub(crate) struct Data {
-    #[allow(dead_code)]
     pub header: Header,
     pub traces: Vec<StackTrace>,
 }
@@ -29,3 +28,17 @@ impl Storage for Data {
         Version(2)
     }
 }
+
+impl From<Data> for v3::Data {
+    fn from(d: Data) -> v3::Data {
+        v3::Data {
+            header: d.header,
+            traces: d.traces,
+            epochs: Vec::new(),
+            notes: Vec::new(),
+            checkpoints: Vec::new(),
+            gaps: Vec::new(),
+            integrity: Integrity::Unavailable,
+        }
+    }
+}
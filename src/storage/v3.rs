@@ -0,0 +1,161 @@
+use crate::core::types::{Header, StackTrace, Timestamp};
+use std::io::prelude::*;
+use std::io::BufReader;
+
+use super::*;
+
+pub(crate) struct Data {
+    pub header: Header,
+    pub traces: Vec<StackTrace>,
+    /// The recording sessions that were appended to the original recording
+    pub epochs: Vec<Epoch>,
+    /// Things that happened while recording that affect how the profile should be read
+    pub notes: Vec<String>,
+    /// When each checkpoint was written
+    pub checkpoints: Vec<Timestamp>,
+    /// When sampling was paused by a duty cycle
+    pub gaps: Vec<Gap>,
+    pub integrity: Integrity,
+}
+
+pub(crate) struct Epoch {
+    pub header: Header,
+    /// The index of the session's first trace in `Data::traces`
+    pub first_trace: usize,
+}
+
+/// Written at the start of each recording session that's appended to an existing recording
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EpochMarker {
+    pub epoch: Header,
+}
+
+// Traces are serialized starting with their `trace` field, so they can't be mistaken for this
+pub(crate) const EPOCH_MARKER_PREFIX: &str = "{\"epoch\":";
+
+/// Written when something happens while recording that affects how the profile should be read,
+/// e.g. when the sample rate had to be lowered
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NoteMarker {
+    pub note: String,
+}
+
+const NOTE_MARKER_PREFIX: &str = "{\"note\":";
+
+/// Written at each checkpoint, so that traces from long recordings can be lined up with the
+/// monotonic clock even if the wall clock was adjusted during the recording
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CheckpointMarker {
+    pub checkpoint: Timestamp,
+}
+
+const CHECKPOINT_MARKER_PREFIX: &str = "{\"checkpoint\":";
+
+/// A time between two traces when nothing was sampled on purpose, rather than because the
+/// process was idle
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Gap {
+    /// When the last trace before the gap was taken
+    pub from: SystemTime,
+    /// When the first trace after the gap was taken
+    pub to: SystemTime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GapMarker {
+    pub gap: Gap,
+}
+
+const GAP_MARKER_PREFIX: &str = "{\"gap\":";
+
+impl Data {
+    /// Reads traces and epoch markers, keeping the traces that were taken within `range`. Reading
+    /// stops a little after the end of the range, since traces are written in roughly the order
+    /// that they were taken. If a seal that has checked the lines before these is given, the lines
+    /// are checked against the recording's footer.
+    pub(crate) fn read_traces<B: BufRead>(
+        &mut self,
+        lines: std::io::Lines<B>,
+        range: &TimeRange,
+        mut seal: Option<Seal>,
+    ) -> Result<(), Error> {
+        for line in lines {
+            let line = match line {
+                Ok(line) => line,
+                // Recordings that were checkpointed but never completed end in a truncated gzip
+                // member. Everything up to the last checkpoint is still usable.
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    warn!("Recording ends early, probably because rbspy was interrupted while recording. Reading the traces up to the last checkpoint.");
+                    if let Some(seal) = &mut seal {
+                        seal.truncated();
+                    }
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(seal) = &mut seal {
+                seal.line(&line)?;
+            }
+            if line.starts_with(FOOTER_MARKER_PREFIX) {
+                continue;
+            }
+            if line.starts_with(EPOCH_MARKER_PREFIX) {
+                let marker: EpochMarker = serde_json::from_str(&line)?;
+                self.epochs.push(Epoch {
+                    header: marker.epoch,
+                    first_trace: self.traces.len(),
+                });
+                continue;
+            }
+            if line.starts_with(NOTE_MARKER_PREFIX) {
+                let marker: NoteMarker = serde_json::from_str(&line)?;
+                self.notes.push(marker.note);
+                continue;
+            }
+            if line.starts_with(CHECKPOINT_MARKER_PREFIX) {
+                let marker: CheckpointMarker = serde_json::from_str(&line)?;
+                self.checkpoints.push(marker.checkpoint);
+                continue;
+            }
+            if line.starts_with(GAP_MARKER_PREFIX) {
+                let marker: GapMarker = serde_json::from_str(&line)?;
+                self.gaps.push(marker.gap);
+                continue;
+            }
+            let trace: StackTrace = serde_json::from_str(&line)?;
+            if range.is_past(trace.time) {
+                seal = None;
+                break;
+            }
+            if range.contains(trace.time) {
+                self.traces.push(trace);
+            }
+        }
+        self.integrity = seal.map_or(Integrity::Unavailable, Seal::integrity);
+        Ok(())
+    }
+}
+
+impl Storage for Data {
+    fn from_reader<R: Read>(r: R) -> Result<Data, Error> {
+        let reader = BufReader::new(r);
+        let mut lines = reader.lines();
+        let header_line = lines.next().unwrap().unwrap();
+        let mut data = Data {
+            header: serde_json::from_str(&header_line)?,
+            traces: Vec::new(),
+            epochs: Vec::new(),
+            notes: Vec::new(),
+            checkpoints: Vec::new(),
+            gaps: Vec::new(),
+            integrity: Integrity::Unavailable,
+        };
+        let mut checksum = Checksum::new();
+        checksum.line(&header_line);
+        data.read_traces(lines, &TimeRange::default(), Some(Seal::new(checksum)))?;
+        Ok(data)
+    }
+    fn version() -> Version {
+        Version(3)
+    }
+}
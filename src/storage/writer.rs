@@ -0,0 +1,175 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::SystemTime;
+
+use anyhow::{format_err, Error, Result};
+
+use crate::core::types::StackTrace;
+use crate::storage::Store;
+
+/// How many writes can wait for the writer thread before callers have to
+const CAPACITY: usize = 1000;
+
+enum Command {
+    Write(StackTrace),
+    Note(String),
+    Gap(SystemTime, SystemTime),
+    Checkpoint(mpsc::Sender<Result<()>>),
+    Complete,
+}
+
+/// Writes to a `Store` from a thread of its own, so that compressing the raw output and writing
+/// it to disk doesn't hold up the recorder. Callers only wait for the thread when it's fallen
+/// `CAPACITY` writes behind, or to checkpoint and complete the store.
+pub(crate) struct StoreWriter {
+    commands: Option<SyncSender<Command>>,
+    thread: Option<JoinHandle<Result<()>>>,
+    size: Arc<AtomicU64>,
+}
+
+impl StoreWriter {
+    pub fn spawn(store: Store) -> Result<StoreWriter> {
+        let (commands, receiver) = mpsc::sync_channel(CAPACITY);
+        let size = Arc::new(AtomicU64::new(store.size()?));
+        let thread = {
+            let size = size.clone();
+            std::thread::Builder::new()
+                .name("raw output".to_string())
+                .spawn(move || write_commands(store, receiver, &size))?
+        };
+        Ok(StoreWriter {
+            commands: Some(commands),
+            thread: Some(thread),
+            size,
+        })
+    }
+
+    pub fn write(&mut self, trace: &StackTrace) -> Result<()> {
+        self.send(Command::Write(trace.clone()))
+    }
+
+    pub fn note(&mut self, note: &str) -> Result<()> {
+        self.send(Command::Note(note.to_string()))
+    }
+
+    pub fn gap(&mut self, from: SystemTime, to: SystemTime) -> Result<()> {
+        self.send(Command::Gap(from, to))
+    }
+
+    /// Waits until everything written so far is checkpointed (see `Store::checkpoint`)
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let (reply, result) = mpsc::channel();
+        self.send(Command::Checkpoint(reply))?;
+        match result.recv() {
+            Ok(result) => result,
+            Err(_) => Err(self.failure()),
+        }
+    }
+
+    /// How many bytes have been written to disk so far. Lags behind what's been written to the
+    /// writer by what the writer thread hasn't caught up with yet.
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the writer thread to write everything and complete the store (see
+    /// `Store::complete`)
+    pub fn complete(mut self) {
+        let result = self.send(Command::Complete).and_then(|()| self.join());
+        if let Err(err) = result {
+            warn!("Failed to finish writing the raw output: {:#}", err);
+        }
+    }
+
+    fn send(&mut self, command: Command) -> Result<()> {
+        let sent = self
+            .commands
+            .as_ref()
+            .map_or(false, |commands| commands.send(command).is_ok());
+        if sent {
+            Ok(())
+        } else {
+            Err(self.failure())
+        }
+    }
+
+    /// Waits for the writer thread to stop, which it does once there are no more commands
+    fn join(&mut self) -> Result<()> {
+        self.commands = None;
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(format_err!("The raw output's writer thread panicked")),
+            None => Ok(()),
+        }
+    }
+
+    /// Why the writer thread stopped taking commands
+    fn failure(&mut self) -> Error {
+        match self.join() {
+            Err(err) => err.context("Failed to write the raw output"),
+            Ok(()) => format_err!("The raw output was already completed"),
+        }
+    }
+}
+
+impl Drop for StoreWriter {
+    fn drop(&mut self) {
+        // Like dropping a store, this finishes what was written without completing it
+        if let Err(err) = self.join() {
+            warn!("Failed to write the raw output: {:#}", err);
+        }
+    }
+}
+
+fn write_commands(mut store: Store, commands: Receiver<Command>, size: &AtomicU64) -> Result<()> {
+    for command in commands {
+        match command {
+            Command::Write(trace) => store.write(&trace)?,
+            Command::Note(note) => store.note(&note)?,
+            Command::Gap(from, to) => store.gap(from, to)?,
+            Command::Checkpoint(reply) => {
+                if let Err(err) = store.checkpoint() {
+                    let _ = reply.send(Err(format_err!("{:#}", err)));
+                    return Err(err);
+                }
+                let _ = reply.send(Ok(()));
+            }
+            Command::Complete => {
+                store.complete();
+                return Ok(());
+            }
+        }
+        size.store(store.size()?, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{Header, RawCompression};
+
+    #[test]
+    fn test_store_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.raw.zst");
+        let store = Store::new(&path, Header::new(100), RawCompression::zstd).unwrap();
+        let mut writer = StoreWriter::spawn(store).unwrap();
+        let mut trace = StackTrace::new_empty();
+        writer.write(&trace).unwrap();
+        writer.checkpoint().unwrap();
+        assert!(writer.size() > 0);
+        writer.note("Lowered the sample rate to 50.").unwrap();
+        trace.thread_id = Some(1);
+        writer.write(&trace).unwrap();
+        writer.complete();
+
+        let data = crate::storage::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(data.traces.len(), 2);
+        assert_eq!(data.checkpoints.len(), 1);
+        assert_eq!(data.notes.len(), 1);
+        data.integrity.verify(None).unwrap();
+    }
+}
@@ -3,6 +3,7 @@ use std::cmp::min;
 use std::collections::{BTreeMap, HashMap};
 use std::io;
 
+use crate::core::process::Pid;
 use crate::core::types::StackFrame;
 
 /*
@@ -51,8 +52,19 @@ use crate::core::types::StackFrame;
  * c: {exclusive: 0, calls: {d -> {inclusive: 3, count: 1}}}
  * d: {exclusive: 3, calls: {d -> {inclusive: 4, count: 2}, g -> {inclusive: 1, count: 1}}}
  *
+ * **threads**
+ *
+ * Only consecutive stacks of the same thread say anything about its calls. When several threads
+ * (or processes) are sampled, their stacks are interleaved, so each thread's stack is tracked
+ * separately. Otherwise every switch between threads would look like all of the calls on one
+ * thread's stack returning and a new set of calls being made, which inflates the call counts and
+ * splits up the inclusive costs.
+ *
  */
 
+/// Identifies the thread that a stack was sampled from: its process and Ruby thread
+pub type ThreadKey = (Option<Pid>, Option<usize>);
+
 // Stats about the relationship between two functions, one of which
 // calls the other.
 #[derive(Debug)]
@@ -60,17 +72,17 @@ struct Call {
     // Estimate of number of times this call was made (see above comment)
     count: usize,
 
-    // Number of stack traces including this call.
+    // Number of stack traces including this call, weighted by each trace's weight.
     // 'a b c d' includes the call b -> c, 'a b e c d' does not
-    inclusive: usize,
+    inclusive: u64,
 }
 
 // Stats about a single function.
 #[derive(Debug, Default)]
 struct Location {
     // How many times does this function appear at the top of a stack trace
-    // where it's the most recent function called?
-    exclusive: usize,
+    // where it's the most recent function called? Weighted by each trace's weight.
+    exclusive: u64,
 
     // Data about the calls from this function to other functions.
     calls: HashMap<StackFrame, Call>,
@@ -86,19 +98,19 @@ struct StackEntry {
     frame: StackFrame,
 
     // How many samples were found inside this call only?
-    exclusive: usize,
+    exclusive: u64,
 
     // How many samples were found in this call, and sub-calls?
-    inclusive: usize,
+    inclusive: u64,
 }
 
 // Tracks statistics about a program being sampled.
 #[derive(Default, Debug)]
 pub struct Stats {
-    // The current stack, along with tracking information.
+    // The current stack of each thread, along with tracking information.
     // The root function is at element zero.
     // Not used in final reporting, only for tracking an ongoing profile.
-    stack: Vec<StackEntry>,
+    stacks: HashMap<ThreadKey, Vec<StackEntry>>,
 
     // Overall stats about this program.
     locations: Locations,
@@ -148,8 +160,16 @@ impl Stats {
         }
     }
 
-    // Add a single stack sample to this Stats.
-    pub fn add(&mut self, stack: &[StackFrame]) {
+    // Add a single stack sample to this Stats, counting it `weight` times.
+    pub fn add(&mut self, stack: &[StackFrame], weight: u64) {
+        self.add_for_thread((None, None), stack, weight)
+    }
+
+    // Add a single stack sample of the given thread to this Stats, counting it `weight` times.
+    // It's compared with the previous sample of the same thread only.
+    pub fn add_for_thread(&mut self, thread: ThreadKey, stack: &[StackFrame], weight: u64) {
+        let stored = self.stacks.entry(thread).or_default();
+
         // The input sample has the root function at the end. Reverse that!
         let rev: Vec<_> = stack.iter().rev().collect();
 
@@ -180,8 +200,8 @@ impl Stats {
         // 1. Common items we can ignore. Find out how many there are, so we
         // can skip them.
         let mut common = 0;
-        let max_common = min(self.stack.len(), rev.len());
-        while common < max_common && &self.stack[common].frame == rev[common] {
+        let max_common = min(stored.len(), rev.len());
+        while common < max_common && &stored[common].frame == rev[common] {
             common += 1;
         }
 
@@ -201,13 +221,13 @@ impl Stats {
         //    We add up the inclusive counts because we only increment the 'inclusive' number on
         //    the top element of the stack, so the 'inclusive' number of every stack element is the
         //    sum of its inclusive property and that of all its children.
-        while self.stack.len() > common {
+        while stored.len() > common {
             // For each entry, pop it from our stored stack, and track its
             // exclusive sample count.
-            let entry = self.stack.pop().unwrap();
+            let entry = stored.pop().unwrap();
             self.locations.add_exclusive(&entry);
 
-            if let Some(parent) = self.stack.last_mut() {
+            if let Some(parent) = stored.last_mut() {
                 // If a parent is present, also track the inclusive sample count.
                 self.locations.add_inclusive(&parent.frame, &entry);
 
@@ -217,12 +237,12 @@ impl Stats {
                 parent.inclusive += entry.inclusive;
             }
         }
-        // Now our stored stack (self.stack) only includes common items, since we
+        // Now our stored stack only includes common items, since we
         // popped all the old ones.
 
         // 3. Add new stack frames to our stored stack.
         for item in rev.iter().skip(common) {
-            self.stack.push(StackEntry {
+            stored.push(StackEntry {
                 frame: (*item).clone(),
                 exclusive: 0,
                 inclusive: 0,
@@ -235,18 +255,22 @@ impl Stats {
         //
         // We don't increment the inclusive time of everything on the stack here,
         // it's easier to do the addition in step 2 above.
-        if let Some(entry) = self.stack.last_mut() {
-            entry.exclusive += 1;
-            entry.inclusive += 1;
+        if let Some(entry) = stored.last_mut() {
+            entry.exclusive += weight;
+            entry.inclusive += weight;
         }
     }
 
     // Finish adding samples to this Stats.
     pub fn finish(&mut self) {
-        // To handle whatever remains on the stored stack, we can just add
-        // an empty stack. This causes us to integrate info for each of those
-        // frames--see step 2 in add().
-        self.add(&[]);
+        // To handle whatever remains on the stored stacks, we can just add
+        // an empty stack to each thread. This causes us to integrate info for
+        // each of those frames--see step 2 in add_for_thread().
+        let threads: Vec<ThreadKey> = self.stacks.keys().copied().collect();
+        for thread in threads {
+            self.add_for_thread(thread, &[], 0);
+        }
+        self.stacks.clear();
     }
 
     // Write a callgrind file based on the stats collected.
@@ -284,6 +308,7 @@ impl Stats {
 
 #[cfg(test)]
 mod tests {
+    use crate::core::types::FrameKind;
     use crate::ui::callgrind::*;
 
     // Build a test stackframe
@@ -293,6 +318,7 @@ mod tests {
             relative_path: format!("file{}.rb", i),
             absolute_path: None,
             lineno: Some(i),
+            kind: FrameKind::Method,
         }
     }
 
@@ -303,11 +329,12 @@ mod tests {
             relative_path: "file1.rb".to_owned(),
             absolute_path: None,
             lineno: Some(42),
+            kind: FrameKind::Method,
         }
     }
 
     // Assert that basic stats for a stack frame is as expected.
-    fn assert_location(stats: &Stats, f: StackFrame, exclusive: usize, children: usize) {
+    fn assert_location(stats: &Stats, f: StackFrame, exclusive: u64, children: usize) {
         let loc = stats
             .locations
             .0
@@ -323,7 +350,7 @@ mod tests {
         parent: StackFrame,
         child: StackFrame,
         count: usize,
-        inclusive: usize,
+        inclusive: u64,
     ) {
         let ploc = stats
             .locations
@@ -350,12 +377,12 @@ mod tests {
     fn build_test_stats() -> Stats {
         let mut stats = Stats::new();
 
-        stats.add(&vec![f(1)]);
-        stats.add(&vec![f(3), f(2), f(1)]);
-        stats.add(&vec![f(2), f(1)]);
-        stats.add(&vec![f(3), f(1)]);
-        stats.add(&vec![f(2), f(1)]);
-        stats.add(&vec![f(3), fdup(), f(1)]);
+        stats.add(&vec![f(1)], 1);
+        stats.add(&vec![f(3), f(2), f(1)], 1);
+        stats.add(&vec![f(2), f(1)], 1);
+        stats.add(&vec![f(3), f(1)], 1);
+        stats.add(&vec![f(2), f(1)], 1);
+        stats.add(&vec![f(3), fdup(), f(1)], 1);
         stats.finish();
 
         stats
@@ -366,9 +393,9 @@ mod tests {
     fn stats_aggregate() {
         let stats = &build_test_stats();
         assert!(
-            stats.stack.is_empty(),
-            "Stack not empty: {:#?}",
-            stats.stack
+            stats.stacks.is_empty(),
+            "Stacks not empty: {:#?}",
+            stats.stacks
         );
         let len = stats.locations.0.len();
         assert_eq!(len, 4, "Bad location count");
@@ -435,4 +462,20 @@ fn=func3
         let actual = String::from_utf8(buf).expect("Callgrind output not utf8");
         assert_eq!(actual, expected, "Unexpected callgrind output");
     }
+
+    // Interleaved samples of different threads don't break up each other's calls
+    #[test]
+    fn stats_by_thread() {
+        let mut stats = Stats::new();
+        for _ in 0..2 {
+            stats.add_for_thread((Some(1), Some(1)), &[f(2), f(1)], 1);
+            stats.add_for_thread((Some(1), Some(2)), &[f(3), f(1)], 1);
+        }
+        stats.finish();
+        assert_location(&stats, f(1), 0, 2);
+        assert_location(&stats, f(2), 2, 0);
+        assert_location(&stats, f(3), 2, 0);
+        assert_inclusive(&stats, f(1), f(2), 1, 2);
+        assert_inclusive(&stats, f(1), f(3), 1, 2);
+    }
 }
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::core::process::Pid;
+use crate::core::types::{StackFrame, StackTrace};
+use crate::ui::samples::{FrameTable, UntimedClock};
+
+/*
+ * This file contains code to export rbspy recordings in Chrome's trace event format, which
+ * Perfetto (https://ui.perfetto.dev) and chrome://tracing open.
+ *
+ * The format is described here:
+ * https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+ *
+ * Each thread's samples are turned into "complete" (`X`) events, one for every stretch of time
+ * that a frame stayed on the thread's stack across consecutive samples. Timestamps are
+ * microseconds since the Unix epoch, so that recordings line up with other traces of the same
+ * time.
+ */
+
+/// How long a sample lasts when it has no interval, e.g. in old recordings. That's the interval at
+/// rbspy's default sample rate.
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Serialize)]
+struct TraceFile {
+    #[serde(rename = "traceEvents")]
+    trace_events: Vec<Event>,
+    #[serde(rename = "displayTimeUnit")]
+    display_time_unit: &'static str,
+    #[serde(rename = "otherData")]
+    other_data: BTreeMap<&'static str, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Event {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cat: Option<&'static str>,
+    ph: &'static str,
+    /// In microseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ts: Option<f64>,
+    /// In microseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    pid: Pid,
+    tid: usize,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    args: BTreeMap<&'static str, String>,
+}
+
+struct Sample {
+    at: f64,
+    duration: f64,
+    /// Indices of the frames, outermost first
+    stack: Vec<usize>,
+}
+
+struct Thread {
+    pid: Pid,
+    tid: usize,
+    name: String,
+    samples: Vec<Sample>,
+}
+
+/// The samples of each thread, to be laid out along a time axis as trace events
+#[derive(Default)]
+pub struct Stats {
+    threads: HashMap<(Option<Pid>, Option<usize>), Thread>,
+    frames: FrameTable<StackFrame>,
+    untimed_clock: UntimedClock<f64>,
+    description: Option<String>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    pub fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        let indices = self.frames.indices(&stack.trace, StackFrame::clone);
+
+        let duration = match stack.interval {
+            Some(interval) => micros(interval),
+            None => micros(DEFAULT_INTERVAL) * f64::from(1 + stack.repeats),
+        };
+        let at = match stack.time {
+            Some(time) => micros(time.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            None => self.untimed_clock.tick(duration),
+        };
+
+        // Ruby threads are identified by their VM struct, which outlives changes of native
+        // thread. Trace viewers need a number for each thread, so threads without one are
+        // numbered in the order they were first sampled.
+        let key = (stack.pid, stack.thread_address.or(stack.thread_id));
+        let next_tid = self.threads.len();
+        let thread = self.threads.entry(key).or_insert_with(|| Thread {
+            pid: stack.pid.unwrap_or(0),
+            tid: stack.os_thread_id.or(stack.thread_id).unwrap_or(next_tid),
+            name: thread_name(stack),
+            samples: Vec::new(),
+        });
+        thread.samples.push(Sample {
+            at,
+            duration,
+            stack: indices,
+        });
+        Ok(())
+    }
+
+    /// Adds a description of what was profiled to the trace's processes
+    pub fn set_description(&mut self, description: String) {
+        self.description = Some(description).filter(|description| !description.is_empty());
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        let mut threads: Vec<&Thread> = self.threads.values().collect();
+        threads.sort_by_key(|thread| (thread.pid, thread.tid));
+
+        let mut events = Vec::new();
+        let mut pids: Vec<Pid> = threads.iter().map(|thread| thread.pid).collect();
+        pids.dedup();
+        for pid in pids {
+            let name = match &self.description {
+                Some(description) => format!("ruby {} - {}", pid, description),
+                None => format!("ruby {}", pid),
+            };
+            events.push(metadata_event("process_name", pid, 0, name));
+        }
+        for thread in threads {
+            events.push(metadata_event(
+                "thread_name",
+                thread.pid,
+                thread.tid,
+                thread.name.clone(),
+            ));
+            let mut spans = spans(&thread.samples);
+            // Viewers nest events that start at the same time in the order they come in
+            spans.sort_by(|a, b| {
+                a.start
+                    .partial_cmp(&b.start)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then(a.depth.cmp(&b.depth))
+            });
+            events.extend(spans.into_iter().map(|span| {
+                let frame = &self.frames.frames()[span.frame];
+                let mut args = BTreeMap::new();
+                args.insert("file", frame.relative_path.clone());
+                if let Some(lineno) = frame.lineno {
+                    args.insert("line", lineno.to_string());
+                }
+                Event {
+                    name: frame.name.clone(),
+                    cat: Some("ruby"),
+                    ph: "X",
+                    ts: Some(span.start),
+                    dur: Some(span.end - span.start),
+                    pid: thread.pid,
+                    tid: thread.tid,
+                    args,
+                }
+            }));
+        }
+
+        let mut other_data = BTreeMap::new();
+        other_data.insert("exporter", format!("rbspy@{}", env!("CARGO_PKG_VERSION")));
+        if let Some(description) = &self.description {
+            other_data.insert("description", description.clone());
+        }
+        let file = TraceFile {
+            trace_events: events,
+            display_time_unit: "ms",
+            other_data,
+        };
+        serde_json::to_writer(&mut *w, &file)?;
+        writeln!(w)?;
+        Ok(())
+    }
+}
+
+fn metadata_event(name: &str, pid: Pid, tid: usize, value: String) -> Event {
+    let mut args = BTreeMap::new();
+    args.insert("name", value);
+    Event {
+        name: name.to_string(),
+        cat: None,
+        ph: "M",
+        ts: None,
+        dur: None,
+        pid,
+        tid,
+        args,
+    }
+}
+
+fn thread_name(stack: &StackTrace) -> String {
+    match (&stack.thread_name, stack.os_thread_id.or(stack.thread_id)) {
+        (Some(thread_name), _) => thread_name.clone(),
+        (None, Some(thread_id)) => format!("thread {}", thread_id),
+        (None, None) => "ruby".to_string(),
+    }
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000_000.0
+}
+
+/// A stretch of time that a frame stayed on a thread's stack, at `depth` frames from the bottom
+struct Span {
+    frame: usize,
+    depth: usize,
+    start: f64,
+    end: f64,
+}
+
+/// Turns a thread's samples into the spans of its frames. Each sample lasts until the thread's
+/// next sample, unless the thread wasn't sampled for a while (e.g. because it was idle and only
+/// on-CPU samples were kept), in which case its stack is closed after the sample's own interval.
+fn spans(samples: &[Sample]) -> Vec<Span> {
+    let mut samples: Vec<&Sample> = samples.iter().collect();
+    samples.sort_by(|a, b| a.at.partial_cmp(&b.at).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut spans = Vec::new();
+    // The frames on the stack, outermost first, and when they were opened
+    let mut open: Vec<(usize, f64)> = Vec::new();
+    let mut last_end = 0.0;
+    let close = |open: &mut Vec<(usize, f64)>, spans: &mut Vec<Span>, keep: usize, at: f64| {
+        while open.len() > keep {
+            let (frame, start) = open.pop().unwrap_or_default();
+            spans.push(Span {
+                frame,
+                depth: open.len(),
+                start,
+                end: at,
+            });
+        }
+    };
+    for (i, sample) in samples.iter().enumerate() {
+        if sample.at > last_end {
+            close(&mut open, &mut spans, 0, last_end);
+        }
+        let at = sample.at.max(last_end);
+        let common = open
+            .iter()
+            .zip(&sample.stack)
+            .take_while(|((open, _), frame)| open == *frame)
+            .count();
+        close(&mut open, &mut spans, common, at);
+        open.extend(sample.stack[common..].iter().map(|&frame| (frame, at)));
+        let end = sample.at + sample.duration;
+        last_end = match samples.get(i + 1) {
+            // Allow for samples that are late
+            Some(next) if next.at <= sample.at + 2.0 * sample.duration => next.at,
+            _ => end,
+        }
+        .max(at);
+    }
+    close(&mut open, &mut spans, 0, last_end);
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::FrameKind;
+
+    fn frame(name: &str, lineno: usize) -> StackFrame {
+        StackFrame {
+            name: name.to_string(),
+            relative_path: "a.rb".to_string(),
+            absolute_path: None,
+            lineno: Some(lineno),
+            kind: FrameKind::Method,
+        }
+    }
+
+    #[test]
+    fn test_chrome_trace() {
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let mut stats = Stats::new();
+        let samples = [
+            (0, vec![frame("inner", 2), frame("outer", 1)]),
+            (10, vec![frame("inner", 2), frame("outer", 1)]),
+            (20, vec![frame("other", 5), frame("outer", 1)]),
+            // After a gap, the stack is opened again
+            (100, vec![frame("outer", 1)]),
+        ];
+        for (ms, trace) in samples.iter() {
+            let mut stack = StackTrace::new_empty();
+            stack.pid = Some(42);
+            stack.os_thread_id = Some(7);
+            stack.thread_name = Some("worker".to_string());
+            stack.time = Some(start + Duration::from_millis(*ms));
+            stack.interval = Some(Duration::from_millis(10));
+            stack.trace = trace.clone();
+            stats.record(&stack).unwrap();
+        }
+        stats.set_description("puma".to_string());
+        let mut output = Vec::new();
+        stats.write(&mut output).unwrap();
+
+        let file: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let events: Vec<String> = file["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| match event["ph"].as_str().unwrap() {
+                "M" => format!("{} {}", event["name"], event["args"]["name"]),
+                _ => format!(
+                    "{} {}:{} {} {}",
+                    event["name"],
+                    event["pid"],
+                    event["tid"],
+                    event["ts"].as_f64().unwrap() - 1e6,
+                    event["dur"]
+                ),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "\"process_name\" \"ruby 42 - puma\"",
+                "\"thread_name\" \"worker\"",
+                "\"outer\" 42:7 0 30000.0",
+                "\"inner\" 42:7 0 20000.0",
+                "\"other\" 42:7 20000 10000.0",
+                "\"outer\" 42:7 100000 10000.0",
+            ]
+        );
+        assert_eq!(file["traceEvents"][3]["args"]["line"], "2");
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::core::process::Pid;
+use crate::core::types::{FrameKind, StackFrame, StackTrace};
+use crate::ui::samples::UntimedClock;
+
+/*
+ * This file contains code to export rbspy recordings in the Gecko profile format, which the
+ * Firefox Profiler (https://profiler.firefox.com) opens.
+ *
+ * The format is described here:
+ * https://github.com/firefox-devtools/profiler/blob/main/docs-developer/gecko-profile-format.md
+ *
+ * Each thread has its own tables. Strings, frames and stacks are stored once and referred to by
+ * their index: a stack is a frame and the index of the stack it was called from (its prefix), so
+ * the stack table is a tree with the outermost frames at its roots. Samples are a stack and the
+ * time they were taken, in milliseconds since the profile's `startTime`. The profiler upgrades
+ * older versions of the format, so this writes the version it was written against.
+ *
+ * Ruby frames' locations are written as `name (path:line)`, which the profiler shows as a
+ * function in a file, like a JavaScript function. C functions only have their name.
+ */
+
+/// The version of the Gecko profile format that's written
+const GECKO_VERSION: u32 = 24;
+
+/// How long a sample lasts when it has no interval, e.g. in old recordings. That's the interval at
+/// rbspy's default sample rate.
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The categories that frames are colored by, which are indices into this list
+const CATEGORIES: [(&str, &str); 3] = [("Other", "grey"), ("Ruby", "red"), ("Native", "blue")];
+const RUBY_CATEGORY: usize = 1;
+const NATIVE_CATEGORY: usize = 2;
+
+#[derive(Debug, Serialize)]
+struct Profile {
+    meta: Meta,
+    libs: Vec<()>,
+    threads: Vec<ThreadProfile>,
+    #[serde(rename = "pausedRanges")]
+    paused_ranges: Vec<()>,
+    processes: Vec<()>,
+}
+
+#[derive(Debug, Serialize)]
+struct Meta {
+    version: u32,
+    /// In milliseconds since the Unix epoch
+    #[serde(rename = "startTime")]
+    start_time: f64,
+    #[serde(rename = "shutdownTime")]
+    shutdown_time: Option<f64>,
+    /// In milliseconds
+    interval: f64,
+    stackwalk: u32,
+    debug: u32,
+    gcpoison: u32,
+    asyncstack: u32,
+    #[serde(rename = "processType")]
+    process_type: u32,
+    platform: String,
+    oscpu: String,
+    misc: String,
+    abi: String,
+    toolkit: String,
+    product: String,
+    /// Frames are already named, so there's nothing for the profiler to symbolicate
+    presymbolicated: bool,
+    categories: Vec<Category>,
+    #[serde(rename = "markerSchema")]
+    marker_schema: Vec<()>,
+}
+
+#[derive(Debug, Serialize)]
+struct Category {
+    name: &'static str,
+    color: &'static str,
+    subcategories: [&'static str; 1],
+}
+
+#[derive(Debug, Serialize)]
+struct Table<S, T> {
+    schema: S,
+    data: Vec<T>,
+}
+
+#[derive(Debug, Serialize)]
+struct SampleSchema {
+    stack: u32,
+    time: u32,
+    #[serde(rename = "eventDelay")]
+    event_delay: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct MarkerSchema {
+    name: u32,
+    #[serde(rename = "startTime")]
+    start_time: u32,
+    #[serde(rename = "endTime")]
+    end_time: u32,
+    phase: u32,
+    category: u32,
+    data: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct StackSchema {
+    prefix: u32,
+    frame: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct FrameSchema {
+    location: u32,
+    #[serde(rename = "relevantForJS")]
+    relevant_for_js: u32,
+    #[serde(rename = "innerWindowID")]
+    inner_window_id: u32,
+    implementation: u32,
+    line: u32,
+    column: u32,
+    category: u32,
+    subcategory: u32,
+}
+
+/// A sample's stack, time and event delay
+type SampleRow = (Option<usize>, f64, f64);
+/// A stack's prefix and frame
+type StackRow = (Option<usize>, usize);
+/// A frame's location (in the string table), whether it's relevant for JavaScript, its inner
+/// window ID, implementation, line, column, category and subcategory
+type FrameRow = (
+    usize,
+    bool,
+    u32,
+    Option<()>,
+    Option<usize>,
+    Option<()>,
+    usize,
+    usize,
+);
+
+#[derive(Debug, Serialize)]
+struct ThreadProfile {
+    name: String,
+    #[serde(rename = "processType")]
+    process_type: &'static str,
+    #[serde(rename = "processName")]
+    process_name: String,
+    pid: Pid,
+    tid: usize,
+    #[serde(rename = "registerTime")]
+    register_time: f64,
+    #[serde(rename = "unregisterTime")]
+    unregister_time: Option<f64>,
+    samples: Table<SampleSchema, SampleRow>,
+    markers: Table<MarkerSchema, ()>,
+    #[serde(rename = "stackTable")]
+    stack_table: Table<StackSchema, StackRow>,
+    #[serde(rename = "frameTable")]
+    frame_table: Table<FrameSchema, FrameRow>,
+    #[serde(rename = "stringTable")]
+    string_table: Vec<String>,
+}
+
+struct Thread {
+    pid: Pid,
+    tid: usize,
+    name: String,
+    strings: Vec<String>,
+    string_to_index: HashMap<String, usize>,
+    frames: Vec<FrameRow>,
+    frame_to_index: HashMap<StackFrame, usize>,
+    stacks: Vec<StackRow>,
+    stack_to_index: HashMap<StackRow, usize>,
+    /// When each sample was taken, in milliseconds since the Unix epoch, and its stack
+    samples: Vec<(f64, Option<usize>)>,
+}
+
+impl Thread {
+    fn string(&mut self, string: String) -> usize {
+        let strings = &mut self.strings;
+        *self
+            .string_to_index
+            .entry(string)
+            .or_insert_with_key(|string| {
+                strings.push(string.clone());
+                strings.len() - 1
+            })
+    }
+
+    fn frame(&mut self, frame: &StackFrame) -> usize {
+        if let Some(&index) = self.frame_to_index.get(frame) {
+            return index;
+        }
+        let (location, category) = match (frame.kind, frame.lineno) {
+            (FrameKind::CFunction, _) => (frame.name.clone(), NATIVE_CATEGORY),
+            (_, Some(lineno)) => (
+                format!("{} ({}:{})", frame.name, frame.relative_path, lineno),
+                RUBY_CATEGORY,
+            ),
+            (_, None) => (
+                format!("{} ({})", frame.name, frame.relative_path),
+                RUBY_CATEGORY,
+            ),
+        };
+        let location = self.string(location);
+        self.frames
+            .push((location, false, 0, None, frame.lineno, None, category, 0));
+        self.frame_to_index
+            .insert(frame.clone(), self.frames.len() - 1);
+        self.frames.len() - 1
+    }
+
+    /// The index of the stack, which is given innermost frame first
+    fn stack(&mut self, trace: &[StackFrame]) -> Option<usize> {
+        let mut prefix = None;
+        for frame in trace.iter().rev() {
+            let stack = (prefix, self.frame(frame));
+            let stacks = &mut self.stacks;
+            prefix = Some(*self.stack_to_index.entry(stack).or_insert_with(|| {
+                stacks.push(stack);
+                stacks.len() - 1
+            }));
+        }
+        prefix
+    }
+}
+
+/// The samples of each thread, with the tables that they refer to
+#[derive(Default)]
+pub struct Stats {
+    threads: HashMap<(Option<Pid>, Option<usize>), Thread>,
+    untimed_clock: UntimedClock<f64>,
+    interval: Option<Duration>,
+    description: Option<String>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    pub fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        // Samples that were merged because they were identical are spread out again
+        let count = 1 + stack.repeats;
+        let interval = stack
+            .interval
+            .map_or(DEFAULT_INTERVAL, |interval| interval / count);
+        let at = match stack.time {
+            Some(time) => millis(time.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            None => self.untimed_clock.tick(millis(interval) * f64::from(count)),
+        };
+
+        // Threads are told apart the same way as in the Chrome trace format
+        let key = (stack.pid, stack.thread_address.or(stack.thread_id));
+        let next_tid = self.threads.len();
+        let thread = self.threads.entry(key).or_insert_with(|| Thread {
+            pid: stack.pid.unwrap_or(0),
+            tid: stack.os_thread_id.or(stack.thread_id).unwrap_or(next_tid),
+            name: thread_name(stack),
+            strings: Vec::new(),
+            string_to_index: HashMap::new(),
+            frames: Vec::new(),
+            frame_to_index: HashMap::new(),
+            stacks: Vec::new(),
+            stack_to_index: HashMap::new(),
+            samples: Vec::new(),
+        });
+        let index = thread.stack(&stack.trace);
+        for i in 0..count {
+            thread
+                .samples
+                .push((at + millis(interval) * f64::from(i), index));
+        }
+        Ok(())
+    }
+
+    /// Sets the sample interval that the profiler shows, from the recording's sample rate
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate > 0 {
+            self.interval = Some(Duration::from_secs(1) / sample_rate);
+        }
+    }
+
+    /// Adds a description of what was profiled to the profile's processes
+    pub fn set_description(&mut self, description: String) {
+        self.description = Some(description).filter(|description| !description.is_empty());
+    }
+
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        let start = self
+            .threads
+            .values()
+            .flat_map(|thread| thread.samples.iter().map(|(at, _)| *at))
+            .fold(None, |start: Option<f64>, at| {
+                Some(start.map_or(at, |start| start.min(at)))
+            })
+            .unwrap_or_default();
+        let mut threads: Vec<&Thread> = self.threads.values().collect();
+        threads.sort_by_key(|thread| (thread.pid, thread.tid));
+
+        let threads = threads
+            .into_iter()
+            .map(|thread| {
+                let mut samples = thread.samples.clone();
+                samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+                let process_name = match &self.description {
+                    Some(description) => format!("ruby {} - {}", thread.pid, description),
+                    None => format!("ruby {}", thread.pid),
+                };
+                ThreadProfile {
+                    name: thread.name.clone(),
+                    process_type: "default",
+                    process_name,
+                    pid: thread.pid,
+                    tid: thread.tid,
+                    register_time: 0.0,
+                    unregister_time: None,
+                    samples: Table {
+                        schema: SampleSchema {
+                            stack: 0,
+                            time: 1,
+                            event_delay: 2,
+                        },
+                        data: samples
+                            .into_iter()
+                            .map(|(at, stack)| (stack, at - start, 0.0))
+                            .collect(),
+                    },
+                    markers: Table {
+                        schema: MarkerSchema {
+                            name: 0,
+                            start_time: 1,
+                            end_time: 2,
+                            phase: 3,
+                            category: 4,
+                            data: 5,
+                        },
+                        data: Vec::new(),
+                    },
+                    stack_table: Table {
+                        schema: StackSchema {
+                            prefix: 0,
+                            frame: 1,
+                        },
+                        data: thread.stacks.clone(),
+                    },
+                    frame_table: Table {
+                        schema: FrameSchema {
+                            location: 0,
+                            relevant_for_js: 1,
+                            inner_window_id: 2,
+                            implementation: 3,
+                            line: 4,
+                            column: 5,
+                            category: 6,
+                            subcategory: 7,
+                        },
+                        data: thread.frames.clone(),
+                    },
+                    string_table: thread.strings.clone(),
+                }
+            })
+            .collect();
+
+        let profile = Profile {
+            meta: Meta {
+                version: GECKO_VERSION,
+                start_time: start,
+                shutdown_time: None,
+                interval: millis(self.interval.unwrap_or(DEFAULT_INTERVAL)),
+                stackwalk: 0,
+                debug: 0,
+                gcpoison: 0,
+                asyncstack: 0,
+                process_type: 0,
+                // The report can be made on another machine than the recording
+                platform: String::new(),
+                oscpu: String::new(),
+                misc: self.description.clone().unwrap_or_default(),
+                abi: String::new(),
+                toolkit: String::new(),
+                product: format!("rbspy {}", env!("CARGO_PKG_VERSION")),
+                presymbolicated: true,
+                categories: CATEGORIES
+                    .iter()
+                    .map(|&(name, color)| Category {
+                        name,
+                        color,
+                        subcategories: ["Other"],
+                    })
+                    .collect(),
+                marker_schema: Vec::new(),
+            },
+            libs: Vec::new(),
+            threads,
+            paused_ranges: Vec::new(),
+            processes: Vec::new(),
+        };
+        serde_json::to_writer(&mut *w, &profile)?;
+        writeln!(w)?;
+        Ok(())
+    }
+}
+
+fn thread_name(stack: &StackTrace) -> String {
+    match (&stack.thread_name, stack.os_thread_id.or(stack.thread_id)) {
+        (Some(thread_name), _) => thread_name.clone(),
+        (None, Some(thread_id)) => format!("thread {}", thread_id),
+        (None, None) => "ruby".to_string(),
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(name: &str, lineno: usize, kind: FrameKind) -> StackFrame {
+        StackFrame {
+            name: name.to_string(),
+            relative_path: "a.rb".to_string(),
+            absolute_path: None,
+            lineno: Some(lineno),
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_firefox_profile() {
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let mut stats = Stats::new();
+        let samples = [
+            (
+                0,
+                0,
+                vec![
+                    frame("inner", 2, FrameKind::Method),
+                    frame("outer", 1, FrameKind::Method),
+                ],
+            ),
+            (
+                10,
+                1,
+                vec![
+                    frame("sleep", 0, FrameKind::CFunction),
+                    frame("outer", 1, FrameKind::Method),
+                ],
+            ),
+            (30, 0, vec![]),
+        ];
+        for (ms, repeats, trace) in samples.iter() {
+            let mut stack = StackTrace::new_empty();
+            stack.pid = Some(42);
+            stack.os_thread_id = Some(7);
+            stack.thread_name = Some("worker".to_string());
+            stack.time = Some(start + Duration::from_millis(*ms));
+            stack.interval = Some(Duration::from_millis(10) * (1 + repeats));
+            stack.repeats = *repeats;
+            stack.trace = trace.clone();
+            stats.record(&stack).unwrap();
+        }
+        stats.set_sample_rate(100);
+        stats.set_description("puma".to_string());
+        let mut output = Vec::new();
+        stats.write(&mut output).unwrap();
+
+        let profile: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(profile["meta"]["version"], GECKO_VERSION);
+        assert_eq!(profile["meta"]["startTime"], 1000.0);
+        assert_eq!(profile["meta"]["interval"], 10.0);
+        let thread = &profile["threads"][0];
+        assert_eq!(thread["name"], "worker");
+        assert_eq!(thread["processName"], "ruby 42 - puma");
+        assert_eq!(thread["pid"], 42);
+        assert_eq!(thread["tid"], 7);
+
+        let strings = thread["stringTable"].as_array().unwrap();
+        let frames = thread["frameTable"]["data"].as_array().unwrap();
+        let stacks = thread["stackTable"]["data"].as_array().unwrap();
+        // Follows a stack's prefixes to its outermost frame
+        let frames_of = |stack: &serde_json::Value| {
+            let mut names = Vec::new();
+            let mut stack = stack.as_u64();
+            while let Some(index) = stack {
+                let row = &stacks[index as usize];
+                let frame = &frames[row[1].as_u64().unwrap() as usize];
+                names.push(
+                    strings[frame[0].as_u64().unwrap() as usize]
+                        .as_str()
+                        .unwrap(),
+                );
+                stack = row[0].as_u64();
+            }
+            names
+        };
+        let samples: Vec<(f64, Vec<&str>)> = thread["samples"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|sample| (sample[1].as_f64().unwrap(), frames_of(&sample[0])))
+            .collect();
+        assert_eq!(
+            samples,
+            [
+                (0.0, vec!["inner (a.rb:2)", "outer (a.rb:1)"]),
+                (10.0, vec!["sleep", "outer (a.rb:1)"]),
+                // The repeated sample is spread out again
+                (20.0, vec!["sleep", "outer (a.rb:1)"]),
+                (30.0, vec![]),
+            ]
+        );
+        // `outer` is shared by both stacks
+        assert_eq!(frames.len(), 3);
+        assert_eq!(stacks.len(), 3);
+        assert_eq!(frames[1][4], 2);
+        assert_eq!(frames[2][6], NATIVE_CATEGORY);
+    }
+}
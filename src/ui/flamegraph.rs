@@ -1,44 +1,192 @@
-use anyhow::Result;
+use anyhow::{format_err, Result};
+use inferno::flamegraph::color::{Color, PaletteMap};
 use inferno::flamegraph::{Direction, Options};
 use std::collections::HashMap;
 use std::io::Write;
 
-use crate::core::types::StackFrame;
+use crate::core::types::{FlamegraphPalette, FrameCategory, Gem, StackFrame};
 
 // Simple counter that maps stacks to flamegraph collapsed format
 #[derive(Default)]
 pub struct Stats {
-    pub counts: HashMap<String, usize>,
+    pub counts: HashMap<String, u64>,
+    /// The category of each frame in `counts`, for the `category` palette
+    categories: HashMap<String, FrameCategory>,
+    /// The gem that each frame in `counts` was loaded from, for the frames' tooltips
+    gems: HashMap<String, Gem>,
+    /// How much of each stack's weight in `counts` was from samples whose thread was blocked,
+    /// for the `state` palette
+    off_cpu: HashMap<String, u64>,
+    /// What the weights in `counts` are, for the frames' tooltips (`samples` if not set)
+    count_name: Option<&'static str>,
 }
 
 impl Stats {
-    pub fn record(&mut self, stack: &[StackFrame]) -> Result<()> {
+    pub fn record(&mut self, stack: &[StackFrame], weight: u64) -> Result<()> {
+        self.record_stack(stack, weight);
+        Ok(())
+    }
+
+    /// Records a stack along with whether its thread was running on a CPU (see
+    /// `StackTrace::on_cpu`)
+    pub fn record_sample(&mut self, stack: &[StackFrame], weight: u64, on_cpu: Option<bool>) {
+        let frame = self.record_stack(stack, weight);
+        if on_cpu == Some(false) {
+            *self.off_cpu.entry(frame).or_insert(0) += weight;
+        }
+    }
+
+    /// Sets what the recorded weights are, e.g. `µs` (see `StackTrace::weight_unit`)
+    pub fn set_count_name(&mut self, count_name: &'static str) {
+        self.count_name = Some(count_name);
+    }
+
+    fn record_stack(&mut self, stack: &[StackFrame], weight: u64) -> String {
         let frame = stack
             .iter()
             .rev()
-            .map(|frame| format!("{}", frame))
+            .map(|frame| {
+                let name = format!("{}", frame);
+                if !self.categories.contains_key(&name) {
+                    self.categories.insert(name.clone(), frame.category());
+                    if let Some(gem) = frame.gem() {
+                        self.gems.insert(name.clone(), gem);
+                    }
+                }
+                name
+            })
             .collect::<Vec<String>>()
             .join(";");
 
-        *self.counts.entry(frame).or_insert(0) += 1;
-        Ok(())
+        *self.counts.entry(frame.clone()).or_insert(0) += weight;
+        frame
     }
 
-    pub fn write_flamegraph<W: Write>(&self, w: W, min_width: f64) -> Result<()> {
+    pub fn write_flamegraph<W: Write>(
+        &self,
+        mut w: W,
+        min_width: f64,
+        subtitle: Option<String>,
+        palette: FlamegraphPalette,
+    ) -> Result<()> {
         if self.is_empty() {
             eprintln!("Warning: no profile samples were collected");
-        } else {
-            let mut opts = Options::default();
-            opts.direction = Direction::Inverted;
-            opts.hash = true;
-            opts.min_width = min_width;
-            inferno::flamegraph::from_lines(
-                &mut opts,
-                self.get_lines().iter().map(|x| x.as_str()),
-                w,
-            )?;
+            return Ok(());
         }
+        let mut palette_map = PaletteMap::default();
+        let mut opts = Options::default();
+        opts.direction = Direction::Inverted;
+        opts.hash = true;
+        opts.min_width = min_width;
+        opts.subtitle = subtitle;
+        if let Some(count_name) = self.count_name {
+            opts.count_name = count_name.to_string();
+        }
+        let lines = match palette {
+            FlamegraphPalette::state => self.get_state_lines(),
+            _ => self.get_lines(),
+        };
+        let lines = lines.iter().map(|x| x.as_str());
+        let mut svg = Vec::new();
+        let legend = match palette {
+            FlamegraphPalette::hot => {
+                inferno::flamegraph::from_lines(&mut opts, lines, &mut svg)?;
+                None
+            }
+            FlamegraphPalette::category => {
+                for (frame, category) in &self.categories {
+                    palette_map.insert(frame, category_color(*category));
+                }
+                opts.palette_map = Some(&mut palette_map);
+                inferno::flamegraph::from_lines(&mut opts, lines, &mut svg)?;
+                let legend: Vec<(String, Color)> = FrameCategory::ALL
+                    .iter()
+                    .map(|category| (category.to_string(), category_color(*category)))
+                    .collect();
+                Some(legend)
+            }
+            FlamegraphPalette::state => {
+                palette_map.insert(StackFrame::off_cpu().to_string(), OFF_CPU_COLOR);
+                opts.palette_map = Some(&mut palette_map);
+                inferno::flamegraph::from_lines(&mut opts, lines, &mut svg)?;
+                Some(vec![("off-CPU".to_string(), OFF_CPU_COLOR)])
+            }
+        };
+        let svg = self.add_gem_tooltips(&svg)?;
+        match legend {
+            Some(legend) => write_with_legend(&svg, opts.font_size, &legend, w)?,
+            None => w.write_all(&svg)?,
+        }
+        Ok(())
+    }
+
+    /// Adds the gem that each frame was loaded from to its tooltip, e.g.
+    /// `call - rack.rb:10 (5 samples, 2.50%, gem rack-2.2.4)`. inferno only lets a frame's whole
+    /// tooltip be replaced, which would lose its counts, so they're added to the written SVG.
+    fn add_gem_tooltips(&self, svg: &[u8]) -> Result<Vec<u8>> {
+        if self.gems.is_empty() {
+            return Ok(svg.to_vec());
+        }
+        let svg = std::str::from_utf8(svg)?;
+        let mut annotated = String::with_capacity(svg.len());
+        let mut rest = svg;
+        while let Some(start) = rest.find("<title>") {
+            let start = start + "<title>".len();
+            let end = match rest[start..].find("</title>") {
+                Some(end) => start + end,
+                None => break,
+            };
+            // Titles are the frame's name followed by its counts in parentheses
+            let title = &rest[start..end];
+            let gem = title
+                .rfind(" (")
+                .filter(|_| title.ends_with(')'))
+                .and_then(|i| self.gems.get(&unescape_xml(&title[..i])));
+            annotated.push_str(&rest[..start]);
+            match gem {
+                Some(gem) => {
+                    annotated.push_str(&title[..title.len() - 1]);
+                    annotated.push_str(&escape_xml(&format!(", gem {})", gem)));
+                }
+                None => annotated.push_str(title),
+            }
+            rest = &rest[end..];
+        }
+        annotated.push_str(rest);
+        Ok(annotated.into_bytes())
+    }
+
+    /// Writes a differential flamegraph of this profile compared to `before`: this profile's
+    /// stacks, with each frame colored by whether it took more (red) or less (blue) of the time
+    /// than before. `before` is scaled to the same total weight first, so that profiles of
+    /// different lengths can be compared. Stacks that are only in `before` aren't shown.
+    pub fn write_differential<W: Write>(&self, before: &Stats, min_width: f64, w: W) -> Result<()> {
+        if self.is_empty() || before.is_empty() {
+            return Err(format_err!("Both profiles need samples to be compared"));
+        }
+        let collapsed = |stats: &Stats| {
+            let mut lines = stats.get_lines();
+            lines.sort_unstable();
+            lines.join("\n").into_bytes()
+        };
+        let mut differential = Vec::new();
+        inferno::differential::from_readers(
+            inferno::differential::Options {
+                normalize: true,
+                strip_hex: false,
+            },
+            &collapsed(before)[..],
+            &collapsed(self)[..],
+            &mut differential,
+        )?;
 
+        let mut opts = Options::default();
+        opts.direction = Direction::Inverted;
+        opts.min_width = min_width;
+        opts.title = "Differential Flame Graph".to_string();
+        opts.subtitle =
+            Some("Red frames took more of the time than before, blue frames less".to_string());
+        inferno::flamegraph::from_reader(&mut opts, &differential[..], w)?;
         Ok(())
     }
 
@@ -60,13 +208,118 @@ impl Stats {
             .collect()
     }
 
+    /// The collapsed stacks with the weight of blocked samples split off into stacks that end in
+    /// an `(off-CPU)` frame
+    fn get_state_lines(&self) -> Vec<String> {
+        let off_cpu_frame = StackFrame::off_cpu().to_string();
+        let mut lines = Vec::new();
+        for (frame, count) in &self.counts {
+            let off_cpu = self.off_cpu.get(frame).copied().unwrap_or(0);
+            if *count > off_cpu {
+                lines.push(format!("{} {}", frame, count - off_cpu));
+            }
+            if off_cpu > 0 {
+                lines.push(format!("{};{} {}", frame, off_cpu_frame, off_cpu));
+            }
+        }
+        lines
+    }
+
     fn is_empty(&self) -> bool {
         self.counts.is_empty()
     }
 }
 
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The color of the `(off-CPU)` frames in the `state` palette, which stands out from the warm
+/// colors of the other frames
+const OFF_CPU_COLOR: Color = Color {
+    r: 110,
+    g: 150,
+    b: 200,
+};
+
+fn category_color(category: FrameCategory) -> Color {
+    let (r, g, b) = match category {
+        FrameCategory::App => (225, 87, 89),
+        FrameCategory::Gem => (78, 121, 167),
+        FrameCategory::Stdlib => (89, 161, 79),
+        FrameCategory::Native => (186, 176, 172),
+    };
+    Color { r, g, b }
+}
+
+/// Writes a flamegraph with a legend of its palette's colors added to its bottom left corner,
+/// which inferno leaves empty for inverted flamegraphs
+fn write_with_legend<W: Write>(
+    svg: &[u8],
+    font_size: usize,
+    legend_colors: &[(String, Color)],
+    mut w: W,
+) -> Result<()> {
+    const HEIGHT: &[u8] = b"height=\"";
+    const END: &[u8] = b"</svg>";
+    let height: usize = svg
+        .windows(HEIGHT.len())
+        .position(|window| window == HEIGHT)
+        .and_then(|start| svg[start + HEIGHT.len()..].split(|&b| b == b'"').next())
+        .and_then(|height| std::str::from_utf8(height).ok())
+        .and_then(|height| height.parse().ok())
+        .ok_or_else(|| format_err!("Flamegraph has no height"))?;
+    let end = svg
+        .windows(END.len())
+        .rposition(|window| window == END)
+        .ok_or_else(|| format_err!("Flamegraph has no end tag"))?;
+
+    // Laid out on the same line as inferno's "matched" text, which is on the right
+    let y = height - (font_size + 10) / 2;
+    let mut legend = String::from("<g id=\"legend\">\n");
+    let mut x = 10;
+    for (label, color) in legend_colors {
+        legend.push_str(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"rgb({},{},{})\"/>\n",
+            x,
+            y - font_size + 2,
+            font_size,
+            font_size,
+            color.r,
+            color.g,
+            color.b
+        ));
+        legend.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\">{}</text>\n",
+            x + font_size + 4,
+            y,
+            label
+        ));
+        x += font_size * 6;
+    }
+    legend.push_str("</g>\n");
+
+    w.write_all(&svg[..end])?;
+    w.write_all(legend.as_bytes())?;
+    w.write_all(&svg[end..])?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::core::types::FrameKind;
     use crate::ui::flamegraph::*;
     use std::io::Cursor;
 
@@ -77,22 +330,23 @@ mod tests {
             relative_path: format!("file{}.rb", i),
             absolute_path: None,
             lineno: Some(i),
+            kind: FrameKind::Method,
         }
     }
 
     // Build test stats
     fn build_stats() -> Result<Stats> {
         let mut stats = Stats::default();
-        stats.record(&vec![f(1)])?;
-        stats.record(&vec![f(2), f(1)])?;
-        stats.record(&vec![f(2), f(1)])?;
-        stats.record(&vec![f(2), f(3), f(1)])?;
-        stats.record(&vec![f(2), f(3), f(1)])?;
-        stats.record(&vec![f(2), f(3), f(1)])?;
+        stats.record(&vec![f(1)], 1)?;
+        stats.record(&vec![f(2), f(1)], 1)?;
+        stats.record(&vec![f(2), f(1)], 1)?;
+        stats.record(&vec![f(2), f(3), f(1)], 1)?;
+        stats.record(&vec![f(2), f(3), f(1)], 1)?;
+        stats.record(&vec![f(2), f(3), f(1)], 1)?;
         Ok(stats)
     }
 
-    fn assert_contains(counts: &HashMap<String, usize>, s: &str, val: usize) {
+    fn assert_contains(counts: &HashMap<String, u64>, s: &str, val: u64) {
         assert_eq!(counts.get(&s.to_string()), Some(&val));
     }
 
@@ -126,6 +380,80 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_category_palette() -> Result<()> {
+        let mut stats = build_stats()?;
+        let mut gem = f(4);
+        gem.absolute_path = Some("/gems/3.1.0/gems/rack-2.2.4/lib/rack.rb".to_string());
+        stats.record(&[gem.clone(), f(1)], 1)?;
+        assert_eq!(stats.categories[&gem.to_string()], FrameCategory::Gem);
+        assert_eq!(stats.categories["func1 - file1.rb:1"], FrameCategory::App);
+
+        let mut svg = Vec::new();
+        stats.write_flamegraph(&mut svg, 0.1, None, FlamegraphPalette::category)?;
+        let svg = String::from_utf8(svg)?;
+        // Once for the gem's frame and once in the legend
+        assert_eq!(svg.matches("fill=\"rgb(78,121,167)\"").count(), 2);
+        assert!(svg.contains("<text x=\"26\" y=\"127\">app</text>"));
+        assert!(svg.trim_end().ends_with("</g>\n</svg>"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gem_tooltips() -> Result<()> {
+        let mut stats = build_stats()?;
+        let mut gem = f(4);
+        gem.name = "<main>".to_string();
+        gem.absolute_path = Some("/gems/3.1.0/gems/rack-2.2.4/lib/rack.rb".to_string());
+        stats.record(&[gem, f(1)], 1)?;
+
+        let mut svg = Vec::new();
+        stats.write_flamegraph(&mut svg, 0.1, None, FlamegraphPalette::hot)?;
+        let svg = String::from_utf8(svg)?;
+        assert!(svg.contains(
+            "<title>&lt;main&gt; - /gems/3.1.0/gems/rack-2.2.4/lib/rack.rb:4 \
+             (1 samples, 14.29%, gem rack-2.2.4)</title>"
+        ));
+        assert!(svg.contains("<title>func3 - file3.rb:3 (3 samples, 42.86%)</title>"));
+
+        stats.set_count_name("µs");
+        let mut svg = Vec::new();
+        stats.write_flamegraph(&mut svg, 0.1, None, FlamegraphPalette::hot)?;
+        let svg = String::from_utf8(svg)?;
+        assert!(svg.contains("<title>func3 - file3.rb:3 (3 µs, 42.86%)</title>"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_palette() -> Result<()> {
+        let mut stats = Stats::default();
+        stats.record_sample(&[f(2), f(1)], 3, Some(true));
+        stats.record_sample(&[f(2), f(1)], 1, Some(false));
+        stats.record_sample(&[f(3), f(1)], 2, Some(false));
+        stats.record_sample(&[f(1)], 1, None);
+        let mut lines = stats.get_state_lines();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "func1 - file1.rb:1 1",
+                "func1 - file1.rb:1;func2 - file2.rb:2 3",
+                "func1 - file1.rb:1;func2 - file2.rb:2;(off-CPU) - (unknown) 1",
+                "func1 - file1.rb:1;func3 - file3.rb:3;(off-CPU) - (unknown) 2",
+            ]
+        );
+        // Other palettes don't split the stacks
+        assert_eq!(stats.counts["func1 - file1.rb:1;func2 - file2.rb:2"], 4);
+
+        let mut svg = Vec::new();
+        stats.write_flamegraph(&mut svg, 0.1, None, FlamegraphPalette::state)?;
+        let svg = String::from_utf8(svg)?;
+        // Once for each off-CPU frame and once in the legend
+        assert_eq!(svg.matches("fill=\"rgb(110,150,200)\"").count(), 3);
+        assert!(svg.contains(">off-CPU</text>"));
+        Ok(())
+    }
+
     #[test]
     fn test_flamegraph_from_collapsed() -> Result<()> {
         let stats = build_stats()?;
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::core::process::Pid;
+use crate::core::types::{StackTrace, ThreadState};
+
+/// How many frames of a stack to show for the code that was running when a GC started
+const TRIGGER_FRAMES: usize = 3;
+
+/// Estimates how much time the profiled processes spent in GC, from the VM counters that are
+/// read with each trace when `vm_stats` is on. It's an estimate: GC pauses that start and end
+/// between two samples aren't seen, so the pause count is a lower bound, while the time in GC
+/// is as accurate as the rest of the profile.
+#[derive(Default)]
+pub struct Stats {
+    /// The weight of the traces that have VM counters
+    weight: u64,
+    gc_weight: u64,
+    first_time: Option<SystemTime>,
+    last_time: Option<SystemTime>,
+    processes: HashMap<Option<Pid>, Process>,
+    pauses: u64,
+    /// How many GC pauses started while each stack was running
+    triggers: HashMap<String, u64>,
+}
+
+#[derive(Default)]
+struct Process {
+    in_gc: bool,
+    /// Whether the stack that was running when the current GC started is yet to be seen
+    looking_for_trigger: bool,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    pub fn record(&mut self, trace: &StackTrace) {
+        let vm_stats = match trace.vm_stats {
+            Some(vm_stats) => vm_stats,
+            None => return,
+        };
+        let weight = trace.weight();
+        self.weight += weight;
+        if let Some(time) = trace.time {
+            self.first_time = Some(self.first_time.map_or(time, |first| first.min(time)));
+            self.last_time = Some(self.last_time.map_or(time, |last| last.max(time)));
+        }
+
+        let process = self.processes.entry(trace.pid).or_default();
+        if !vm_stats.during_gc {
+            process.in_gc = false;
+            process.looking_for_trigger = false;
+            return;
+        }
+        self.gc_weight += weight;
+        if !process.in_gc {
+            process.in_gc = true;
+            process.looking_for_trigger = true;
+            self.pauses += 1;
+        }
+        // The GC runs on the thread that holds the GVL, so the other threads' stacks have
+        // nothing to do with it
+        if process.looking_for_trigger && trace.thread_state != Some(ThreadState::Blocked) {
+            process.looking_for_trigger = false;
+            let stack: Vec<&str> = trace
+                .trace
+                .iter()
+                .take(TRIGGER_FRAMES)
+                .map(|frame| frame.name.as_str())
+                .collect();
+            *self.triggers.entry(stack.join(" < ")).or_insert(0) += 1;
+        }
+    }
+
+    /// Whether any of the traces had VM counters
+    pub fn is_empty(&self) -> bool {
+        self.weight == 0
+    }
+
+    /// Writes the estimates, with at most `top` of the stacks that started GC pauses
+    pub fn write(&self, w: &mut dyn io::Write, top: usize) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let fraction = self.gc_weight as f64 / self.weight as f64;
+        let elapsed = match (self.first_time, self.last_time) {
+            (Some(first), Some(last)) => last.duration_since(first).unwrap_or_default(),
+            _ => Default::default(),
+        };
+        writeln!(w, "Garbage collection (estimated):")?;
+        if elapsed.as_secs_f64() > 0.0 {
+            writeln!(
+                w,
+                "  Time in GC: {:.1}% of samples, about {:.2}s of {:.2}s",
+                100.0 * fraction,
+                fraction * elapsed.as_secs_f64(),
+                elapsed.as_secs_f64()
+            )?;
+            writeln!(
+                w,
+                "  GC pauses seen: {} ({:.1} per minute)",
+                self.pauses,
+                self.pauses as f64 / elapsed.as_secs_f64() * 60.0
+            )?;
+        } else {
+            writeln!(w, "  Time in GC: {:.1}% of samples", 100.0 * fraction)?;
+            writeln!(w, "  GC pauses seen: {}", self.pauses)?;
+        }
+        if self.triggers.is_empty() {
+            return Ok(());
+        }
+        writeln!(w, "  Stacks running when GC started:")?;
+        let mut triggers: Vec<(&String, &u64)> = self.triggers.iter().collect();
+        triggers.sort_unstable_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+        for (stack, count) in triggers.into_iter().take(top) {
+            writeln!(w, "  {:>6}  {}", count, stack)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{StackFrame, VmStats};
+    use std::time::Duration;
+
+    fn trace(ms: u64, during_gc: bool, state: ThreadState, names: &[&str]) -> StackTrace {
+        let mut trace = StackTrace::new_empty();
+        trace.pid = Some(1);
+        trace.time = Some(SystemTime::UNIX_EPOCH + Duration::from_millis(ms));
+        trace.thread_state = Some(state);
+        trace.vm_stats = Some(VmStats {
+            total_allocated_objects: ms,
+            during_gc,
+        });
+        trace.trace = names
+            .iter()
+            .map(|name| StackFrame {
+                name: name.to_string(),
+                ..StackFrame::unknown_c_function()
+            })
+            .collect();
+        trace
+    }
+
+    #[test]
+    fn test_gc_estimates() {
+        use ThreadState::{Blocked, Running};
+
+        let mut stats = Stats::new();
+        assert!(stats.is_empty());
+        let samples = [
+            (0, false, Running, &["render"][..]),
+            (1000, true, Blocked, &["sleep"][..]),
+            (1000, true, Running, &["new", "build", "call"][..]),
+            (2000, true, Running, &["new", "build", "call"][..]),
+            (3000, false, Running, &["render"][..]),
+            (4000, true, Running, &["dup", "render", "call"][..]),
+            (5000, true, Running, &["new", "build", "call", "run"][..]),
+            (6000, false, Running, &["render"][..]),
+        ];
+        for (ms, during_gc, state, names) in samples.iter() {
+            stats.record(&trace(*ms, *during_gc, *state, names));
+        }
+        // Traces without VM counters aren't counted
+        stats.record(&StackTrace::new_empty());
+
+        let mut output = Vec::new();
+        stats.write(&mut output, 10).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Garbage collection (estimated):\n  \
+             Time in GC: 62.5% of samples, about 3.75s of 6.00s\n  \
+             GC pauses seen: 2 (20.0 per minute)\n  \
+             Stacks running when GC started:\n  \
+             \x20    1  dup < render < call\n  \
+             \x20    1  new < build < call\n"
+        );
+    }
+}
@@ -1,6 +1,13 @@
 pub mod callgrind;
+pub mod chrometrace;
+pub mod firefox;
 pub mod flamegraph;
+pub mod gc;
 pub mod output;
 pub mod pprof;
+pub mod profile;
+pub mod progress;
+pub(crate) mod samples;
 pub mod speedscope;
 pub mod summary;
+pub mod timeline;
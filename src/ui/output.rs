@@ -1,28 +1,56 @@
+use std::borrow::Cow;
 use std::io::Write;
 
-use crate::core::types::{StackFrame, StackTrace};
-use crate::ui::{callgrind, flamegraph, pprof, speedscope, summary};
+use crate::core::types::{
+    Allocations, FlamegraphPalette, Granularity, PathMapping, ProfileMetadata, ProfileView,
+    StackFrame, StackTrace,
+};
+use crate::ui::{
+    callgrind, chrometrace, firefox, flamegraph, pprof, speedscope, summary, timeline,
+};
 
 use anyhow::Result;
 
 pub trait Outputter {
     fn record(&mut self, stack: &StackTrace) -> Result<()>;
     fn complete(&mut self, write: &mut dyn Write) -> Result<()>;
+    /// Describes what the profile was recorded from, for formats that can show it. Called before
+    /// `complete`.
+    fn set_metadata(&mut self, _metadata: &ProfileMetadata) {}
+    /// How to order, cut down and group the functions, for the summary formats. Called before
+    /// `record`, since functions are grouped as they're recorded.
+    fn set_summary_options(&mut self, _options: &summary::SummaryOptions) {}
+    /// How to color the frames, for the flamegraph format. Called before `complete`.
+    fn set_flamegraph_palette(&mut self, _palette: FlamegraphPalette) {}
 }
 
 // Uses Inferno to visualize stack traces
 pub struct Flamegraph {
     stats: flamegraph::Stats,
     min_width: f64,
+    subtitle: Option<String>,
+    palette: FlamegraphPalette,
 }
 
 impl Outputter for Flamegraph {
     fn record(&mut self, stack: &StackTrace) -> Result<()> {
-        self.stats.record(&stack.trace)
+        self.stats
+            .record_sample(&stack.trace, stack.weight(), stack.on_cpu());
+        self.stats.set_count_name(stack.weight_unit());
+        Ok(())
     }
 
     fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
-        self.stats.write_flamegraph(write, self.min_width)
+        self.stats
+            .write_flamegraph(write, self.min_width, self.subtitle.clone(), self.palette)
+    }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.subtitle = Some(metadata.describe()).filter(|subtitle| !subtitle.is_empty());
+    }
+
+    fn set_flamegraph_palette(&mut self, palette: FlamegraphPalette) {
+        self.palette = palette;
     }
 }
 
@@ -31,6 +59,8 @@ impl Flamegraph {
         Flamegraph {
             min_width,
             stats: Default::default(),
+            subtitle: None,
+            palette: Default::default(),
         }
     }
 }
@@ -42,7 +72,7 @@ pub struct Collapsed(pub flamegraph::Stats);
 
 impl Outputter for Collapsed {
     fn record(&mut self, stack: &StackTrace) -> Result<()> {
-        self.0.record(&stack.trace)
+        self.0.record(&stack.trace, stack.weight())
     }
 
     fn complete(&mut self, mut write: &mut dyn Write) -> Result<()> {
@@ -54,7 +84,11 @@ pub struct Callgrind(pub callgrind::Stats);
 
 impl Outputter for Callgrind {
     fn record(&mut self, stack: &StackTrace) -> Result<()> {
-        self.0.add(&filter_unknown(&stack.trace));
+        self.0.add_for_thread(
+            (stack.pid, stack.thread_address.or(stack.thread_id)),
+            &filter_unknown(&stack.trace),
+            stack.weight(),
+        );
         Ok(())
     }
 
@@ -64,29 +98,47 @@ impl Outputter for Callgrind {
     }
 }
 
-pub struct Summary(pub summary::Stats);
+pub struct Summary(pub summary::Stats, pub summary::SummaryOptions);
 
 impl Outputter for Summary {
     fn record(&mut self, stack: &StackTrace) -> Result<()> {
-        self.0.add_function_name(&filter_unknown(&stack.trace));
+        let trace = filter_unknown(&stack.trace);
+        match self.1.group_by {
+            Some(group) => self.0.add_grouped(&trace, stack.weight(), group),
+            None => self.0.add_function_name(&trace, stack.weight()),
+        }
+        self.0.add_vm_stats(stack);
         Ok(())
     }
 
     fn complete(&mut self, mut write: &mut dyn Write) -> Result<()> {
-        self.0.write(&mut write)
+        self.0.write_with_options(&mut write, &self.1, None)
+    }
+
+    fn set_summary_options(&mut self, options: &summary::SummaryOptions) {
+        self.1 = options.clone();
     }
 }
 
-pub struct SummaryLine(pub summary::Stats);
+pub struct SummaryLine(pub summary::Stats, pub summary::SummaryOptions);
 
 impl Outputter for SummaryLine {
     fn record(&mut self, stack: &StackTrace) -> Result<()> {
-        self.0.add_lineno(&filter_unknown(&stack.trace));
+        let trace = filter_unknown(&stack.trace);
+        match self.1.group_by {
+            Some(group) => self.0.add_grouped(&trace, stack.weight(), group),
+            None => self.0.add_lineno(&trace, stack.weight()),
+        }
+        self.0.add_vm_stats(stack);
         Ok(())
     }
 
     fn complete(&mut self, mut write: &mut dyn Write) -> Result<()> {
-        self.0.write(&mut write)
+        self.0.write_with_options(&mut write, &self.1, None)
+    }
+
+    fn set_summary_options(&mut self, options: &summary::SummaryOptions) {
+        self.1 = options.clone();
     }
 }
 
@@ -100,6 +152,73 @@ impl Outputter for Speedscope {
     fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
         self.0.write(write)
     }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.0.set_metadata(metadata);
+    }
+}
+
+pub struct Flamechart(pub speedscope::Flamechart);
+
+impl Outputter for Flamechart {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        self.0.record(stack)
+    }
+
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        self.0.write(write)
+    }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.0.set_metadata(metadata);
+    }
+}
+
+pub struct Timeline(pub timeline::Stats);
+
+impl Outputter for Timeline {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        self.0.record(stack)
+    }
+
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        self.0.write(write)
+    }
+}
+
+pub struct ChromeTrace(pub chrometrace::Stats);
+
+impl Outputter for ChromeTrace {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        self.0.record(stack)
+    }
+
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        self.0.write(write)
+    }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.0.set_description(metadata.describe());
+    }
+}
+
+pub struct Firefox(pub firefox::Stats);
+
+impl Outputter for Firefox {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        self.0.record(stack)
+    }
+
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        self.0.write(write)
+    }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        if let Some(sample_rate) = metadata.sample_rate {
+            self.0.set_sample_rate(sample_rate);
+        }
+        self.0.set_description(metadata.describe());
+    }
 }
 
 pub struct Pprof(pub pprof::Stats);
@@ -112,6 +231,153 @@ impl Outputter for Pprof {
     fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
         self.0.write(write)
     }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.0.set_metadata(metadata)
+    }
+}
+
+/// Brings stack traces to a common granularity before passing them on to another outputter, so
+/// that every format aggregates frames the same way.
+pub struct Granular {
+    inner: Box<dyn Outputter>,
+    granularity: Granularity,
+}
+
+impl Granular {
+    pub fn new(inner: Box<dyn Outputter>, granularity: Granularity) -> Granular {
+        Granular { inner, granularity }
+    }
+}
+
+impl Outputter for Granular {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        match self.granularity {
+            Granularity::line => self.inner.record(stack),
+            Granularity::function => {
+                let mut stack = stack.clone();
+                stack.strip_line_numbers();
+                self.inner.record(&stack)
+            }
+        }
+    }
+
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        self.inner.complete(write)
+    }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.inner.set_metadata(metadata)
+    }
+
+    fn set_summary_options(&mut self, options: &summary::SummaryOptions) {
+        self.inner.set_summary_options(options)
+    }
+
+    fn set_flamegraph_palette(&mut self, palette: FlamegraphPalette) {
+        self.inner.set_flamegraph_palette(palette)
+    }
+}
+
+/// Passes on only the traces that belong in a profile view, e.g. just the on-CPU samples from a
+/// wall-clock recording, with their frames' paths remapped. For the `alloc` view, traces are
+/// weighted by their process's allocations instead.
+pub struct Viewed<O = Box<dyn Outputter>> {
+    inner: O,
+    view: ProfileView,
+    granularity: Granularity,
+    path_map: Vec<PathMapping>,
+    allocations: Allocations,
+}
+
+impl<O: Outputter> Viewed<O> {
+    pub fn new(inner: O, view: ProfileView) -> Viewed<O> {
+        Viewed {
+            inner,
+            view,
+            granularity: Granularity::line,
+            path_map: Vec::new(),
+            allocations: Allocations::default(),
+        }
+    }
+
+    /// Rewrites the start of frames' paths with the first mapping that applies to each
+    pub fn with_path_map(mut self, path_map: Vec<PathMapping>) -> Viewed<O> {
+        self.path_map = path_map;
+        self
+    }
+
+    /// Brings traces to a granularity, for outputters that don't come from
+    /// `OutputFormat::outputter` (which are already `Granular`)
+    pub fn with_granularity(mut self, granularity: Granularity) -> Viewed<O> {
+        self.granularity = granularity;
+        self
+    }
+
+    /// The outputter that the traces were passed on to
+    pub fn into_inner(self) -> O {
+        self.inner
+    }
+}
+
+impl<O: Outputter> Outputter for Viewed<O> {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        if !self.view.includes(stack) {
+            return Ok(());
+        }
+        let mut stack = match self.view {
+            ProfileView::alloc => match self.allocations.weigh(stack) {
+                Some(stack) => Cow::Owned(stack),
+                None => return Ok(()),
+            },
+            _ => Cow::Borrowed(stack),
+        };
+        if self.granularity == Granularity::function {
+            stack.to_mut().strip_line_numbers();
+        }
+        if !self.path_map.is_empty() {
+            stack.to_mut().map_paths(&self.path_map);
+        }
+        self.inner.record(&stack)
+    }
+
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        self.inner.complete(write)
+    }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.inner.set_metadata(metadata)
+    }
+
+    fn set_summary_options(&mut self, options: &summary::SummaryOptions) {
+        self.inner.set_summary_options(options)
+    }
+
+    fn set_flamegraph_palette(&mut self, palette: FlamegraphPalette) {
+        self.inner.set_flamegraph_palette(palette)
+    }
+}
+
+impl Outputter for Box<dyn Outputter> {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        (**self).record(stack)
+    }
+
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        (**self).complete(write)
+    }
+
+    fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        (**self).set_metadata(metadata)
+    }
+
+    fn set_summary_options(&mut self, options: &summary::SummaryOptions) {
+        (**self).set_summary_options(options)
+    }
+
+    fn set_flamegraph_palette(&mut self, palette: FlamegraphPalette) {
+        (**self).set_flamegraph_palette(palette)
+    }
 }
 
 /// Filter out unknown functions from stack trace before reporting.
@@ -125,3 +391,162 @@ fn filter_unknown(trace: &[StackFrame]) -> Vec<StackFrame> {
         vec
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::core::types::{
+        FrameKind, Granularity, OutputFormat, ProfileMetadata, ProfileView, StackFrame, StackTrace,
+        SummarySort, ThreadState, VmStats,
+    };
+    use crate::ui::output::{Outputter, Viewed};
+    use crate::ui::summary::SummaryOptions;
+    use std::time::Duration;
+
+    fn trace(lineno: usize, thread_state: Option<ThreadState>) -> StackTrace {
+        StackTrace {
+            trace: vec![StackFrame {
+                name: "aaa".to_string(),
+                relative_path: "a.rb".to_string(),
+                absolute_path: None,
+                lineno: Some(lineno),
+                kind: FrameKind::Method,
+            }],
+            pid: None,
+            thread_id: None,
+            thread_address: None,
+            thread_name: None,
+            os_thread_id: None,
+            thread_state,
+            wait_channel: None,
+            time: None,
+            interval: None,
+            vm_stats: None,
+            labels: std::collections::BTreeMap::new(),
+            repeats: 0,
+            allocated: None,
+        }
+    }
+
+    // Records the traces and returns the collapsed output's lines, sorted
+    fn collapsed(mut outputter: Box<dyn Outputter>, traces: &[StackTrace]) -> Vec<String> {
+        for trace in traces {
+            outputter.record(trace).unwrap();
+        }
+        let mut output = Vec::new();
+        outputter.complete(&mut output).unwrap();
+        let mut lines: Vec<String> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_string())
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn test_granularity() {
+        let traces = [trace(1, None), trace(2, None)];
+        assert_eq!(
+            collapsed(
+                OutputFormat::collapsed.outputter_with_granularity(0.1, Granularity::function),
+                &traces
+            ),
+            vec!["aaa - a.rb 2"]
+        );
+        assert_eq!(
+            collapsed(OutputFormat::collapsed.outputter(0.1), &traces),
+            vec!["aaa - a.rb:1 1", "aaa - a.rb:2 1"]
+        );
+    }
+
+    #[test]
+    fn test_weighted_by_interval() {
+        let mut traces = [trace(1, None), trace(2, None), trace(2, None)];
+        traces[0].interval = Some(Duration::from_millis(30));
+        traces[1].interval = Some(Duration::from_millis(10));
+        traces[2].interval = Some(Duration::from_millis(5));
+        assert_eq!(
+            collapsed(OutputFormat::collapsed.outputter(0.1), &traces),
+            vec!["aaa - a.rb:1 30000", "aaa - a.rb:2 15000"]
+        );
+    }
+
+    #[test]
+    fn test_profile_views() {
+        let traces = [
+            trace(1, Some(ThreadState::Running)),
+            trace(2, Some(ThreadState::Blocked)),
+            trace(3, None),
+        ];
+        let viewed = |view| {
+            let outputter = OutputFormat::collapsed.outputter(0.1);
+            collapsed(Box::new(Viewed::new(outputter, view)), &traces)
+        };
+        assert_eq!(
+            viewed(ProfileView::wall),
+            vec!["aaa - a.rb:1 1", "aaa - a.rb:2 1", "aaa - a.rb:3 1"]
+        );
+        assert_eq!(viewed(ProfileView::on_cpu), vec!["aaa - a.rb:1 1"]);
+        assert_eq!(viewed(ProfileView::off_cpu), vec!["aaa - a.rb:2 1"]);
+        assert_eq!(viewed(ProfileView::alloc), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_allocation_view() {
+        let allocated = [(1, 100), (2, 130), (2, 130), (3, 200), (1, 210)];
+        let traces: Vec<StackTrace> = allocated
+            .iter()
+            .map(|&(lineno, total_allocated_objects)| {
+                let mut trace = trace(lineno, Some(ThreadState::Running));
+                trace.interval = Some(Duration::from_millis(10));
+                trace.vm_stats = Some(VmStats {
+                    total_allocated_objects,
+                    during_gc: false,
+                });
+                trace
+            })
+            .collect();
+        let outputter = OutputFormat::collapsed.outputter(0.1);
+        // The first sample only starts the count, and samples without allocations are left out
+        assert_eq!(
+            collapsed(
+                Box::new(Viewed::new(outputter, ProfileView::alloc)),
+                &traces
+            ),
+            vec!["aaa - a.rb:1 10", "aaa - a.rb:2 30", "aaa - a.rb:3 70"]
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut outputter = OutputFormat::speedscope.outputter(0.1);
+        outputter.set_metadata(&ProfileMetadata {
+            cmdline: Some("puma 6.0.0".to_string()),
+            sample_rate: Some(100),
+            ..Default::default()
+        });
+        let mut output = Vec::new();
+        outputter.record(&trace(1, None)).unwrap();
+        outputter.complete(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\"rbspy profile - puma 6.0.0 | 100 Hz\""));
+        let file: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(file["metadata"]["cmdline"], "puma 6.0.0");
+        assert_eq!(file["metadata"]["sample_rate"], 100);
+    }
+
+    #[test]
+    fn test_summary_options() {
+        let traces = [trace(2, None), trace(1, None), trace(2, None)];
+        let mut outputter = OutputFormat::summary_by_line.outputter(0.1);
+        outputter.set_summary_options(&SummaryOptions {
+            sort: SummarySort::name,
+            top: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(
+            collapsed(outputter, &traces),
+            vec![" 33.33    33.33  aaa - a.rb:1", "% self  % total  name"]
+        );
+    }
+}
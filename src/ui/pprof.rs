@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::io::prelude::*;
 use std::time::SystemTime;
 
-use crate::core::types::{StackFrame, StackTrace};
+use crate::core::types::{FrameKind, ProfileMetadata, StackFrame, StackTrace};
 
 use anyhow::Result;
 
@@ -14,34 +14,64 @@ pub mod pprofs {
 }
 use self::pprofs::{Function, Label, Line, Location, Profile, Sample, ValueType};
 
+/// Builds a profile in pprof's protobuf format (`profile.proto`), which `go tool pprof`, Parca,
+/// Pyroscope and Grafana can read. Each sample has two values: how many samples it stands for
+/// (`samples`/`count`) and the wall-clock time since the previous sample (`wall`/`nanoseconds`),
+/// which is the default.
 #[derive(Default)]
 pub struct Stats {
     profile: Profile,
     known_frames: HashMap<StackFrame, u64>,
+    /// The IDs of the functions, by name and file
+    known_functions: HashMap<(String, String), u64>,
+    /// The indexes of the strings in the string table
+    strings: HashMap<String, i64>,
     prev_time: Option<SystemTime>,
+    first_time: Option<SystemTime>,
+    last_time: Option<SystemTime>,
 }
 
 impl Stats {
     pub fn new() -> Stats {
-        Stats {
-            profile: Profile {
-                // string index 0 must point to "" according to the .proto spec, while "wall" and "nanoseconds" are for our sample_type field
-                string_table: vec![
-                    "".to_string(),
-                    "wall".to_string(),
-                    "nanoseconds".to_string(),
-                ],
-                sample_type: vec![ValueType { r#type: 1, unit: 2 }], // 1 and 2 are indexes from string_table
-                ..Profile::default()
-            },
-            ..Stats::default()
+        let mut stats = Stats::default();
+        // String index 0 must point to "" according to the .proto spec
+        stats.string_id("");
+        let samples = stats.value_type("samples", "count");
+        let wall = stats.value_type("wall", "nanoseconds");
+        stats.profile.default_sample_type = wall.r#type;
+        stats.profile.sample_type = vec![samples, wall.clone()];
+        stats.profile.period_type = Some(wall);
+        stats
+    }
+
+    /// Sets the sampling period from the sample rate, and describes the recording in the
+    /// profile's comments
+    pub fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        if let Some(rate) = metadata.sample_rate.filter(|&rate| rate > 0) {
+            self.profile.period = 1_000_000_000 / i64::from(rate);
+        }
+        let description = metadata.describe();
+        self.profile.comment = if description.is_empty() {
+            Vec::new()
+        } else {
+            vec![self.string_id(&description)]
+        };
+    }
+
+    fn value_type(&mut self, r#type: &str, unit: &str) -> ValueType {
+        ValueType {
+            r#type: self.string_id(r#type),
+            unit: self.string_id(unit),
         }
     }
 
     pub fn record(&mut self, stack: &StackTrace) -> Result<()> {
         let this_time = stack.time.unwrap_or_else(SystemTime::now);
-        let ns_since_last_sample = match self.prev_time {
-            Some(prev_time) => match this_time.duration_since(prev_time) {
+        let ns_since_last_sample = match (stack.interval, self.prev_time) {
+            // Prefer the interval measured by the sampler, which isn't thrown off by samples from
+            // several processes being interleaved
+            (Some(interval), _) => interval.as_nanos(),
+            (None, Some(prev_time)) => match this_time.duration_since(prev_time) {
                 Ok(duration) => duration.as_nanos(),
                 Err(e) => {
                     // It's possible that samples will arrive out of order, e.g. if we're sampling
@@ -50,17 +80,35 @@ impl Stats {
                     0
                 }
             },
-            None => 0,
+            (None, None) => 0,
         } as i64;
-        self.add_sample(stack, ns_since_last_sample);
+        match stack.allocated {
+            Some(allocated) => {
+                self.count_allocations();
+                self.add_sample(stack, allocated as i64);
+            }
+            None => self.add_sample(stack, ns_since_last_sample),
+        }
         self.prev_time = Some(this_time);
+        if let Some(time) = stack.time {
+            self.first_time = Some(self.first_time.map_or(time, |first| first.min(time)));
+            self.last_time = Some(self.last_time.map_or(time, |last| last.max(time)));
+        }
         Ok(())
     }
 
+    /// Makes the second value of each sample the number of objects allocated, for traces that
+    /// are weighted by allocations rather than time (see `StackTrace::allocated`)
+    fn count_allocations(&mut self) {
+        let objects = self.value_type("alloc_objects", "count");
+        self.profile.default_sample_type = objects.r#type;
+        self.profile.sample_type[1] = objects;
+    }
+
     fn add_sample(&mut self, stack: &StackTrace, sample_time: i64) {
         let s = Sample {
             location_id: self.location_ids(stack),
-            value: vec![sample_time],
+            value: vec![i64::from(1 + stack.repeats), sample_time],
             label: self.labels(stack),
         };
         self.profile.sample.push(s);
@@ -101,45 +149,36 @@ impl Stats {
     }
 
     fn get_or_create_function_id(&mut self, frame: &StackFrame) -> u64 {
-        let strings = &self.profile.string_table;
-        let mut functions = self.profile.function.iter();
-        if let Some(function) = functions.find(|f| {
-            frame.name == strings[f.name as usize]
-                && frame.relative_path == strings[f.filename as usize]
-        }) {
-            function.id
-        } else {
-            let functions = self.profile.function.iter();
-            let mapped_iter = functions.map(|f| f.id);
-            let max_map = mapped_iter.max();
-            let next_id = match max_map {
-                Some(id) => id + 1,
-                None => 1,
-            };
-            let f = self.new_function(next_id, frame);
-            self.profile.function.push(f);
-            next_id
+        let key = (frame.name.clone(), frame.relative_path.clone());
+        if let Some(id) = self.known_functions.get(&key) {
+            return *id;
         }
+        let next_id = self.profile.function.len() as u64 + 1;
+        let f = self.new_function(next_id, frame);
+        self.profile.function.push(f);
+        self.known_functions.insert(key, next_id);
+        next_id
     }
 
     fn new_function(&mut self, id: u64, frame: &StackFrame) -> Function {
+        let name = self.string_id(&frame.name);
         Function {
             id,
-            name: self.string_id(&frame.name),
+            name,
+            system_name: name,
             filename: self.string_id(&frame.relative_path),
             ..Function::default()
         }
     }
 
     fn string_id(&mut self, text: &str) -> i64 {
-        let strings = &mut self.profile.string_table;
-        if let Some(id) = strings.iter().position(|s| *s == *text) {
-            id as i64
-        } else {
-            let next_id = strings.len() as i64;
-            strings.push((*text).to_owned());
-            next_id
+        if let Some(id) = self.strings.get(text) {
+            return *id;
         }
+        let next_id = self.profile.string_table.len() as i64;
+        self.profile.string_table.push(text.to_owned());
+        self.strings.insert(text.to_owned(), next_id);
+        next_id
     }
 
     fn labels(&mut self, stack: &StackTrace) -> Vec<Label> {
@@ -158,10 +197,25 @@ impl Stats {
                 ..Label::default()
             });
         }
+        for (key, value) in &stack.labels {
+            labels.push(Label {
+                key: self.string_id(key),
+                str: self.string_id(value),
+                ..Label::default()
+            });
+        }
         labels
     }
 
     pub fn write(&mut self, w: &mut dyn Write) -> Result<()> {
+        if let (Some(first), Some(last)) = (self.first_time, self.last_time) {
+            let since_epoch = first
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            self.profile.time_nanos = since_epoch.as_nanos() as i64;
+            self.profile.duration_nanos =
+                last.duration_since(first).unwrap_or_default().as_nanos() as i64;
+        }
         let mut pprof_data = Vec::new();
         let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
 
@@ -173,8 +227,120 @@ impl Stats {
     }
 }
 
+/// Reads the samples from a pprof profile (gzipped or not) as stack traces. Samples are weighted
+/// by the first sample type that measures time, or by the default sample type if none does.
+pub(crate) fn read_traces(r: &mut dyn Read) -> Result<Vec<StackTrace>> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    if data.starts_with(&[0x1f, 0x8b]) {
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+        data = decompressed;
+    }
+    let profile = Profile::decode(data.as_slice())?;
+    let string = |id: i64| {
+        profile
+            .string_table
+            .get(id as usize)
+            .map_or("", |s| s.as_str())
+    };
+
+    let units: Vec<&str> = profile.sample_type.iter().map(|t| string(t.unit)).collect();
+    let value_index = units
+        .iter()
+        .position(|unit| time_unit(unit).is_some())
+        .or_else(|| {
+            profile
+                .sample_type
+                .iter()
+                .position(|t| t.r#type == profile.default_sample_type)
+        })
+        .unwrap_or(0);
+    let to_interval = |value: i64| {
+        let value = value.max(0) as u64;
+        match units.get(value_index).and_then(|unit| time_unit(unit)) {
+            Some(nanos_per_unit) => std::time::Duration::from_nanos(value * nanos_per_unit),
+            // Traces with an interval are weighted by its length in microseconds
+            None => std::time::Duration::from_micros(value),
+        }
+    };
+
+    let functions: HashMap<u64, &Function> = profile.function.iter().map(|f| (f.id, f)).collect();
+    let locations: HashMap<u64, Vec<StackFrame>> = profile
+        .location
+        .iter()
+        .map(|location| {
+            let mut frames: Vec<StackFrame> = location
+                .line
+                .iter()
+                .map(|line| {
+                    let function = functions.get(&line.function_id);
+                    let file = function.map_or("", |f| string(f.filename));
+                    StackFrame {
+                        name: function.map_or("", |f| string(f.name)).to_string(),
+                        relative_path: match file {
+                            "" => "(unknown)".to_string(),
+                            file => file.to_string(),
+                        },
+                        absolute_path: None,
+                        lineno: Some(line.line as usize).filter(|&line| line > 0),
+                        kind: FrameKind::Unknown,
+                    }
+                })
+                .collect();
+            if frames.is_empty() {
+                frames.push(StackFrame {
+                    name: format!("{:#x}", location.address),
+                    ..StackFrame::unknown_c_function()
+                });
+            }
+            (location.id, frames)
+        })
+        .collect();
+
+    profile
+        .sample
+        .iter()
+        .map(|sample| {
+            let mut trace = StackTrace::new_empty();
+            for id in &sample.location_id {
+                let frames = locations
+                    .get(id)
+                    .ok_or_else(|| anyhow::format_err!("Sample has unknown location {}", id))?;
+                trace.trace.extend(frames.iter().cloned());
+            }
+            trace.interval = Some(to_interval(
+                sample.value.get(value_index).copied().unwrap_or(0),
+            ));
+            for label in &sample.label {
+                match (string(label.key), string(label.str)) {
+                    ("pid", "") => trace.pid = Some(label.num as crate::core::process::Pid),
+                    ("thread_id", "") => trace.thread_id = Some(label.num as usize),
+                    (_, "") => {}
+                    (key, value) => {
+                        trace.labels.insert(key.to_string(), value.to_string());
+                    }
+                }
+            }
+            Ok(trace)
+        })
+        .collect()
+}
+
+/// The number of nanoseconds in a pprof time unit
+fn time_unit(unit: &str) -> Option<u64> {
+    match unit {
+        "nanoseconds" => Some(1),
+        "microseconds" => Some(1_000),
+        "milliseconds" => Some(1_000_000),
+        "seconds" => Some(1_000_000_000),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use crate::core::types::FrameKind;
     use crate::ui::pprof::*;
     use flate2::read::GzDecoder;
     use std::time::Duration;
@@ -185,7 +351,17 @@ mod test {
             trace: frames,
             pid: Some(9),
             thread_id: Some(999),
+            thread_address: None,
+            thread_name: None,
+            os_thread_id: None,
+            thread_state: None,
+            wait_channel: None,
             time: Some(time),
+            interval: None,
+            vm_stats: None,
+            labels: std::collections::BTreeMap::new(),
+            repeats: 0,
+            allocated: None,
         }
     }
 
@@ -196,6 +372,7 @@ mod test {
             relative_path: format!("file{}.rb", i),
             absolute_path: None,
             lineno: Some(i),
+            kind: FrameKind::Method,
         }
     }
 
@@ -206,6 +383,7 @@ mod test {
             relative_path: "file1.rb".to_owned(),
             absolute_path: None,
             lineno: Some(42),
+            kind: FrameKind::Method,
         }
     }
 
@@ -248,20 +426,23 @@ mod test {
 
         let actual = pprofs::Profile::decode(&*stats_buf).expect("decode failed");
         let expected = Profile {
-            sample_type: vec![ValueType { r#type: 1, unit: 2 }],
+            sample_type: vec![
+                ValueType { r#type: 1, unit: 2 },
+                ValueType { r#type: 3, unit: 4 },
+            ],
             sample: vec![
                 Sample {
                     location_id: vec![1],
-                    value: vec![0],
+                    value: vec![1, 0],
                     label: vec![
                         Label {
-                            key: 5,
+                            key: 7,
                             str: 0,
                             num: 9,
                             num_unit: 0,
                         },
                         Label {
-                            key: 6,
+                            key: 8,
                             str: 0,
                             num: 999,
                             num_unit: 0,
@@ -270,16 +451,16 @@ mod test {
                 },
                 Sample {
                     location_id: vec![2, 3, 1],
-                    value: vec![200],
+                    value: vec![1, 200],
                     label: vec![
                         Label {
-                            key: 5,
+                            key: 7,
                             str: 0,
                             num: 9,
                             num_unit: 0,
                         },
                         Label {
-                            key: 6,
+                            key: 8,
                             str: 0,
                             num: 999,
                             num_unit: 0,
@@ -288,16 +469,16 @@ mod test {
                 },
                 Sample {
                     location_id: vec![3, 1],
-                    value: vec![400],
+                    value: vec![1, 400],
                     label: vec![
                         Label {
-                            key: 5,
+                            key: 7,
                             str: 0,
                             num: 9,
                             num_unit: 0,
                         },
                         Label {
-                            key: 6,
+                            key: 8,
                             str: 0,
                             num: 999,
                             num_unit: 0,
@@ -306,16 +487,16 @@ mod test {
                 },
                 Sample {
                     location_id: vec![2, 1],
-                    value: vec![600],
+                    value: vec![1, 600],
                     label: vec![
                         Label {
-                            key: 5,
+                            key: 7,
                             str: 0,
                             num: 9,
                             num_unit: 0,
                         },
                         Label {
-                            key: 6,
+                            key: 8,
                             str: 0,
                             num: 999,
                             num_unit: 0,
@@ -324,16 +505,16 @@ mod test {
                 },
                 Sample {
                     location_id: vec![3, 1],
-                    value: vec![800],
+                    value: vec![1, 800],
                     label: vec![
                         Label {
-                            key: 5,
+                            key: 7,
                             str: 0,
                             num: 9,
                             num_unit: 0,
                         },
                         Label {
-                            key: 6,
+                            key: 8,
                             str: 0,
                             num: 999,
                             num_unit: 0,
@@ -342,16 +523,16 @@ mod test {
                 },
                 Sample {
                     location_id: vec![2, 4, 1],
-                    value: vec![1000],
+                    value: vec![1, 1000],
                     label: vec![
                         Label {
-                            key: 5,
+                            key: 7,
                             str: 0,
                             num: 9,
                             num_unit: 0,
                         },
                         Label {
-                            key: 6,
+                            key: 8,
                             str: 0,
                             num: 999,
                             num_unit: 0,
@@ -405,35 +586,37 @@ mod test {
             function: vec![
                 Function {
                     id: 1,
-                    name: 3,
-                    system_name: 0,
-                    filename: 4,
+                    name: 5,
+                    system_name: 5,
+                    filename: 6,
                     start_line: 0,
                 },
                 Function {
                     id: 2,
-                    name: 7,
-                    system_name: 0,
-                    filename: 8,
+                    name: 9,
+                    system_name: 9,
+                    filename: 10,
                     start_line: 0,
                 },
                 Function {
                     id: 3,
-                    name: 9,
-                    system_name: 0,
-                    filename: 10,
+                    name: 11,
+                    system_name: 11,
+                    filename: 12,
                     start_line: 0,
                 },
                 Function {
                     id: 4,
-                    name: 11,
-                    system_name: 0,
-                    filename: 4,
+                    name: 13,
+                    system_name: 13,
+                    filename: 6,
                     start_line: 0,
                 },
             ],
             string_table: vec![
                 "".to_string(),
+                "samples".to_string(),
+                "count".to_string(),
                 "wall".to_string(),
                 "nanoseconds".to_string(),
                 "func1".to_string(),
@@ -448,13 +631,79 @@ mod test {
             ],
             drop_frames: 0,
             keep_frames: 0,
-            time_nanos: 0,
-            duration_nanos: 0,
-            period_type: None,
+            time_nanos: actual.time_nanos,
+            duration_nanos: 3000,
+            period_type: Some(ValueType { r#type: 3, unit: 4 }),
             period: 0,
             comment: vec![],
-            default_sample_type: 0,
+            default_sample_type: 3,
         };
         assert_eq!(actual, expected, "stats don't match");
     }
+
+    #[test]
+    fn includes_trace_labels() {
+        let mut stats = Stats::new();
+        let mut trace = s(vec![f(1)], SystemTime::now());
+        trace
+            .labels
+            .insert("deploy".to_string(), "abc123".to_string());
+        stats.record(&trace).unwrap();
+
+        let label = &stats.profile.sample[0].label[2];
+        let strings = &stats.profile.string_table;
+        assert_eq!(strings[label.key as usize], "deploy");
+        assert_eq!(strings[label.str as usize], "abc123");
+    }
+
+    #[test]
+    fn includes_metadata() {
+        let mut stats = test_stats();
+        stats.set_metadata(&ProfileMetadata {
+            cmdline: Some("puma 6.0.0".to_string()),
+            sample_rate: Some(100),
+            ..Default::default()
+        });
+        assert_eq!(stats.profile.period, 10_000_000);
+        let comments: Vec<&str> = stats
+            .profile
+            .comment
+            .iter()
+            .map(|&id| stats.profile.string_table[id as usize].as_str())
+            .collect();
+        assert_eq!(comments, vec!["puma 6.0.0 | 100 Hz"]);
+    }
+
+    #[test]
+    fn can_read_traces_from_pprof_format() {
+        let mut data = Vec::new();
+        test_stats().write(&mut data).unwrap();
+        let traces = read_traces(&mut data.as_slice()).unwrap();
+        let stacks: Vec<Vec<(String, Option<usize>)>> = traces
+            .iter()
+            .map(|trace| {
+                trace
+                    .trace
+                    .iter()
+                    .map(|frame| (frame.name.clone(), frame.lineno))
+                    .collect()
+            })
+            .collect();
+        let frame = |name: &str, lineno| (name.to_string(), Some(lineno));
+        assert_eq!(
+            stacks,
+            vec![
+                vec![frame("func1", 1)],
+                vec![frame("func3", 3), frame("func2", 2), frame("func1", 1)],
+                vec![frame("func2", 2), frame("func1", 1)],
+                vec![frame("func3", 3), frame("func1", 1)],
+                vec![frame("func2", 2), frame("func1", 1)],
+                vec![frame("func3", 3), frame("funcX", 42), frame("func1", 1)],
+            ]
+        );
+        assert_eq!(traces[1].interval, Some(Duration::from_nanos(200)));
+        assert_eq!(traces[1].pid, Some(9));
+        assert_eq!(traces[1].thread_id, Some(999));
+        assert_eq!(traces[1].trace[0].relative_path, "file3.rb");
+    }
 }
@@ -0,0 +1,262 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use crate::core::types::{StackFrame, StackTrace};
+use crate::ui::flamegraph;
+use crate::ui::output::Outputter;
+
+/// Stack traces added up by stack, which can be merged with or compared to other profiles
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// The total weight of each distinct stack, with the innermost frame first
+    pub stacks: BTreeMap<Vec<StackFrame>, u64>,
+}
+
+/// How much of a profile was spent in a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameWeight {
+    /// The weight of the stacks where the frame was running
+    pub self_weight: u64,
+    /// The weight of the stacks where the frame was anywhere on the stack
+    pub total_weight: u64,
+}
+
+/// How a frame's weight changed between two profiles
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameDelta {
+    pub frame: StackFrame,
+    pub before: FrameWeight,
+    pub after: FrameWeight,
+}
+
+impl FrameDelta {
+    pub fn self_delta(&self) -> i64 {
+        self.after.self_weight as i64 - self.before.self_weight as i64
+    }
+
+    pub fn total_delta(&self) -> i64 {
+        self.after.total_weight as i64 - self.before.total_weight as i64
+    }
+}
+
+/// The differences between two profiles, frame by frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProfileDiff {
+    pub before_weight: u64,
+    pub after_weight: u64,
+    /// Every frame in either profile, with the biggest change in total weight first
+    pub frames: Vec<FrameDelta>,
+}
+
+impl ProfileDiff {
+    /// How much the frame's share of self time changed, in percentage points. Unlike the raw
+    /// weights, shares can be compared between profiles of different lengths.
+    pub fn self_percent_delta(&self, delta: &FrameDelta) -> f64 {
+        percent(delta.after.self_weight, self.after_weight)
+            - percent(delta.before.self_weight, self.before_weight)
+    }
+
+    /// How much the frame's share of total time changed, in percentage points
+    pub fn total_percent_delta(&self, delta: &FrameDelta) -> f64 {
+        percent(delta.after.total_weight, self.after_weight)
+            - percent(delta.before.total_weight, self.before_weight)
+    }
+}
+
+fn percent(weight: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        _ => 100.0 * weight as f64 / total as f64,
+    }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Default::default()
+    }
+
+    /// Adds a stack, with its innermost frame first
+    pub fn add(&mut self, stack: &[StackFrame], weight: u64) {
+        *self.stacks.entry(stack.to_vec()).or_insert(0) += weight;
+    }
+
+    pub fn total_weight(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// The self and total weight of each frame. Frames that appear more than once in a stack
+    /// (i.e. recursive calls) only count towards their total weight once.
+    pub fn frame_weights(&self) -> BTreeMap<StackFrame, FrameWeight> {
+        let mut weights: BTreeMap<StackFrame, FrameWeight> = BTreeMap::new();
+        for (stack, &weight) in &self.stacks {
+            if let Some(frame) = stack.first() {
+                weights.entry(frame.clone()).or_default().self_weight += weight;
+            }
+            let frames: BTreeSet<&StackFrame> = stack.iter().collect();
+            for frame in frames {
+                weights.entry(frame.clone()).or_default().total_weight += weight;
+            }
+        }
+        weights
+    }
+
+    /// Adds another profile's stacks to this one
+    pub fn merge(&mut self, other: &Profile) {
+        for (stack, &weight) in &other.stacks {
+            self.add(stack, weight);
+        }
+    }
+
+    fn flamegraph_stats(&self) -> Result<flamegraph::Stats> {
+        let mut stats = flamegraph::Stats::default();
+        for (stack, &weight) in &self.stacks {
+            stats.record(stack, weight)?;
+        }
+        Ok(stats)
+    }
+}
+
+impl Outputter for Profile {
+    fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        self.add(&stack.trace, stack.weight());
+        Ok(())
+    }
+
+    /// Writes the profile in the collapsed format
+    fn complete(&mut self, write: &mut dyn Write) -> Result<()> {
+        for (stack, weight) in &self.stacks {
+            let frames: Vec<String> = stack.iter().rev().map(|frame| frame.to_string()).collect();
+            writeln!(write, "{} {}", frames.join(";"), weight)?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds two profiles together, e.g. the profiles of several processes or recordings
+pub fn merge(a: &Profile, b: &Profile) -> Profile {
+    let mut merged = a.clone();
+    merged.merge(b);
+    merged
+}
+
+/// Compares two profiles frame by frame, e.g. to fail a CI job when a change makes a function
+/// take a bigger share of the time
+pub fn diff(before: &Profile, after: &Profile) -> ProfileDiff {
+    let before_weights = before.frame_weights();
+    let mut after_weights = after.frame_weights();
+    let mut frames: Vec<FrameDelta> = before_weights
+        .into_iter()
+        .map(|(frame, before)| FrameDelta {
+            after: after_weights.remove(&frame).unwrap_or_default(),
+            frame,
+            before,
+        })
+        .collect();
+    frames.extend(after_weights.into_iter().map(|(frame, after)| FrameDelta {
+        frame,
+        before: FrameWeight::default(),
+        after,
+    }));
+    frames.sort_by(|a, b| {
+        (b.total_delta().abs(), b.self_delta().abs(), &a.frame).cmp(&(
+            a.total_delta().abs(),
+            a.self_delta().abs(),
+            &b.frame,
+        ))
+    });
+    ProfileDiff {
+        before_weight: before.total_weight(),
+        after_weight: after.total_weight(),
+        frames,
+    }
+}
+
+/// Writes a differential flamegraph, which shows the stacks of `after` with each frame colored
+/// by whether it takes a bigger (red) or smaller (blue) share of the time than in `before`, e.g.
+/// to check that an optimization moved time out of a hot path
+pub fn diff_flamegraph(before: &Profile, after: &Profile, w: &mut dyn Write) -> Result<()> {
+    after
+        .flamegraph_stats()?
+        .write_differential(&before.flamegraph_stats()?, 0.1, w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::FrameKind;
+
+    fn frame(name: &str) -> StackFrame {
+        StackFrame {
+            name: name.to_string(),
+            relative_path: "a.rb".to_string(),
+            absolute_path: None,
+            lineno: None,
+            kind: FrameKind::Method,
+        }
+    }
+
+    fn profile(stacks: &[(&[&str], u64)]) -> Profile {
+        let mut profile = Profile::new();
+        for (stack, weight) in stacks {
+            let stack: Vec<StackFrame> = stack.iter().map(|name| frame(name)).collect();
+            profile.add(&stack, *weight);
+        }
+        profile
+    }
+
+    #[test]
+    fn test_merge() {
+        let a = profile(&[(&["b", "a"], 2), (&["a"], 1)]);
+        let b = profile(&[(&["b", "a"], 3), (&["c", "a"], 4)]);
+        assert_eq!(
+            merge(&a, &b),
+            profile(&[(&["b", "a"], 5), (&["a"], 1), (&["c", "a"], 4)])
+        );
+
+        let mut output = Vec::new();
+        merge(&a, &b).complete(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "a - a.rb 1\na - a.rb;b - a.rb 5\na - a.rb;c - a.rb 4\n"
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = profile(&[(&["b", "a"], 2), (&["a", "a"], 2)]);
+        let after = profile(&[(&["b", "a"], 1), (&["c", "a"], 7)]);
+        let diff = diff(&before, &after);
+        assert_eq!((diff.before_weight, diff.after_weight), (4, 8));
+
+        let deltas: Vec<(&str, i64, i64)> = diff
+            .frames
+            .iter()
+            .map(|delta| {
+                (
+                    delta.frame.name.as_str(),
+                    delta.self_delta(),
+                    delta.total_delta(),
+                )
+            })
+            .collect();
+        assert_eq!(deltas, vec![("c", 7, 7), ("a", -2, 4), ("b", -1, -1)]);
+        assert_eq!(diff.self_percent_delta(&diff.frames[0]), 87.5);
+        assert_eq!(diff.total_percent_delta(&diff.frames[2]), -37.5);
+    }
+
+    #[test]
+    fn test_diff_flamegraph() {
+        let before = profile(&[(&["b", "a"], 2), (&["c", "a"], 2)]);
+        let after = profile(&[(&["b", "a"], 1), (&["c", "a"], 7)]);
+        let mut output = Vec::new();
+        diff_flamegraph(&before, &after, &mut output).unwrap();
+        let svg = String::from_utf8(output).unwrap();
+        assert!(svg.contains("Differential Flame Graph"));
+        // Frames are labelled with the change in their share of the samples
+        assert!(svg.contains("b - a.rb (1 samples, 12.50%; -37.50%)"));
+        assert!(svg.contains("c - a.rb (7 samples, 87.50%; +37.50%)"));
+
+        assert!(diff_flamegraph(&Profile::new(), &after, &mut Vec::new()).is_err());
+    }
+}
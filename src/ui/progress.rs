@@ -0,0 +1,101 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// How often the progress line is redrawn
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A single line on standard error that's redrawn in place while recording, showing how long the
+/// recording has run, how many samples were collected and how many of them failed
+pub struct Progress {
+    duration: Option<Duration>,
+    start: Instant,
+    last_update: (Instant, usize),
+    drawn: bool,
+}
+
+impl Progress {
+    /// `duration` is how long the recording will run for, if that's known
+    pub fn new(duration: Option<Duration>) -> Progress {
+        let start = Instant::now();
+        Progress {
+            duration,
+            start,
+            last_update: (start, 0),
+            drawn: false,
+        }
+    }
+
+    /// Redraws the progress line, unless it was drawn recently or standard error isn't a terminal
+    pub fn update(&mut self, samples: usize, errors: usize) {
+        let now = Instant::now();
+        let (last_time, last_samples) = self.last_update;
+        if now - last_time < UPDATE_INTERVAL || term_size::dimensions_stderr().is_none() {
+            return;
+        }
+        let rate = samples.saturating_sub(last_samples) as f64 / (now - last_time).as_secs_f64();
+        self.last_update = (now, samples);
+
+        let elapsed = now - self.start;
+        let remaining = self
+            .duration
+            .map(|duration| duration.saturating_sub(elapsed));
+        let line = format_progress(elapsed, remaining, samples, errors, rate);
+        let mut stderr = std::io::stderr();
+        // Clear the rest of the line in case the last one was longer
+        let _ = write!(stderr, "\r{}\x1b[K", line);
+        let _ = stderr.flush();
+        self.drawn = true;
+    }
+
+    /// Moves past the progress line, so that later output doesn't overwrite it
+    pub fn finish(&self) {
+        if self.drawn {
+            eprintln!();
+        }
+    }
+}
+
+fn format_progress(
+    elapsed: Duration,
+    remaining: Option<Duration>,
+    samples: usize,
+    errors: usize,
+    rate: f64,
+) -> String {
+    let mut line = format!("{}s elapsed", elapsed.as_secs());
+    if let Some(remaining) = remaining {
+        line.push_str(&format!(", {}s remaining", remaining.as_secs()));
+    }
+    let error_percent = match samples {
+        0 => 0.0,
+        _ => errors as f64 / samples as f64 * 100.0,
+    };
+    line.push_str(&format!(
+        " | {} samples | {:.0}/s | {:.1}% errors",
+        samples, rate, error_percent
+    ));
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_progress() {
+        assert_eq!(
+            format_progress(
+                Duration::from_millis(12_500),
+                Some(Duration::from_secs(47)),
+                1250,
+                5,
+                99.6
+            ),
+            "12s elapsed, 47s remaining | 1250 samples | 100/s | 0.4% errors"
+        );
+        assert_eq!(
+            format_progress(Duration::from_secs(0), None, 0, 0, 0.0),
+            "0s elapsed | 0 samples | 0/s | 0.0% errors"
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::ops::AddAssign;
+
+use crate::core::types::StackFrame;
+
+/// Where samples without a time go, one after another, for the formats that lay samples out
+/// along a time axis
+#[derive(Default)]
+pub(crate) struct UntimedClock<T> {
+    next: T,
+}
+
+impl<T: Copy + AddAssign> UntimedClock<T> {
+    /// The time of a sample that lasts `duration`, right after the previous one
+    pub fn tick(&mut self, duration: T) -> T {
+        let at = self.next;
+        self.next += duration;
+        at
+    }
+}
+
+/// The frames of all samples, numbered in the order they were first seen, so that samples can
+/// refer to them by index
+pub(crate) struct FrameTable<F> {
+    frames: Vec<F>,
+    frame_to_index: HashMap<StackFrame, usize>,
+}
+
+impl<F> Default for FrameTable<F> {
+    fn default() -> FrameTable<F> {
+        FrameTable {
+            frames: Vec::new(),
+            frame_to_index: HashMap::new(),
+        }
+    }
+}
+
+impl<F> FrameTable<F> {
+    /// The indices of a trace's frames, outermost first. Frames that weren't seen before are
+    /// added with `new`.
+    pub fn indices(&mut self, trace: &[StackFrame], new: impl Fn(&StackFrame) -> F) -> Vec<usize> {
+        let frames = &mut self.frames;
+        let frame_to_index = &mut self.frame_to_index;
+        trace
+            .iter()
+            .rev()
+            .map(|frame| {
+                *frame_to_index.entry(frame.clone()).or_insert_with(|| {
+                    frames.push(new(frame));
+                    frames.len() - 1
+                })
+            })
+            .collect()
+    }
+
+    pub fn frames(&self) -> &[F] {
+        &self.frames
+    }
+}
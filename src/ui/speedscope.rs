@@ -1,11 +1,12 @@
 use std::collections::HashMap;
-use std::io::Write;
-use std::time::SystemTime;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime};
 
 use crate::core::process::Pid;
-use crate::core::types::{StackFrame, StackTrace};
+use crate::core::types::{FrameKind, ProfileMetadata, StackFrame, StackTrace};
+use crate::ui::samples::{FrameTable, UntimedClock};
 
-use anyhow::Result;
+use anyhow::{format_err, Result};
 
 /*
  * This file contains code to export rbspy profiles for use in https://speedscope.app
@@ -22,12 +23,13 @@ use anyhow::Result;
  *
  * There are multiple variants of the file format. The variant we're going to generate
  * is the "type: sampled" profile, since it most closely maps to rbspy's data recording
- * structure.
+ * structure. Flame charts are generated as "type: evented" profiles instead, with the frames
+ * of each thread's samples opening and closing at the times the samples were taken.
  */
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SpeedscopeFile {
-    #[serde(rename = "$schema")]
+    #[serde(rename = "$schema", default)]
     schema: String,
     profiles: Vec<Profile>,
     shared: Shared,
@@ -38,6 +40,11 @@ struct SpeedscopeFile {
     exporter: Option<String>,
 
     name: Option<String>,
+
+    /// What the profile was recorded from. Not part of speedscope's format, which ignores it, but
+    /// it keeps the file self-describing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<ProfileMetadata>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,8 +61,31 @@ struct Profile {
     #[serde(rename = "endValue")]
     end_value: f64,
 
+    // Sampled profiles only
+    #[serde(default)]
     samples: Vec<Vec<usize>>,
+    #[serde(default)]
     weights: Vec<f64>,
+
+    // Evented profiles only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    event_type: EventType,
+    at: f64,
+    frame: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum EventType {
+    #[serde(rename = "O")]
+    OpenFrame,
+    #[serde(rename = "C")]
+    CloseFrame,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -95,48 +125,6 @@ enum ValueUnit {
     Seconds,
 }
 
-impl SpeedscopeFile {
-    pub fn new(
-        samples: HashMap<Option<Pid>, Vec<Vec<usize>>>,
-        frames: Vec<Frame>,
-        weights: Vec<f64>,
-    ) -> SpeedscopeFile {
-        let end_value = samples.len();
-
-        SpeedscopeFile {
-            // This is always the same
-            schema: "https://www.speedscope.app/file-format-schema.json".to_string(),
-
-            active_profile_index: None,
-
-            name: Some("rbspy profile".to_string()),
-
-            exporter: Some(format!("rbspy@{}", env!("CARGO_PKG_VERSION"))),
-
-            profiles: samples
-                .iter()
-                .map(|(option_pid, samples)| Profile {
-                    profile_type: ProfileType::Sampled,
-
-                    name: option_pid.map_or("rbspy profile".to_string(), |pid| {
-                        format!("rbspy profile - pid {}", pid)
-                    }),
-
-                    unit: ValueUnit::Seconds,
-
-                    start_value: 0.0,
-                    end_value: end_value as f64,
-
-                    samples: samples.clone(),
-                    weights: weights.clone(),
-                })
-                .collect(),
-
-            shared: Shared { frames },
-        }
-    }
-}
-
 impl Frame {
     pub fn new(stack_frame: &StackFrame) -> Frame {
         Frame {
@@ -146,13 +134,108 @@ impl Frame {
             col: None,
         }
     }
+
+    fn to_stack_frame(&self) -> StackFrame {
+        StackFrame {
+            name: self.name.clone(),
+            relative_path: self.file.clone().unwrap_or_else(|| "(unknown)".to_string()),
+            absolute_path: None,
+            lineno: self.line,
+            kind: FrameKind::Unknown,
+        }
+    }
 }
 
+impl ValueUnit {
+    /// How long a value lasted, or for units that aren't times, a duration in microseconds that
+    /// gives the trace the same weight
+    fn interval(&self, value: f64) -> Duration {
+        let value = value.max(0.0);
+        match self {
+            ValueUnit::Seconds => Duration::from_secs_f64(value),
+            ValueUnit::Milliseconds => Duration::from_secs_f64(value / 1e3),
+            ValueUnit::Microseconds => Duration::from_secs_f64(value / 1e6),
+            ValueUnit::Nanoseconds => Duration::from_secs_f64(value / 1e9),
+            ValueUnit::Bytes | ValueUnit::None => Duration::from_micros(value.round() as u64),
+        }
+    }
+}
+
+/// Reads the samples from a speedscope profile as stack traces. Evented profiles (e.g. from
+/// instrumenting profilers) become one trace for each span of time between events.
+pub(crate) fn read_traces(r: &mut dyn Read) -> Result<Vec<StackTrace>> {
+    let file: SpeedscopeFile = serde_json::from_reader(r)?;
+    let frames: Vec<StackFrame> = file
+        .shared
+        .frames
+        .iter()
+        .map(Frame::to_stack_frame)
+        .collect();
+    let frame = |index: usize| {
+        frames
+            .get(index)
+            .cloned()
+            .ok_or_else(|| format_err!("Unknown frame {}", index))
+    };
+    let trace = |stack: &[usize], interval: Duration| -> Result<StackTrace> {
+        let mut trace = StackTrace::new_empty();
+        trace.trace = stack
+            .iter()
+            .rev()
+            .map(|&i| frame(i))
+            .collect::<Result<_>>()?;
+        trace.interval = Some(interval);
+        Ok(trace)
+    };
+
+    let mut traces = Vec::new();
+    for profile in &file.profiles {
+        match profile.profile_type {
+            ProfileType::Sampled => {
+                for (i, stack) in profile.samples.iter().enumerate() {
+                    let weight = profile.weights.get(i).copied().unwrap_or(1.0);
+                    traces.push(trace(stack, profile.unit.interval(weight))?);
+                }
+            }
+            ProfileType::Evented => {
+                let mut stack = Vec::new();
+                let mut last_at = profile.start_value;
+                for event in &profile.events {
+                    if !stack.is_empty() && event.at > last_at {
+                        traces.push(trace(&stack, profile.unit.interval(event.at - last_at))?);
+                    }
+                    last_at = event.at;
+                    match event.event_type {
+                        EventType::OpenFrame => stack.push(event.frame),
+                        EventType::CloseFrame => {
+                            stack.pop();
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(traces)
+}
+
+/// A sampled profile of each thread, so that the stacks of different threads (or processes) can
+/// be looked at on their own instead of being mixed together
 #[derive(Default)]
 pub struct Stats {
-    samples: HashMap<Option<Pid>, Vec<Vec<usize>>>,
+    threads: HashMap<(Option<Pid>, Option<usize>), SampledThread>,
     frames: Vec<Frame>,
     frame_to_index: HashMap<StackFrame, usize>,
+    description: Option<String>,
+    metadata: Option<ProfileMetadata>,
+    /// Whether the weights are numbers of allocated objects rather than seconds (see
+    /// `StackTrace::allocated`)
+    allocations: bool,
+}
+
+struct SampledThread {
+    name: String,
+    /// Indices of the frames of each sample, outermost first
+    samples: Vec<Vec<usize>>,
     weights: Vec<f64>,
     prev_time: Option<SystemTime>,
 }
@@ -177,35 +260,435 @@ impl Stats {
             .collect();
         frame_indices.reverse();
 
-        self.samples
-            .entry(stack.pid)
-            .or_insert_with(|| vec![])
-            .push(frame_indices);
-
-        if let Some(time) = stack.time {
-            if let Some(prev_time) = self.prev_time {
+        // Ruby threads are identified by their VM struct, which outlives changes of native
+        // thread
+        let thread_id = stack.thread_address.or(stack.thread_id);
+        let thread = self
+            .threads
+            .entry((stack.pid, thread_id))
+            .or_insert_with(|| SampledThread {
+                name: thread_name(stack),
+                samples: Vec::new(),
+                weights: Vec::new(),
+                prev_time: None,
+            });
+        thread.samples.push(frame_indices);
+
+        if let Some(allocated) = stack.allocated {
+            thread.weights.push(allocated as f64);
+            self.allocations = true;
+        } else if let Some(interval) = stack.interval {
+            thread.weights.push(interval.as_secs_f64());
+        } else if let Some(time) = stack.time {
+            if let Some(prev_time) = thread.prev_time {
                 let delta = time.duration_since(prev_time)?;
-                self.weights.push(delta.as_secs_f64());
+                thread.weights.push(delta.as_secs_f64());
             } else {
                 // drop first sample, since we have no delta to compare against
-                self.weights.push(0.0);
+                thread.weights.push(0.0);
             }
-            self.prev_time = stack.time;
+            thread.prev_time = stack.time;
         } else {
             // support for import from old profiles that have no timestamps
-            self.weights.push(1.0);
+            thread.weights.push(f64::from(1 + stack.repeats));
         }
 
         Ok(())
     }
 
+    /// Adds a description of what was profiled to the profile names
+    pub fn set_description(&mut self, description: String) {
+        self.description = Some(description).filter(|description| !description.is_empty());
+    }
+
+    /// Adds a description of what was profiled to the profile names, and the metadata itself to
+    /// the file
+    pub fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.set_description(metadata.describe());
+        self.metadata = Some(metadata.clone());
+    }
+
+    pub fn write(&self, mut w: &mut dyn Write) -> Result<()> {
+        let profile_name = |name: &str| match &self.description {
+            Some(description) => format!("{} - {}", name, description),
+            None => name.to_string(),
+        };
+        let mut threads: Vec<&SampledThread> = self.threads.values().collect();
+        threads.sort_by(|a, b| a.name.cmp(&b.name));
+        let profiles = threads
+            .into_iter()
+            .map(|thread| Profile {
+                profile_type: ProfileType::Sampled,
+                name: profile_name(&thread.name),
+                unit: if self.allocations {
+                    ValueUnit::None
+                } else {
+                    ValueUnit::Seconds
+                },
+                start_value: 0.0,
+                end_value: thread.weights.iter().sum(),
+                samples: thread.samples.clone(),
+                weights: thread.weights.clone(),
+                events: vec![],
+            })
+            .collect();
+        let file = SpeedscopeFile {
+            // This is always the same
+            schema: "https://www.speedscope.app/file-format-schema.json".to_string(),
+            profiles,
+            shared: Shared {
+                frames: self.frames.clone(),
+            },
+            active_profile_index: None,
+            exporter: Some(format!("rbspy@{}", env!("CARGO_PKG_VERSION"))),
+            name: Some(profile_name("rbspy profile")),
+            metadata: self.metadata.clone(),
+        };
+        writeln!(&mut w, "{}", serde_json::to_string(&file)?)?;
+        Ok(())
+    }
+}
+
+/// How long a sample lasts when it has no interval, e.g. in old recordings. That's the interval
+/// at rbspy's default sample rate.
+const DEFAULT_INTERVAL: f64 = 0.01;
+
+/// A sample of a thread in a flame chart, taken `at` seconds into the profile
+struct Sample {
+    at: f64,
+    duration: f64,
+    /// Indices of the frames, outermost first
+    stack: Vec<usize>,
+}
+
+/// A flame chart: the samples of each thread laid out along a time axis, so that phases of a
+/// program (e.g. booting, warming up, and bursts of requests) show up one after another instead
+/// of being added together like they are in a flamegraph
+#[derive(Default)]
+pub struct Flamechart {
+    threads: HashMap<(Option<Pid>, Option<usize>), Thread>,
+    frames: FrameTable<Frame>,
+    start_time: Option<SystemTime>,
+    untimed_clock: UntimedClock<f64>,
+    description: Option<String>,
+    metadata: Option<ProfileMetadata>,
+}
+
+struct Thread {
+    name: String,
+    samples: Vec<Sample>,
+}
+
+impl Flamechart {
+    pub fn new() -> Flamechart {
+        Default::default()
+    }
+
+    pub fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        let indices = self.frames.indices(&stack.trace, Frame::new);
+
+        let duration = match stack.interval {
+            Some(interval) => interval.as_secs_f64(),
+            None => DEFAULT_INTERVAL * f64::from(1 + stack.repeats),
+        };
+        let at = match stack.time {
+            Some(time) => {
+                let start_time = *self.start_time.get_or_insert(time);
+                // Samples from different threads can arrive slightly out of order
+                match time.duration_since(start_time) {
+                    Ok(since_start) => since_start.as_secs_f64(),
+                    Err(_) => 0.0,
+                }
+            }
+            None => self.untimed_clock.tick(duration),
+        };
+
+        // Ruby threads are identified by their VM struct, which outlives changes of native
+        // thread
+        let thread_id = stack.thread_address.or(stack.thread_id);
+        let thread = self
+            .threads
+            .entry((stack.pid, thread_id))
+            .or_insert_with(|| Thread {
+                name: thread_name(stack),
+                samples: Vec::new(),
+            });
+        thread.samples.push(Sample {
+            at,
+            duration,
+            stack: indices,
+        });
+        Ok(())
+    }
+
+    /// Adds a description of what was profiled to the profile names
+    pub fn set_description(&mut self, description: String) {
+        self.description = Some(description).filter(|description| !description.is_empty());
+    }
+
+    /// Adds a description of what was profiled to the profile names, and the metadata itself to
+    /// the file
+    pub fn set_metadata(&mut self, metadata: &ProfileMetadata) {
+        self.set_description(metadata.describe());
+        self.metadata = Some(metadata.clone());
+    }
+
     pub fn write(&self, mut w: &mut dyn Write) -> Result<()> {
-        let json = serde_json::to_string(&SpeedscopeFile::new(
-            self.samples.clone(),
-            self.frames.clone(),
-            self.weights.clone(),
-        ))?;
-        writeln!(&mut w, "{}", json)?;
+        let profile_name = |name: &str| match &self.description {
+            Some(description) => format!("{} - {}", name, description),
+            None => name.to_string(),
+        };
+        let mut threads: Vec<&Thread> = self.threads.values().collect();
+        threads.sort_by(|a, b| a.name.cmp(&b.name));
+        let profiles = threads
+            .into_iter()
+            .map(|thread| {
+                let events = events(&thread.samples);
+                Profile {
+                    profile_type: ProfileType::Evented,
+                    name: profile_name(&thread.name),
+                    unit: ValueUnit::Seconds,
+                    start_value: events.first().map_or(0.0, |event| event.at),
+                    end_value: events.last().map_or(0.0, |event| event.at),
+                    samples: vec![],
+                    weights: vec![],
+                    events,
+                }
+            })
+            .collect();
+        let file = SpeedscopeFile {
+            schema: "https://www.speedscope.app/file-format-schema.json".to_string(),
+            profiles,
+            shared: Shared {
+                frames: self.frames.frames().to_vec(),
+            },
+            active_profile_index: None,
+            exporter: Some(format!("rbspy@{}", env!("CARGO_PKG_VERSION"))),
+            name: Some(profile_name("rbspy flame chart")),
+            metadata: self.metadata.clone(),
+        };
+        writeln!(&mut w, "{}", serde_json::to_string(&file)?)?;
         Ok(())
     }
 }
+
+fn thread_name(stack: &StackTrace) -> String {
+    let mut name = match stack.pid {
+        Some(pid) => format!("pid {}", pid),
+        None => "rbspy".to_string(),
+    };
+    if let Some(thread_name) = &stack.thread_name {
+        name.push_str(&format!(" - {}", thread_name));
+    } else if let Some(thread_id) = stack.os_thread_id.or(stack.thread_id) {
+        name.push_str(&format!(" - thread {}", thread_id));
+    }
+    name
+}
+
+/// Turns a thread's samples into events that open and close frames. Each sample lasts until the
+/// thread's next sample, unless the thread wasn't sampled for a while (e.g. because it was idle
+/// and only on-CPU samples were kept), in which case its stack is closed after the sample's own
+/// interval.
+fn events(samples: &[Sample]) -> Vec<Event> {
+    let mut samples: Vec<&Sample> = samples.iter().collect();
+    samples.sort_by(|a, b| a.at.partial_cmp(&b.at).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut events = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    let mut last_end = 0.0;
+    let close = |open: &mut Vec<usize>, events: &mut Vec<Event>, keep: usize, at: f64| {
+        while open.len() > keep {
+            let frame = open.pop().unwrap_or_default();
+            events.push(Event {
+                event_type: EventType::CloseFrame,
+                at,
+                frame,
+            });
+        }
+    };
+    for (i, sample) in samples.iter().enumerate() {
+        if sample.at > last_end {
+            close(&mut open, &mut events, 0, last_end);
+        }
+        let at = sample.at.max(last_end);
+        let common = open
+            .iter()
+            .zip(&sample.stack)
+            .take_while(|(open, frame)| open == frame)
+            .count();
+        close(&mut open, &mut events, common, at);
+        for &frame in &sample.stack[common..] {
+            events.push(Event {
+                event_type: EventType::OpenFrame,
+                at,
+                frame,
+            });
+            open.push(frame);
+        }
+        let end = sample.at + sample.duration;
+        last_end = match samples.get(i + 1) {
+            // Allow for samples that are late
+            Some(next) if next.at <= sample.at + 2.0 * sample.duration => next.at,
+            _ => end,
+        }
+        .max(at);
+    }
+    close(&mut open, &mut events, 0, last_end);
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_evented_traces() {
+        let json = r#"{
+            "shared": {"frames": [{"name": "main", "file": "a.rb"}, {"name": "work", "line": 3}]},
+            "profiles": [{
+                "type": "evented", "name": "stackprof", "unit": "milliseconds",
+                "startValue": 0, "endValue": 10,
+                "events": [
+                    {"type": "O", "at": 0, "frame": 0},
+                    {"type": "O", "at": 2, "frame": 1},
+                    {"type": "C", "at": 7, "frame": 1},
+                    {"type": "C", "at": 10, "frame": 0}
+                ]
+            }]
+        }"#;
+        let traces = read_traces(&mut json.as_bytes()).unwrap();
+        let stacks: Vec<(Vec<&str>, Option<Duration>)> = traces
+            .iter()
+            .map(|trace| {
+                let names = trace.trace.iter().map(|f| f.name.as_str()).collect();
+                (names, trace.interval)
+            })
+            .collect();
+        assert_eq!(
+            stacks,
+            vec![
+                (vec!["main"], Some(Duration::from_millis(2))),
+                (vec!["work", "main"], Some(Duration::from_millis(5))),
+                (vec!["main"], Some(Duration::from_millis(3))),
+            ]
+        );
+        assert_eq!(traces[1].trace[0].relative_path, "(unknown)");
+        assert_eq!(traces[1].trace[0].lineno, Some(3));
+    }
+
+    #[test]
+    fn test_flamechart() {
+        let frame = |name: &str| StackFrame {
+            name: name.to_string(),
+            relative_path: "app.rb".to_string(),
+            absolute_path: None,
+            lineno: Some(1),
+            kind: FrameKind::Method,
+        };
+        let start = SystemTime::now();
+        let mut chart = Flamechart::new();
+        // Boots, then handles a request after a while of being idle
+        for (ms, names) in [
+            (0, vec!["require", "main"]),
+            (10, vec!["require", "main"]),
+            (20, vec!["run", "main"]),
+            (500, vec!["handle", "run", "main"]),
+        ] {
+            let mut trace = StackTrace::new_empty();
+            trace.pid = Some(1);
+            trace.thread_id = Some(7);
+            trace.trace = names.into_iter().map(frame).collect();
+            trace.time = Some(start + Duration::from_millis(ms));
+            trace.interval = Some(Duration::from_millis(10));
+            chart.record(&trace).unwrap();
+        }
+        chart.set_description("puma".to_string());
+        let mut output = Vec::new();
+        chart.write(&mut output).unwrap();
+
+        let file: SpeedscopeFile = serde_json::from_slice(&output).unwrap();
+        assert_eq!(file.profiles.len(), 1);
+        assert_eq!(file.profiles[0].name, "pid 1 - thread 7 - puma");
+        let name = |event: &Event| file.shared.frames[event.frame].name.as_str();
+        let events: Vec<(EventType, f64, &str)> = file.profiles[0]
+            .events
+            .iter()
+            .map(|event| (event.event_type, (event.at * 1000.0).round(), name(event)))
+            .collect();
+        use EventType::{CloseFrame as C, OpenFrame as O};
+        assert_eq!(
+            events,
+            vec![
+                (O, 0.0, "main"),
+                (O, 0.0, "require"),
+                (C, 20.0, "require"),
+                (O, 20.0, "run"),
+                (C, 30.0, "run"),
+                (C, 30.0, "main"),
+                (O, 500.0, "main"),
+                (O, 500.0, "run"),
+                (O, 500.0, "handle"),
+                (C, 510.0, "handle"),
+                (C, 510.0, "run"),
+                (C, 510.0, "main"),
+            ]
+        );
+
+        // The flame chart reads back as the time that each stack was on the CPU
+        let traces = read_traces(&mut output.as_slice()).unwrap();
+        let durations: Vec<u128> = traces
+            .iter()
+            .map(|trace| trace.interval.unwrap().as_millis())
+            .collect();
+        assert_eq!(durations, vec![20, 10, 10]);
+    }
+
+    #[test]
+    fn test_read_sampled_traces() {
+        let mut stats = Stats::new();
+        let mut trace = StackTrace::new_empty();
+        trace.trace = vec![StackFrame::unknown_c_function()];
+        trace.interval = Some(Duration::from_millis(10));
+        stats.record(&trace).unwrap();
+        let mut output = Vec::new();
+        stats.write(&mut output).unwrap();
+        let traces = read_traces(&mut output.as_slice()).unwrap();
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].trace[0].name, trace.trace[0].name);
+        assert_eq!(traces[0].interval, trace.interval);
+    }
+
+    #[test]
+    fn test_profile_per_thread() {
+        let mut stats = Stats::new();
+        for (pid, thread_id, ms) in [(1, 7, 10), (1, 8, 20), (1, 7, 10), (2, 7, 30)] {
+            let mut trace = StackTrace::new_empty();
+            trace.pid = Some(pid);
+            trace.thread_id = Some(thread_id);
+            trace.trace = vec![StackFrame::unknown_c_function()];
+            trace.interval = Some(Duration::from_millis(ms));
+            stats.record(&trace).unwrap();
+        }
+        let mut output = Vec::new();
+        stats.write(&mut output).unwrap();
+
+        let file: SpeedscopeFile = serde_json::from_slice(&output).unwrap();
+        let profiles: Vec<(&str, usize, f64)> = file
+            .profiles
+            .iter()
+            .map(|profile| {
+                let end = (profile.end_value * 1000.0).round();
+                (profile.name.as_str(), profile.samples.len(), end)
+            })
+            .collect();
+        assert_eq!(
+            profiles,
+            vec![
+                ("pid 1 - thread 7", 2, 20.0),
+                ("pid 1 - thread 8", 1, 20.0),
+                ("pid 2 - thread 7", 1, 30.0),
+            ]
+        );
+        assert_eq!(file.shared.frames.len(), 1);
+    }
+}
@@ -1,45 +1,82 @@
 use anyhow::Result;
 use std::collections::{HashMap, HashSet};
 use std::io;
+use std::path::Path;
 
-use crate::core::types::StackFrame;
+use crate::core::types::{StackFrame, StackTrace, SummaryGroup, SummarySort};
+use crate::ui::gc;
 
 struct Counts {
     self_: u64,
     total: u64,
 }
 
+/// How much of a profile was spent in a function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionStats {
+    pub name: String,
+    /// The weight of the traces where the function was running
+    pub self_weight: u64,
+    /// The weight of the traces where the function was on the stack
+    pub total_weight: u64,
+}
+
+/// How a summary is ordered, cut down and displayed
+#[derive(Clone, Debug, Default)]
+pub struct SummaryOptions {
+    /// The order of the functions. Default: by self time.
+    pub sort: SummarySort,
+    /// Only show this many functions. Default: none (show every function).
+    pub top: Option<usize>,
+    /// Only show functions with at least this percentage of self or total time. Default: none.
+    pub min_percent: Option<f64>,
+    /// Only show functions whose name (including the file and line) contains this text, ignoring
+    /// case. Percentages are still of the whole profile. Default: none.
+    pub filter: Option<String>,
+    /// Color each line by its share of self time, with ANSI escape codes, so that the hottest
+    /// functions stand out in a terminal. Default: `false`.
+    pub color: bool,
+    /// Add up the time of bigger groups of frames than functions (or lines), e.g. to see the time
+    /// spent in each gem. Frames are grouped as they're recorded, so this has to be set before
+    /// any are. Default: none (each function, or line for the by-line summary).
+    pub group_by: Option<SummaryGroup>,
+}
+
 pub struct Stats {
     counts: HashMap<String, Counts>,
     start_time: std::time::Instant,
-    total_traces: u32,
+    total_weight: u64,
+    gc: gc::Stats,
 }
 
 impl Stats {
     const HEADER: &'static str = "% self  % total  name";
+    /// How many of the stacks that started GC pauses to show
+    const GC_TRIGGERS: usize = 10;
 
     pub fn new() -> Stats {
         Stats {
             counts: HashMap::new(),
             start_time: std::time::Instant::now(),
-            total_traces: 0,
+            total_weight: 0,
+            gc: gc::Stats::new(),
         }
     }
 
-    fn inc_self(&mut self, name: String) {
+    fn inc_self(&mut self, name: String, weight: u64) {
         let entry = self
             .counts
             .entry(name)
             .or_insert(Counts { self_: 0, total: 0 });
-        entry.self_ += 1;
+        entry.self_ += weight;
     }
 
-    fn inc_tot(&mut self, name: String) {
+    fn inc_tot(&mut self, name: String, weight: u64) {
         let entry = self
             .counts
             .entry(name)
             .or_insert(Counts { self_: 0, total: 0 });
-        entry.total += 1;
+        entry.total += weight;
     }
 
     fn name_function(frame: &StackFrame) -> String {
@@ -55,39 +92,76 @@ impl Stats {
     }
 
     // Aggregate by function name
-    pub fn add_function_name(&mut self, stack: &[StackFrame]) {
+    pub fn add_function_name(&mut self, stack: &[StackFrame], weight: u64) {
         if stack.is_empty() {
             return;
         }
-        self.total_traces += 1;
-        self.inc_self(Stats::name_function(&stack[0]));
+        self.total_weight += weight;
+        self.inc_self(Stats::name_function(&stack[0]), weight);
         let mut set: HashSet<String> = HashSet::new();
         for frame in stack {
             set.insert(Stats::name_function(frame));
         }
         for name in set.into_iter() {
-            self.inc_tot(name);
+            self.inc_tot(name, weight);
         }
     }
 
     // Aggregate by function name + line number
-    pub fn add_lineno(&mut self, stack: &[StackFrame]) {
+    pub fn add_lineno(&mut self, stack: &[StackFrame], weight: u64) {
         if stack.is_empty() {
             return;
         }
-        self.total_traces += 1;
-        self.inc_self(Stats::name_lineno(&stack[0]));
+        self.total_weight += weight;
+        self.inc_self(Stats::name_lineno(&stack[0]), weight);
         let mut set: HashSet<&StackFrame> = HashSet::new();
         for frame in stack {
             set.insert(&frame);
         }
         for frame in set {
-            self.inc_tot(Stats::name_lineno(frame));
+            self.inc_tot(Stats::name_lineno(frame), weight);
+        }
+    }
+
+    /// Aggregate by a group of frames, e.g. the gem they're from
+    pub fn add_grouped(&mut self, stack: &[StackFrame], weight: u64, group: SummaryGroup) {
+        if stack.is_empty() {
+            return;
         }
+        self.total_weight += weight;
+        self.inc_self(Stats::name_group(&stack[0], group), weight);
+        let set: HashSet<String> = stack
+            .iter()
+            .map(|frame| Stats::name_group(frame, group))
+            .collect();
+        for name in set {
+            self.inc_tot(name, weight);
+        }
+    }
+
+    fn name_group(frame: &StackFrame, group: SummaryGroup) -> String {
+        match group {
+            SummaryGroup::function => Stats::name_function(frame),
+            SummaryGroup::file => frame.relative_path.clone(),
+            SummaryGroup::gem => match frame.gem() {
+                Some(gem) => gem.to_string(),
+                None => format!("<{}>", frame.category()),
+            },
+            SummaryGroup::directory => match Path::new(&frame.relative_path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.display().to_string(),
+                _ => ".".to_string(),
+            },
+        }
+    }
+
+    /// Adds the trace's VM counters, if it has any, to the garbage collection estimates that are
+    /// written after the functions
+    pub fn add_vm_stats(&mut self, trace: &StackTrace) {
+        self.gc.record(trace);
     }
 
     pub fn write(&self, w: &mut dyn io::Write) -> Result<()> {
-        self.write_counts(w, None, None)
+        self.write_with_options(w, &SummaryOptions::default(), None)
     }
 
     pub fn write_top_n(
@@ -96,45 +170,116 @@ impl Stats {
         n: usize,
         truncate: Option<usize>,
     ) -> Result<()> {
-        self.write_counts(w, Some(n), truncate)
-    }
-
-    pub fn elapsed_time(&self) -> std::time::Duration {
-        std::time::Instant::now() - self.start_time
+        let options = SummaryOptions {
+            top: Some(n),
+            ..Default::default()
+        };
+        self.write_with_options(w, &options, truncate)
     }
 
-    fn write_counts(
+    /// Writes the summary with the given ordering, cutoffs and colors. If `truncate` is given,
+    /// lines are cut down to that many characters.
+    pub fn write_with_options(
         &self,
         w: &mut dyn io::Write,
-        top: Option<usize>,
+        options: &SummaryOptions,
         truncate: Option<usize>,
     ) -> Result<()> {
-        let top = top.unwrap_or(::std::usize::MAX);
-        let truncate = truncate.unwrap_or(::std::usize::MAX);
-        let mut sorted: Vec<(u64, u64, &str)> = self
-            .counts
+        let percent = |weight: u64| 100.0 * (weight as f64) / (self.total_weight as f64);
+        let mut functions = self.functions();
+        match options.sort {
+            SummarySort::self_ => {}
+            SummarySort::total => functions.sort_by(|a, b| {
+                (b.total_weight, b.self_weight, &a.name).cmp(&(
+                    a.total_weight,
+                    a.self_weight,
+                    &b.name,
+                ))
+            }),
+            SummarySort::name => functions.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+        let min_percent = options.min_percent.unwrap_or(0.0);
+        let filter = options.filter.as_ref().map(|filter| filter.to_lowercase());
+        let functions = functions
             .iter()
-            .map(|(x, y)| (y.self_, y.total, x.as_ref()))
-            .collect();
-        sorted.sort_unstable();
-        let counts = sorted.iter().rev().take(top);
+            .filter(|f| percent(f.self_weight).max(percent(f.total_weight)) >= min_percent)
+            .filter(|f| match &filter {
+                Some(filter) => f.name.to_lowercase().contains(filter.as_str()),
+                None => true,
+            })
+            .take(options.top.unwrap_or(usize::MAX));
+
         writeln!(w, "{}", Stats::HEADER)?;
-        for &(self_, total, name) in counts {
-            writeln!(
-                w,
-                "{:>6.2} {:>8.2}  {:.*}",
-                100.0 * (self_ as f64) / f64::from(self.total_traces),
-                100.0 * (total as f64) / f64::from(self.total_traces),
-                truncate - 14 - 3,
-                name
-            )?;
+        for function in functions {
+            let self_percent = percent(function.self_weight);
+            let mut line = format!(
+                "{:>6.2} {:>8.2}  {}",
+                self_percent,
+                percent(function.total_weight),
+                function.name
+            );
+            if let Some(width) = truncate {
+                if let Some((end, _)) = line.char_indices().nth(width.saturating_sub(3)) {
+                    line.truncate(end);
+                }
+            }
+            match heat_color(self_percent) {
+                Some(color) if options.color => writeln!(w, "{}{}\x1b[0m", color, line)?,
+                _ => writeln!(w, "{}", line)?,
+            }
+        }
+        if !self.gc.is_empty() {
+            writeln!(w)?;
+            self.gc.write(w, Stats::GC_TRIGGERS)?;
         }
         Ok(())
     }
+
+    /// The stats for each function, or line, with the most self weight first
+    pub fn functions(&self) -> Vec<FunctionStats> {
+        let mut functions: Vec<FunctionStats> = self
+            .counts
+            .iter()
+            .map(|(name, counts)| FunctionStats {
+                name: name.clone(),
+                self_weight: counts.self_,
+                total_weight: counts.total,
+            })
+            .collect();
+        functions.sort_unstable_by(|a, b| {
+            (b.self_weight, b.total_weight, &b.name).cmp(&(a.self_weight, a.total_weight, &a.name))
+        });
+        functions
+    }
+
+    pub fn total_weight(&self) -> u64 {
+        self.total_weight
+    }
+
+    pub fn elapsed_time(&self) -> std::time::Duration {
+        std::time::Instant::now() - self.start_time
+    }
+}
+
+/// The ANSI color for a line in the summary, from red for the functions that take the most time
+/// to no color for the ones that barely show up
+fn heat_color(self_percent: f64) -> Option<&'static str> {
+    if self_percent >= 20.0 {
+        Some("\x1b[1;31m")
+    } else if self_percent >= 10.0 {
+        Some("\x1b[31m")
+    } else if self_percent >= 5.0 {
+        Some("\x1b[33m")
+    } else if self_percent >= 1.0 {
+        Some("\x1b[32m")
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::core::types::FrameKind;
     use crate::ui::summary::*;
 
     // Build a test stackframe
@@ -144,6 +289,7 @@ mod tests {
             relative_path: format!("file{}.rb", i),
             absolute_path: None,
             lineno: Some(i),
+            kind: FrameKind::Method,
         }
     }
 
@@ -151,11 +297,11 @@ mod tests {
     fn stats_by_function() {
         let mut stats = Stats::new();
 
-        stats.add_function_name(&vec![f(1)]);
-        stats.add_function_name(&vec![f(3), f(2), f(1)]);
-        stats.add_function_name(&vec![f(2), f(1)]);
-        stats.add_function_name(&vec![f(3), f(1)]);
-        stats.add_function_name(&vec![f(2), f(3), f(1)]);
+        stats.add_function_name(&vec![f(1)], 1);
+        stats.add_function_name(&vec![f(3), f(2), f(1)], 1);
+        stats.add_function_name(&vec![f(2), f(1)], 1);
+        stats.add_function_name(&vec![f(3), f(1)], 1);
+        stats.add_function_name(&vec![f(2), f(3), f(1)], 1);
 
         let expected = "% self  % total  name
  40.00    60.00  func3 - file3.rb:3
@@ -173,11 +319,11 @@ mod tests {
     fn stats_by_line_number() {
         let mut stats = Stats::new();
 
-        stats.add_lineno(&vec![f(1)]);
-        stats.add_lineno(&vec![f(3), f(2), f(1)]);
-        stats.add_lineno(&vec![f(2), f(1)]);
-        stats.add_lineno(&vec![f(3), f(1)]);
-        stats.add_lineno(&vec![f(2), f(3), f(1)]);
+        stats.add_lineno(&vec![f(1)], 1);
+        stats.add_lineno(&vec![f(3), f(2), f(1)], 1);
+        stats.add_lineno(&vec![f(2), f(1)], 1);
+        stats.add_lineno(&vec![f(3), f(1)], 1);
+        stats.add_lineno(&vec![f(2), f(3), f(1)], 1);
 
         let expected = "% self  % total  name
  40.00    60.00  func3 - file3.rb:3
@@ -190,4 +336,149 @@ mod tests {
         let actual = String::from_utf8(buf).expect("summary output not utf8");
         assert_eq!(actual, expected, "Unexpected summary output");
     }
+
+    #[test]
+    fn function_stats() {
+        let mut stats = Stats::new();
+
+        stats.add_function_name(&vec![f(1)], 10);
+        stats.add_function_name(&vec![f(2), f(1)], 30);
+
+        let stats_for = |name: &str, self_weight, total_weight| FunctionStats {
+            name: name.to_string(),
+            self_weight,
+            total_weight,
+        };
+        assert_eq!(
+            stats.functions(),
+            vec![
+                stats_for("func2 - file2.rb:2", 30, 30),
+                stats_for("func1 - file1.rb:1", 10, 40),
+            ]
+        );
+        assert_eq!(stats.total_weight(), 40);
+    }
+
+    #[test]
+    fn summary_options() {
+        let mut stats = Stats::new();
+
+        stats.add_function_name(&vec![f(1)], 1);
+        stats.add_function_name(&vec![f(3), f(2), f(1)], 1);
+        stats.add_function_name(&vec![f(2), f(1)], 1);
+        stats.add_function_name(&vec![f(3), f(1)], 1);
+        stats.add_function_name(&vec![f(2), f(3), f(1)], 1);
+        stats.add_function_name(&vec![f(4), f(1)], 1);
+
+        let write = |options: SummaryOptions| {
+            let mut buf: Vec<u8> = Vec::new();
+            stats
+                .write_with_options(&mut buf, &options, None)
+                .expect("summary write failed");
+            String::from_utf8(buf).expect("summary output not utf8")
+        };
+
+        let by_total = write(SummaryOptions {
+            sort: SummarySort::total,
+            top: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(
+            by_total,
+            "% self  % total  name
+ 16.67   100.00  func1 - file1.rb:1
+ 33.33    50.00  func2 - file2.rb:2
+"
+        );
+
+        let by_name = write(SummaryOptions {
+            sort: SummarySort::name,
+            min_percent: Some(20.0),
+            ..Default::default()
+        });
+        assert_eq!(
+            by_name,
+            "% self  % total  name
+ 16.67   100.00  func1 - file1.rb:1
+ 33.33    50.00  func2 - file2.rb:2
+ 33.33    50.00  func3 - file3.rb:3
+"
+        );
+
+        let filtered = write(SummaryOptions {
+            filter: Some("FILE2".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            filtered,
+            "% self  % total  name
+ 33.33    50.00  func2 - file2.rb:2
+"
+        );
+
+        let colored = write(SummaryOptions {
+            top: Some(1),
+            color: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            colored,
+            "% self  % total  name
+\x1b[1;31m 33.33    50.00  func3 - file3.rb:3\x1b[0m
+"
+        );
+    }
+
+    #[test]
+    fn stats_by_group() {
+        let frame = |name: &str, path: &str| StackFrame {
+            name: name.to_string(),
+            relative_path: path.to_string(),
+            absolute_path: None,
+            lineno: Some(1),
+            kind: FrameKind::Method,
+        };
+        let app = frame("index", "app/controllers/posts_controller.rb");
+        let model = frame("find", "app/models/post.rb");
+        let rails = frame(
+            "call",
+            "vendor/bundle/gems/actionpack-7.0.4/lib/action_controller.rb",
+        );
+        let pg = frame("exec", "vendor/bundle/gems/pg-1.4.5/lib/pg/connection.rb");
+        let stacks = [
+            vec![pg.clone(), model.clone(), app.clone(), rails.clone()],
+            vec![pg, model, app.clone(), rails.clone()],
+            vec![app, rails.clone()],
+            vec![rails],
+        ];
+        let by = |group| {
+            let mut stats = Stats::new();
+            for stack in &stacks {
+                stats.add_grouped(stack, 1, group);
+            }
+            let mut buf: Vec<u8> = Vec::new();
+            stats.write(&mut buf).expect("summary write failed");
+            String::from_utf8(buf).expect("summary output not utf8")
+        };
+
+        assert_eq!(
+            by(SummaryGroup::gem),
+            "% self  % total  name
+ 50.00    50.00  pg-1.4.5
+ 25.00   100.00  actionpack-7.0.4
+ 25.00    75.00  <app>
+"
+        );
+        assert_eq!(
+            by(SummaryGroup::directory),
+            "% self  % total  name
+ 50.00    50.00  vendor/bundle/gems/pg-1.4.5/lib/pg
+ 25.00   100.00  vendor/bundle/gems/actionpack-7.0.4/lib
+ 25.00    75.00  app/controllers
+  0.00    50.00  app/models
+"
+        );
+        assert!(by(SummaryGroup::file)
+            .contains(" 25.00    75.00  app/controllers/posts_controller.rb\n"));
+    }
 }
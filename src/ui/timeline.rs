@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use crate::core::process::Pid;
+use crate::core::types::{StackTrace, ThreadState};
+use crate::ui::samples::UntimedClock;
+
+/// How much time each row of the timeline covers
+const BUCKET: Duration = Duration::from_secs(1);
+
+/// How long a sample lasts when it has no time, e.g. in old recordings. That's the interval at
+/// rbspy's default sample rate.
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A thread in the timeline: its process, and the Ruby VM's thread struct or the native thread
+type ThreadKey = (Option<Pid>, Option<usize>);
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    samples: u64,
+    running: u64,
+    blocked: u64,
+}
+
+/// A timeline of what each thread was doing during the recording: how many of its samples in
+/// each second found it running or blocked, and how many threads were sampled at all. It's a
+/// CSV file, e.g. for a spreadsheet or a plotting tool.
+#[derive(Default)]
+pub struct Stats {
+    buckets: BTreeMap<u64, BTreeMap<ThreadKey, Counts>>,
+    names: BTreeMap<ThreadKey, String>,
+    start_time: Option<SystemTime>,
+    untimed_clock: UntimedClock<Duration>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    pub fn record(&mut self, stack: &StackTrace) -> Result<()> {
+        let since_start = match stack.time {
+            Some(time) => {
+                let start_time = *self.start_time.get_or_insert(time);
+                time.duration_since(start_time).unwrap_or_default()
+            }
+            None => self
+                .untimed_clock
+                .tick(DEFAULT_INTERVAL * (1 + stack.repeats)),
+        };
+        let bucket = since_start.as_secs() / BUCKET.as_secs();
+        let key = (stack.pid, stack.thread_address.or(stack.thread_id));
+        self.names.entry(key).or_insert_with(|| thread_name(stack));
+
+        let samples = u64::from(1 + stack.repeats);
+        let counts = self
+            .buckets
+            .entry(bucket)
+            .or_default()
+            .entry(key)
+            .or_default();
+        counts.samples += samples;
+        match stack.thread_state {
+            Some(ThreadState::Running) => counts.running += samples,
+            Some(ThreadState::Blocked) => counts.blocked += samples,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Writes a row for each thread that was sampled in each second, after a row for all of the
+    /// threads together (`*`). Samples from recordings that don't have thread states are only
+    /// counted in `samples`.
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        writeln!(w, "time,pid,thread,threads,samples,running,blocked")?;
+        for (bucket, threads) in &self.buckets {
+            let time = bucket * BUCKET.as_secs();
+            let mut total = Counts::default();
+            let mut pids = BTreeSet::new();
+            for counts in threads.values() {
+                total.samples += counts.samples;
+                total.running += counts.running;
+                total.blocked += counts.blocked;
+            }
+            for (pid, _) in threads.keys() {
+                pids.insert(*pid);
+            }
+            let pid = match (pids.len(), pids.iter().next()) {
+                (1, Some(Some(pid))) => pid.to_string(),
+                _ => String::new(),
+            };
+            writeln!(
+                w,
+                "{},{},*,{},{},{},{}",
+                time,
+                pid,
+                threads.len(),
+                total.samples,
+                total.running,
+                total.blocked
+            )?;
+            for (key, counts) in threads {
+                writeln!(
+                    w,
+                    "{},{},{},1,{},{},{}",
+                    time,
+                    key.0.map(|pid| pid.to_string()).unwrap_or_default(),
+                    csv_field(&self.names[key]),
+                    counts.samples,
+                    counts.running,
+                    counts.blocked
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn thread_name(stack: &StackTrace) -> String {
+    if let Some(name) = &stack.thread_name {
+        return name.clone();
+    }
+    match (stack.os_thread_id, stack.thread_id) {
+        (Some(tid), _) => format!("thread {}", tid),
+        (None, Some(thread_id)) => format!("thread {:#x}", thread_id),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+/// Quotes a CSV field if it has to be
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline() {
+        let start = SystemTime::now();
+        let mut stats = Stats::new();
+        let samples = [
+            (0, 1, "puma, worker", ThreadState::Running),
+            (200, 1, "puma, worker", ThreadState::Blocked),
+            (300, 2, "reaper", ThreadState::Blocked),
+            (1500, 1, "puma, worker", ThreadState::Running),
+        ];
+        for (ms, address, name, state) in samples.iter() {
+            let mut trace = StackTrace::new_empty();
+            trace.pid = Some(42);
+            trace.thread_address = Some(*address);
+            trace.thread_name = Some(name.to_string());
+            trace.thread_state = Some(*state);
+            trace.time = Some(start + Duration::from_millis(*ms));
+            stats.record(&trace).unwrap();
+        }
+        let mut output = Vec::new();
+        stats.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "time,pid,thread,threads,samples,running,blocked\n\
+             0,42,*,2,3,1,2\n\
+             0,42,\"puma, worker\",1,2,1,1\n\
+             0,42,reaper,1,1,0,1\n\
+             1,42,*,1,1,1,0\n\
+             1,42,\"puma, worker\",1,1,1,0\n"
+        );
+    }
+}